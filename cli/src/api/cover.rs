@@ -1,24 +1,27 @@
 use blake2::{Blake2b512, Digest};
-use hex;
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read, path::Path, time::Duration};
+use std::{
+    fs::File,
+    io::{Cursor, Read},
+    path::Path,
+    time::Duration,
+};
 
-use crate::global_options;
-use crate::helpers::s3;
+use crate::KoishiClient;
 
-use super::request::{self, *};
+use super::request::*;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type Result<T> = crate::Result<T>;
 
 #[derive(Serialize)]
-struct Req {
-    hash: String,
+pub(crate) struct Req {
+    pub(crate) hash: String,
 }
 
 #[derive(Deserialize)]
-struct ResCover {
-    exists: bool,
-    url: Option<String>,
+pub(crate) struct ResCover {
+    pub(crate) exists: bool,
+    pub(crate) url: Option<String>,
 }
 
 pub struct UploadCoverResult {
@@ -26,29 +29,33 @@ pub struct UploadCoverResult {
     pub hash: String,
 }
 
-fn upload_url(hash: String) -> super::Result<ResCover> {
+fn upload_url(client: &KoishiClient, hash: String) -> super::Result<ResCover> {
     let req_body = Req { hash };
 
-    if global_options::DRY.get().unwrap().clone() {
-        println!("skipping request due to being dry run");
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
         return Ok(ResCover {
             exists: true,
             url: None,
         });
     }
 
-    request::post("cover").json(&req_body).send()?.api_result()
+    client
+        .post("cover")
+        .json(&req_body)
+        .send_api_replayable(client)
 }
 
-fn hash(content: &[u8]) -> String {
+pub(crate) fn hash(content: &[u8]) -> String {
     let digest = Blake2b512::digest(content);
     hex::encode(digest)
 }
 
-pub(crate) fn upload_cover(content: Vec<u8>) -> Result<UploadCoverResult> {
+pub fn upload_cover(client: &KoishiClient, content: Vec<u8>) -> Result<UploadCoverResult> {
     let hash = hash(content.as_slice());
+    let size = content.len() as u64;
 
-    let res_url = upload_url(hash.clone())?;
+    let res_url = upload_url(client, hash.clone())?;
     let ret = UploadCoverResult {
         exists: res_url.exists,
         hash,
@@ -57,20 +64,25 @@ pub(crate) fn upload_cover(content: Vec<u8>) -> Result<UploadCoverResult> {
         return Ok(ret);
     }
 
-    s3::Uploader::with_timeout(Duration::from_secs(300))?
+    client
+        .uploader()
+        .timeout(Duration::from_secs(300))
         .url(res_url.url.unwrap())
         .mimetype("image/jpeg")
-        .body(content)
+        .from_reader_sized(Cursor::new(content), size)
         .upload()?;
 
     Ok(ret)
 }
 
-pub(crate) fn upload_cover_from_file<P: AsRef<Path>>(path: P) -> Result<UploadCoverResult> {
+pub fn upload_cover_from_file<P: AsRef<Path>>(
+    client: &KoishiClient,
+    path: P,
+) -> Result<UploadCoverResult> {
     let mut f = File::open(path)?;
     let f_size = f.metadata()?.len();
     let mut buf = vec![0; f_size as usize];
-    f.read(&mut buf)?;
+    f.read_exact(&mut buf)?;
 
-    upload_cover(buf)
+    upload_cover(client, buf)
 }
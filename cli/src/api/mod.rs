@@ -2,12 +2,16 @@ use serde::Deserialize;
 use serde_json::Value as JsonValue;
 
 pub mod cover;
-mod request;
+mod pages;
+pub(crate) mod request;
 pub mod room;
 pub mod video;
 
+pub use pages::{MAX_PAGE_SIZE, Pages};
+pub use request::RetryPolicy;
+
 #[derive(Deserialize)]
-pub(crate) struct ServerError {
+pub struct ServerError {
     #[serde(rename = "error")]
     pub error_type: ServerErrorType,
     pub message: Option<String>,
@@ -20,10 +24,10 @@ impl std::fmt::Display for ServerError {
         if let Some(msg) = self.message.as_ref() {
             write!(f, ": {}", msg)?;
         };
-        if f.alternate() {
-            if let Some(details) = self.details.as_ref() {
-                write!(f, "\n{:#}\n", details)?;
-            }
+        if f.alternate()
+            && let Some(details) = self.details.as_ref()
+        {
+            write!(f, "\n{:#}\n", details)?;
         }
         Ok(())
     }
@@ -39,7 +43,7 @@ impl std::error::Error for ServerError {}
 
 #[derive(Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "snake_case")]
-pub(crate) enum ServerErrorType {
+pub enum ServerErrorType {
     BadRequest,
     Unauthorized,
     Forbidden,
@@ -75,13 +79,44 @@ impl std::fmt::Display for ServerErrorType {
     }
 }
 
-pub(crate) type Result<T> = std::result::Result<T, APIError>;
+pub type Result<T> = std::result::Result<T, APIError>;
 
-pub(crate) enum APIError {
+pub enum APIError {
     ServerError(ServerError),
     RequestError(reqwest::Error),
 }
 
+impl APIError {
+    /// Whether the error is likely transient, so that sending the same
+    /// request again may succeed.
+    ///
+    /// Timeouts, connection failures and server-side failures are; errors
+    /// about the request itself, like `Forbidden`, `Conflict` or
+    /// `UnprocessableEntity`, are not. Neither are failures to send the
+    /// request or read the response, which may have been carried out.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Self::ServerError(err) => matches!(
+                err.error_type,
+                ServerErrorType::InternalServerError
+                    | ServerErrorType::DBTransactionError
+                    | ServerErrorType::S3Error
+            ),
+            Self::RequestError(err) => {
+                err.is_timeout()
+                    || err.is_connect()
+                    || err.status().is_some_and(|s| s.is_server_error())
+            }
+        }
+    }
+
+    /// Whether the request failed before reaching the server, so that it
+    /// is safe to send again even if it is not idempotent.
+    pub fn is_unsent(&self) -> bool {
+        matches!(self, Self::RequestError(err) if err.is_connect())
+    }
+}
+
 impl From<reqwest::Error> for APIError {
     fn from(value: reqwest::Error) -> Self {
         APIError::RequestError(value)
@@ -166,4 +201,33 @@ mod tests {
         let value = from_str::<ServerErrorType>("\"some_random_error\"").unwrap();
         assert_eq!(value, ServerErrorType::Unknown("some_random_error".into()));
     }
+
+    #[test]
+    fn test_retriable_errors() {
+        let error = |error_type| {
+            APIError::ServerError(ServerError {
+                error_type,
+                message: None,
+                details: None,
+            })
+        };
+
+        assert!(error(ServerErrorType::InternalServerError).is_retriable());
+        assert!(error(ServerErrorType::DBTransactionError).is_retriable());
+        assert!(error(ServerErrorType::S3Error).is_retriable());
+        assert!(!error(ServerErrorType::Forbidden).is_retriable());
+        assert!(!error(ServerErrorType::Conflict).is_retriable());
+        assert!(!error(ServerErrorType::UnprocessableEntity).is_retriable());
+
+        let policy = RetryPolicy::attempts(3);
+        assert!(policy.should_retry(&error(ServerErrorType::S3Error), 2, true));
+        assert!(!policy.should_retry(&error(ServerErrorType::S3Error), 3, true));
+        // The server may have done the work before failing.
+        assert!(!policy.should_retry(&error(ServerErrorType::S3Error), 1, false));
+        for attempt in 1..=20 {
+            let delay = policy.delay(attempt);
+            assert!(delay <= policy.max_delay);
+            assert!(delay >= policy.base_delay / 2);
+        }
+    }
 }
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+use super::Result;
+
+/// Largest `limit` the server honours; bigger values are silently capped.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+type FetchPage<'a, T> = Box<dyn FnMut(u64, u64) -> Result<Vec<T>> + 'a>;
+
+/// Lazily walks a paginated listing, fetching the next page only once the
+/// current one has been consumed.
+///
+/// Iteration stops after the first page shorter than the page size, or after
+/// the first error, which is yielded as is.
+pub struct Pages<'a, T> {
+    fetch: FetchPage<'a, T>,
+    buffer: VecDeque<T>,
+    page_size: u64,
+    offset: u64,
+    done: bool,
+}
+
+impl<'a, T> Pages<'a, T> {
+    pub(crate) fn new<F>(fetch: F) -> Self
+    where
+        F: FnMut(u64, u64) -> Result<Vec<T>> + 'a,
+    {
+        Self {
+            fetch: Box::new(fetch),
+            buffer: VecDeque::new(),
+            page_size: MAX_PAGE_SIZE,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Number of entries requested per page, clamped to `1..=MAX_PAGE_SIZE`.
+    pub fn page_size(mut self, page_size: u64) -> Self {
+        self.page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+        self
+    }
+
+    /// Skip the first `offset` entries of the listing.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Fetch the next page, or `None` once the listing is exhausted. Use this
+    /// instead of iterating to handle entries a page at a time.
+    pub fn next_page(&mut self) -> Option<Result<Vec<T>>> {
+        if !self.buffer.is_empty() {
+            return Some(Ok(self.buffer.drain(..).collect()));
+        }
+        if self.done {
+            return None;
+        }
+
+        let page = match (self.fetch)(self.page_size, self.offset) {
+            Ok(page) => page,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        self.offset += page.len() as u64;
+        if (page.len() as u64) < self.page_size {
+            self.done = true;
+        }
+        if page.is_empty() {
+            return None;
+        }
+        Some(Ok(page))
+    }
+}
+
+impl<T> Iterator for Pages<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            match self.next_page()? {
+                Ok(page) => self.buffer.extend(page),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::{APIError, ServerError, ServerErrorType};
+
+    #[test]
+    fn test_pages_stop_on_short_page() {
+        let mut calls = vec![];
+        let items: Vec<u64> = Pages::new(|limit, offset| {
+            calls.push((limit, offset));
+            Ok((offset..(offset + limit).min(7)).collect())
+        })
+        .page_size(3)
+        .map(Result::unwrap)
+        .collect();
+
+        assert_eq!(items, (0..7).collect::<Vec<_>>());
+        assert_eq!(calls, [(3, 0), (3, 3), (3, 6)]);
+    }
+
+    #[test]
+    fn test_pages_empty_last_page() {
+        let mut calls = 0;
+        let pages = Pages::new(|limit, offset| {
+            calls += 1;
+            Ok((offset..(offset + limit).min(4)).collect::<Vec<u64>>())
+        })
+        .page_size(2);
+
+        assert_eq!(pages.count(), 4);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_pages_stop_after_error() {
+        let mut pages = Pages::<u64>::new(|_, _| {
+            Err(APIError::ServerError(ServerError {
+                error_type: ServerErrorType::InternalServerError,
+                message: None,
+                details: None,
+            }))
+        });
+
+        assert!(pages.next().unwrap().is_err());
+        assert!(pages.next().is_none());
+    }
+}
@@ -1,12 +1,67 @@
 use reqwest::{
-    Url,
-    blocking::{self as req, RequestBuilder, Response},
+    Method, Url,
+    blocking::{Request, RequestBuilder, Response},
 };
 use serde::{Deserialize, de::DeserializeOwned};
+use std::time::Duration;
 
-use crate::global_options;
+use crate::KoishiClient;
 
-use super::{Result, ServerError};
+use super::{APIError, Result, ServerError};
+
+/// How often and how patiently API requests are retried after errors
+/// [`APIError::is_retriable`] considers transient.
+///
+/// The wait before retry `n` is drawn between half and all of
+/// `base_delay * 2^(n - 1)`, capped at `max_delay`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one; `1` disables retrying.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn attempts(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// Policy that gives up after the first error.
+    pub fn none() -> Self {
+        Self::attempts(1)
+    }
+
+    /// Time to wait after failed attempt number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        exp / 2 + exp.mul_f64(fastrand::f64() / 2.0)
+    }
+
+    /// Whether `err` after attempt number `attempt` should be retried.
+    ///
+    /// Requests that are not `replayable`, like a `POST` that would do its
+    /// work twice, are only retried when they never reached the server.
+    pub(crate) fn should_retry(&self, err: &APIError, attempt: u32, replayable: bool) -> bool {
+        attempt < self.max_attempts && err.is_retriable() && (replayable || err.is_unsent())
+    }
+}
 
 #[derive(Deserialize)]
 pub(crate) struct APISuccess<T> {
@@ -15,46 +70,73 @@ pub(crate) struct APISuccess<T> {
 
 #[derive(Deserialize)]
 #[serde(untagged)]
-enum APIRawResult<T> {
+pub(crate) enum APIRawResult<T> {
     Error(ServerError),
     Success(APISuccess<T>),
 }
 
-fn concat_api_url<P: AsRef<str>>(path: P) -> Url {
-    let url = global_options::BASE_URL.get().unwrap();
-    let base = Url::parse(url).unwrap();
-    base.join(path.as_ref()).unwrap()
+impl<T> APIRawResult<T> {
+    pub(crate) fn into_result(self) -> Result<T> {
+        match self {
+            APIRawResult::Success(success) => Ok(success.result),
+            APIRawResult::Error(err) => Err(err.into()),
+        }
+    }
 }
 
-pub(super) fn get<P: AsRef<str>>(path: P) -> RequestBuilder {
-    let url = concat_api_url(path);
-    req::Client::new().get(url)
-}
+impl KoishiClient {
+    fn api_url<P: AsRef<str>>(&self, path: P) -> Url {
+        self.base_url().join(path.as_ref()).unwrap()
+    }
 
-pub(super) fn post<P: AsRef<str>>(path: P) -> RequestBuilder {
-    let url = concat_api_url(path);
-    req::Client::new().post(url).api_auth()
-}
+    fn request<P: AsRef<str>>(&self, method: Method, path: P) -> RequestBuilder {
+        let rb = self.http().request(method, self.api_url(path));
+        match self.timeout() {
+            Some(timeout) => rb.timeout(timeout),
+            None => rb,
+        }
+    }
+
+    pub(super) fn get<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::GET, path)
+    }
+
+    pub(super) fn post<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::POST, path).api_auth(self)
+    }
+
+    pub(super) fn put<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::PUT, path).api_auth(self)
+    }
+
+    pub(super) fn delete<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::DELETE, path).api_auth(self)
+    }
 
-pub(super) fn put<P: AsRef<str>>(path: P) -> RequestBuilder {
-    let url = concat_api_url(path);
-    req::Client::new().put(url).api_auth()
+    /// Send a request after charging its body to the rate limit, if any.
+    fn execute(&self, req: Request) -> reqwest::Result<Response> {
+        if let Some(limiter) = self.rate_limiter()
+            && let Some(body) = req.body().and_then(|b| b.as_bytes())
+        {
+            limiter.acquire(body.len() as u64);
+        }
+        self.http().execute(req)
+    }
 }
 
 pub(super) trait APIRequestBuilder {
-    fn api_auth(self) -> Self;
+    fn api_auth(self, client: &KoishiClient) -> Self;
     fn limit_offset(self, limit: u64, offset: u64) -> Self;
 }
 
 impl APIRequestBuilder for RequestBuilder {
-    fn api_auth(self) -> Self {
-        let token = global_options::AUTH_KEY
-            .get()
-            .expect("Authorization key must be initialized")
-            .as_ref()
-            .expect("Authorization key required");
-
-        self.bearer_auth(token)
+    fn api_auth(self, client: &KoishiClient) -> Self {
+        // Without a key the request goes out unauthenticated and the server
+        // answers with an `unauthorized` error, which is reported as usual.
+        match client.auth_key() {
+            Some(token) => self.bearer_auth(token),
+            None => self,
+        }
     }
 
     fn limit_offset(self, limit: u64, offset: u64) -> Self {
@@ -68,10 +150,72 @@ pub(super) trait APIResult {
 
 impl APIResult for Response {
     fn api_result<T: DeserializeOwned + 'static>(self) -> Result<T> {
-        let raw: APIRawResult<T> = self.json()?;
-        match raw {
-            APIRawResult::Success(success) => Ok(success.result),
-            APIRawResult::Error(err) => Err(err.into()),
+        // Proxies in front of the functions answer 5xx with HTML pages;
+        // report those by status so that they can be retried.
+        let status_error = self.error_for_status_ref().err();
+        match self.json::<APIRawResult<T>>() {
+            Ok(raw) => raw.into_result(),
+            Err(e) => Err(status_error.unwrap_or(e).into()),
+        }
+    }
+}
+
+pub(super) trait APISend {
+    /// Send the request and parse the result, retrying according to the
+    /// client's [`RetryPolicy`] if its method is idempotent.
+    fn send_api<T: DeserializeOwned + 'static>(self, client: &KoishiClient) -> Result<T>;
+
+    /// Like [`send_api`](Self::send_api), for a `POST` that does no more
+    /// when sent twice, like one that only signs URLs.
+    fn send_api_replayable<T: DeserializeOwned + 'static>(self, client: &KoishiClient)
+    -> Result<T>;
+}
+
+impl APISend for RequestBuilder {
+    fn send_api<T: DeserializeOwned + 'static>(self, client: &KoishiClient) -> Result<T> {
+        send(self, client, false)
+    }
+
+    fn send_api_replayable<T: DeserializeOwned + 'static>(
+        self,
+        client: &KoishiClient,
+    ) -> Result<T> {
+        send(self, client, true)
+    }
+}
+
+fn send<T: DeserializeOwned + 'static>(
+    rb: RequestBuilder,
+    client: &KoishiClient,
+    replayable: bool,
+) -> Result<T> {
+    let policy = client.retry_policy();
+    let mut attempt = 1;
+    loop {
+        // Streamed bodies cannot be replayed; send those only once.
+        let Some(req) = rb.try_clone() else {
+            return rb.send()?.api_result();
+        };
+        let req = req.build()?;
+        let replayable = replayable || req.method().is_idempotent();
+
+        match client
+            .execute(req)
+            .map_err(APIError::from)
+            .and_then(|res| res.api_result())
+        {
+            Err(e) if policy.should_retry(&e, attempt, replayable) => {
+                let delay = policy.delay(attempt);
+                log::warn!(
+                    "{e}; retrying in {:.1}s (attempt {}/{})",
+                    delay.as_secs_f64(),
+                    attempt + 1,
+                    policy.max_attempts
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            res => return res,
         }
     }
 }
@@ -1,16 +1,13 @@
 use serde::{Deserialize, Serialize};
 use tabled::{Tabled, derive::display};
 
-use crate::global_options;
+use crate::KoishiClient;
 use crate::helpers::{self, se::BoolAsInt};
 
-use super::{
-    Result,
-    request::{self, *},
-};
+use super::{Pages, Result, request::*};
 
-#[derive(Serialize, Deserialize, Tabled)]
-pub(crate) struct Room {
+#[derive(Clone, Serialize, Deserialize, Tabled)]
+pub struct Room {
     #[tabled(rename = "Room ID")]
     pub id: u64,
     #[tabled(rename = "Short ID", display("display::option", ""))]
@@ -21,46 +18,61 @@ pub(crate) struct Room {
     pub image: String,
 }
 
-#[derive(Deserialize, Tabled)]
-pub(crate) struct RoomListVideoEntry {
+#[derive(Serialize, Deserialize, Tabled)]
+pub struct RoomListVideoEntry {
     #[tabled(rename = "UUID")]
-    uuid: String,
+    pub uuid: String,
     #[tabled(rename = "Title")]
-    title: String,
+    pub title: String,
     #[tabled(rename = "Cover", display("display::option", "<Not set>"))]
-    cover: Option<String>,
+    pub cover: Option<String>,
     #[tabled(rename = "Stream Time", display("helpers::tabled::timestamp", self))]
-    stream_time: i64,
+    pub stream_time: i64,
     #[tabled(rename = "Restricted")]
-    restricted: BoolAsInt,
+    pub restricted: BoolAsInt,
 }
 
-pub(crate) fn create(room: Room) -> Result<()> {
-    if global_options::DRY.get().unwrap().clone() {
-        println!("skipping request due to being dry run");
+pub fn create(client: &KoishiClient, room: Room) -> Result<()> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
         return Ok(());
     }
 
-    request::post(format!("room/{}", room.id))
+    client
+        .post(format!("room/{}", room.id))
         .json(&room)
-        .send()?
-        .api_result()
+        .send_api(client)
 }
 
-pub(crate) fn get(id: u64) -> Result<Room> {
-    request::get(format!("room/{id}")).send()?.api_result()
+pub fn get(client: &KoishiClient, id: u64) -> Result<Room> {
+    client.get(format!("room/{id}")).send_api(client)
 }
 
-pub(crate) fn list(limit: u64, offset: u64) -> Result<Vec<Room>> {
-    request::get("room")
+pub fn list(client: &KoishiClient, limit: u64, offset: u64) -> Result<Vec<Room>> {
+    client
+        .get("room")
         .limit_offset(limit, offset)
-        .send()?
-        .api_result()
+        .send_api(client)
+}
+
+/// Iterate over every room, fetching pages as needed.
+pub fn iter(client: &KoishiClient) -> Pages<'_, Room> {
+    Pages::new(move |limit, offset| list(client, limit, offset))
 }
 
-pub(crate) fn list_videos(id: u64, limit: u64, offset: u64) -> Result<Vec<RoomListVideoEntry>> {
-    request::get(format!("room/{id}/video"))
+pub fn list_videos(
+    client: &KoishiClient,
+    id: u64,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<RoomListVideoEntry>> {
+    client
+        .get(format!("room/{id}/video"))
         .limit_offset(limit, offset)
-        .send()?
-        .api_result()
+        .send_api(client)
+}
+
+/// Iterate over every video of room `id`, fetching pages as needed.
+pub fn iter_videos(client: &KoishiClient, id: u64) -> Pages<'_, RoomListVideoEntry> {
+    Pages::new(move |limit, offset| list_videos(client, id, limit, offset))
 }
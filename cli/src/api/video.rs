@@ -1,17 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::{error, path::Path, result, time::Duration};
+use std::{path::Path, time::Duration};
 use tabled::{Tabled, derive::display};
 
-use crate::global_options;
-use crate::helpers::{self, s3, se::BoolAsInt};
+use crate::KoishiClient;
+use crate::helpers::{self, se::BoolAsInt};
 
-use super::{
-    Result,
-    request::{self, *},
-};
+use super::{Result, request::*};
 
 #[derive(Serialize, Deserialize, Tabled)]
-pub(crate) struct Video {
+pub struct Video {
     #[tabled(rename = "UUID")]
     pub uuid: String,
     #[tabled(rename = "Title")]
@@ -29,75 +26,120 @@ pub(crate) struct Video {
 }
 
 #[derive(Serialize)]
-struct VideoCreateInfo {
-    title: String,
-    cover: Option<String>,
-    stream_time: i64,
-    record_time: i64,
-    room: u64,
-    restricted: BoolAsInt,
-    restricted_hash: Option<String>,
+pub(crate) struct VideoCreateInfo {
+    pub(crate) title: String,
+    pub(crate) cover: Option<String>,
+    pub(crate) stream_time: i64,
+    pub(crate) record_time: i64,
+    pub(crate) room: u64,
+    pub(crate) restricted: BoolAsInt,
+    pub(crate) restricted_hash: Option<String>,
 }
 
 #[derive(Deserialize)]
-pub(crate) struct MetadataUploadResponse {
+pub struct MetadataUploadResponse {
     pub url: String,
 }
 
 #[derive(Deserialize)]
-pub(crate) struct VideoUploadStartResponse {
+pub struct VideoUploadStartResponse {
     pub urls: Vec<String>,
     pub upload_id: String,
     pub video: Video,
 }
 
 #[derive(Deserialize)]
-pub(crate) struct VideoSetRestrictedResponse {
+pub struct VideoUploadUrlsResponse {
+    /// Signed URLs in the order of the part numbers asked for.
+    pub urls: Vec<String>,
+}
+
+/// Part of an unfinished upload that storage has received.
+#[derive(Deserialize)]
+pub struct UploadedPart {
+    pub part_number: u64,
+    pub etag: String,
+    pub size: u64,
+}
+
+#[derive(Deserialize)]
+pub struct VideoUploadParts {
+    pub upload_id: String,
+    pub parts: Vec<UploadedPart>,
+}
+
+/// The video object as stored, as far as a HEAD request tells.
+#[derive(Deserialize)]
+pub struct VideoObject {
+    pub size: u64,
+    pub etag: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VideoSetRestrictedResponse {
     pub copy_source: Option<String>,
+    /// Size of the object at `copy_source`; older servers do not send it.
+    #[serde(default)]
+    pub length: Option<u64>,
 }
 
 #[derive(Deserialize)]
-pub(crate) struct RestrictedCopyStartResponse {
+pub struct RestrictedCopyStartResponse {
     pub length: u64,
     pub upload_id: String,
     pub urls: Vec<String>,
 }
 
 #[derive(Serialize)]
-struct VideoUpdateInfo {
+pub(crate) struct VideoUpdateInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
-    title: Option<String>,
+    pub(crate) title: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    cover: Option<String>,
+    pub(crate) cover: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    stream_time: Option<i64>,
+    pub(crate) stream_time: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    record_time: Option<i64>,
+    pub(crate) record_time: Option<i64>,
 }
 
 #[derive(Serialize)]
-struct ReqUploadStart {
-    size: u64,
-    part_size: u64,
-    restricted_hash: Option<String>,
+pub(crate) struct ReqUploadStart {
+    pub(crate) size: u64,
+    pub(crate) part_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) upload_id: Option<String>,
+    pub(crate) restricted_hash: Option<String>,
 }
 
 #[derive(Serialize)]
-struct ReqUploadFinish {
-    upload_id: String,
-    etags: Vec<String>,
-    restricted_hash: Option<String>,
+pub(crate) struct ReqUploadFinish {
+    pub(crate) upload_id: String,
+    pub(crate) etags: Vec<String>,
+    pub(crate) restricted_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReqUploadUrls {
+    pub(crate) upload_id: String,
+    pub(crate) part_numbers: Vec<u64>,
+    pub(crate) restricted_hash: Option<String>,
 }
 
 #[derive(Serialize)]
-struct ReqSetRestricted {
-    restricted: u64,
-    hash: String,
+pub(crate) struct ReqUploadAbort {
+    pub(crate) upload_id: String,
+    pub(crate) restricted_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReqSetRestricted {
+    pub(crate) restricted: u64,
+    pub(crate) hash: String,
 }
 
 #[derive(Serialize)]
 #[serde(tag = "command", rename_all = "snake_case")]
-enum ReqPostRestricted {
+pub(crate) enum ReqPostRestricted {
     CopyStart {
         copy_source: String,
         hash: Option<String>,
@@ -111,11 +153,13 @@ enum ReqPostRestricted {
     },
 }
 
-pub(crate) fn get(uuid: &str) -> Result<Video> {
-    request::get(format!("video/{uuid}")).send()?.api_result()
+pub fn get(client: &KoishiClient, uuid: &str) -> Result<Video> {
+    client.get(format!("video/{uuid}")).send_api(client)
 }
 
-pub(crate) fn create(
+#[allow(clippy::too_many_arguments)]
+pub fn create(
+    client: &KoishiClient,
     uuid: &str,
     title: String,
     cover: Option<String>,
@@ -124,8 +168,8 @@ pub(crate) fn create(
     room: u64,
     restricted_hash: Option<String>,
 ) -> Result<()> {
-    if global_options::DRY.get().unwrap().clone() {
-        println!("skipping request due to being dry run");
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
         return Ok(());
     }
 
@@ -136,26 +180,27 @@ pub(crate) fn create(
         cover,
         room,
         stream_time,
-	record_time,
+        record_time,
         restricted,
         restricted_hash,
     };
 
-    request::post(format!("video/{uuid}"))
+    client
+        .post(format!("video/{uuid}"))
         .json(&video)
-        .send()?
-        .api_result()
+        .send_api(client)
 }
 
-pub(crate) fn update(
+pub fn update(
+    client: &KoishiClient,
     uuid: &str,
     title: Option<String>,
     cover: Option<String>,
     stream_time: Option<i64>,
     record_time: Option<i64>,
 ) -> Result<()> {
-    if global_options::DRY.get().unwrap().clone() {
-        println!("skipping request due to being dry run");
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
         return Ok(());
     }
 
@@ -163,39 +208,57 @@ pub(crate) fn update(
         title,
         cover,
         stream_time,
-	record_time,
+        record_time,
     };
 
-    request::put(format!("video/{uuid}"))
+    client
+        .put(format!("video/{uuid}"))
         .json(&video)
-        .send()?
-        .api_result()
+        .send_api(client)
+}
+
+/// Remove the video with its object and metadata; restricted videos need
+/// their `hash`.
+pub fn delete(client: &KoishiClient, uuid: &str, hash: Option<&str>) -> Result<()> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok(());
+    }
+
+    let mut rb = client.delete(format!("video/{uuid}"));
+    if let Some(hash) = hash {
+        rb = rb.query(&[("hash", hash)]);
+    }
+    rb.send_api(client)
 }
 
-fn metadata_upload_url(uuid: &str) -> Result<String> {
-    if global_options::DRY.get().unwrap().clone() {
-        println!("skipping request due to being dry run");
+fn metadata_upload_url(client: &KoishiClient, uuid: &str) -> Result<String> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
         return Ok("".into());
     }
 
-    let res: MetadataUploadResponse = request::post(format!("video/{uuid}/upload_metadata"))
-        .send()?
-        .api_result()?;
+    let res: MetadataUploadResponse = client
+        .post(format!("video/{uuid}/upload_metadata"))
+        .send_api_replayable(client)?;
 
     Ok(res.url)
 }
 
-pub(crate) fn upload_metadata<P: AsRef<Path>>(
+pub fn upload_metadata<P: AsRef<Path>>(
+    client: &KoishiClient,
     uuid: &str,
     path: P,
-) -> result::Result<(), Box<dyn error::Error>> {
-    let url = metadata_upload_url(uuid)?;
+) -> crate::Result<()> {
+    let url = metadata_upload_url(client, uuid)?;
 
-    if global_options::DRY.get().unwrap().clone() {
+    if client.is_dry() {
         return Ok(());
     }
 
-    s3::Uploader::with_timeout(Duration::from_secs(300))?
+    client
+        .uploader()
+        .timeout(Duration::from_secs(300))
         .url(url)
         .from_file_path(path)?
         .upload()?;
@@ -203,7 +266,8 @@ pub(crate) fn upload_metadata<P: AsRef<Path>>(
     Ok(())
 }
 
-pub(crate) fn upload_start(
+pub fn upload_start(
+    client: &KoishiClient,
     uuid: &str,
     file_size: u64,
     part_size: u64,
@@ -212,15 +276,38 @@ pub(crate) fn upload_start(
     let req_body = ReqUploadStart {
         size: file_size,
         part_size,
+        upload_id: None,
         restricted_hash: hash,
     };
-    request::post(format!("video/{uuid}/upload_start"))
+    client
+        .post(format!("video/{uuid}/upload_start"))
         .json(&req_body)
-        .send()?
-        .api_result()
+        .send_api(client)
 }
 
-pub(crate) fn upload_finish(
+/// Get new part URLs for the unfinished upload `upload_id`.
+pub fn upload_resume(
+    client: &KoishiClient,
+    uuid: &str,
+    file_size: u64,
+    part_size: u64,
+    upload_id: String,
+    hash: Option<String>,
+) -> Result<VideoUploadStartResponse> {
+    let req_body = ReqUploadStart {
+        size: file_size,
+        part_size,
+        upload_id: Some(upload_id),
+        restricted_hash: hash,
+    };
+    client
+        .post(format!("video/{uuid}/upload_start"))
+        .json(&req_body)
+        .send_api(client)
+}
+
+pub fn upload_finish(
+    client: &KoishiClient,
     uuid: &str,
     upload_id: String,
     etags: Vec<String>,
@@ -232,13 +319,79 @@ pub(crate) fn upload_finish(
         restricted_hash: hash,
     };
 
-    request::post(format!("video/{uuid}/upload_finish"))
+    client
+        .post(format!("video/{uuid}/upload_finish"))
+        .json(&req_body)
+        .send_api(client)
+}
+
+/// Discard an unfinished upload along with the parts stored so far.
+pub fn upload_abort(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: String,
+    hash: Option<String>,
+) -> Result<()> {
+    let req_body = ReqUploadAbort {
+        upload_id,
+        restricted_hash: hash,
+    };
+
+    client
+        .post(format!("video/{uuid}/upload_abort"))
         .json(&req_body)
-        .send()?
-        .api_result()
+        .send_api(client)
+}
+
+/// Sign the URLs of parts `part_numbers` of `upload_id` again.
+pub fn upload_urls(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: String,
+    part_numbers: Vec<u64>,
+    hash: Option<String>,
+) -> Result<VideoUploadUrlsResponse> {
+    let req_body = ReqUploadUrls {
+        upload_id,
+        part_numbers,
+        restricted_hash: hash,
+    };
+
+    client
+        .post(format!("video/{uuid}/upload_urls"))
+        .json(&req_body)
+        .send_api_replayable(client)
+}
+
+/// List the parts storage has received for `upload_id`, or for the latest
+/// unfinished upload of the video if not given.
+pub fn upload_parts(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: Option<&str>,
+    hash: Option<&str>,
+) -> Result<VideoUploadParts> {
+    let mut rb = client.get(format!("video/{uuid}/upload_parts"));
+    if let Some(upload_id) = upload_id {
+        rb = rb.query(&[("upload_id", upload_id)]);
+    }
+    if let Some(hash) = hash {
+        rb = rb.query(&[("hash", hash)]);
+    }
+    rb.send_api(client)
+}
+
+/// Look up the stored video object; restricted videos need their `hash`.
+pub fn object(client: &KoishiClient, uuid: &str, hash: Option<&str>) -> Result<VideoObject> {
+    let mut rb = client.get(format!("video/{uuid}/object"));
+    if let Some(hash) = hash {
+        rb = rb.query(&[("hash", hash)]);
+    }
+    rb.send_api(client)
 }
 
-pub(crate) fn set_restricted(
+pub fn set_restricted(
+    client: &KoishiClient,
     uuid: &str,
     restricted: bool,
     hash: &str,
@@ -248,13 +401,14 @@ pub(crate) fn set_restricted(
         hash: hash.to_string(),
     };
 
-    request::put(format!("video/{uuid}/restricted"))
+    client
+        .put(format!("video/{uuid}/restricted"))
         .json(&req_body)
-        .send()?
-        .api_result()
+        .send_api(client)
 }
 
-pub(crate) fn restricted_copy_start(
+pub fn restricted_copy_start(
+    client: &KoishiClient,
     uuid: &str,
     copy_source: &str,
     hash: Option<String>,
@@ -267,13 +421,14 @@ pub(crate) fn restricted_copy_start(
         part_size,
     };
 
-    request::post(format!("video/{uuid}/restricted"))
+    client
+        .post(format!("video/{uuid}/restricted"))
         .json(&req_body)
-        .send()?
-        .api_result()
+        .send_api(client)
 }
 
-pub(crate) fn restricted_copy_finish(
+pub fn restricted_copy_finish(
+    client: &KoishiClient,
     uuid: &str,
     copy_source: &str,
     hash: Option<String>,
@@ -289,8 +444,8 @@ pub(crate) fn restricted_copy_finish(
         upload_id,
     };
 
-    request::post(format!("video/{uuid}/restricted"))
+    client
+        .post(format!("video/{uuid}/restricted"))
         .json(&req_body)
-        .send()?
-        .api_result()
+        .send_api(client)
 }
@@ -0,0 +1,396 @@
+use reqwest::{Url, blocking::Client};
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use crate::{
+    api::RetryPolicy,
+    helpers::{
+        ratelimit::{Rate, RateLimiter, Schedule},
+        s3,
+    },
+};
+
+pub const DEFAULT_BASE_URL: &str = "http://localhost:8788/api/";
+
+pub const DEFAULT_USER_AGENT: &str = concat!("koishi/", env!("CARGO_PKG_VERSION"));
+
+/// Longest wait for a single read from a connection unless configured
+/// otherwise, so that no upload can hang for good.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// HTTP version spoken to the API and object storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HttpVersion {
+    /// HTTP/2 where TLS negotiates it, HTTP/1.1 otherwise.
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 without negotiation, also over plain HTTP.
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "1" | "1.1" | "http1" => Ok(Self::Http1),
+            "2" | "http2" => Ok(Self::Http2),
+            _ => Err(format!("Unknown HTTP version {s}; expected auto, 1 or 2")),
+        }
+    }
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Http1 => write!(f, "1"),
+            Self::Http2 => write!(f, "2"),
+        }
+    }
+}
+
+/// Handle to a single Koishi deployment.
+///
+/// Holds everything the `api` functions need to talk to the server: where it
+/// lives, how to authenticate, whether mutating requests should be skipped,
+/// and one `reqwest` client shared by every request made through it, object
+/// storage uploads included, so that connections are kept alive and reused.
+#[derive(Clone)]
+pub struct KoishiClient {
+    base_url: Url,
+    auth_key: Option<String>,
+    dry: bool,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    rate_limiter: Option<RateLimiter>,
+    http: Client,
+}
+
+impl KoishiClient {
+    pub fn new<S: AsRef<str>>(base_url: S) -> Result<Self, ClientBuildError> {
+        Self::builder().base_url(base_url).build()
+    }
+
+    pub fn builder() -> KoishiClientBuilder {
+        KoishiClientBuilder::default()
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub fn auth_key(&self) -> Option<&str> {
+        self.auth_key.as_deref()
+    }
+
+    pub fn is_dry(&self) -> bool {
+        self.dry
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Total timeout of a single API request.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Limiter shared by every request body sent through this client.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub(crate) fn http(&self) -> &Client {
+        &self.http
+    }
+
+    /// Uploader to object storage sharing this client's connection pool and
+    /// rate limit.
+    ///
+    /// The blocking client cannot time out single reads, so the read timeout
+    /// is the uploader's stall timeout instead, which covers the wait for an
+    /// answer as well.
+    pub fn uploader(&self) -> s3::Uploader {
+        let uploader =
+            s3::Uploader::from_client(self.http.clone()).rate_limiter(self.rate_limiter.clone());
+        match self.read_timeout {
+            Some(timeout) => uploader.stall_timeout(timeout),
+            None => uploader,
+        }
+    }
+}
+
+pub struct KoishiClientBuilder {
+    base_url: String,
+    auth_key: Option<String>,
+    dry: bool,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    http_version: HttpVersion,
+    user_agent: String,
+    rate: Rate,
+    schedule: Schedule,
+}
+
+impl Default for KoishiClientBuilder {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.into(),
+            auth_key: None,
+            dry: false,
+            retry: RetryPolicy::default(),
+            timeout: Some(Duration::from_secs(60)),
+            connect_timeout: None,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            pool_max_idle_per_host: None,
+            http_version: HttpVersion::Auto,
+            user_agent: DEFAULT_USER_AGENT.into(),
+            rate: Rate::Unlimited,
+            schedule: Schedule::default(),
+        }
+    }
+}
+
+impl KoishiClientBuilder {
+    pub fn base_url<S: AsRef<str>>(mut self, url: S) -> Self {
+        self.base_url = url.as_ref().to_string();
+        self
+    }
+
+    pub fn auth_key<S: Into<String>>(mut self, key: Option<S>) -> Self {
+        self.auth_key = key.map(Into::into);
+        self
+    }
+
+    /// Skip every mutating request, printing a notice instead.
+    pub fn dry(mut self, dry: bool) -> Self {
+        self.dry = dry;
+        self
+    }
+
+    /// How API requests are retried after transient errors.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Total timeout of a single API request; `None` disables it.
+    ///
+    /// Uploads to object storage are not bound by it, as parts may take
+    /// arbitrarily long on a slow link; see [`Self::read_timeout`].
+    pub fn timeout<T: Into<Option<Duration>>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    pub fn connect_timeout<T: Into<Option<Duration>>>(mut self, timeout: T) -> Self {
+        self.connect_timeout = timeout.into();
+        self
+    }
+
+    /// Longest wait for a single read from any connection, uploads included;
+    /// [`DEFAULT_READ_TIMEOUT`] unless set, `None` disables it.
+    ///
+    /// The blocking client applies it to uploads only, see
+    /// [`KoishiClient::uploader`]; API requests have [`Self::timeout`].
+    pub fn read_timeout<T: Into<Option<Duration>>>(mut self, timeout: T) -> Self {
+        self.read_timeout = timeout.into();
+        self
+    }
+
+    /// Idle connections kept open per host; unlimited by default.
+    pub fn pool_max_idle_per_host<T: Into<Option<usize>>>(mut self, max: T) -> Self {
+        self.pool_max_idle_per_host = max.into();
+        self
+    }
+
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.http_version = version;
+        self
+    }
+
+    pub fn user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Limit the upload rate of request bodies, object storage uploads
+    /// included, across all clones of the client.
+    ///
+    /// Only the blocking client enforces it.
+    pub fn rate_limit(mut self, rate: Rate) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Time-of-day windows overriding [`Self::rate_limit`].
+    pub fn rate_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    fn parse_base_url(&self) -> Result<Url, ClientBuildError> {
+        // Url::join() replaces the last path segment unless the base ends
+        // with a slash, so normalize it here once.
+        let mut base_url = self.base_url.clone();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        Ok(Url::parse(&base_url)?)
+    }
+
+    /// Connection settings shared by the blocking and the async client.
+    ///
+    /// The blocking builder lacks some of the async one's settings, but can
+    /// be created from it. Not the read timeout though: the blocking client
+    /// reads bodies outside of a runtime, where its timer panics.
+    fn http_builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder().user_agent(&self.user_agent);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        match self.http_version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        }
+    }
+
+    pub fn build(self) -> Result<KoishiClient, ClientBuildError> {
+        let base_url = self.parse_base_url()?;
+
+        // The total timeout is applied per API request instead, see
+        // `timeout()`.
+        let http = reqwest::blocking::ClientBuilder::from(self.http_builder())
+            .timeout(None)
+            .build()?;
+
+        let rate_limiter = (self.rate != Rate::Unlimited || !self.schedule.0.is_empty())
+            .then(|| RateLimiter::new(self.rate, self.schedule));
+
+        Ok(KoishiClient {
+            base_url,
+            auth_key: self.auth_key,
+            dry: self.dry,
+            retry: self.retry,
+            timeout: self.timeout,
+            read_timeout: self.read_timeout,
+            rate_limiter,
+            http,
+        })
+    }
+
+    /// Build a client for the async API in [`crate::nonblocking`] instead.
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<crate::nonblocking::KoishiClient, ClientBuildError> {
+        let base_url = self.parse_base_url()?;
+        let mut http = self.http_builder();
+        if let Some(timeout) = self.read_timeout {
+            http = http.read_timeout(timeout);
+        }
+        let http = http.build()?;
+
+        Ok(crate::nonblocking::KoishiClient::from_parts(
+            base_url,
+            self.auth_key,
+            self.dry,
+            self.retry,
+            self.timeout,
+            http,
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientBuildError {
+    InvalidBaseUrl(url::ParseError),
+    Http(reqwest::Error),
+}
+
+impl From<url::ParseError> for ClientBuildError {
+    fn from(value: url::ParseError) -> Self {
+        ClientBuildError::InvalidBaseUrl(value)
+    }
+}
+
+impl From<reqwest::Error> for ClientBuildError {
+    fn from(value: reqwest::Error) -> Self {
+        ClientBuildError::Http(value)
+    }
+}
+
+impl Display for ClientBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidBaseUrl(err) => write!(f, "Invalid base URL: {}", err),
+            Self::Http(err) => write!(f, "Failed to create HTTP client: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientBuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidBaseUrl(err) => Some(err),
+            Self::Http(err) => Some(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_url_normalized() {
+        let client = KoishiClient::new("http://example.com/api").unwrap();
+        assert_eq!(client.base_url().as_str(), "http://example.com/api/");
+        assert_eq!(
+            client.base_url().join("room/1").unwrap().as_str(),
+            "http://example.com/api/room/1"
+        );
+    }
+
+    #[test]
+    fn test_http_version_roundtrip() {
+        for version in [HttpVersion::Auto, HttpVersion::Http1, HttpVersion::Http2] {
+            assert_eq!(version.to_string().parse::<HttpVersion>(), Ok(version));
+        }
+        assert_eq!("1.1".parse::<HttpVersion>(), Ok(HttpVersion::Http1));
+        assert!("3".parse::<HttpVersion>().is_err());
+    }
+
+    #[test]
+    fn test_build_with_connection_settings() {
+        let client = KoishiClient::builder()
+            .timeout(Duration::from_secs(5))
+            .connect_timeout(Duration::from_secs(1))
+            .read_timeout(Duration::from_secs(10))
+            .pool_max_idle_per_host(4)
+            .http_version(HttpVersion::Http1)
+            .user_agent("test/1.0")
+            .build()
+            .unwrap();
+        assert_eq!(client.timeout(), Some(Duration::from_secs(5)));
+        assert!(client.rate_limiter().is_none());
+
+        let client = KoishiClient::builder()
+            .rate_limit("5M".parse().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.rate_limiter().unwrap().current_rate(),
+            Rate::Limited(5 << 20)
+        );
+    }
+}
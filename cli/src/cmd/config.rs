@@ -0,0 +1,208 @@
+use clap::{Parser, Subcommand};
+use koishi::{Error, KoishiClient, Result};
+
+use crate::config::{ConfigError, ConfigFile, Overrides, Resolved, Settings, select_profile};
+
+#[derive(Parser)]
+pub(crate) struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the effective settings and where each of them comes from
+    Show(ShowArgs),
+    /// Store a setting in the selected profile
+    Set(SetArgs),
+    /// Remove a setting from the selected profile
+    Unset(UnsetArgs),
+    /// Check the config file and the effective settings
+    Validate,
+    /// Print the location of the config file
+    Path,
+}
+
+#[derive(Parser)]
+struct ShowArgs {
+    #[arg(long)]
+    show_secrets: bool,
+}
+
+#[derive(Parser)]
+struct SetArgs {
+    key: String,
+    value: String,
+}
+
+#[derive(Parser)]
+struct UnsetArgs {
+    key: String,
+}
+
+fn print_setting<T: std::fmt::Display>(name: &str, value: &Resolved<Option<T>>) {
+    match value.value.as_ref() {
+        Some(v) => println!("{name:16}{v}  ({})", value.source),
+        None => println!("{name:16}<not set>"),
+    }
+}
+
+fn show(overrides: Overrides, args: ShowArgs) -> Result<()> {
+    let settings = Settings::resolve(overrides)?;
+
+    let config_path = settings
+        .config_path
+        .as_ref()
+        .map(|p| p.display().to_string())
+        .unwrap_or("<none>".into());
+    println!("{:16}{}", "config", config_path);
+    println!(
+        "{:16}{}  ({})",
+        "profile", settings.profile.value, settings.profile.source
+    );
+    println!(
+        "{:16}{}  ({})",
+        "base_url", settings.base_url.value, settings.base_url.source
+    );
+
+    let auth_key = Resolved {
+        value: settings.auth_key.value.as_ref().map(|key| {
+            if args.show_secrets {
+                key.clone()
+            } else {
+                "********".into()
+            }
+        }),
+        source: settings.auth_key.source,
+    };
+    print_setting("auth_key", &auth_key);
+    print_setting("part_size", &settings.part_size);
+    print_setting("thread_count", &settings.thread_count);
+    print_setting("retry", &settings.retry);
+    print_setting("retry_delay", &settings.retry_delay);
+    print_setting("api_retry", &settings.api_retry);
+    print_setting("timeout", &settings.timeout);
+    print_setting("stall_timeout", &settings.stall_timeout);
+    print_setting("connect_timeout", &settings.connect_timeout);
+    print_setting("read_timeout", &settings.read_timeout);
+    print_setting("pool_size", &settings.pool_size);
+    print_setting("http_version", &settings.http_version);
+    print_setting("user_agent", &settings.user_agent);
+    print_setting("limit_rate", &settings.limit_rate);
+    print_setting("rate_schedule", &settings.rate_schedule);
+    Ok(())
+}
+
+fn edit<F>(overrides: Overrides, f: F) -> Result<()>
+where
+    F: FnOnce(&mut crate::config::Profile) -> std::result::Result<(), ConfigError>,
+{
+    let Some(path) = Settings::config_path(&overrides) else {
+        return Err(Error::validation(
+            "Cannot locate the config file; pass --config",
+        ));
+    };
+    let mut file = ConfigFile::load(&path)?;
+    let profile = select_profile(overrides.profile, &file).value;
+
+    f(file.profiles.entry(profile.clone()).or_default())?;
+    file.save(&path)?;
+
+    println!("Updated profile {profile} in {}", path.display());
+    Ok(())
+}
+
+fn validate(overrides: Overrides) -> Result<()> {
+    let settings = Settings::resolve(overrides)
+        .map_err(|e| Error::validation(format!("Invalid configuration: {e}")))?;
+
+    let mut errors = vec![];
+    let mut warnings = vec![];
+
+    if let Err(e) = KoishiClient::new(&settings.base_url.value) {
+        errors.push(format!("base_url: {e}"));
+    }
+    if settings.auth_key.value.is_none() {
+        warnings.push("auth_key: not set; commands changing the server will be refused".into());
+    }
+    if settings.part_size.value == Some(0) {
+        errors.push("part_size: must be greater than zero".into());
+    }
+    if settings.thread_count.value == Some(0) {
+        errors.push("thread_count: must be greater than zero".into());
+    }
+    if settings.retry.value == Some(0) {
+        errors.push("retry: must be greater than zero".into());
+    }
+    if settings
+        .retry_delay
+        .value
+        .is_some_and(|delay| !(delay >= 0.0 && delay.is_finite()))
+    {
+        errors.push("retry_delay: must be a number of seconds".into());
+    }
+    if settings.api_retry.value == Some(0) {
+        errors.push("api_retry: must be greater than zero".into());
+    }
+    for (key, timeout) in [
+        ("timeout", &settings.timeout),
+        ("connect_timeout", &settings.connect_timeout),
+        ("read_timeout", &settings.read_timeout),
+        ("stall_timeout", &settings.stall_timeout),
+    ] {
+        if timeout.value == Some(0) {
+            errors.push(format!("{key}: must be greater than zero"));
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = settings.config_path.as_ref()
+        && let Ok(meta) = std::fs::metadata(path)
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if meta.permissions().mode() & 0o077 != 0 {
+            warnings.push(format!(
+                "{} is accessible by other users; consider chmod 600",
+                path.display()
+            ));
+        }
+    }
+
+    for warning in warnings.iter() {
+        println!("warning: {warning}");
+    }
+    for error in errors.iter() {
+        println!("error: {error}");
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::validation(format!(
+            "Configuration of profile {} is invalid",
+            settings.profile.value
+        )));
+    }
+    println!(
+        "Configuration of profile {} is valid",
+        settings.profile.value
+    );
+    Ok(())
+}
+
+pub(crate) fn main(overrides: Overrides, args: Args) -> Result<()> {
+    let Some(command) = args.command else {
+        return Ok(());
+    };
+    match command {
+        Commands::Show(args) => show(overrides, args),
+        Commands::Set(args) => edit(overrides, |profile| profile.set(&args.key, &args.value)),
+        Commands::Unset(args) => edit(overrides, |profile| profile.unset(&args.key)),
+        Commands::Validate => validate(overrides),
+        Commands::Path => {
+            match Settings::config_path(&overrides) {
+                Some(path) => println!("{}", path.display()),
+                None => println!("<none>"),
+            }
+            Ok(())
+        }
+    }
+}
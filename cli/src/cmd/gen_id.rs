@@ -1,7 +1,9 @@
+use koishi::Result;
 use uuid::Uuid;
 
-pub(crate) fn main() {
+pub(crate) fn main() -> Result<()> {
     let uuid = Uuid::now_v7();
 
     println!("{}", uuid.simple());
+    Ok(())
 }
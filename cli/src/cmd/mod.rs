@@ -1,4 +1,22 @@
+use koishi::KoishiClient;
+
+use crate::{config::Settings, output::Output};
+
+pub mod config;
 pub mod gen_id;
+pub mod recording;
 pub mod restricted_hash;
 pub mod room;
+#[cfg(feature = "server")]
+pub mod serve;
 pub mod video;
+pub mod watch;
+#[cfg(feature = "server")]
+pub mod webhook_server;
+
+/// State shared by every command that talks to the server.
+pub(crate) struct Context {
+    pub client: KoishiClient,
+    pub settings: Settings,
+    pub output: Output,
+}
@@ -0,0 +1,232 @@
+//! Recordings made by a recorder and the pipeline that ingests each one:
+//! cover, video, upload and metadata, in that order.
+//!
+//! A recording is a video file with an `.xml` of the same name beside it,
+//! and optionally a `.jpg` or `.cover.jpg` cover. Both `koishi watch` and
+//! `koishi webhook-server` queue them and take them through the pipeline.
+
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    error::Error as _,
+    path::{Path, PathBuf},
+};
+use tabled::{Tabled, derive::display};
+use uuid::Uuid;
+
+use koishi::{Error, ErrorKind, Result, ResultExt, api};
+
+use crate::{
+    cmd::{Context, video},
+    output::Record,
+};
+
+pub(crate) mod queue;
+
+use queue::{Job, Step};
+
+const VIDEO_EXTENSIONS: &[&str] = &["flv", "mp4", "mkv", "ts"];
+
+#[derive(Serialize, Tabled)]
+pub(crate) struct QueuedRecording {
+    pub video: String,
+    #[tabled(display("display::option", ""))]
+    pub uuid: Option<String>,
+    pub status: &'static str,
+    pub step: String,
+    pub attempts: u32,
+    #[tabled(display("display::option", ""))]
+    pub error: Option<String>,
+}
+
+impl Record for QueuedRecording {}
+
+pub(crate) fn queued_recording(job: &Job, max_attempts: u32) -> QueuedRecording {
+    let status = if job.step == Step::Done {
+        "done"
+    } else if job.is_failed(max_attempts) {
+        "failed"
+    } else {
+        "queued"
+    };
+    QueuedRecording {
+        video: job.video.display().to_string(),
+        uuid: job.uuid.clone(),
+        status,
+        step: job.step.to_string(),
+        attempts: job.attempts,
+        error: job.error.clone(),
+    }
+}
+
+/// An error with its causes, on one line for the queue.
+pub(crate) fn describe(e: &Error) -> String {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        msg.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    msg
+}
+
+/// Queue file called `name` in directory `kind` of the state directory.
+pub(crate) fn default_queue_file(kind: &str, name: &str) -> Result<PathBuf> {
+    let Some(state_home) = dirs::state_dir().or_else(dirs::data_local_dir) else {
+        return Err(Error::validation("No state directory found; pass --state"));
+    };
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Ok(state_home
+        .join("koishi")
+        .join(kind)
+        .join(format!("{name}.json")))
+}
+
+pub(crate) fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// The XML and cover beside `video`, if they exist.
+pub(crate) fn companions(video: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+    let xml = Some(video.with_extension("xml")).filter(|p| p.is_file());
+    let cover = [
+        video.with_extension("jpg"),
+        video.with_extension("cover.jpg"),
+    ]
+    .into_iter()
+    .find(|p| p.is_file());
+    (xml, cover)
+}
+
+/// Carry out the next step of `job`, returning the step that follows.
+fn run_step(
+    ctx: &Context,
+    job: &mut Job,
+    hash: Option<&str>,
+    save: &mut impl FnMut(&Job) -> Result<()>,
+) -> Result<Step> {
+    let client = &ctx.client;
+    match job.step {
+        Step::Cover => {
+            if let Some(cover) = &job.cover {
+                let res = api::cover::upload_cover_from_file(client, cover)
+                    .with_context(|| format!("Failed to upload cover {}", cover.display()))?;
+                job.cover_hash = Some(res.hash);
+            }
+            Ok(Step::Create)
+        }
+        Step::Create => {
+            if job.uuid.is_none() {
+                job.uuid = Some(Uuid::now_v7().as_simple().to_string());
+                save(job)?;
+            }
+            let uuid = job.uuid.as_deref().unwrap();
+            let cover = job.cover_hash.clone();
+            let created = match (&job.info, &job.xml) {
+                (Some(info), _) => api::video::create(
+                    client,
+                    uuid,
+                    info.title.clone(),
+                    cover,
+                    info.stream_time,
+                    info.record_time,
+                    info.room_id,
+                    hash.map(str::to_string),
+                )
+                .map_err(Error::from),
+                (None, Some(xml)) => {
+                    video::create_from_xml(ctx, uuid, xml, cover, hash.map(str::to_string))
+                }
+                (None, None) => Err(Error::validation(format!(
+                    "No metadata XML beside {}",
+                    job.video.display()
+                ))),
+            };
+            match created {
+                // Created by an attempt whose response got lost.
+                Err(e) if e.kind() == ErrorKind::Conflict => {}
+                res => res.context("Failed to create video")?,
+            }
+            ctx.output.info(format!("Created video {uuid}"));
+            Ok(Step::Upload)
+        }
+        Step::Upload => {
+            let uuid = job.uuid.as_deref().unwrap();
+            video::upload_video(ctx, uuid, &job.video, hash.map(str::to_string))?;
+            Ok(Step::Metadata)
+        }
+        Step::Metadata => {
+            let uuid = job.uuid.as_deref().unwrap();
+            if let Some(xml) = &job.xml {
+                api::video::upload_metadata(client, uuid, xml)
+                    .context("Failed to upload metadata")?;
+            }
+            Ok(Step::Done)
+        }
+        Step::Done => Ok(Step::Done),
+    }
+}
+
+/// Take `job` through the rest of the pipeline, for a video restricted
+/// under `hash` if given, handing it to `save` after each step so that a
+/// restart does not repeat it. Stops at the first step that fails.
+pub(crate) fn advance(
+    ctx: &Context,
+    job: &mut Job,
+    hash: Option<&str>,
+    mut save: impl FnMut(&Job) -> Result<()>,
+) -> Result<()> {
+    while job.step != Step::Done {
+        job.step = run_step(ctx, job, hash, &mut save)?;
+        job.succeed();
+        save(job)?;
+    }
+    Ok(())
+}
+
+/// Like [`advance`], but a failure is recorded in the job for a later
+/// retry rather than returned; only failing to save and being interrupted
+/// are.
+pub(crate) fn ingest(
+    ctx: &Context,
+    job: &mut Job,
+    max_attempts: u32,
+    mut save: impl FnMut(&Job) -> Result<()>,
+) -> Result<()> {
+    ctx.output.info(format!(
+        "Ingesting {} from step {}",
+        job.video.display(),
+        job.step
+    ));
+    if let Err(e) = advance(ctx, job, None, &mut save) {
+        // Not a failure of the job, which goes on at its step next time.
+        if e.kind() == ErrorKind::Interrupted {
+            return Err(e);
+        }
+        let retry_at = job.fail(describe(&e), Utc::now());
+        save(job)?;
+        match job.is_failed(max_attempts) {
+            true => eprintln!(
+                "warning: Giving up on {} after {} attempts: {e}",
+                job.video.display(),
+                job.attempts
+            ),
+            false => eprintln!(
+                "warning: Failed to ingest {}: {e}; retrying at {retry_at}",
+                job.video.display()
+            ),
+        }
+        return Ok(());
+    }
+    ctx.output.info(format!(
+        "Ingested {} as video {}",
+        job.video.display(),
+        job.uuid.as_deref().unwrap_or_default()
+    ));
+    Ok(())
+}
@@ -0,0 +1,216 @@
+//! The recordings found or announced and how far each got, kept in a file
+//! so that a restarted watcher or webhook server carries on where the last
+//! one stopped.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use koishi::{Error, Result, ResultExt};
+
+use crate::files::write_atomic;
+
+/// Wait before the first retry, doubled on each failure after that.
+const BACKOFF_BASE: i64 = 60;
+/// Longest wait between retries.
+const BACKOFF_MAX: i64 = 3600;
+
+/// Step of the pipeline a recording is at; each is done once it succeeds.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Step {
+    Cover,
+    Create,
+    Upload,
+    Metadata,
+    Done,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Step::Cover => "cover",
+            Step::Create => "create",
+            Step::Upload => "upload",
+            Step::Metadata => "metadata",
+            Step::Done => "done",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What the recorder told about a recording that has no metadata XML.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct RecordingInfo {
+    pub room_id: u64,
+    pub title: String,
+    pub stream_time: i64,
+    pub record_time: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Job {
+    pub video: PathBuf,
+    /// Metadata XML, which also tells what to create the video with unless
+    /// `info` does.
+    pub xml: Option<PathBuf>,
+    pub cover: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<RecordingInfo>,
+    /// Picked before the video is created, so that a retry after a lost
+    /// response does not create it twice.
+    pub uuid: Option<String>,
+    /// Whether the video is created restricted, so that going on needs the
+    /// password again.
+    #[serde(default)]
+    pub restricted: bool,
+    pub cover_hash: Option<String>,
+    pub step: Step,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+/// Wait after `attempts` failures in a row.
+fn backoff(attempts: u32) -> TimeDelta {
+    let shift = attempts.saturating_sub(1).min(16);
+    TimeDelta::seconds((BACKOFF_BASE << shift).min(BACKOFF_MAX))
+}
+
+impl Job {
+    pub fn new(video: PathBuf, xml: Option<PathBuf>, cover: Option<PathBuf>) -> Self {
+        Self {
+            video,
+            xml,
+            cover,
+            info: None,
+            uuid: None,
+            restricted: false,
+            cover_hash: None,
+            step: Step::Cover,
+            attempts: 0,
+            error: None,
+            retry_at: None,
+        }
+    }
+
+    /// Whether the job ran out of attempts and waits for --retry-failed.
+    pub fn is_failed(&self, max_attempts: u32) -> bool {
+        self.step != Step::Done && self.attempts >= max_attempts
+    }
+
+    pub fn is_pending(&self, max_attempts: u32) -> bool {
+        self.step != Step::Done && !self.is_failed(max_attempts)
+    }
+
+    /// Whether a pending job may be tried at `now`, or at all if not given.
+    pub fn is_due(&self, max_attempts: u32, now: Option<DateTime<Utc>>) -> bool {
+        let waiting = match (now, self.retry_at) {
+            (Some(now), Some(retry_at)) => retry_at > now,
+            _ => false,
+        };
+        self.is_pending(max_attempts) && !waiting
+    }
+
+    /// Record a failed attempt, returning when to try again.
+    pub fn fail(&mut self, error: String, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.attempts += 1;
+        self.error = Some(error);
+        let retry_at = now + backoff(self.attempts);
+        self.retry_at = Some(retry_at);
+        retry_at
+    }
+
+    pub fn succeed(&mut self) {
+        self.attempts = 0;
+        self.error = None;
+        self.retry_at = None;
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Queue {
+    #[serde(skip)]
+    path: PathBuf,
+    pub jobs: Vec<Job>,
+}
+
+impl Queue {
+    /// Read the queue at `path`, or start an empty one if there is none.
+    pub fn load(path: &Path) -> Result<Self> {
+        let context = || format!("Failed to read recording queue {}", path.display());
+        let mut queue = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(io::Error::from)
+                .with_context(context)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Queue::default(),
+            Err(e) => return Err(Error::from(e).context(context())),
+        };
+        queue.path = path.to_path_buf();
+        Ok(queue)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let context = || format!("Failed to write recording queue {}", self.path.display());
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(context)?;
+        }
+        let data = serde_json::to_vec(self).map_err(io::Error::from)?;
+        write_atomic(&self.path, &data).with_context(context)
+    }
+
+    pub fn contains(&self, video: &Path) -> bool {
+        self.jobs.iter().any(|j| j.video == video)
+    }
+
+    /// When the earliest pending job waiting to be retried is due.
+    pub fn next_retry(&self, max_attempts: u32) -> Option<DateTime<Utc>> {
+        self.jobs
+            .iter()
+            .filter(|j| j.is_pending(max_attempts))
+            .filter_map(|j| j.retry_at)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), TimeDelta::seconds(60));
+        assert_eq!(backoff(3), TimeDelta::seconds(240));
+        assert_eq!(backoff(7), TimeDelta::seconds(3600));
+        assert_eq!(backoff(100), TimeDelta::seconds(3600));
+    }
+
+    #[test]
+    fn test_queue_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watch").join("queue.json");
+
+        let mut queue = Queue::load(&path).unwrap();
+        assert!(queue.jobs.is_empty());
+        let mut job = Job::new("a.flv".into(), Some("a.xml".into()), None);
+        job.step = Step::Upload;
+        let now = Utc::now();
+        let retry_at = job.fail("timed out".into(), now);
+        queue.jobs.push(job);
+        queue.save().unwrap();
+
+        let queue = Queue::load(&path).unwrap();
+        assert!(queue.contains(Path::new("a.flv")));
+        assert_eq!(queue.jobs[0].step, Step::Upload);
+        assert_eq!(queue.jobs[0].attempts, 1);
+        assert!(queue.jobs[0].is_pending(2));
+        assert!(queue.jobs[0].is_failed(1));
+        assert!(!queue.jobs[0].is_due(2, Some(now)));
+        assert!(queue.jobs[0].is_due(2, Some(retry_at)));
+        assert!(queue.jobs[0].is_due(2, None));
+        assert_eq!(queue.next_retry(2), Some(retry_at));
+    }
+}
@@ -1,6 +1,6 @@
 use clap::Parser;
 
-use crate::helpers::cryptography::restricted_hash;
+use koishi::{Result, helpers::cryptography::restricted_hash};
 
 #[derive(Parser)]
 pub(crate) struct Args {
@@ -10,7 +10,8 @@ pub(crate) struct Args {
     uuid: String,
 }
 
-pub(crate) fn main(args: Args) {
-    let hash = restricted_hash(&args.uuid.to_ascii_lowercase(), &args.password.trim()).unwrap();
-    println!("{}", hash)
+pub(crate) fn main(args: Args) -> Result<()> {
+    let hash = restricted_hash(&args.uuid.to_ascii_lowercase(), args.password.trim())?;
+    println!("{}", hash);
+    Ok(())
 }
@@ -1,10 +1,12 @@
 use clap::Parser;
 use reqwest::Url;
 use reqwest::blocking as req;
-use serde::Deserialize;
+use serde::{Deserialize, de::DeserializeOwned};
 use serde_json::Value;
 
-use crate::api;
+use koishi::{Error, Result, ResultExt, api};
+
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -38,33 +40,42 @@ struct ResUserCardInfo {
     face: String,
 }
 
-fn get_room_info(room_id: u64) -> Option<ResRoomInfo> {
+fn get_json(url: Url) -> Result<ResBody> {
+    let res = req::get(url)?.error_for_status()?;
+    Ok(res.json()?)
+}
+
+fn from_data<T: DeserializeOwned>(data: Value) -> Result<T> {
+    serde_json::value::from_value(data)
+        .map_err(|e| Error::validation(format!("Unexpected response from Bilibili: {e}")))
+}
+
+fn get_room_info(room_id: u64) -> Result<Option<ResRoomInfo>> {
     let url = Url::parse_with_params(ROOM_INFO_URL, &[("room_id", room_id.to_string())]).unwrap();
-    let res: ResBody = req::get(url).unwrap().json().unwrap();
+    let res = get_json(url)?;
     if res.code == 1 {
-        None
+        Ok(None)
     } else {
-        let info: ResRoomInfo = serde_json::value::from_value(res.data).unwrap();
-        Some(info)
+        from_data(res.data).map(Some)
     }
 }
 
-fn get_user_info(uid: u64) -> ResUserInfo {
+fn get_user_info(uid: u64) -> Result<ResUserInfo> {
     let url = Url::parse_with_params(USER_INFO_URL, &[("mid", uid.to_string())]).unwrap();
-    let res: ResBody = req::get(url).unwrap().json().unwrap();
-
-    serde_json::value::from_value(res.data).unwrap()
+    from_data(get_json(url)?.data)
 }
 
-fn fetch_info(room_id: u64) -> Option<api::room::Room> {
-    let room_info = get_room_info(room_id)?;
+fn fetch_info(room_id: u64) -> Result<Option<api::room::Room>> {
+    let Some(room_info) = get_room_info(room_id)? else {
+        return Ok(None);
+    };
     let short_id = if room_info.short_id == 0 {
         None
     } else {
         Some(room_info.short_id)
     };
 
-    let user_info = get_user_info(room_info.uid);
+    let user_info = get_user_info(room_info.uid)?;
 
     let ret = api::room::Room {
         id: room_info.room_id,
@@ -72,26 +83,27 @@ fn fetch_info(room_id: u64) -> Option<api::room::Room> {
         username: user_info.card.name,
         image: user_info.card.face,
     };
-    Some(ret)
+    Ok(Some(ret))
 }
 
-pub(super) fn main(args: Args) {
-    println!("Fetching info for room {}", args.room_id);
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    ctx.output
+        .info(format!("Fetching info for room {}", args.room_id));
 
-    let Some(info) = fetch_info(args.room_id) else {
-        println!("Room {} not found", args.room_id);
-        return;
+    let Some(info) = fetch_info(args.room_id).context("Failed to fetch room info")? else {
+        return Err(Error::NotFound(format!("Room {} not found", args.room_id)));
     };
 
-    println!("\tID:\t\t{}", info.id);
+    ctx.output.info(format!("\tID:\t\t{}", info.id));
     if let Some(short_id) = info.short_id {
-        println!("\tShort ID:\t{}", short_id);
+        ctx.output.info(format!("\tShort ID:\t{}", short_id));
     }
-    println!("\tName:\t\t{}", info.username);
-    println!("\tImage:\t\t{}", info.image);
+    ctx.output.info(format!("\tName:\t\t{}", info.username));
+    ctx.output.info(format!("\tImage:\t\t{}", info.image));
 
     let id = info.id;
-    println!("Creating room {}", info.id);
-    api::room::create(info).unwrap();
-    println!("Room {} created", id);
+    ctx.output.info(format!("Creating room {}", info.id));
+    api::room::create(client, info.clone())?;
+    ctx.output.result(info, format!("Room {} created", id))
 }
@@ -1,10 +1,9 @@
 use clap::Parser;
-use tabled::{
-    Table,
-    settings::{Remove, Style, location::ByColumnName},
-};
+use tabled::settings::{Remove, location::ByColumnName};
 
-use crate::api;
+use koishi::{Result, api};
+
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -13,13 +12,13 @@ pub(super) struct Args {
     room: u64,
 }
 
-pub(super) fn main(args: Args) {
-    let room = api::room::get(args.room).unwrap();
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    let room = api::room::get(client, args.room)?;
 
-    let mut table = Table::new(&[room]);
-    table.with(Style::modern());
-    if !args.image {
-        table.with(Remove::column(ByColumnName::new("Image")));
-    }
-    print!("{}", table)
+    ctx.output.one(room, |table| {
+        if !args.image {
+            table.with(Remove::column(ByColumnName::new("Image")));
+        }
+    })
 }
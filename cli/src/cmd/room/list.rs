@@ -1,7 +1,8 @@
 use clap::Parser;
-use tabled::{Table, settings::Style};
 
-use crate::api;
+use koishi::{Result, api};
+
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -9,13 +10,19 @@ pub(super) struct Args {
     limit: u64,
     #[arg(short, long, default_value_t = 0)]
     offset: u64,
+    /// List every room, fetching as many pages as needed
+    #[arg(short, long, conflicts_with = "limit")]
+    all: bool,
 }
 
-pub(super) fn main(args: Args) {
-    let res = api::room::list(args.limit, args.offset).unwrap();
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    if args.all {
+        let rooms = api::room::iter(client).offset(args.offset);
+        return ctx.output.try_list(rooms, |_| {});
+    }
 
-    let mut table = Table::new(res);
-    table.with(Style::modern());
+    let res = api::room::list(client, args.limit, args.offset)?;
 
-    println!("{}", table);
+    ctx.output.list(res, |_| {})
 }
@@ -1,10 +1,12 @@
 use clap::Parser;
 use tabled::{
     Table,
-    settings::{Remove, Style, location::ByColumnName},
+    settings::{Remove, location::ByColumnName},
 };
 
-use crate::api;
+use koishi::{Result, api};
+
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -14,23 +16,27 @@ pub(super) struct Args {
     limit: u64,
     #[arg(short, long, default_value_t = 0)]
     offset: u64,
+    /// List every video of the room, fetching as many pages as needed
+    #[arg(short, long, conflicts_with = "limit")]
+    all: bool,
 
     room: u64,
 }
 
-pub(super) fn main(args: Args) {
-    let res = api::room::list_videos(args.room, args.limit, args.offset);
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    let hide_cover = |table: &mut Table| {
+        if !args.cover {
+            table.with(Remove::column(ByColumnName::new("Cover")));
+        }
+    };
 
-    if let Err(e) = res {
-        eprintln!("API Request Error: {e}");
-        std::process::exit(-1)
+    if args.all {
+        let videos = api::room::iter_videos(client, args.room).offset(args.offset);
+        return ctx.output.try_list(videos, hide_cover);
     }
 
-    let mut table = Table::new(res.unwrap());
-    table.with(Style::modern());
-    if !args.cover {
-        table.with(Remove::column(ByColumnName::new("Cover")));
-    }
+    let res = api::room::list_videos(client, args.room, args.limit, args.offset)?;
 
-    println!("{}", table);
+    ctx.output.list(res, hide_cover)
 }
@@ -1,4 +1,6 @@
+use super::Context;
 use clap::{Parser, Subcommand};
+use koishi::Result;
 
 mod create;
 mod get;
@@ -19,14 +21,14 @@ enum Commands {
     ListVideos(list_videos::Args),
 }
 
-pub(crate) fn main(args: Args) {
-    match args.command {
-        Some(command) => match command {
-            Commands::Create(args) => create::main(args),
-            Commands::Get(args) => get::main(args),
-            Commands::List(args) => list::main(args),
-            Commands::ListVideos(args) => list_videos::main(args),
-        },
-        None => {}
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let Some(command) = args.command else {
+        return Ok(());
+    };
+    match command {
+        Commands::Create(args) => create::main(ctx, args),
+        Commands::Get(args) => get::main(ctx, args),
+        Commands::List(args) => list::main(ctx, args),
+        Commands::ListVideos(args) => list_videos::main(ctx, args),
     }
 }
@@ -0,0 +1,112 @@
+use clap::Parser;
+use std::path::PathBuf;
+use url::Url;
+
+use koishi::{
+    Error, Result,
+    server::{Catalog, Server, ServerConfig, StoreConfig, store::S3Config},
+};
+
+use super::Context;
+
+/// Run a self-hosted Koishi API, for use without Cloudflare
+///
+/// Requests other than GET must carry the auth key of the selected profile.
+/// Objects are kept in a local directory (--store) or an S3-compatible
+/// service (--s3-endpoint and friends).
+#[derive(Parser)]
+pub(crate) struct Args {
+    /// Address to listen on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8788")]
+    listen: String,
+
+    /// SQLite database of rooms and videos, created if missing
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "KOISHI_DB",
+        default_value = "koishi.db"
+    )]
+    db: PathBuf,
+
+    /// Keep objects in this directory and serve them under /s3
+    #[arg(
+        long,
+        value_name = "DIR",
+        env = "KOISHI_STORE",
+        required_unless_present = "s3_endpoint"
+    )]
+    store: Option<PathBuf>,
+
+    /// URL clients reach this server at, for links into the local store
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "KOISHI_PUBLIC_URL",
+        requires = "store"
+    )]
+    public_url: Option<Url>,
+
+    /// Path-style endpoint of an S3-compatible service
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "S3_ENDPOINT",
+        conflicts_with = "store",
+        requires_all = ["s3_key_id", "s3_key"]
+    )]
+    s3_endpoint: Option<Url>,
+
+    #[arg(long, value_name = "ID", env = "S3_KEY_ID")]
+    s3_key_id: Option<String>,
+
+    #[arg(long, value_name = "KEY", env = "S3_KEY", hide_env_values = true)]
+    s3_key: Option<String>,
+
+    #[arg(long, value_name = "REGION", env = "S3_REGION", default_value = "auto")]
+    s3_region: String,
+
+    /// First segment of every object key
+    #[arg(long, value_name = "NAME", env = "S3_BUCKET", default_value = "koishi")]
+    bucket: String,
+
+    /// Built web UI to serve at /
+    #[arg(long, value_name = "DIR")]
+    static_dir: Option<PathBuf>,
+
+    #[arg(long, value_name = "N", default_value_t = 8)]
+    threads: usize,
+}
+
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let auth_key = ctx.settings.auth_key.value.clone().ok_or_else(|| {
+        Error::validation("An auth key is required to serve; set one with -k or KOISHI_AUTH_KEY")
+    })?;
+
+    let store = match (args.store, args.s3_endpoint) {
+        (Some(root), _) => StoreConfig::Local {
+            root,
+            public_url: args.public_url,
+        },
+        (None, Some(endpoint)) => StoreConfig::S3(S3Config {
+            endpoint,
+            key_id: args.s3_key_id.unwrap_or_default(),
+            key: args.s3_key.unwrap_or_default(),
+            region: args.s3_region,
+        }),
+        (None, None) => unreachable!("Required by clap"),
+    };
+
+    let catalog = Catalog::open(&args.db)?;
+    let config = ServerConfig {
+        auth_key,
+        bucket: args.bucket,
+        static_dir: args.static_dir,
+    };
+    let server = Server::bind(&args.listen, config, catalog, store)?;
+
+    ctx.output
+        .info(format!("Listening on http://{}", server.local_addr()));
+    server.run(args.threads);
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! How many parts of a transfer are in flight at once. It starts with a few
+//! and takes one more each time the throughput measured over a while has
+//! risen, and halves on failures, so that a weak link is not swamped with
+//! requests timing out while a fast one is not left idle.
+
+use std::{
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use koishi::{Error, Result};
+
+/// Most parts in flight unless told otherwise.
+pub(super) const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+/// Parts in flight at the start.
+const INITIAL_IN_FLIGHT: usize = 2;
+/// Time over which the throughput is measured before it is compared.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+/// Ratio to the last throughput measured above which it counts as risen.
+const MIN_GAIN: f64 = 1.05;
+
+/// Limit on the parts in flight, adjusted from the throughput and failures.
+pub(super) struct Concurrency {
+    max: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    sample_start: Instant,
+    sample_bytes: u64,
+    /// Bytes per second over the last sample, if one was taken since the
+    /// last failure.
+    last_rate: Option<f64>,
+}
+
+/// Leave to have one part in flight, given back when dropped.
+pub(super) struct Permit<'a>(&'a Concurrency);
+
+impl Concurrency {
+    /// Controller letting at most `max` parts be in flight, or the default
+    /// if not given.
+    pub fn new(max: Option<usize>) -> Result<Self> {
+        let max = max.unwrap_or(DEFAULT_MAX_IN_FLIGHT);
+        if max == 0 {
+            return Err(Error::validation("Thread count must be greater than zero"));
+        }
+        Ok(Self {
+            max,
+            state: Mutex::new(State {
+                limit: INITIAL_IN_FLIGHT.min(max),
+                in_flight: 0,
+                sample_start: Instant::now(),
+                sample_bytes: 0,
+                last_rate: None,
+            }),
+            freed: Condvar::new(),
+        })
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn limit(&self) -> usize {
+        self.lock().limit
+    }
+
+    /// Thread pool with a thread for as many parts as may be in flight.
+    pub fn pool(&self) -> Result<rayon::ThreadPool> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.max)
+            .build()
+            .map_err(|e| Error::validation(format!("Failed to set thread count: {e}")))
+    }
+
+    /// Wait until another part may be in flight.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut state = self.lock();
+        while state.in_flight >= state.limit {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+        Permit(self)
+    }
+
+    /// Take in the bytes transferred so far. Returns the new limit if it
+    /// was raised, the throughput having risen since the last sample.
+    pub fn record(&self, bytes: u64) -> Option<usize> {
+        self.record_at(bytes, Instant::now())
+    }
+
+    fn record_at(&self, bytes: u64, now: Instant) -> Option<usize> {
+        let mut state = self.lock();
+        let elapsed = now.saturating_duration_since(state.sample_start);
+        if elapsed < SAMPLE_INTERVAL {
+            return None;
+        }
+        let rate = bytes.saturating_sub(state.sample_bytes) as f64 / elapsed.as_secs_f64();
+        state.sample_start = now;
+        state.sample_bytes = bytes;
+        let risen = state.last_rate.is_none_or(|last| rate > last * MIN_GAIN);
+        state.last_rate = Some(rate);
+        if !risen || state.limit >= self.max {
+            return None;
+        }
+        state.limit += 1;
+        self.freed.notify_all();
+        Some(state.limit)
+    }
+
+    /// Halve the limit after a part failed or stalled, `bytes` having been
+    /// transferred so far. Returns the new limit if it was lowered.
+    pub fn back_off(&self, bytes: u64) -> Option<usize> {
+        self.back_off_at(bytes, Instant::now())
+    }
+
+    fn back_off_at(&self, bytes: u64, now: Instant) -> Option<usize> {
+        let mut state = self.lock();
+        // Throughput before the failure says nothing of the one after.
+        state.last_rate = None;
+        state.sample_start = now;
+        state.sample_bytes = bytes;
+        if state.limit == 1 {
+            return None;
+        }
+        state.limit = (state.limit / 2).max(1);
+        Some(state.limit)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.lock().in_flight -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grows_while_throughput_rises() {
+        let c = Concurrency::new(Some(4)).unwrap();
+        let start = c.lock().sample_start;
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(c.limit(), 2);
+
+        // Too soon to tell.
+        assert_eq!(c.record_at(1_000, at(1)), None);
+        assert_eq!(c.record_at(2_000, at(2)), Some(3));
+        // 2000 bytes in 2s after 1000 bytes/s.
+        assert_eq!(c.record_at(6_000, at(4)), Some(4));
+        // Not past the most allowed.
+        assert_eq!(c.record_at(16_000, at(6)), None);
+        assert_eq!(c.limit(), 4);
+    }
+
+    #[test]
+    fn test_holds_when_throughput_levels() {
+        let c = Concurrency::new(None).unwrap();
+        let start = c.lock().sample_start;
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(c.record_at(2_000, at(2)), Some(3));
+        assert_eq!(c.record_at(4_000, at(4)), None);
+        assert_eq!(c.record_at(6_050, at(6)), None);
+        assert_eq!(c.limit(), 3);
+        assert_eq!(c.record_at(10_000, at(8)), Some(4));
+    }
+
+    #[test]
+    fn test_backs_off_on_failure() {
+        let c = Concurrency::new(Some(16)).unwrap();
+        let start = c.lock().sample_start;
+        let at = |secs| start + Duration::from_secs(secs);
+        for (i, bytes) in [1, 3, 7, 15, 31].into_iter().enumerate() {
+            c.record_at(bytes * 1_000, at(2 * (i as u64 + 1)));
+        }
+        assert_eq!(c.limit(), 7);
+
+        assert_eq!(c.back_off_at(35_000, at(11)), Some(3));
+        assert_eq!(c.back_off_at(35_000, at(11)), Some(1));
+        assert_eq!(c.back_off_at(35_000, at(11)), None);
+        // Measured from the failure on, rather than compared to before it.
+        assert_eq!(c.record_at(35_001, at(13)), Some(2));
+    }
+
+    #[test]
+    fn test_back_off_starts_sample_over() {
+        let c = Concurrency::new(Some(16)).unwrap();
+        let start = c.lock().sample_start;
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(c.record_at(20_000, at(2)), Some(3));
+
+        // The 20000 bytes before the failure are not taken as sent after it.
+        assert_eq!(c.back_off_at(40_000, at(3)), Some(1));
+        assert_eq!(c.record_at(42_000, at(5)), Some(2));
+        assert_eq!(c.record_at(44_400, at(7)), Some(3));
+    }
+
+    #[test]
+    fn test_acquire_waits_for_limit() {
+        let c = Concurrency::new(Some(1)).unwrap();
+        let permit = c.acquire();
+        std::thread::scope(|s| {
+            let waiting = s.spawn(|| {
+                let _permit = c.acquire();
+                c.lock().in_flight
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            drop(permit);
+            assert_eq!(waiting.join().unwrap(), 1);
+        });
+        assert_eq!(c.lock().in_flight, 0);
+        assert!(Concurrency::new(Some(0)).is_err());
+    }
+}
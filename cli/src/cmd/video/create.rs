@@ -2,8 +2,11 @@ use chrono::{DateTime, Utc};
 use clap::Parser;
 use uuid::Uuid;
 
-use crate::api;
-use crate::helpers::cryptography::restricted_hash;
+use koishi::helpers::cryptography::restricted_hash;
+use koishi::{Error, Result, api};
+
+use super::VideoChanged;
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -29,32 +32,38 @@ pub(super) struct Args {
     room: u64,
 }
 
-fn parse_timestamp(date_str: &str) -> i64 {
-    date_str.parse::<DateTime<Utc>>()
-        .expect(format!("Failed to parse timestamp {date_str}").as_str())
-        .timestamp_millis()
+fn parse_timestamp(date_str: &str) -> Result<i64> {
+    date_str
+        .parse::<DateTime<Utc>>()
+        .map(|t| t.timestamp_millis())
+        .map_err(|e| Error::validation(format!("Failed to parse timestamp {date_str}: {e}")))
 }
 
-pub(super) fn main(args: Args) {
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
     let uuid = args
         .uuid
         .unwrap_or_else(|| Uuid::now_v7().as_simple().to_string());
 
-    let stream_time = parse_timestamp(&args.stream_time);
-    let record_time = parse_timestamp(&args.record_time);
+    let stream_time = parse_timestamp(&args.stream_time)?;
+    let record_time = parse_timestamp(&args.record_time)?;
 
-    let restricted_hash = args.password.map(|v| restricted_hash(&uuid, &v).unwrap());
+    let restricted_hash = args
+        .password
+        .map(|v| restricted_hash(&uuid, &v))
+        .transpose()?;
 
     api::video::create(
+        client,
         &uuid,
         args.title,
         args.cover,
-	stream_time,
-	record_time,
+        stream_time,
+        record_time,
         args.room,
         restricted_hash,
-    )
-    .unwrap();
+    )?;
 
-    println!("Created video {uuid}");
+    let message = format!("Created video {uuid}");
+    ctx.output.result(VideoChanged { uuid }, message)
 }
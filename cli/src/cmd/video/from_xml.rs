@@ -3,14 +3,16 @@ use clap::Parser;
 use quick_xml::de::from_str;
 use serde::Deserialize;
 use std::{
-    fs::File,
-    io::Read,
+    fs,
     path::{Path, PathBuf},
 };
 use uuid::Uuid;
 
-use crate::api;
-use crate::helpers::cryptography::restricted_hash;
+use koishi::helpers::cryptography::restricted_hash;
+use koishi::{Error, Result, ResultExt, api};
+
+use super::VideoChanged;
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct ImportArgs {
@@ -24,7 +26,6 @@ pub(super) struct ImportArgs {
     path: PathBuf,
 }
 
-
 #[derive(Parser)]
 pub(super) struct UpdateArgs {
     #[arg(short, long)]
@@ -46,70 +47,76 @@ struct XMLRoot {
     metadata: XMLRoomMetadata,
 }
 
-fn read_xml(path: &Path) -> XMLRoomMetadata {
-    let mut file = File::open(path).expect("File not found");
-    let mut xml_data = String::new();
-    file.read_to_string(&mut xml_data).expect("XML read failed");
-
-    match from_str::<XMLRoot>(&xml_data) {
-        Ok(root) => root.metadata,
-        Err(e) => {
-            eprintln!("Error deserializing XML: {}", e);
-            std::process::exit(-1)
-        }
-    }
+fn read_xml(path: &Path) -> Result<XMLRoomMetadata> {
+    let context = || format!("Failed to read {}", path.display());
+    let xml_data = fs::read_to_string(path).with_context(context)?;
+    let root = from_str::<XMLRoot>(&xml_data).with_context(context)?;
+    Ok(root.metadata)
 }
 
-pub(super) fn import(args: ImportArgs) {
-    let uuid = args
-        .uuid
-        .unwrap_or_else(|| Uuid::now_v7().as_simple().to_string());
-    let metadata = read_xml(&args.path);
-    let stream_time = metadata
-        .live_start_time
+fn parse_time(name: &str, value: &str) -> Result<i64> {
+    value
         .parse::<DateTime<Utc>>()
-        .expect("Failed to parse live_start_time");
-    let record_time = metadata
-        .record_start_time
-        .parse::<DateTime<Utc>>()
-        .expect("Failed to parse record_start_time");
+        .map(|t| t.timestamp_millis())
+        .map_err(|e| Error::validation(format!("Failed to parse {name} {value}: {e}")))
+}
 
-    let restricted_hash = args.password.map(|v| restricted_hash(&uuid, &v).unwrap());
+/// Create video `uuid` from the recorder metadata in the XML at `path`.
+pub(crate) fn create_from_xml(
+    ctx: &Context,
+    uuid: &str,
+    path: &Path,
+    cover: Option<String>,
+    restricted_hash: Option<String>,
+) -> Result<()> {
+    let metadata = read_xml(path)?;
+    let stream_time = parse_time("live_start_time", &metadata.live_start_time)?;
+    let record_time = parse_time("record_start_time", &metadata.record_start_time)?;
 
     api::video::create(
-        &uuid,
+        &ctx.client,
+        uuid,
         metadata.room_title,
-        args.cover,
-        stream_time.timestamp_millis(),
-	record_time.timestamp_millis(),
+        cover,
+        stream_time,
+        record_time,
         metadata.room_id,
         restricted_hash,
     )
-    .unwrap();
+    .map_err(Into::into)
+}
+
+pub(super) fn import(ctx: &Context, args: ImportArgs) -> Result<()> {
+    let uuid = args
+        .uuid
+        .unwrap_or_else(|| Uuid::now_v7().as_simple().to_string());
+    let restricted_hash = args
+        .password
+        .map(|v| restricted_hash(&uuid, &v))
+        .transpose()?;
 
-    println!("Created video {uuid} from XML");
+    create_from_xml(ctx, &uuid, &args.path, args.cover, restricted_hash)?;
+
+    let message = format!("Created video {uuid} from XML");
+    ctx.output.result(VideoChanged { uuid }, message)
 }
 
-pub(super) fn update(args: UpdateArgs) {
+pub(super) fn update(ctx: &Context, args: UpdateArgs) -> Result<()> {
+    let client = &ctx.client;
     let uuid = args.uuid;
-    let metadata = read_xml(&args.path);
-    let stream_time = metadata
-        .live_start_time
-        .parse::<DateTime<Utc>>()
-        .expect("Failed to parse live_start_time");
-    let record_time = metadata
-        .record_start_time
-        .parse::<DateTime<Utc>>()
-        .expect("Failed to parse record_start_time");
+    let metadata = read_xml(&args.path)?;
+    let stream_time = parse_time("live_start_time", &metadata.live_start_time)?;
+    let record_time = parse_time("record_start_time", &metadata.record_start_time)?;
 
     api::video::update(
+        client,
         &uuid,
         Some(metadata.room_title),
-	None,
-        Some(stream_time.timestamp_millis()),
-	Some(record_time.timestamp_millis()),
-    )
-    .unwrap();
+        None,
+        Some(stream_time),
+        Some(record_time),
+    )?;
 
-    println!("Updated video {uuid} from XML");
+    let message = format!("Updated video {uuid} from XML");
+    ctx.output.result(VideoChanged { uuid }, message)
 }
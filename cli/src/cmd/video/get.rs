@@ -1,10 +1,9 @@
 use clap::Parser;
-use tabled::{
-    Table,
-    settings::{Remove, Style, location::ByColumnName},
-};
+use tabled::settings::{Remove, location::ByColumnName};
 
-use crate::api;
+use koishi::{Result, api};
+
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -14,14 +13,13 @@ pub(super) struct Args {
     cover: bool,
 }
 
-pub(super) fn main(args: Args) {
-    let video = api::video::get(&args.uuid).unwrap();
-
-    let mut table = Table::new([video]);
-    table.with(Style::modern());
-    if !args.cover {
-	table.with(Remove::column(ByColumnName::new("Cover URL")));
-    }
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    let video = api::video::get(client, &args.uuid)?;
 
-    println!("{}", table);
+    ctx.output.one(video, |table| {
+        if !args.cover {
+            table.with(Remove::column(ByColumnName::new("Cover URL")));
+        }
+    })
 }
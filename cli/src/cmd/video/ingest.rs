@@ -0,0 +1,229 @@
+//! Taking one recording through every step of putting it online, with a
+//! journal beside it so that a failed run resumes at the step that failed
+//! or is rolled back.
+
+use clap::Parser;
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+use koishi::{Error, ErrorKind, Result, ResultExt, api, helpers::cryptography::restricted_hash};
+
+use super::{
+    VideoChanged,
+    upload::{abort_unfinished, confirm},
+};
+use crate::{
+    cmd::{
+        Context,
+        recording::{
+            self,
+            queue::{Job, Step},
+        },
+    },
+    files::write_atomic,
+    interrupt,
+};
+
+#[derive(Parser)]
+pub(super) struct Args {
+    /// Metadata XML to create the video from; the `.xml` beside the video
+    /// if not given
+    #[arg(long, value_name = "PATH")]
+    xml: Option<PathBuf>,
+    /// Cover image to upload and set
+    #[arg(short, long, value_name = "PATH")]
+    cover: Option<PathBuf>,
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Undo the steps an unfinished ingest of the video has done
+    #[arg(long)]
+    rollback: bool,
+
+    path: PathBuf,
+}
+
+/// Journal of the ingest of `video`, which exists while it is unfinished.
+fn journal_path(video: &Path) -> PathBuf {
+    video.with_extension("ingest")
+}
+
+fn load(path: &Path) -> Result<Option<Job>> {
+    let context = || format!("Failed to read ingest journal {}", path.display());
+    match fs::read(path) {
+        Ok(data) => Ok(Some(
+            serde_json::from_slice(&data)
+                .map_err(io::Error::from)
+                .with_context(context)?,
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::from(e).context(context())),
+    }
+}
+
+fn save(path: &Path, job: &Job) -> Result<()> {
+    let data = serde_json::to_vec(job).map_err(io::Error::from)?;
+    write_atomic(path, &data)
+        .with_context(|| format!("Failed to write ingest journal {}", path.display()))
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Undo what `job` has done: abort its upload and delete its video. The
+/// cover stays, as other videos may share it.
+fn rollback(ctx: &Context, job: &Job, hash: Option<String>) -> Result<()> {
+    let Some(uuid) = &job.uuid else {
+        return Ok(());
+    };
+    abort_unfinished(ctx, uuid, &job.video, hash.clone())?;
+    if job.step != Step::Cover {
+        match api::video::delete(&ctx.client, uuid, hash.as_deref()).map_err(Error::from) {
+            // Failed before the video was created.
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            res => res.context("Failed to delete video")?,
+        }
+    }
+    Ok(())
+}
+
+/// After the ingest failed, roll it back if the user agrees when asked, or
+/// else tell how to go on.
+fn offer_rollback(ctx: &Context, journal: &Path, job: &Job, hash: Option<String>) {
+    let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+    let question = format!(
+        "Ingest failed at step {}; roll back the steps before it?",
+        job.step
+    );
+    if interactive && confirm(&question) {
+        match rollback(ctx, job, hash).and_then(|()| remove(journal)) {
+            Ok(()) => eprintln!("Ingest rolled back"),
+            Err(e) => eprintln!("warning: {}", recording::describe(&e)),
+        }
+        return;
+    }
+    eprintln!(
+        "Run again to resume at step {}, or roll back with:\n  \
+         koishi video ingest --rollback {}",
+        job.step,
+        job.video.display()
+    );
+}
+
+/// Refuse an XML or cover given on resuming other than the ones the ingest
+/// was started with, which it goes on with.
+fn check_same_files(job: &Job, xml: Option<&Path>, cover: Option<&Path>) -> Result<()> {
+    let same = |a: &Path, b: &Path| {
+        a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+    };
+    for (flag, given, started) in [
+        ("--xml", xml, job.xml.as_deref()),
+        ("--cover", cover, job.cover.as_deref()),
+    ] {
+        let Some(given) = given else {
+            continue;
+        };
+        let started = match started {
+            Some(started) if same(given, started) => continue,
+            Some(started) => format!("with {flag} {}", started.display()),
+            None => format!("without {flag}"),
+        };
+        return Err(Error::validation(format!(
+            "The ingest was started {started}; roll it back with --rollback to change it"
+        )));
+    }
+    Ok(())
+}
+
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let journal = journal_path(&args.path);
+    let resumed = load(&journal)?;
+
+    if args.rollback {
+        let Some(job) = resumed else {
+            return Err(Error::NotFound(format!(
+                "No unfinished ingest of {}",
+                args.path.display()
+            )));
+        };
+        let uuid = job.uuid.clone().unwrap_or_default();
+        let hash = args
+            .password
+            .map(|v| restricted_hash(&uuid, &v))
+            .transpose()?;
+        rollback(ctx, &job, hash)?;
+        remove(&journal)?;
+        let message = format!("Rolled back ingest of video {uuid}");
+        return ctx.output.result(VideoChanged { uuid }, message);
+    }
+
+    let mut job = match resumed {
+        Some(job) => {
+            check_same_files(&job, args.xml.as_deref(), args.cover.as_deref())?;
+            ctx.output.info(format!(
+                "Resuming ingest of {} at step {}",
+                args.path.display(),
+                job.step
+            ));
+            job
+        }
+        None => {
+            let xml = args.xml.unwrap_or_else(|| args.path.with_extension("xml"));
+            for path in [&args.path, &xml].into_iter().chain(&args.cover) {
+                if !path.is_file() {
+                    return Err(Error::NotFound(format!("{} not found", path.display())));
+                }
+            }
+            let mut job = Job::new(args.path.clone(), Some(xml), args.cover);
+            job.uuid = Some(Uuid::now_v7().as_simple().to_string());
+            job.restricted = args.password.is_some();
+            save(&journal, &job)?;
+            job
+        }
+    };
+    match (job.restricted, &args.password) {
+        (true, None) => {
+            return Err(Error::validation(
+                "The video is restricted; pass its --password",
+            ));
+        }
+        (false, Some(_)) => {
+            return Err(Error::validation(
+                "The ingest was started without --password",
+            ));
+        }
+        _ => {}
+    }
+
+    let uuid = job.uuid.clone().unwrap_or_default();
+    let hash = args
+        .password
+        .map(|v| restricted_hash(&uuid, &v))
+        .transpose()?;
+    if let Err(e) = recording::advance(ctx, &mut job, hash.as_deref(), |job| save(&journal, job)) {
+        if e.kind() == ErrorKind::Interrupted {
+            eprintln!(
+                "Ingest interrupted at step {}; resume it with:\n  {}",
+                job.step,
+                interrupt::command_line(|_| {})
+            );
+            return Err(e);
+        }
+        job.error = Some(recording::describe(&e));
+        job.attempts += 1;
+        save(&journal, &job)?;
+        offer_rollback(ctx, &journal, &job, hash);
+        return Err(e);
+    }
+    remove(&journal)?;
+
+    let message = format!("Ingested video {uuid}");
+    ctx.output.result(VideoChanged { uuid }, message)
+}
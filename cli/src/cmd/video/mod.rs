@@ -1,12 +1,25 @@
+use super::Context;
 use clap::{Parser, Subcommand};
+use koishi::Result;
+use serde::Serialize;
+use tabled::Tabled;
 
+use crate::output::Record;
+
+mod concurrency;
 mod create;
-mod get;
 mod from_xml;
+mod get;
+mod ingest;
+mod progress;
 mod restrict;
 mod set_cover;
 mod set_metadata;
 mod upload;
+mod verify;
+
+pub(crate) use from_xml::create_from_xml;
+pub(crate) use upload::upload_video;
 
 #[derive(Parser)]
 pub(crate) struct Args {
@@ -19,27 +32,40 @@ enum Commands {
     Create(create::Args),
     Get(get::Args),
     ImportFromXml(from_xml::ImportArgs),
+    /// Create, upload, and add the metadata and cover of a video in one go
+    Ingest(ingest::Args),
     Restrict(restrict::Args),
     SetCover(set_cover::Args),
     SetMetadata(set_metadata::Args),
     Unrestrict(restrict::Args),
     UpdateFromXml(from_xml::UpdateArgs),
     Upload(upload::Args),
+    Verify(verify::Args),
 }
 
-pub(crate) fn main(args: Args) {
-    match args.command {
-        Some(command) => match command {
-            Commands::Create(args) => create::main(args),
-	    Commands::Get(args) => get::main(args),
-            Commands::ImportFromXml(args) => from_xml::import(args),
-            Commands::Restrict(args) => restrict::main(args, true),
-            Commands::SetCover(args) => set_cover::main(args),
-            Commands::SetMetadata(args) => set_metadata::main(args),
-            Commands::Unrestrict(args) => restrict::main(args, false),
-	    Commands::UpdateFromXml(args) => from_xml::update(args),
-            Commands::Upload(args) => upload::main(args),
-        },
-        None => {}
+/// Result of commands that create or update a video.
+#[derive(Serialize, Tabled)]
+struct VideoChanged {
+    uuid: String,
+}
+
+impl Record for VideoChanged {}
+
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let Some(command) = args.command else {
+        return Ok(());
+    };
+    match command {
+        Commands::Create(args) => create::main(ctx, args),
+        Commands::Get(args) => get::main(ctx, args),
+        Commands::ImportFromXml(args) => from_xml::import(ctx, args),
+        Commands::Ingest(args) => ingest::main(ctx, args),
+        Commands::Restrict(args) => restrict::main(ctx, args, true),
+        Commands::SetCover(args) => set_cover::main(ctx, args),
+        Commands::SetMetadata(args) => set_metadata::main(ctx, args),
+        Commands::Unrestrict(args) => restrict::main(ctx, args, false),
+        Commands::UpdateFromXml(args) => from_xml::update(ctx, args),
+        Commands::Upload(args) => upload::main(ctx, args),
+        Commands::Verify(args) => verify::main(ctx, args),
     }
 }
@@ -0,0 +1,165 @@
+//! How transfers report their progress: bars for a terminal, nothing, or
+//! events as JSON lines on stderr for programs supervising them, leaving
+//! stdout to the result.
+
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Least time between two `bytes` events.
+const BYTES_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub(super) enum ProgressMode {
+    /// Progress bars
+    #[default]
+    Bar,
+    /// Nothing but the messages
+    None,
+    /// One JSON object per event on stderr
+    Jsonl,
+}
+
+impl ProgressMode {
+    /// The mode asked for, where `no_progress` is the older `-P` for none.
+    pub fn resolve(mode: ProgressMode, no_progress: bool) -> Self {
+        match no_progress {
+            true => Self::None,
+            false => mode,
+        }
+    }
+}
+
+/// What happened to a transfer. Parts are numbered from 1.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(super) enum Event<'a> {
+    Started {
+        upload_id: &'a str,
+        parts: u64,
+        part_size: u64,
+    },
+    PartStarted {
+        part: usize,
+        part_bytes: u64,
+    },
+    /// Data sent so far, now and then while a part is in flight.
+    Bytes {
+        part: usize,
+        part_bytes: u64,
+    },
+    PartDone {
+        part: usize,
+        part_bytes: u64,
+        /// Done before a resume rather than now.
+        skipped: bool,
+    },
+    Retry {
+        part: usize,
+        /// Number of the attempt about to be made, from 2.
+        attempt: u32,
+        delay_secs: f64,
+        error: String,
+    },
+    Finished,
+    Failed {
+        error: String,
+    },
+}
+
+/// An event along with the progress of the whole transfer.
+#[derive(Serialize)]
+struct Line<'a> {
+    #[serde(flatten)]
+    event: Event<'a>,
+    bytes: u64,
+    total_bytes: Option<u64>,
+    bytes_per_sec: f64,
+    eta_secs: Option<f64>,
+}
+
+/// Writer of events as JSON lines on stderr.
+pub(super) struct Events {
+    last_bytes: Mutex<Instant>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self {
+            last_bytes: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Write `event`, with the progress of the whole transfer as counted by
+    /// `total`.
+    pub fn emit(&self, total: &ProgressBar, event: Event) {
+        let bytes = total.position();
+        let total_bytes = total.length();
+        let line = Line {
+            event,
+            bytes,
+            total_bytes,
+            bytes_per_sec: total.per_sec(),
+            eta_secs: total_bytes
+                .filter(|&len| bytes < len)
+                .map(|_| total.eta().as_secs_f64()),
+        };
+        let line = serde_json::to_string(&line).unwrap();
+        // Whole lines only, even with parts finishing at the same time.
+        let mut stderr = io::stderr().lock();
+        let _ = writeln!(stderr, "{line}");
+    }
+
+    /// Write a `bytes` event unless one went out less than a second ago.
+    pub fn bytes(&self, total: &ProgressBar, part: usize, part_bytes: u64) {
+        {
+            let mut last = self.last_bytes.lock().unwrap();
+            if last.elapsed() < BYTES_INTERVAL {
+                return;
+            }
+            *last = Instant::now();
+        }
+        self.emit(total, Event::Bytes { part, part_bytes });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let total = ProgressBar::hidden();
+        total.set_length(100);
+        total.set_position(40);
+        let line = Line {
+            event: Event::PartDone {
+                part: 2,
+                part_bytes: 20,
+                skipped: false,
+            },
+            bytes: total.position(),
+            total_bytes: total.length(),
+            bytes_per_sec: 0.0,
+            eta_secs: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&line).unwrap(),
+            serde_json::json!({
+                "event": "part_done",
+                "part": 2,
+                "part_bytes": 20,
+                "skipped": false,
+                "bytes": 40,
+                "total_bytes": 100,
+                "bytes_per_sec": 0.0,
+                "eta_secs": null,
+            })
+        );
+    }
+}
@@ -1,100 +1,392 @@
+use chrono::{TimeDelta, Utc};
 use clap::Parser;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use rayon::prelude::*;
-use std::cmp::min;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::min,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tabled::Tabled;
 
-use crate::api;
-use crate::helpers::{cryptography::restricted_hash, s3};
+use koishi::helpers::{cryptography::restricted_hash, multipart, s3::presigned_expiry};
+use koishi::{Error, ErrorKind, KoishiClient, Result, ResultExt, api};
+
+use super::{
+    concurrency::Concurrency,
+    progress::{Event, Events, ProgressMode},
+    upload::PartRetry,
+};
+use crate::{
+    cmd::{Context, recording::describe},
+    files::write_atomic,
+    interrupt,
+    output::Record,
+};
 
 #[derive(Parser)]
 pub(super) struct Args {
+    /// Show no progress bar; the same as --progress none
     #[arg(short = 'P', long)]
     no_progress: bool,
+    /// How to show progress; events are written to stderr
+    #[arg(long, value_enum, default_value_t, value_name = "MODE")]
+    progress: ProgressMode,
     #[arg(short, long)]
     password: String,
-    #[arg(short = 's', long, default_value_t = 100_000_000)]
-    part_size: u64,
+    /// Size of the copied parts in bytes; picked from the video size if not
+    /// given
+    #[arg(short = 's', long)]
+    part_size: Option<u64>,
 
+    /// Most parts copied at once, going up from a few while that speeds
+    /// the copy up [default: 16]
     #[arg(short, long)]
     thread_count: Option<usize>,
 
     uuid: String,
 }
 
-pub(super) fn main(args: Args, restricted: bool) {
-    let hash = restricted_hash(&args.uuid, &args.password).unwrap();
+pub(super) const DEFAULT_PART_SIZE: u64 = 100_000_000;
+
+#[derive(Serialize, Tabled)]
+struct RestrictedSet {
+    uuid: String,
+    restricted: bool,
+    /// Whether the uploaded video had to be copied to its new location.
+    copied: bool,
+}
+
+impl Record for RestrictedSet {}
+
+/// Least time left on the URLs of the parts still to copy for a copy to be
+/// resumed rather than started over.
+const EXPIRY_MARGIN: TimeDelta = TimeDelta::minutes(15);
+
+/// Longest a single part copy may take before it is tried again.
+const COPY_PART_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Progress of a multi-part copy, kept in the state directory so that a
+/// copy that was stopped resumes rather than starts over.
+#[derive(Serialize, Deserialize)]
+struct CopyState {
+    copy_source: String,
+    upload_id: String,
+    urls: Vec<String>,
+    part_size: u64,
+    length: u64,
+    etags: Vec<Option<String>>,
+}
+
+fn copy_state_path(uuid: &str) -> Option<PathBuf> {
+    let state_home = dirs::state_dir().or_else(dirs::data_local_dir)?;
+    let name: String = uuid
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Some(
+        state_home
+            .join("koishi")
+            .join("copies")
+            .join(format!("{name}.json")),
+    )
+}
+
+impl CopyState {
+    /// The copy from `source` saved at `path`, if it can still be resumed.
+    fn load(path: &Path, source: &str) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        let state: Self = match serde_json::from_slice(&data) {
+            Ok(state) => state,
+            Err(e) => {
+                log::warn!("Ignoring {}: {e}", path.display());
+                return None;
+            }
+        };
+        let deadline = Utc::now() + EXPIRY_MARGIN;
+        let expired = state
+            .urls
+            .iter()
+            .zip(&state.etags)
+            .filter(|(_, etag)| etag.is_none())
+            .any(|(url, _)| presigned_expiry(url).is_some_and(|expiry| expiry < deadline));
+        (state.copy_source == source && state.urls.len() == state.etags.len() && !expired)
+            .then_some(state)
+    }
+
+    /// Save the progress, which only spares a resume some work, so failing
+    /// to is logged rather than failing the copy.
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_vec(self)
+            .map_err(io::Error::from)
+            .and_then(|data| {
+                fs::create_dir_all(path.parent().unwrap())?;
+                write_atomic(path, &data)
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to save copy progress in {}: {e}", path.display());
+        }
+    }
+}
+
+/// Length of the uploaded video; `None` if it has not been uploaded.
+fn uploaded_length(client: &KoishiClient, uuid: &str, hash: &str) -> Result<Option<u64>> {
+    match api::video::object(client, uuid, Some(hash)).map_err(Error::from) {
+        Ok(object) => Ok(Some(object.size)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to look up the uploaded video"),
+    }
+}
 
-    println!("Updating restricted state");
-    let ret = api::video::set_restricted(&args.uuid, restricted, &hash).unwrap();
+pub(super) fn main(ctx: &Context, args: Args, restricted: bool) -> Result<()> {
+    let client = &ctx.client;
+    let part_size = args.part_size.or(ctx.settings.part_size.value);
+    let concurrency = Concurrency::new(args.thread_count.or(ctx.settings.thread_count.value))?;
+    let retry = PartRetry::resolve(ctx, None, None)?;
 
-    if ret.copy_source.is_none() {
-        println!("Video not uploaded yet; skipped renaming");
-        return;
+    // Refuse a part size that does not suit the video before changing
+    // anything, as a refused copy would leave it restricted at the old key.
+    let hash = restricted_hash(&args.uuid, &args.password)?;
+    if let Some(part_size) = part_size {
+        multipart::validate_part_size_bounds(part_size)?;
+        if let Some(length) = uploaded_length(client, &args.uuid, &hash)? {
+            multipart::validate_part_size(length, part_size)?;
+        }
     }
-    let source = ret.copy_source.unwrap();
+
+    ctx.output.info("Updating restricted state");
+    let ret = api::video::set_restricted(client, &args.uuid, restricted, &hash)
+        .context("Failed to update restricted state")?;
+
+    let Some(source) = ret.copy_source else {
+        ctx.output.info("Video not uploaded yet; skipped renaming");
+        let record = RestrictedSet {
+            uuid: args.uuid,
+            restricted,
+            copied: false,
+        };
+        return ctx.output.result(record, "Completed");
+    };
 
     let hash = if restricted { Some(hash) } else { None };
 
-    std::println!("Intiating multi-part copy");
-    let copy_start = api::video::restricted_copy_start(
-        &args.uuid,
-        source.as_str(),
-        hash.clone(),
-        args.part_size,
-    )
-    .expect("Failed to initiate multi-part copy");
-
-    args.thread_count.map(|tc| {
-        println!("Setting thread count to {tc}");
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(tc)
-            .build_global()
-            .expect("Failed to set thread count")
-    });
+    let state_path = copy_state_path(&args.uuid);
+    let resumed = state_path
+        .as_deref()
+        .and_then(|path| CopyState::load(path, &source));
+    let state = match resumed {
+        Some(state) => {
+            let left = state.etags.iter().filter(|v| v.is_none()).count();
+            ctx.output.info(format!(
+                "Resuming multi-part copy {} with {left} parts left",
+                state.upload_id
+            ));
+            state
+        }
+        None => {
+            let part_size = match (part_size, ret.length) {
+                (Some(part_size), Some(length)) => {
+                    multipart::validate_part_size(length, part_size)?;
+                    part_size
+                }
+                (Some(part_size), None) => part_size,
+                (None, Some(length)) => {
+                    multipart::choose_part_size(length, concurrency.max(), DEFAULT_PART_SIZE)
+                }
+                // Servers not telling the length get the previous default.
+                (None, None) => DEFAULT_PART_SIZE,
+            };
+
+            ctx.output.info("Initiating multi-part copy");
+            let copy_start = api::video::restricted_copy_start(
+                client,
+                &args.uuid,
+                source.as_str(),
+                hash.clone(),
+                part_size,
+            )
+            .context("Failed to initiate multi-part copy")?;
+            CopyState {
+                copy_source: source.clone(),
+                upload_id: copy_start.upload_id,
+                etags: vec![None; copy_start.urls.len()],
+                urls: copy_start.urls,
+                part_size,
+                length: copy_start.length,
+            }
+        }
+    };
+    if let Some(path) = &state_path {
+        state.save(path);
+    }
 
-    let parts = copy_start.urls.len() as u64;
+    let parts = state.urls.len() as u64;
+    let part_size = state.part_size;
 
+    let progress = ProgressMode::resolve(args.progress, args.no_progress);
     let pb = ProgressBar::new(parts);
-    if args.no_progress {
+    if progress != ProgressMode::Bar {
         pb.set_draw_target(ProgressDrawTarget::hidden());
     }
 
     pb.set_style(
         ProgressStyle::default_bar()
-            .template("Parts   {bar:40.cyan/blue} {pos}/{len}")
+            .template("Parts   {bar:40.cyan/blue} {pos}/{len} {msg}")
             .unwrap()
             .progress_chars("##-"),
     );
-    pb.set_position(0);
-
-    let uploader = s3::Uploader::new().expect("Failed to create S3 Uploader");
-
-    let etags = copy_start
-        .urls
-        .par_iter()
-        .enumerate()
-        .map(|(i, url)| {
-            let range_from = (i as u64) * args.part_size;
-            let range_to = min(range_from + args.part_size, copy_start.length) - 1;
-            let etag = uploader
-                .url(url)
-                .mimetype("video/mp4")
-                .copy(&source)
-                .copy_range_from_to(range_from, range_to)
-                .upload()
-                .expect("S3 upload error")
-                .etag;
+    pb.set_position(state.etags.iter().filter(|v| v.is_some()).count() as u64);
+    let set_in_flight = |limit| pb.set_message(format!("({limit} at once)"));
+    set_in_flight(concurrency.limit());
 
+    // Bytes copied, counted for the events alone.
+    let total = ProgressBar::hidden();
+    total.set_length(state.length);
+    total.set_position(
+        (0..state.etags.len())
+            .filter(|&i| state.etags[i].is_some())
+            .map(|i| min(part_size, state.length - i as u64 * part_size))
+            .sum(),
+    );
+    total.reset_eta();
+    let events = (progress == ProgressMode::Jsonl).then(Events::new);
+    let emit = |event| {
+        if let Some(events) = &events {
+            events.emit(&total, event);
+        }
+    };
+    let upload_id = state.upload_id.clone();
+    emit(Event::Started {
+        upload_id: &upload_id,
+        parts,
+        part_size,
+    });
+
+    // Copies send no body, so they cannot be told to have stalled; they
+    // are given a while to finish instead.
+    let uploader = client
+        .uploader()
+        .stall_timeout(None)
+        .timeout(COPY_PART_TIMEOUT);
+    let copied = AtomicU64::new(0);
+    let pool = concurrency.pool()?;
+    let urls = state.urls.clone();
+    let length = state.length;
+    let state = Mutex::new(state);
+
+    let running = interrupt::running();
+    let finished = pool.install(|| {
+        urls.par_iter().enumerate().try_for_each(|(i, url)| {
+            if state.lock().unwrap().etags[i].is_some() {
+                return Ok(());
+            }
+            let range_from = (i as u64) * part_size;
+            let range_to = min(range_from + part_size, length) - 1;
+            let bytes = range_to - range_from + 1;
+            let _permit = concurrency.acquire();
+            if interrupt::requested() {
+                return Ok(());
+            }
+            emit(Event::PartStarted {
+                part: i + 1,
+                part_bytes: bytes,
+            });
+            let copy = || {
+                Ok(uploader
+                    .url(url)
+                    .mimetype("video/mp4")
+                    .copy(&source)
+                    .copy_range_from_to(range_from, range_to)
+                    .upload()?
+                    .etag)
+            };
+            let etag = retry
+                .run(copy, |e, attempt, delay| {
+                    if let Some(limit) = concurrency.back_off(copied.load(Ordering::Relaxed)) {
+                        set_in_flight(limit);
+                    }
+                    match events {
+                        Some(_) => emit(Event::Retry {
+                            part: i + 1,
+                            attempt,
+                            delay_secs: delay.as_secs_f64(),
+                            error: describe(e),
+                        }),
+                        None => pb.suspend(|| {
+                            eprintln!(
+                                "warning: Part {} failed: {e}; retrying in {:.1}s (attempt {attempt})",
+                                i + 1,
+                                delay.as_secs_f64()
+                            )
+                        }),
+                    }
+                })
+                .with_context(|| format!("Failed to copy part {}", i + 1))?;
+
+            let copied = copied.fetch_add(bytes, Ordering::Relaxed) + bytes;
+            if let Some(limit) = concurrency.record(copied) {
+                set_in_flight(limit);
+            }
+            let mut state = state.lock().unwrap();
+            state.etags[i] = Some(etag);
+            if let Some(path) = &state_path {
+                state.save(path);
+            }
             pb.inc(1);
-            etag
+            total.inc(bytes);
+            emit(Event::PartDone {
+                part: i + 1,
+                part_bytes: bytes,
+                skipped: false,
+            });
+            Ok(())
         })
-        .collect();
+    });
+    drop(running);
+    let state = state.into_inner().unwrap();
+    let finished = finished
+        .and_then(|()| interrupt::check())
+        .inspect_err(|e| emit(Event::Failed { error: describe(e) }));
+    match finished {
+        Err(e) if e.kind() == ErrorKind::Interrupted => {
+            pb.abandon();
+            eprintln!(
+                "Copy interrupted; resume it with:\n  {}",
+                interrupt::command_line(|_| {})
+            );
+            return Err(e);
+        }
+        res => res?,
+    }
+    let etags = state
+        .etags
+        .into_iter()
+        .map(|v| v.ok_or_else(|| Error::validation("Copy progress is missing parts")))
+        .collect::<Result<_>>()?;
 
     pb.finish();
-    println!("Multi-part copy finished");
+    ctx.output.info("Multi-part copy finished");
 
-    api::video::restricted_copy_finish(&args.uuid, &source, hash, &copy_start.upload_id, etags)
-        .expect("Failed to finish upload");
+    api::video::restricted_copy_finish(client, &args.uuid, &source, hash, &state.upload_id, etags)
+        .context("Failed to finish multi-part copy")
+        .inspect_err(|e| emit(Event::Failed { error: describe(e) }))?;
+    emit(Event::Finished);
+    if let Some(path) = &state_path
+        && let Err(e) = fs::remove_file(path)
+    {
+        log::warn!("Failed to remove copy progress {}: {e}", path.display());
+    }
 
-    println!("Completed")
+    let record = RestrictedSet {
+        uuid: args.uuid,
+        restricted,
+        copied: true,
+    };
+    ctx.output.result(record, "Completed")
 }
@@ -1,7 +1,11 @@
 use clap::Parser;
+use serde::Serialize;
 use std::path::PathBuf;
+use tabled::Tabled;
 
-use crate::api;
+use koishi::{Result, ResultExt, api};
+
+use crate::{cmd::Context, output::Record};
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -9,13 +13,33 @@ pub(super) struct Args {
     path: PathBuf,
 }
 
-pub(crate) fn main(args: Args) {
-    let res = api::cover::upload_cover_from_file(args.path).unwrap();
+#[derive(Serialize, Tabled)]
+struct CoverSet {
+    uuid: String,
+    cover: String,
+    uploaded: bool,
+}
+
+impl Record for CoverSet {}
+
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    let res = api::cover::upload_cover_from_file(client, &args.path)
+        .with_context(|| format!("Failed to upload cover {}", args.path.display()))?;
     if res.exists {
-        println!("Cover already presented in remote; skipping")
+        ctx.output
+            .info("Cover already presented in remote; skipping")
     } else {
-        println!("Cover {} uploaded", res.hash)
+        ctx.output.info(format!("Cover {} uploaded", res.hash))
     }
 
-    crate::api::video::update(&args.uuid, None, Some(res.hash), None, None).unwrap()
+    api::video::update(client, &args.uuid, None, Some(res.hash.clone()), None, None)?;
+
+    let message = format!("Cover {} set for video {}", res.hash, args.uuid);
+    let record = CoverSet {
+        uuid: args.uuid,
+        cover: res.hash,
+        uploaded: !res.exists,
+    };
+    ctx.output.result(record, message)
 }
@@ -1,7 +1,10 @@
 use clap::Parser;
 use std::path::PathBuf;
 
-use crate::api;
+use koishi::{Result, ResultExt, api};
+
+use super::VideoChanged;
+use crate::cmd::Context;
 
 #[derive(Parser)]
 pub(super) struct Args {
@@ -9,8 +12,16 @@ pub(super) struct Args {
     path: PathBuf,
 }
 
-pub(crate) fn main(args: Args) {
-    std::println!("Uploading metadata file {path}", path = args.path.display());
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    ctx.output.info(format!(
+        "Uploading metadata file {path}",
+        path = args.path.display()
+    ));
+
+    api::video::upload_metadata(client, &args.uuid, &args.path)
+        .context("Failed to upload metadata")?;
 
-    api::video::upload_metadata(&args.uuid, &args.path).unwrap();
+    let message = format!("Metadata of video {} uploaded", args.uuid);
+    ctx.output.result(VideoChanged { uuid: args.uuid }, message)
 }
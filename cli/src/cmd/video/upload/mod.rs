@@ -0,0 +1,766 @@
+use clap::Parser;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    cmp::min,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tabled::{
+    Table, Tabled,
+    settings::{Remove, Style, location::ByColumnName},
+};
+
+use koishi::helpers::{
+    multipart::{self, PartDigest},
+    s3,
+};
+use koishi::{Error, ErrorKind, Result, ResultExt, api, helpers::cryptography::restricted_hash};
+
+use crate::{
+    cmd::{Context, recording::describe},
+    interrupt,
+    output::{Output, Record},
+};
+
+use super::{
+    concurrency::Concurrency,
+    progress::{Event, Events, ProgressMode},
+    verify,
+};
+
+mod pending;
+mod presign;
+mod reconcile;
+mod retry;
+mod state;
+mod stream;
+
+use pending::OnFailure;
+pub(super) use pending::{abort_unfinished, confirm};
+pub(super) use retry::PartRetry;
+use state::{Fingerprint, UploadState};
+
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub(super) struct Args {
+    #[command(subcommand)]
+    command: Option<pending::Commands>,
+
+    /// Show no progress bars; the same as --progress none
+    #[arg(short = 'P', long)]
+    no_progress: bool,
+    /// How to show progress; events are written to stderr, and are not
+    /// available for uploads from a stream
+    #[arg(long, value_enum, default_value_t, value_name = "MODE")]
+    progress: ProgressMode,
+    /// Part size in bytes; picked from the file size if not given
+    #[arg(short = 's', long, conflicts_with = "resume")]
+    part_size: Option<u64>,
+    /// Attempts at each part before giving up on it [default: 10]
+    #[arg(short = 'R', long)]
+    retry_part: Option<u64>,
+    /// Seconds an attempt at a part may go without moving any data before
+    /// it is given up on [default: 60]
+    #[arg(long, value_name = "SECS")]
+    stall_timeout: Option<u64>,
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Most parts uploaded at once, going up from a few while that speeds
+    /// the upload up [default: 16, or 4 from a stream]
+    #[arg(short, long)]
+    thread_count: Option<usize>,
+    #[arg(short, long)]
+    resume: bool,
+    /// Upload to resume when there is no progress file; the one started
+    /// last from this machine, or else the latest on the server, if not given
+    #[arg(long, requires = "resume")]
+    upload_id: Option<String>,
+    /// Abort the upload on failure instead of keeping it for --resume
+    #[arg(long)]
+    abort_on_failure: bool,
+    /// Upload the file as it is being written, sending parts as they fill
+    /// up and finishing once the writer closes it
+    #[arg(short, long, conflicts_with = "resume")]
+    follow: bool,
+    /// Seconds a followed file may go without growing before it is taken
+    /// as finished, in case its writer cannot be seen [default: 30]
+    #[arg(long, requires = "follow")]
+    idle_timeout: Option<u64>,
+
+    #[arg(required = true)]
+    uuid: Option<String>,
+    /// Video file, or `-` to stream from stdin
+    #[arg(required = true)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Tabled)]
+pub(crate) struct UploadFinished {
+    uuid: String,
+    upload_id: String,
+    size: u64,
+    parts: u64,
+    etag: String,
+}
+
+impl Record for UploadFinished {}
+
+fn print_video_info(i: &api::video::Video) {
+    let mut table = Table::new([i]);
+    table.with(Style::modern());
+    table.with(Remove::column(ByColumnName::new("Cover URL")));
+
+    println!("{table}");
+}
+
+struct UploadMultiProgress {
+    mp: MultiProgress,
+    output: Output,
+    pb_parts: ProgressBar,
+    pb_total: ProgressBar,
+    /// Bytes of the parts skipped as uploaded before, which count towards
+    /// the total but were not sent now.
+    skipped: AtomicU64,
+    events: Option<Arc<Events>>,
+}
+
+struct UploadProgress {
+    mp: MultiProgress,
+    pb_parts: ProgressBar,
+    pb_total: ProgressBar,
+    pb_current: ProgressBar,
+    events: Option<Arc<Events>>,
+    /// Number of the part, from 1.
+    part: usize,
+    part_size: u64,
+}
+
+struct UploadProgressReadWrapper<R: Read> {
+    read: R,
+    pb_total: ProgressBar,
+    pb_current: ProgressBar,
+    events: Option<Arc<Events>>,
+    part: usize,
+}
+
+impl UploadMultiProgress {
+    fn new(parts: u64, total: u64, output: Output, mode: ProgressMode) -> Self {
+        let mp = MultiProgress::new();
+
+        let pb_parts = mp
+            .add(ProgressBar::new(parts))
+            .with_prefix("Parts")
+            .with_style(
+                ProgressStyle::default_bar()
+                    .template("{prefix:8} {wide_bar:40.cyan/blue} {pos}/{len} {msg}")
+                    .unwrap()
+                    .progress_chars("##-"),
+            );
+        pb_parts.set_position(0);
+
+        let pb_total = mp
+            .add(ProgressBar::new(total))
+            .with_prefix("Total")
+            .with_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{prefix:8} {wide_bar:40.green/black} {bytes}/{total_bytes} \
+			       {bytes_per_sec} elapsed {elapsed} ETA {eta}",
+                    )
+                    .unwrap(),
+            );
+
+        let multi = Self {
+            mp,
+            output,
+            pb_parts,
+            pb_total,
+            skipped: AtomicU64::new(0),
+            events: (mode == ProgressMode::Jsonl).then(|| Arc::new(Events::new())),
+        };
+        if mode != ProgressMode::Bar {
+            multi.hide();
+        }
+        multi
+    }
+
+    fn new_part(&self, part_idx: usize, part_size: u64) -> UploadProgress {
+        let pb_current = self
+            .mp
+            .add(ProgressBar::new(part_size))
+            .with_prefix(format!("#{}", part_idx + 1))
+            .with_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{prefix:8} {wide_bar:40.yellow/black} {bytes}/{total_bytes} \
+			       {bytes_per_sec}",
+                    )
+                    .unwrap(),
+            )
+            .with_position(0);
+
+        let pb = UploadProgress {
+            mp: self.mp.clone(),
+            pb_parts: self.pb_parts.clone(),
+            pb_total: self.pb_total.clone(),
+            pb_current,
+            events: self.events.clone(),
+            part: part_idx + 1,
+            part_size,
+        };
+        pb.emit(Event::PartStarted {
+            part: pb.part,
+            part_bytes: part_size,
+        });
+        pb
+    }
+
+    /// Write `event` if events were asked for.
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(&self.pb_total, event);
+        }
+    }
+
+    fn hide(&self) {
+        self.mp.set_draw_target(ProgressDrawTarget::hidden())
+    }
+
+    /// Print a message, unless events stand in for them.
+    fn println<S: AsRef<str>>(&self, msg: S) -> io::Result<()> {
+        if self.events.is_some() {
+            Ok(())
+        } else if self.mp.is_hidden() {
+            self.output.info(msg.as_ref());
+            Ok(())
+        } else {
+            self.mp.println(msg)
+        }
+    }
+
+    /// Print a warning to stderr without tearing through the progress bars.
+    fn warn<S: AsRef<str>>(&self, msg: S) {
+        self.mp.suspend(|| eprintln!("warning: {}", msg.as_ref()))
+    }
+
+    /// Bytes sent so far, leaving out the parts uploaded before.
+    fn sent(&self) -> u64 {
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        self.pb_total.position().saturating_sub(skipped)
+    }
+
+    /// Show how many parts may be in flight now.
+    fn set_in_flight(&self, limit: usize) {
+        self.pb_parts.set_message(format!("({limit} at once)"));
+    }
+
+    fn finish(&self) {
+        self.pb_parts.finish();
+        self.pb_total.finish();
+    }
+}
+
+impl UploadProgress {
+    fn wrap_read<R: Read>(&self, read: R) -> UploadProgressReadWrapper<R> {
+        UploadProgressReadWrapper {
+            read,
+            pb_total: self.pb_total.clone(),
+            pb_current: self.pb_current.clone(),
+            events: self.events.clone(),
+            part: self.part,
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(&self.pb_total, event);
+        }
+    }
+
+    fn finish(&self) {
+        self.done(false);
+    }
+
+    fn skip(&self) {
+        self.pb_total.inc(self.part_size);
+        self.done(true);
+    }
+
+    fn done(&self, skipped: bool) {
+        self.pb_current.finish();
+        self.pb_parts.inc(1);
+        self.mp.remove(&self.pb_current);
+        self.emit(Event::PartDone {
+            part: self.part,
+            part_bytes: self.part_size,
+            skipped,
+        });
+    }
+
+    fn reset(&self) {
+        self.pb_current.reset();
+    }
+}
+
+impl<R: Read> Read for UploadProgressReadWrapper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.read.read(buf)?;
+        self.pb_current.inc(bytes_read as u64);
+        self.pb_total.inc(bytes_read as u64);
+        if let Some(events) = &self.events {
+            events.bytes(&self.pb_total, self.part, self.pb_current.position());
+        }
+        Ok(bytes_read)
+    }
+}
+
+fn do_upload_part(
+    uploader: &s3::Uploader,
+    path: &Path,
+    url: &str,
+    offset: u64,
+    size: u64,
+    digest: &PartDigest,
+    pb: &UploadProgress,
+) -> Result<String> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+
+    let part_reader = f.take(size);
+    let buf_reader = BufReader::new(part_reader);
+
+    let req = uploader
+        .url(url)
+        .mimetype("video/mp4")
+        .content_md5(digest)
+        .from_reader_sized(pb.wrap_read(buf_reader), size);
+
+    let res = req.upload()?;
+
+    Ok(res.etag)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn upload_part(
+    uploader: &s3::Uploader,
+    path: &Path,
+    url: impl Fn() -> Result<String>,
+    offset: u64,
+    size: u64,
+    pb: &UploadProgress,
+    mp: &UploadMultiProgress,
+    retry: &PartRetry,
+    concurrency: &Concurrency,
+) -> Result<(String, PartDigest)> {
+    // Hashed up front so that S3 can refuse a part corrupted on the way.
+    let digest = multipart::digest_part(path, offset, size)?;
+
+    let etag = retry.run(
+        // Taken anew for each attempt, since it may have been refreshed.
+        || url().and_then(|url| do_upload_part(uploader, path, &url, offset, size, &digest, pb)),
+        |e, attempt, delay| {
+            pb.reset();
+            if let Some(limit) = concurrency.back_off(mp.sent()) {
+                mp.set_in_flight(limit);
+            }
+            match mp.events {
+                Some(_) => mp.emit(Event::Retry {
+                    part: pb.part,
+                    attempt,
+                    delay_secs: delay.as_secs_f64(),
+                    error: describe(e),
+                }),
+                None => mp.warn(format!(
+                    "Part {} failed: {e}; retrying in {:.1}s (attempt {attempt})",
+                    pb.pb_current.prefix(),
+                    delay.as_secs_f64()
+                )),
+            }
+        },
+    )?;
+    pb.finish();
+    Ok((etag, digest))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn do_upload(
+    ctx: &Context,
+    uuid: &str,
+    path: &Path,
+    part_size: Option<u64>,
+    progress: ProgressMode,
+    retry: &PartRetry,
+    thread_count: Option<usize>,
+    hash: Option<String>,
+    resume: bool,
+    upload_id: Option<String>,
+    on_failure: OnFailure,
+) -> Result<UploadFinished> {
+    let client = &ctx.client;
+    let f = File::open(path)?;
+    let f_size = f.metadata()?.len();
+    let concurrency = Concurrency::new(thread_count)?;
+    let pool = concurrency.pool()?;
+
+    let state_file_path = state::state_file_path(path);
+    let state = if resume && !fs::exists(state_file_path.as_path())? {
+        ctx.output
+            .info("No upload progress file; looking up the parts stored so far");
+        reconcile::restore(
+            ctx,
+            uuid,
+            path,
+            f_size,
+            part_size,
+            upload_id,
+            hash.clone(),
+            &state_file_path,
+        )?
+    } else if resume {
+        let state = UploadState::restore_from(state_file_path.as_path())
+            .context("Failed to restore upload progress")?;
+        if let Some(recorded) = state.uuid.as_deref()
+            && recorded != uuid
+        {
+            return Err(Error::validation(format!(
+                "{} belongs to an upload to video {recorded}, not {uuid}",
+                state_file_path.display()
+            )));
+        }
+        if let Some(upload_id) = upload_id
+            && upload_id != state.upload_id
+        {
+            return Err(Error::validation(format!(
+                "{} belongs to upload {}, not {upload_id}",
+                state_file_path.display(),
+                state.upload_id
+            )));
+        }
+        state.check_source(path)?;
+        state
+    } else {
+        if fs::exists(state_file_path.as_path())? {
+            return Err(Error::validation(format!(
+                "Upload progress file exists; cowardly refuse to start new upload.\n\
+                 Pass --resume to pick up previous progress, \
+                 or remove {} if you would like to start fresh.",
+                state_file_path.display()
+            )));
+        }
+
+        // A part size given by the user is taken as is, but must be refused
+        // before the server creates a multipart upload nobody will finish.
+        let part_size = match part_size {
+            Some(part_size) => {
+                multipart::validate_part_size(f_size, part_size)?;
+                part_size
+            }
+            None => {
+                let part_size =
+                    multipart::choose_part_size(f_size, concurrency.max(), DEFAULT_PART_SIZE);
+                ctx.output.info(format!("Using part size {part_size}"));
+                part_size
+            }
+        };
+
+        let upload_start = api::video::upload_start(client, uuid, f_size, part_size, hash.clone())
+            .context("Failed to start upload")?;
+        if ctx.output.is_human() {
+            print_video_info(&upload_start.video);
+        }
+        UploadState::new(
+            uuid,
+            upload_start.upload_id,
+            upload_start.urls,
+            part_size,
+            state_file_path.as_path(),
+            Fingerprint::of(path)?,
+        )
+    };
+    state.write_state_file()?;
+    state::register(&state, path);
+
+    ctx.output.info("Initiating multi-part upload");
+
+    let part_size = state.part_size();
+    let parts = state.part_count() as u64;
+
+    let mp = UploadMultiProgress::new(parts, f_size, ctx.output.clone(), progress);
+    mp.emit(Event::Started {
+        upload_id: &state.upload_id,
+        parts,
+        part_size,
+    });
+    mp.set_in_flight(concurrency.limit());
+
+    let uploader = retry.uploader(client);
+
+    let urls = presign::UrlRefresher::new(client, uuid, hash.as_deref(), &state, &mp);
+
+    let failures = Mutex::new(vec![]);
+    let stop = AtomicBool::new(false);
+    let running = interrupt::running();
+    let uploaded = urls.refresh().and_then(|_| {
+        let upload_all = || {
+            (0..state.part_count()).into_par_iter().for_each(|i| {
+                if stop.load(Ordering::Relaxed) || interrupt::requested() {
+                    return;
+                }
+                let offset = (i as u64) * part_size;
+                let size = min(part_size, f_size - offset);
+                // Taken before the part shows, so that only those in flight do.
+                let permit = (!state.is_finished(i)).then(|| concurrency.acquire());
+                if interrupt::requested() {
+                    return;
+                }
+                let pb = mp.new_part(i, size);
+
+                let done = if state.is_finished(i) {
+                    mp.println(format!(
+                        "Skipping part {} since it's already finished",
+                        i + 1
+                    ))
+                    .map_err(Error::from)
+                    .and_then(|_| {
+                        multipart::digest_part(path, offset, size)
+                            .with_context(|| format!("Failed to read part {}", i + 1))
+                    })
+                    .map(|digest| {
+                        state.set_digest(i, digest);
+                        mp.skipped.fetch_add(size, Ordering::Relaxed);
+                        pb.skip();
+                    })
+                } else {
+                    let url = || urls.url(i);
+                    let uploaded = upload_part(
+                        &uploader,
+                        path,
+                        url,
+                        offset,
+                        size,
+                        &pb,
+                        &mp,
+                        retry,
+                        &concurrency,
+                    );
+                    drop(permit);
+                    // Failed attempts have backed off already.
+                    if uploaded.is_ok()
+                        && let Some(limit) = concurrency.record(mp.sent())
+                    {
+                        mp.set_in_flight(limit);
+                    }
+                    uploaded
+                        .with_context(|| format!("Failed to upload part {}", i + 1))
+                        .and_then(|(etag, digest)| {
+                            state.set_digest(i, digest);
+                            state.set_etag(i, etag);
+                            state
+                                .write_state_file()
+                                .context("Failed to write state file")?;
+                            Ok(mp.println(format!("Part {} uploaded", i + 1))?)
+                        })
+                };
+                if let Err(e) = done {
+                    // Failures that would only repeat are bound to hit the
+                    // other parts as well; the rest leave them to go on, so
+                    // that a resume has less to do.
+                    if !e.is_retriable() {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    failures.lock().unwrap().push((i, e));
+                }
+            })
+        };
+        pool.install(upload_all);
+        // The parts uploaded so far are saved already.
+        interrupt::check().inspect_err(|_| mp.hide())?;
+        let failures = failures.into_inner().unwrap();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        mp.hide();
+        Err(retry::report(failures))
+    });
+
+    // Parts finished before a resume were uploaded from whatever the file
+    // held back then, so the upload is not finished if any has changed.
+    let checked = uploaded.and_then(|_| {
+        let (digests, mismatched) = state.check_etags();
+        if !mismatched.is_empty() {
+            let parts: Vec<_> = mismatched.iter().map(usize::to_string).collect();
+            return Err(Error::Integrity(format!(
+                "Uploaded parts {} do not match {}",
+                parts.join(", "),
+                path.display()
+            )));
+        }
+        Ok(digests)
+    });
+    if let Err(e) = &checked {
+        mp.emit(Event::Failed { error: describe(e) });
+    }
+    let digests = match checked {
+        Ok(digests) => digests,
+        // Kept for a resume without asking.
+        Err(e) if e.kind() == ErrorKind::Interrupted => return Err(e),
+        Err(e) => {
+            mp.hide();
+            pending::offer_abort(ctx, uuid, path, &state, hash, on_failure);
+            return Err(e);
+        }
+    };
+    drop(running);
+
+    let etags = state.collect_etags();
+    let upload_id = state.upload_id.clone();
+    api::video::upload_finish(client, uuid, state.upload_id, etags, hash.clone())
+        .context("Failed to finish upload")
+        .inspect_err(|e| mp.emit(Event::Failed { error: describe(e) }))?;
+    mp.finish();
+    fs::remove_file(state_file_path)?;
+    state::unregister(&upload_id);
+
+    let etag = verify::check_object(
+        client,
+        uuid,
+        hash.as_deref(),
+        f_size,
+        &multipart::multipart_etag(&digests),
+    )
+    .context("Failed to verify upload")
+    .inspect_err(|e| mp.emit(Event::Failed { error: describe(e) }))?;
+    mp.emit(Event::Finished);
+
+    Ok(UploadFinished {
+        uuid: uuid.to_string(),
+        upload_id,
+        size: f_size,
+        parts,
+        etag,
+    })
+}
+
+pub(super) const DEFAULT_PART_SIZE: u64 = 10_000_000;
+
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    if let Some(command) = args.command {
+        return pending::main(ctx, command);
+    }
+    let (Some(uuid), Some(path)) = (args.uuid, args.path) else {
+        unreachable!("clap requires both without a subcommand");
+    };
+
+    let part_size = args.part_size.or(ctx.settings.part_size.value);
+    let retry = PartRetry::resolve(ctx, args.retry_part, args.stall_timeout)?;
+    let thread_count = args.thread_count.or(ctx.settings.thread_count.value);
+    let progress = ProgressMode::resolve(args.progress, args.no_progress);
+
+    ctx.output.info(format!(
+        "Uploading video file {path}",
+        path = path.display()
+    ));
+
+    if let Some(tc) = thread_count {
+        ctx.output
+            .info(format!("Uploading at most {tc} parts at once"));
+    }
+
+    if let Some(limiter) = ctx.client.rate_limiter() {
+        ctx.output.info(format!(
+            "Limiting upload rate to {}",
+            limiter.current_rate()
+        ));
+    }
+
+    let hash = args
+        .password
+        .map(|v| restricted_hash(&uuid, &v))
+        .transpose()?;
+
+    if args.follow || path.as_os_str() == "-" {
+        if args.resume {
+            return Err(Error::validation("Uploads from stdin cannot be resumed"));
+        }
+        if progress == ProgressMode::Jsonl {
+            return Err(Error::validation(
+                "Progress events are not available for uploads from a stream",
+            ));
+        }
+        let options = stream::StreamOptions {
+            part_size,
+            workers: thread_count,
+            retry,
+            idle_timeout: Duration::from_secs(
+                args.idle_timeout.unwrap_or(stream::DEFAULT_IDLE_TIMEOUT),
+            ),
+            no_progress: progress != ProgressMode::Bar,
+        };
+        let finished = stream::upload(ctx, &uuid, &path, hash, options)?;
+        return ctx.output.result(finished, "Upload finished");
+    }
+
+    let uploaded = do_upload(
+        ctx,
+        &uuid,
+        &path,
+        part_size,
+        progress,
+        &retry,
+        thread_count,
+        hash,
+        args.resume,
+        args.upload_id,
+        match args.abort_on_failure {
+            true => OnFailure::Abort,
+            false => OnFailure::Ask,
+        },
+    );
+    let finished = match uploaded {
+        Err(e) if e.kind() == ErrorKind::Interrupted => {
+            let command = interrupt::command_line(|args| {
+                interrupt::remove_option(args, &["-s", "--part-size"]);
+                if !args.iter().any(|v| v == "-r" || v == "--resume") {
+                    args.push("--resume".to_string());
+                }
+            });
+            eprintln!("Upload interrupted; resume it with:\n  {command}");
+            return Err(e);
+        }
+        res => res?,
+    };
+    ctx.output.result(finished, "Upload finished")
+}
+
+/// Upload the video at `path` without progress bars or questions, picking
+/// up where an earlier call left off and keeping the upload if it fails.
+pub(crate) fn upload_video(
+    ctx: &Context,
+    uuid: &str,
+    path: &Path,
+    hash: Option<String>,
+) -> Result<UploadFinished> {
+    let resume = state::state_file_path(path).exists();
+    let retry = PartRetry::resolve(ctx, None, None)?;
+    do_upload(
+        ctx,
+        uuid,
+        path,
+        ctx.settings.part_size.value.filter(|_| !resume),
+        ProgressMode::None,
+        &retry,
+        ctx.settings.thread_count.value,
+        hash,
+        resume,
+        None,
+        OnFailure::Keep,
+    )
+}
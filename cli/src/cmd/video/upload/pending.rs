@@ -0,0 +1,290 @@
+//! Commands for uploads that have not finished: inspecting their progress
+//! and aborting them so that storage does not keep their parts around.
+
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+use tabled::{Tabled, derive::display};
+
+use koishi::{Error, KoishiClient, Result, ResultExt, api, helpers::cryptography::restricted_hash};
+
+use crate::{cmd::Context, output::Record};
+
+use super::state::{self, UploadState};
+
+#[derive(Subcommand)]
+pub(super) enum Commands {
+    /// Show the finished and missing parts of an upload
+    Status(StatusArgs),
+    /// List the unfinished uploads started from this machine
+    List,
+    /// Discard an unfinished upload, on the server and locally
+    Abort(AbortArgs),
+}
+
+#[derive(Parser)]
+pub(super) struct StatusArgs {
+    path: PathBuf,
+}
+
+#[derive(Parser)]
+pub(super) struct AbortArgs {
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Video the upload is for; only needed for progress files written
+    /// before they recorded it
+    #[arg(long)]
+    uuid: Option<String>,
+
+    path: PathBuf,
+}
+
+#[derive(Serialize, Tabled)]
+struct PendingUpload {
+    video: String,
+    #[tabled(display("display::option", "<Unknown>"))]
+    uuid: Option<String>,
+    upload_id: String,
+    #[tabled(display("display::option", "<Unknown>"))]
+    parts: Option<u64>,
+    #[tabled(display("display::option", "<Unknown>"))]
+    finished: Option<u64>,
+    #[tabled(display("display_parts"))]
+    missing: Vec<u64>,
+    /// Why the progress file could not be read, if it could not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[tabled(display("display::option", ""))]
+    error: Option<String>,
+}
+
+impl Record for PendingUpload {}
+
+#[derive(Serialize, Tabled)]
+struct UploadAborted {
+    uuid: String,
+    upload_id: String,
+}
+
+impl Record for UploadAborted {}
+
+/// Part numbers with runs collapsed, such as `1-3,7`.
+fn display_parts(parts: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &n in parts {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == n => *end = n,
+            _ => ranges.push((n, n)),
+        }
+    }
+    let ranges: Vec<_> = ranges
+        .iter()
+        .map(|&(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{start}-{end}"),
+        })
+        .collect();
+    ranges.join(",")
+}
+
+fn restore(path: &Path) -> Result<UploadState> {
+    let state_file = state::state_file_path(path);
+    if !state_file.exists() {
+        return Err(Error::NotFound(format!(
+            "No unfinished upload of {}",
+            path.display()
+        )));
+    }
+    UploadState::restore_from(&state_file).context("Failed to restore upload progress")
+}
+
+fn pending_upload(path: &Path, state: &UploadState) -> PendingUpload {
+    let missing = state.missing_parts();
+    let parts = state.part_count() as u64;
+    PendingUpload {
+        video: path.display().to_string(),
+        uuid: state.uuid.clone(),
+        upload_id: state.upload_id.clone(),
+        parts: Some(parts),
+        finished: Some(parts - missing.len() as u64),
+        missing,
+        error: None,
+    }
+}
+
+/// Abort the upload on the server and forget about it locally.
+pub(super) fn abort_upload(
+    client: &KoishiClient,
+    uuid: &str,
+    state: &UploadState,
+    hash: Option<String>,
+) -> Result<()> {
+    api::video::upload_abort(client, uuid, state.upload_id.clone(), hash)
+        .context("Failed to abort upload")?;
+    match fs::remove_file(state.state_file()) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    state::unregister(&state.upload_id);
+    Ok(())
+}
+
+pub(crate) fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Abort the unfinished upload of `video` to `uuid`, if there is one,
+/// returning whether there was.
+pub(crate) fn abort_unfinished(
+    ctx: &Context,
+    uuid: &str,
+    video: &Path,
+    hash: Option<String>,
+) -> Result<bool> {
+    let state_file = state::state_file_path(video);
+    if !state_file.exists() {
+        return Ok(false);
+    }
+    let state =
+        UploadState::restore_from(&state_file).context("Failed to restore upload progress")?;
+    abort_upload(&ctx.client, uuid, &state, hash)?;
+    ctx.output
+        .info(format!("Upload {} aborted", state.upload_id));
+    Ok(true)
+}
+
+/// What becomes of an upload that failed.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum OnFailure {
+    /// Ask whether to abort it when on a terminal, or else keep it.
+    Ask,
+    /// Abort it.
+    Abort,
+    /// Keep it quietly, for whoever resumes it by itself.
+    Keep,
+}
+
+/// After an upload failed, abort it if `on_failure` says so or the user
+/// agrees when asked, or else tell how to deal with it later.
+pub(super) fn offer_abort(
+    ctx: &Context,
+    uuid: &str,
+    video: &Path,
+    state: &UploadState,
+    hash: Option<String>,
+    on_failure: OnFailure,
+) {
+    if on_failure == OnFailure::Keep {
+        return;
+    }
+    let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+    if on_failure == OnFailure::Abort
+        || (interactive && confirm("Upload failed; abort it and discard the uploaded parts?"))
+    {
+        match abort_upload(&ctx.client, uuid, state, hash) {
+            Ok(()) => eprintln!("Upload {} aborted", state.upload_id),
+            Err(e) => eprintln!("warning: {e}"),
+        }
+        return;
+    }
+    eprintln!(
+        "Pass --resume to pick up the upload later, or discard it with:\n  \
+         koishi video upload abort {}",
+        video.display()
+    );
+}
+
+fn status(ctx: &Context, args: StatusArgs) -> Result<()> {
+    let state = restore(&args.path)?;
+    ctx.output.one(pending_upload(&args.path, &state), |_| {})
+}
+
+fn list(ctx: &Context) -> Result<()> {
+    let mut uploads = vec![];
+    for entry in state::registered()? {
+        let state_file = state::state_file_path(&entry.video);
+        let forget = |reason: &str| {
+            ctx.output.info(format!(
+                "Forgetting upload {} of {}, {reason}",
+                entry.upload_id,
+                entry.video.display()
+            ));
+            state::unregister(&entry.upload_id);
+        };
+        // Only an upload known to be gone is forgotten: the entry may be all
+        // that is left of one storage still keeps the parts of.
+        let res = match state_file.try_exists() {
+            // Finished or aborted meanwhile.
+            Ok(false) => {
+                forget("which has no progress file");
+                continue;
+            }
+            Ok(true) => UploadState::restore_from(&state_file),
+            Err(e) => Err(e.into()),
+        };
+        match res {
+            Ok(state) if state.upload_id == entry.upload_id => {
+                uploads.push(pending_upload(&entry.video, &state))
+            }
+            Ok(_) => forget("whose progress file is of another upload now"),
+            Err(e) => uploads.push(PendingUpload {
+                video: entry.video.display().to_string(),
+                uuid: entry.uuid,
+                upload_id: entry.upload_id,
+                parts: None,
+                finished: None,
+                missing: vec![],
+                error: Some(format!("Failed to read {}: {e}", state_file.display())),
+            }),
+        }
+    }
+    ctx.output.list(uploads, |_| {})
+}
+
+fn abort(ctx: &Context, args: AbortArgs) -> Result<()> {
+    let state = restore(&args.path)?;
+    let Some(uuid) = args.uuid.or(state.uuid.clone()) else {
+        return Err(Error::validation(format!(
+            "{} does not record the video it is for; pass --uuid",
+            state.state_file().display()
+        )));
+    };
+    let hash = args
+        .password
+        .map(|v| restricted_hash(&uuid, &v))
+        .transpose()?;
+
+    abort_upload(&ctx.client, &uuid, &state, hash)?;
+    let record = UploadAborted {
+        uuid,
+        upload_id: state.upload_id.clone(),
+    };
+    ctx.output.result(record, "Upload aborted")
+}
+
+pub(super) fn main(ctx: &Context, command: Commands) -> Result<()> {
+    match command {
+        Commands::Status(args) => status(ctx, args),
+        Commands::List => list(ctx),
+        Commands::Abort(args) => abort(ctx, args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_parts() {
+        assert_eq!(display_parts(&[]), "");
+        assert_eq!(display_parts(&[3]), "3");
+        assert_eq!(display_parts(&[1, 2, 3, 7, 9, 10]), "1-3,7,9-10");
+    }
+}
@@ -0,0 +1,95 @@
+//! Keeping the signed part URLs of an upload from expiring under it, which
+//! storage would answer with 403s that look like any other failure.
+
+use chrono::{TimeDelta, Utc};
+use std::sync::Mutex;
+
+use koishi::{Error, KoishiClient, Result, ResultExt, api, helpers::s3::presigned_expiry};
+
+use super::{UploadMultiProgress, state::UploadState};
+
+/// How long before they expire part URLs are signed again. Storage checks
+/// the signature as a request comes in, so this only has to cover the time
+/// until a part is sent.
+pub(super) const EXPIRY_MARGIN: TimeDelta = TimeDelta::minutes(15);
+
+pub(super) struct UrlRefresher<'a> {
+    client: &'a KoishiClient,
+    uuid: &'a str,
+    hash: Option<&'a str>,
+    state: &'a UploadState,
+    mp: &'a UploadMultiProgress,
+    /// Held while refreshing, so that parts finding their URLs expiring at
+    /// the same time ask the server only once.
+    lock: Mutex<()>,
+}
+
+impl<'a> UrlRefresher<'a> {
+    pub fn new(
+        client: &'a KoishiClient,
+        uuid: &'a str,
+        hash: Option<&'a str>,
+        state: &'a UploadState,
+        mp: &'a UploadMultiProgress,
+    ) -> Self {
+        Self {
+            client,
+            uuid,
+            hash,
+            state,
+            mp,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// URL of part `i`, refreshed first if it is about to expire.
+    pub fn url(&self, i: usize) -> Result<String> {
+        let url = self.state.url(i);
+        match presigned_expiry(&url) {
+            Some(expiry) if expiry < Utc::now() + EXPIRY_MARGIN => {
+                self.refresh()?;
+                Ok(self.state.url(i))
+            }
+            _ => Ok(url),
+        }
+    }
+
+    /// Have the server sign the URLs of the parts not uploaded yet again if
+    /// they are about to expire, and save them for a later resume.
+    pub fn refresh(&self) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let now = Utc::now();
+        let (parts, earliest) = self.state.expiring_parts(now + EXPIRY_MARGIN);
+        let Some(earliest) = earliest else {
+            return Ok(());
+        };
+
+        let expire = if earliest < now { "expired" } else { "expire" };
+        self.mp.warn(format!(
+            "URLs of {} parts {expire} at {earliest}; signing them again",
+            parts.len()
+        ));
+        let res = api::video::upload_urls(
+            self.client,
+            self.uuid,
+            self.state.upload_id.clone(),
+            parts.clone(),
+            self.hash.map(str::to_string),
+        )
+        .context("Failed to refresh part URLs")?;
+        if res.urls.len() != parts.len() {
+            return Err(Error::validation(format!(
+                "Server signed {} URLs for {} parts",
+                res.urls.len(),
+                parts.len()
+            )));
+        }
+
+        for (n, url) in parts.into_iter().zip(res.urls) {
+            self.state.set_url(n as usize - 1, url);
+        }
+        self.state
+            .write_state_file()
+            .context("Failed to write state file")
+    }
+}
@@ -0,0 +1,174 @@
+//! Rebuilding the resume state of an upload from the parts storage has
+//! received, for when the progress file is gone or the upload was started
+//! on another machine.
+
+use rayon::prelude::*;
+use std::{cmp::min, fs, path::Path};
+
+use koishi::helpers::multipart::{self, PartDigest};
+use koishi::{Error, Result, ResultExt, api, api::video::UploadedPart};
+
+use crate::cmd::Context;
+
+use super::{
+    DEFAULT_PART_SIZE, print_video_info,
+    state::{self, Fingerprint, UploadState},
+};
+
+/// Whether `parts` could have been cut from a file of `size` bytes split
+/// into parts of `part_size` bytes.
+fn parts_fit(size: u64, part_size: u64, parts: &[UploadedPart]) -> bool {
+    multipart::validate_part_size(size, part_size).is_ok()
+        && parts.iter().all(|p| {
+            let offset = (p.part_number.max(1) - 1) * part_size;
+            p.part_number >= 1 && offset < size && p.size == min(part_size, size - offset)
+        })
+}
+
+/// Part size the listed `parts` were uploaded with.
+///
+/// Any part but the last has the part size, so the largest part tells when
+/// there is another part besides it. A part stored alone may be the last
+/// one, which fits a smaller part size as well; the `preferred` sizes are
+/// tried first then.
+fn infer_part_size(size: u64, parts: &[UploadedPart], preferred: &[u64]) -> Option<u64> {
+    let largest = parts.iter().map(|p| p.size).max();
+    let pinned = largest.filter(|_| parts.len() > 1);
+    pinned
+        .into_iter()
+        .chain(preferred.iter().copied())
+        .chain(largest)
+        .find(|&part_size| parts_fit(size, part_size, parts))
+}
+
+/// Upload of the video at `path` to `uuid` recorded in the registry.
+fn registered_upload(uuid: &str, path: &Path) -> Option<String> {
+    let video = fs::canonicalize(path).ok()?;
+    let entries = state::registered().ok()?;
+    entries
+        .into_iter()
+        .find(|e| e.video == video && e.uuid.as_deref() == Some(uuid))
+        .map(|e| e.upload_id)
+}
+
+/// Build the state of the upload `upload_id`, or of the one found in the
+/// registry or else the latest one of the video on the server, from the
+/// parts already stored.
+///
+/// Parts that do not match the local file are left out so that they are
+/// uploaded again.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn restore(
+    ctx: &Context,
+    uuid: &str,
+    path: &Path,
+    size: u64,
+    part_size: Option<u64>,
+    upload_id: Option<String>,
+    hash: Option<String>,
+    state_file: &Path,
+) -> Result<UploadState> {
+    let client = &ctx.client;
+    let upload_id = upload_id.or_else(|| registered_upload(uuid, path));
+    let listed = api::video::upload_parts(client, uuid, upload_id.as_deref(), hash.as_deref())
+        .context("Failed to list uploaded parts")?;
+    ctx.output.info(format!(
+        "Found upload {} with {} parts stored",
+        listed.upload_id,
+        listed.parts.len()
+    ));
+
+    let mut preferred = vec![];
+    preferred.extend(part_size);
+    preferred.extend([
+        DEFAULT_PART_SIZE,
+        multipart::choose_part_size(size, rayon::current_num_threads(), DEFAULT_PART_SIZE),
+    ]);
+    let Some(part_size) = infer_part_size(size, &listed.parts, &preferred) else {
+        return Err(Error::validation(format!(
+            "Parts stored for upload {} do not fit {}",
+            listed.upload_id,
+            path.display()
+        )));
+    };
+    ctx.output.info(format!("Using part size {part_size}"));
+
+    let matched = listed
+        .parts
+        .par_iter()
+        .map(|p| {
+            let offset = (p.part_number - 1) * part_size;
+            let digest = multipart::digest_part(path, offset, p.size)
+                .with_context(|| format!("Failed to read part {}", p.part_number))?;
+            Ok((p, digest))
+        })
+        .collect::<Result<Vec<(&UploadedPart, PartDigest)>>>()?;
+
+    let resumed = api::video::upload_resume(
+        client,
+        uuid,
+        size,
+        part_size,
+        listed.upload_id.clone(),
+        hash,
+    )
+    .context("Failed to resume upload")?;
+    if ctx.output.is_human() {
+        print_video_info(&resumed.video);
+    }
+
+    let state = UploadState::new(
+        uuid,
+        resumed.upload_id,
+        resumed.urls,
+        part_size,
+        state_file,
+        Fingerprint::of(path)?,
+    );
+    for (part, digest) in matched {
+        let i = (part.part_number - 1) as usize;
+        if multipart::etag_eq(&part.etag, &multipart::part_etag(&digest)) {
+            state.set_etag(i, part.etag.clone());
+            state.set_digest(i, digest);
+        } else {
+            ctx.output.info(format!(
+                "Stored part {} does not match the file; uploading it again",
+                part.part_number
+            ));
+        }
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(part_number: u64, size: u64) -> UploadedPart {
+        UploadedPart {
+            part_number,
+            etag: String::new(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_infer_part_size() {
+        let size = 25 << 20;
+        let mib = 1 << 20;
+
+        // The largest part tells, unless it is the last one.
+        let parts = [part(1, 10 * mib), part(3, 5 * mib)];
+        assert_eq!(infer_part_size(size, &parts, &[8 * mib]), Some(10 * mib));
+        let parts = [part(3, 5 * mib)];
+        assert_eq!(infer_part_size(size, &parts, &[10 * mib]), Some(10 * mib));
+        assert_eq!(infer_part_size(size, &parts, &[8 * mib]), Some(5 * mib));
+
+        // Nothing stored yet fits any valid size.
+        assert_eq!(infer_part_size(size, &[], &[mib, 8 * mib]), Some(8 * mib));
+
+        // Parts past the end of the file do not fit.
+        let parts = [part(4, 10 * mib)];
+        assert_eq!(infer_part_size(size, &parts, &[]), None);
+    }
+}
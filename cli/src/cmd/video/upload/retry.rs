@@ -0,0 +1,184 @@
+//! Retrying the parts of an upload: failures that may pass are tried again
+//! after a delay growing with each attempt, while those that would only
+//! repeat, such as a refused signature, give up on the part at once.
+
+use std::{thread, time::Duration};
+
+use koishi::{Error, KoishiClient, Result, api::RetryPolicy, helpers::s3};
+
+use crate::{
+    cmd::{Context, recording::describe},
+    interrupt,
+};
+
+/// Attempts per part unless told otherwise.
+pub(super) const DEFAULT_RETRY_PART: u64 = 10;
+/// Seconds before the first retry of a part.
+const DEFAULT_RETRY_DELAY: f64 = 1.0;
+/// Longest wait between two attempts at a part.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Seconds an attempt may go without moving any data.
+const DEFAULT_STALL_TIMEOUT: u64 = 60;
+
+/// How the parts of an upload are retried.
+#[derive(Clone)]
+pub(crate) struct PartRetry {
+    policy: RetryPolicy,
+    stall_timeout: Duration,
+}
+
+impl PartRetry {
+    /// Attempts and stall timeout given on the command line, or else from
+    /// the settings.
+    pub fn resolve(
+        ctx: &Context,
+        attempts: Option<u64>,
+        stall_timeout: Option<u64>,
+    ) -> Result<Self> {
+        let attempts = attempts
+            .or(ctx.settings.retry.value)
+            .unwrap_or(DEFAULT_RETRY_PART);
+        let delay = ctx
+            .settings
+            .retry_delay
+            .value
+            .unwrap_or(DEFAULT_RETRY_DELAY);
+        let delay = Duration::try_from_secs_f64(delay)
+            .map_err(|_| Error::validation(format!("Invalid retry delay {delay}")))?;
+        let stall_timeout = stall_timeout
+            .or(ctx.settings.stall_timeout.value)
+            .unwrap_or(DEFAULT_STALL_TIMEOUT);
+        if stall_timeout == 0 {
+            return Err(Error::validation("Stall timeout must be greater than zero"));
+        }
+        Ok(Self {
+            policy: RetryPolicy {
+                max_attempts: attempts.clamp(1, u32::MAX as u64) as u32,
+                base_delay: delay,
+                max_delay: MAX_RETRY_DELAY.max(delay),
+            },
+            stall_timeout: Duration::from_secs(stall_timeout),
+        })
+    }
+
+    /// Uploader that gives up on attempts that stall.
+    pub fn uploader(&self, client: &KoishiClient) -> s3::Uploader {
+        client.uploader().stall_timeout(self.stall_timeout)
+    }
+
+    /// Run `attempt` until it succeeds, fails in a way that would only
+    /// repeat, or runs out of attempts. `on_retry` is told of each failure
+    /// retried, along with the number of the next attempt and the wait
+    /// before it.
+    pub fn run<T>(
+        &self,
+        mut attempt: impl FnMut() -> Result<T>,
+        mut on_retry: impl FnMut(&Error, u32, Duration),
+    ) -> Result<T> {
+        let mut n = 1;
+        loop {
+            match attempt() {
+                // Stopping is not held up by waiting for another attempt.
+                Err(e) if !e.is_retriable() || interrupt::requested() => return Err(e),
+                Err(e) if n >= self.policy.max_attempts => {
+                    return Err(match n {
+                        1 => e,
+                        n => e.context(format!("Gave up after {n} attempts")),
+                    });
+                }
+                Err(e) => {
+                    let delay = self.policy.delay(n);
+                    n += 1;
+                    on_retry(&e, n, delay);
+                    thread::sleep(delay);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Tell which parts failed and why, returning the error of the first.
+pub(super) fn report(mut failures: Vec<(usize, Error)>) -> Error {
+    failures.sort_by_key(|(i, _)| *i);
+    let count = failures.len();
+    if count > 1 {
+        eprintln!("{count} parts failed:");
+        for (_, e) in &failures {
+            eprintln!("  {}", describe(e));
+        }
+    }
+    let (_, first) = failures.remove(0);
+    match count {
+        1 => first,
+        count => first.context(format!("{count} parts failed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry(attempts: u32) -> PartRetry {
+        PartRetry {
+            policy: RetryPolicy {
+                max_attempts: attempts,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+            stall_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn stalled() -> Error {
+        Error::S3(s3::S3UploaderError::Stalled(Duration::from_secs(1)))
+    }
+
+    #[test]
+    fn test_run_retries_until_success() {
+        let mut calls = 0;
+        let mut retried = vec![];
+        let res = retry(3).run(
+            || {
+                calls += 1;
+                match calls {
+                    3 => Ok(calls),
+                    _ => Err(stalled()),
+                }
+            },
+            |_, attempt, _| retried.push(attempt),
+        );
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(retried, [2, 3]);
+    }
+
+    #[test]
+    fn test_run_gives_up() {
+        let mut calls = 0;
+        let res: Result<()> = retry(3).run(
+            || {
+                calls += 1;
+                Err(stalled())
+            },
+            |_, _, _| {},
+        );
+        assert_eq!(calls, 3);
+        assert!(
+            res.unwrap_err()
+                .to_string()
+                .starts_with("Gave up after 3 attempts")
+        );
+
+        // Not retried when it would only fail again.
+        let mut calls = 0;
+        let res: Result<()> = retry(3).run(
+            || {
+                calls += 1;
+                Err(Error::validation("no"))
+            },
+            |_, _, _| {},
+        );
+        assert_eq!(calls, 1);
+        assert_eq!(res.unwrap_err().to_string(), "no");
+    }
+}
@@ -0,0 +1,394 @@
+//! Resume state of uploads.
+//!
+//! The state of each upload lives in a `.progress` file next to the video.
+//! Pointers to those files are kept in a registry under the user's state
+//! directory as well, so that unfinished uploads can be found again without
+//! knowing where their videos are.
+
+use serde::{Deserialize, Serialize};
+use serde_json::from_reader;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+
+use koishi::helpers::{
+    multipart::{self, PartDigest},
+    s3::presigned_expiry,
+};
+use koishi::{Error, Result};
+
+use crate::files::write_atomic;
+
+/// Where the resume state of an upload of the video at `path` is kept.
+pub(super) fn state_file_path(path: &Path) -> PathBuf {
+    path.with_extension("progress")
+}
+
+/// Format of the state files written, raised whenever older versions could
+/// not read them correctly. Files written before it was recorded are 0.
+const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct UploadStateData {
+    #[serde(default)]
+    version: u32,
+    /// Missing from files written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    upload_id: String,
+    urls: Vec<String>,
+    part_size: u64,
+    etags: Vec<Option<String>>,
+    /// Missing from files written before version 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Fingerprint>,
+}
+
+/// What the video looked like when its upload started, so that a resume
+/// does not take the remaining parts from a file replaced meanwhile.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub(super) struct Fingerprint {
+    size: u64,
+    /// Missing where the file system does not keep it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified: Option<DateTime<Utc>>,
+    /// Hex MD5 of the first, middle and last MiB; see
+    /// [`multipart::sample_digest`].
+    sample: String,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let f = File::open(path)?;
+        let metadata = f.metadata()?;
+        let size = metadata.len();
+        Ok(Self {
+            size,
+            modified: metadata.modified().ok().map(DateTime::from),
+            sample: hex::encode(multipart::sample_digest(BufReader::new(f), size)?),
+        })
+    }
+
+    /// What tells `self` from `other`, if anything does.
+    fn difference(&self, other: &Self) -> Option<&'static str> {
+        if self.size != other.size {
+            Some("size")
+        } else if self.sample != other.sample {
+            Some("content")
+        } else if self.modified != other.modified {
+            Some("modification time")
+        } else {
+            None
+        }
+    }
+}
+
+pub(super) struct UploadState {
+    pub uuid: Option<String>,
+    pub upload_id: String,
+
+    /// Signed part URLs, replaced as they are about to expire.
+    urls: Mutex<Vec<String>>,
+    part_size: u64,
+    etags: Mutex<Vec<Option<String>>>,
+    /// Digests of the local parts, which are not saved since the file may
+    /// change before the upload is resumed.
+    digests: Mutex<Vec<Option<PartDigest>>>,
+    state_file: PathBuf,
+    source: Option<Fingerprint>,
+    /// Held while the state file is written, so that parts finishing at
+    /// the same time do not write over each other.
+    saving: Mutex<()>,
+}
+
+impl UploadState {
+    fn to_data(&self) -> UploadStateData {
+        let etags = self.etags.lock().unwrap().clone();
+
+        UploadStateData {
+            version: STATE_VERSION,
+            uuid: self.uuid.clone(),
+            upload_id: self.upload_id.clone(),
+            urls: self.urls.lock().unwrap().clone(),
+            part_size: self.part_size,
+            etags,
+            source: self.source.clone(),
+        }
+    }
+
+    fn from_data(data: UploadStateData, state_file: PathBuf) -> Result<Self> {
+        if data.urls.len() != data.etags.len() {
+            return Err(Error::validation(format!(
+                "{} is corrupted: size of URLs does not match size of finished array",
+                state_file.display()
+            )));
+        }
+
+        let uuid = data.uuid;
+        let upload_id = data.upload_id;
+        let urls = Mutex::new(data.urls);
+        let part_size = data.part_size;
+        let digests = Mutex::new(vec![None; data.etags.len()]);
+        let etags = Mutex::new(data.etags);
+        Ok(Self {
+            uuid,
+            upload_id,
+            urls,
+            part_size,
+            etags,
+            digests,
+            state_file,
+            source: data.source,
+            saving: Mutex::new(()),
+        })
+    }
+
+    /// Save the state, replacing the file at once so that it is never
+    /// left half-written, whenever the process ends.
+    pub fn write_state_file(&self) -> Result<()> {
+        let _saving = self.saving.lock().unwrap();
+        let data = serde_json::to_vec(&self.to_data()).map_err(io::Error::from)?;
+        write_atomic(&self.state_file, &data)?;
+        Ok(())
+    }
+
+    pub fn new<P: AsRef<Path>>(
+        uuid: &str,
+        upload_id: String,
+        urls: Vec<String>,
+        part_size: u64,
+        state_file: P,
+        source: Fingerprint,
+    ) -> Self {
+        let etags: Mutex<Vec<Option<String>>> = Mutex::new(vec![None; urls.len()]);
+        let digests = Mutex::new(vec![None; urls.len()]);
+        Self {
+            uuid: Some(uuid.to_string()),
+            upload_id,
+            urls: Mutex::new(urls),
+            part_size,
+            etags,
+            digests,
+            state_file: state_file.as_ref().to_path_buf(),
+            source: Some(source),
+            saving: Mutex::new(()),
+        }
+    }
+
+    pub fn restore_from<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())?;
+        let value: serde_json::Value = from_reader(file).map_err(io::Error::from)?;
+        // Checked before the rest, which a newer format may have changed.
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if version > STATE_VERSION as u64 {
+            return Err(Error::validation(format!(
+                "{} is of format {version}, newer than the {STATE_VERSION} \
+                 this version of koishi reads; upgrade to resume the upload",
+                path.as_ref().display()
+            )));
+        }
+        let data: UploadStateData = serde_json::from_value(value).map_err(io::Error::from)?;
+        Self::from_data(data, path.as_ref().to_path_buf())
+    }
+
+    /// Fail unless the video at `path` is still the one the upload started
+    /// from. Files written before it was recorded pass, leaving it to the
+    /// check of the ETags once the parts are through.
+    pub fn check_source(&self, path: &Path) -> Result<()> {
+        let Some(source) = &self.source else {
+            return Ok(());
+        };
+        let current = Fingerprint::of(path)?;
+        match source.difference(&current) {
+            Some(what) => Err(Error::validation(format!(
+                "The {what} of {path} changed since the upload started; put \
+                 the original file back, or start over after \
+                 `koishi video upload abort {path}`",
+                path = path.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn part_size(&self) -> u64 {
+        self.part_size
+    }
+
+    pub fn part_count(&self) -> usize {
+        self.urls.lock().unwrap().len()
+    }
+
+    pub fn url(&self, i: usize) -> String {
+        self.urls.lock().unwrap()[i].clone()
+    }
+
+    pub fn set_url(&self, i: usize, url: String) {
+        self.urls.lock().unwrap()[i] = url
+    }
+
+    /// 1-based numbers of the parts not uploaded yet whose URL expires
+    /// before `deadline`, along with the earliest expiry among them.
+    pub fn expiring_parts(&self, deadline: DateTime<Utc>) -> (Vec<u64>, Option<DateTime<Utc>>) {
+        let urls = self.urls.lock().unwrap();
+        let expiring: Vec<_> = self
+            .missing_parts()
+            .into_iter()
+            .filter_map(|n| {
+                let expiry = presigned_expiry(&urls[n as usize - 1])?;
+                (expiry < deadline).then_some((n, expiry))
+            })
+            .collect();
+        let earliest = expiring.iter().map(|&(_, expiry)| expiry).min();
+        (expiring.into_iter().map(|(n, _)| n).collect(), earliest)
+    }
+
+    pub fn is_finished(&self, i: usize) -> bool {
+        self.etags.lock().unwrap()[i].is_some()
+    }
+
+    /// 1-based numbers of the parts not uploaded yet.
+    pub fn missing_parts(&self) -> Vec<u64> {
+        let etags = self.etags.lock().unwrap();
+        (1..)
+            .zip(etags.iter())
+            .filter(|(_, v)| v.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn state_file(&self) -> &Path {
+        &self.state_file
+    }
+
+    pub fn set_etag(&self, i: usize, etag: String) {
+        self.etags.lock().unwrap()[i] = Some(etag)
+    }
+
+    pub fn set_digest(&self, i: usize, digest: PartDigest) {
+        self.digests.lock().unwrap()[i] = Some(digest)
+    }
+
+    /// Digests of all parts, along with the 1-based numbers of the parts
+    /// whose ETag does not match their digest.
+    pub fn check_etags(&self) -> (Vec<PartDigest>, Vec<usize>) {
+        let etags = self.etags.lock().unwrap();
+        let digests: Vec<PartDigest> = self
+            .digests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|v| v.expect("All digests must be set before calling check_etags()"))
+            .collect();
+        let mismatched = etags
+            .iter()
+            .zip(&digests)
+            .enumerate()
+            .filter(|(_, (etag, digest))| {
+                etag.as_ref()
+                    .is_none_or(|v| !multipart::etag_eq(v, &multipart::part_etag(*digest)))
+            })
+            .map(|(i, _)| i + 1)
+            .collect();
+        (digests, mismatched)
+    }
+
+    pub fn collect_etags(&self) -> Vec<String> {
+        self.etags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|v| {
+                v.as_ref()
+                    .expect("All ETags must be set before calling collect_etags()")
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+/// Directory holding a pointer file per upload in progress.
+fn registry_dir() -> Option<PathBuf> {
+    let state_home = dirs::state_dir().or_else(dirs::data_local_dir)?;
+    Some(state_home.join("koishi").join("uploads"))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RegistryEntry {
+    pub upload_id: String,
+    pub uuid: Option<String>,
+    pub video: PathBuf,
+}
+
+fn registry_entry_path(upload_id: &str) -> Option<PathBuf> {
+    // Upload IDs come from storage; keep them from escaping the directory.
+    let name: String = upload_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Some(registry_dir()?.join(format!("{name}.json")))
+}
+
+/// Record that `state` belongs to an upload of the video at `video`.
+///
+/// The registry only helps finding uploads again, so failing to update it
+/// is logged rather than failing the upload.
+pub(super) fn register(state: &UploadState, video: &Path) {
+    let Some(path) = registry_entry_path(&state.upload_id) else {
+        return;
+    };
+    let entry = RegistryEntry {
+        upload_id: state.upload_id.clone(),
+        uuid: state.uuid.clone(),
+        video: fs::canonicalize(video).unwrap_or_else(|_| video.to_path_buf()),
+    };
+    let result = serde_json::to_vec(&entry)
+        .map_err(io::Error::from)
+        .and_then(|data| {
+            fs::create_dir_all(path.parent().unwrap())?;
+            write_atomic(&path, &data)
+        });
+    if let Err(e) = result {
+        log::warn!("Failed to register upload in {}: {e}", path.display());
+    }
+}
+
+pub(super) fn unregister(upload_id: &str) {
+    if let Some(path) = registry_entry_path(upload_id)
+        && let Err(e) = fs::remove_file(&path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::warn!("Failed to unregister upload in {}: {e}", path.display());
+    }
+}
+
+/// Registered uploads, ordered by video.
+pub(super) fn registered() -> Result<Vec<RegistryEntry>> {
+    let Some(dir) = registry_dir() else {
+        return Ok(vec![]);
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ret = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        // Skips what an interrupted write left behind.
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match File::open(&path).and_then(|f| from_reader(f).map_err(io::Error::from)) {
+            Ok(entry) => ret.push(entry),
+            Err(e) => log::warn!("Skipping {}: {e}", path.display()),
+        }
+    }
+    ret.sort_by(|a: &RegistryEntry, b| a.video.cmp(&b.video));
+    Ok(ret)
+}
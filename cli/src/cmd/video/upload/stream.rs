@@ -0,0 +1,378 @@
+//! Uploads of input whose size is not known up front: a file still being
+//! written, such as by a recorder, or whatever is piped to stdin.
+//!
+//! Parts of a fixed size are sent as soon as they fill up, asking the server
+//! for the URL of each on the way, and the upload is finished once the input
+//! ends. There is no resume state, so a failed stream upload is aborted.
+
+use chrono::Utc;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use koishi::helpers::multipart::{self, MAX_PART_SIZE, MAX_PARTS, MIN_PART_SIZE, PartDigest};
+use koishi::helpers::s3::{self, presigned_expiry};
+use koishi::{Error, KoishiClient, Result, ResultExt, api};
+
+use crate::{cmd::Context, files::open_elsewhere, interrupt};
+
+use super::{UploadFinished, presign::EXPIRY_MARGIN, print_video_info, retry::PartRetry, verify};
+
+/// Part size of stream uploads, which cannot be picked from the size. With
+/// a few parts held in memory at once, it allows streams of up to 320 GiB.
+pub(super) const DEFAULT_PART_SIZE: u64 = 32 * 1024 * 1024;
+/// Parts sent at once unless a thread count is given.
+const DEFAULT_WORKERS: usize = 4;
+/// Time a followed file may go without growing before it is taken as
+/// finished, when its writer cannot be seen.
+pub(super) const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+/// How often a followed file is looked at for more data.
+const FOLLOW_POLL: Duration = Duration::from_secs(1);
+
+pub(super) struct StreamOptions {
+    pub part_size: Option<u64>,
+    pub workers: Option<usize>,
+    pub retry: PartRetry,
+    pub idle_timeout: Duration,
+    pub no_progress: bool,
+}
+
+/// A file being written, read until its writer closes it.
+///
+/// The writer counts as gone once it has been seen holding the file open
+/// and no longer does. Only while no writer can be seen does the file count
+/// as finished once it has not grown for a while; writers in other
+/// containers or of other users may not be seen at all.
+struct GrowingFile {
+    file: File,
+    path: PathBuf,
+    pos: u64,
+    idle_timeout: Duration,
+    last_growth: Instant,
+    seen_writer: bool,
+    done: bool,
+}
+
+impl GrowingFile {
+    fn open(path: &Path, idle_timeout: Duration) -> io::Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            path: path.to_path_buf(),
+            pos: 0,
+            idle_timeout,
+            last_growth: Instant::now(),
+            seen_writer: false,
+            done: false,
+        })
+    }
+}
+
+impl Read for GrowingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.pos += n as u64;
+                self.last_growth = Instant::now();
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            if self.file.metadata()?.len() < self.pos {
+                return Err(io::Error::other(format!(
+                    "{} was truncated while being uploaded",
+                    self.path.display()
+                )));
+            }
+
+            // A writer holding the file is waited for however long it
+            // pauses; the idle timeout is for writers that cannot be seen.
+            let finished = match open_elsewhere(&self.path) {
+                Some(true) => {
+                    self.seen_writer = true;
+                    false
+                }
+                Some(false) if self.seen_writer => true,
+                _ => self.last_growth.elapsed() >= self.idle_timeout,
+            };
+            if finished {
+                // Read once more for whatever was written before closing.
+                self.done = true;
+                continue;
+            }
+            thread::sleep(FOLLOW_POLL);
+        }
+    }
+}
+
+/// Read up to `size` bytes, fewer only at the end of the input.
+fn read_part<R: Read + ?Sized>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(size as usize);
+    reader.take(size).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// A filled part on its way from the reader to the workers.
+type Part = (u64, Arc<[u8]>);
+
+struct StreamUpload<'a> {
+    client: &'a KoishiClient,
+    uploader: s3::Uploader,
+    uuid: &'a str,
+    upload_id: String,
+    hash: Option<String>,
+    retry: PartRetry,
+    pb: ProgressBar,
+    parts: Mutex<BTreeMap<u64, (String, PartDigest)>>,
+    error: Mutex<Option<Error>>,
+}
+
+impl StreamUpload<'_> {
+    fn failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    fn fail(&self, e: Error) {
+        self.error.lock().unwrap().get_or_insert(e);
+    }
+
+    /// URL of part `part_number`, signed first if `url` is missing or about
+    /// to expire.
+    fn part_url(&self, part_number: u64, url: &mut Option<String>) -> Result<String> {
+        if let Some(url) = url
+            && presigned_expiry(url).is_none_or(|expiry| expiry >= Utc::now() + EXPIRY_MARGIN)
+        {
+            return Ok(url.clone());
+        }
+        let res = api::video::upload_urls(
+            self.client,
+            self.uuid,
+            self.upload_id.clone(),
+            vec![part_number],
+            self.hash.clone(),
+        )
+        .context("Failed to get part URL")?;
+        let Some(signed) = res.urls.into_iter().next() else {
+            return Err(Error::validation("Server signed no URL for the part"));
+        };
+        Ok(url.insert(signed).clone())
+    }
+
+    fn upload_part(&self, part_number: u64, data: Arc<[u8]>) -> Result<(String, PartDigest)> {
+        let digest = multipart::digest_reader(&data[..])?;
+
+        let size = data.len() as u64;
+        let mut url = None;
+        let res = self.retry.run(
+            // Retries may go on for longer than the URL lasts.
+            || {
+                let url = self.part_url(part_number, &mut url)?;
+                Ok(self
+                    .uploader
+                    .url(&url)
+                    .mimetype("video/mp4")
+                    .content_md5(&digest)
+                    .from_reader_sized(self.pb.wrap_read(Cursor::new(data.clone())), size)
+                    .upload()?)
+            },
+            |e, attempt, delay| {
+                self.pb.suspend(|| {
+                    eprintln!(
+                        "warning: Part {part_number} failed: {e}; retrying in {:.1}s \
+                         (attempt {attempt})",
+                        delay.as_secs_f64()
+                    )
+                })
+            },
+        )?;
+        Ok((res.etag, digest))
+    }
+
+    fn work(&self, rx: &Mutex<mpsc::Receiver<Part>>) {
+        loop {
+            let Ok((part_number, data)) = rx.lock().unwrap().recv() else {
+                return;
+            };
+            // Drain the queue so that the reader is not left blocking.
+            if self.failed() {
+                continue;
+            }
+            match self
+                .upload_part(part_number, data)
+                .with_context(|| format!("Failed to upload part {part_number}"))
+            {
+                Ok(part) => {
+                    self.parts.lock().unwrap().insert(part_number, part);
+                    self.pb.println(format!("Part {part_number} uploaded"));
+                }
+                Err(e) => self.fail(e),
+            }
+        }
+    }
+
+    /// Cut `reader` into parts of `part_size` bytes for the workers, returning
+    /// the number of parts and bytes read.
+    fn read(
+        &self,
+        reader: &mut dyn Read,
+        part_size: u64,
+        tx: mpsc::SyncSender<Part>,
+    ) -> Result<(u64, u64)> {
+        let (mut parts, mut size) = (0, 0);
+        while !self.failed() {
+            // A stream cannot be resumed, so it is aborted once the parts
+            // read so far are through.
+            interrupt::check()?;
+            let data = read_part(reader, part_size).context("Failed to read input")?;
+            let last = (data.len() as u64) < part_size;
+            if data.is_empty() && parts > 0 {
+                break;
+            }
+            if data.is_empty() {
+                return Err(Error::validation("Nothing to upload"));
+            }
+            if parts == MAX_PARTS {
+                return Err(Error::validation(format!(
+                    "Input exceeds {MAX_PARTS} parts of {part_size} bytes; \
+                     pass a larger --part-size"
+                )));
+            }
+
+            parts += 1;
+            size += data.len() as u64;
+            if tx.send((parts, data.into())).is_err() || last {
+                break;
+            }
+        }
+        Ok((parts, size))
+    }
+}
+
+fn progress_bar(hidden: bool) -> ProgressBar {
+    let pb = ProgressBar::new_spinner().with_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner} {bytes} sent {bytes_per_sec} elapsed {elapsed}")
+            .unwrap(),
+    );
+    if hidden {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    } else {
+        pb.enable_steady_tick(Duration::from_millis(200));
+    }
+    pb
+}
+
+/// Upload the file at `path` as it grows, or stdin if `path` is `-`.
+pub(super) fn upload(
+    ctx: &Context,
+    uuid: &str,
+    path: &Path,
+    hash: Option<String>,
+    options: StreamOptions,
+) -> Result<UploadFinished> {
+    let client = &ctx.client;
+    let part_size = options.part_size.unwrap_or(DEFAULT_PART_SIZE);
+    if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&part_size) {
+        return Err(Error::validation(format!(
+            "Part size of streams must be between {MIN_PART_SIZE} and {MAX_PART_SIZE} bytes"
+        )));
+    }
+    let mut reader: Box<dyn Read> = match path.as_os_str() == "-" {
+        true => Box::new(io::stdin().lock()),
+        false => Box::new(GrowingFile::open(path, options.idle_timeout)?),
+    };
+
+    // Nothing is known about the size yet, so no part URLs are handed out
+    // here; each part asks for its own once it fills up.
+    let start = api::video::upload_start(client, uuid, 0, part_size, hash.clone())
+        .context("Failed to start upload")?;
+    if ctx.output.is_human() {
+        print_video_info(&start.video);
+    }
+    ctx.output.info(format!(
+        "Streaming upload {} in parts of {part_size} bytes",
+        start.upload_id
+    ));
+
+    let upload = StreamUpload {
+        client,
+        uploader: options.retry.uploader(client),
+        uuid,
+        upload_id: start.upload_id,
+        hash,
+        retry: options.retry,
+        pb: progress_bar(options.no_progress),
+        parts: Mutex::default(),
+        error: Mutex::default(),
+    };
+
+    // Parts in flight and queued are held in memory, so only a few at once.
+    let workers = options.workers.unwrap_or(DEFAULT_WORKERS).max(1);
+    let (tx, rx) = mpsc::sync_channel(1);
+    let rx = Mutex::new(rx);
+    let _running = interrupt::running();
+    let read = thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| upload.work(&rx));
+        }
+        upload.read(&mut reader, part_size, tx)
+    });
+    upload.pb.finish_and_clear();
+
+    let finished = read.and_then(|(parts, size)| {
+        if let Some(e) = upload.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        let (etags, digests): (Vec<_>, Vec<_>) =
+            upload.parts.lock().unwrap().values().cloned().unzip();
+        api::video::upload_finish(
+            client,
+            uuid,
+            upload.upload_id.clone(),
+            etags,
+            upload.hash.clone(),
+        )
+        .context("Failed to finish upload")?;
+        Ok((parts, size, digests))
+    });
+    let (parts, size, digests) = match finished {
+        Ok(finished) => finished,
+        Err(e) => {
+            match api::video::upload_abort(
+                client,
+                uuid,
+                upload.upload_id.clone(),
+                upload.hash.clone(),
+            ) {
+                Ok(()) => eprintln!("Upload {} aborted", upload.upload_id),
+                Err(e) => eprintln!("warning: Failed to abort upload: {e}"),
+            }
+            return Err(e);
+        }
+    };
+
+    let etag = verify::check_object(
+        client,
+        uuid,
+        upload.hash.as_deref(),
+        size,
+        &multipart::multipart_etag(&digests),
+    )
+    .context("Failed to verify upload")?;
+
+    Ok(UploadFinished {
+        uuid: uuid.to_string(),
+        upload_id: upload.upload_id,
+        size,
+        parts,
+        etag,
+    })
+}
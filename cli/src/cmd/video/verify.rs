@@ -0,0 +1,157 @@
+use clap::Parser;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{cmp::min, fs, path::Path, path::PathBuf};
+use tabled::Tabled;
+
+use koishi::helpers::{
+    cryptography::restricted_hash,
+    multipart::{self, PartDigest},
+};
+use koishi::{Error, KoishiClient, Result, ResultExt, api};
+
+use crate::{cmd::Context, output::Record};
+
+use super::{restrict, upload};
+
+#[derive(Parser)]
+pub(super) struct Args {
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Part size the video was uploaded or last copied with; guessed from
+    /// the part count of its ETag if not given
+    #[arg(short = 's', long)]
+    part_size: Option<u64>,
+
+    uuid: String,
+    path: PathBuf,
+}
+
+#[derive(Serialize, Tabled)]
+struct Verified {
+    uuid: String,
+    size: u64,
+    etag: String,
+    #[tabled(display("display_part_size"))]
+    part_size: Option<u64>,
+}
+
+fn display_part_size(part_size: &Option<u64>) -> String {
+    part_size.map_or_else(|| "-".into(), |v| v.to_string())
+}
+
+impl Record for Verified {}
+
+/// Digests of the parts of `size` bytes each the file at `path` splits into.
+pub(super) fn digest_parts(path: &Path, size: u64, part_size: u64) -> Result<Vec<PartDigest>> {
+    (0..size.div_ceil(part_size))
+        .into_par_iter()
+        .map(|i| {
+            let offset = i * part_size;
+            multipart::digest_part(path, offset, min(part_size, size - offset))
+                .with_context(|| format!("Failed to read part {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Check that the stored video object has the size and ETag of the local
+/// file, returning its ETag.
+pub(super) fn check_object(
+    client: &KoishiClient,
+    uuid: &str,
+    hash: Option<&str>,
+    size: u64,
+    etag: &str,
+) -> Result<String> {
+    let object = api::video::object(client, uuid, hash).context("Failed to look up video")?;
+    let stored = object.etag.unwrap_or_default();
+    if object.size != size {
+        return Err(Error::Integrity(format!(
+            "Stored video has {} bytes instead of {size}",
+            object.size
+        )));
+    }
+    if !multipart::etag_eq(&stored, etag) {
+        return Err(Error::Integrity(format!(
+            "Stored video has ETag {stored} instead of {etag}"
+        )));
+    }
+    Ok(stored)
+}
+
+pub(super) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let client = &ctx.client;
+    let size = fs::metadata(&args.path)?.len();
+    let hash = args
+        .password
+        .map(|v| restricted_hash(&args.uuid, &v))
+        .transpose()?;
+
+    let object = api::video::object(client, &args.uuid, hash.as_deref())
+        .context("Failed to look up video")?;
+    if object.size != size {
+        return Err(Error::Integrity(format!(
+            "Stored video has {} bytes, but {} has {size}",
+            object.size,
+            args.path.display()
+        )));
+    }
+    let stored = object.etag.unwrap_or_default();
+
+    // Objects put in one piece have the plain MD5 as ETag.
+    let Some(parts) = multipart::etag_parts(&stored) else {
+        ctx.output.info("Checking the file in one piece");
+        let digest = multipart::digest_part(&args.path, 0, size)?;
+        if !multipart::etag_eq(&stored, &multipart::part_etag(&digest)) {
+            return Err(Error::Integrity(format!(
+                "Stored video with ETag {stored} does not match {}",
+                args.path.display()
+            )));
+        }
+        let record = Verified {
+            uuid: args.uuid,
+            size,
+            etag: stored,
+            part_size: None,
+        };
+        return ctx.output.result(record, "Video matches the file");
+    };
+
+    let candidates = match args.part_size {
+        Some(part_size) => vec![part_size],
+        None => {
+            let mut preferred = vec![];
+            preferred.extend(ctx.settings.part_size.value);
+            preferred.extend([upload::DEFAULT_PART_SIZE, restrict::DEFAULT_PART_SIZE]);
+            multipart::candidate_part_sizes(size, parts, &preferred)
+        }
+    };
+    if candidates.is_empty() {
+        return Err(Error::validation(format!(
+            "Cannot tell the size of the {parts} parts the video was stored in; \
+             pass --part-size"
+        )));
+    }
+
+    for &part_size in &candidates {
+        ctx.output
+            .info(format!("Checking with part size {part_size}"));
+        let digests = digest_parts(&args.path, size, part_size)?;
+        if multipart::etag_eq(&stored, &multipart::multipart_etag(&digests)) {
+            let record = Verified {
+                uuid: args.uuid,
+                size,
+                etag: stored,
+                part_size: Some(part_size),
+            };
+            return ctx.output.result(record, "Video matches the file");
+        }
+    }
+
+    let tried: Vec<_> = candidates.iter().map(u64::to_string).collect();
+    Err(Error::Integrity(format!(
+        "Stored video with ETag {stored} does not match {} split into parts of {} bytes",
+        args.path.display(),
+        tried.join(", ")
+    )))
+}
@@ -0,0 +1,260 @@
+//! Watching the directory a recorder writes to, and putting each finished
+//! recording through the whole pipeline.
+//!
+//! A recording counts as finished once none of its files has changed for a
+//! while and no other process holds the video open.
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use notify::{PollWatcher, RecursiveMode, Watcher};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use koishi::{Error, Result, ResultExt};
+
+use crate::{
+    cmd::{
+        Context,
+        recording::{
+            self,
+            queue::{Job, Queue},
+        },
+    },
+    files::open_elsewhere,
+};
+/// Quiet time after a change before scanning, so that a burst of writes
+/// leads to one scan.
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+pub(crate) struct Args {
+    /// Seconds between scans, and between polls with --poll
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    interval: u64,
+    /// Seconds the files of a recording must go unchanged before it is
+    /// taken as finished
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    settle: u64,
+    /// Attempts at a recording before giving up on it
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    max_attempts: u32,
+    /// Poll the directory instead of relying on inotify, such as for
+    /// network filesystems that do not report changes
+    #[arg(long)]
+    poll: bool,
+    /// Queue file; kept in the state directory under the name of the
+    /// watched directory if not given
+    #[arg(long, value_name = "PATH")]
+    state: Option<PathBuf>,
+    /// Ingest the recordings finished by now, retrying unfinished ones
+    /// right away, then print the queue and exit
+    #[arg(long)]
+    once: bool,
+    /// Try the recordings that ran out of attempts again
+    #[arg(long)]
+    retry_failed: bool,
+
+    /// Directory the recorder writes to; watched with its subdirectories
+    dir: PathBuf,
+}
+
+fn find_recordings(dir: &Path, found: &mut Vec<Job>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_recordings(&path, found)?;
+        } else if recording::is_video(&path)
+            && let (Some(xml), cover) = recording::companions(&path)
+        {
+            found.push(Job::new(path, Some(xml), cover));
+        }
+    }
+    Ok(())
+}
+
+/// Time until the files of `job` have gone unchanged for `settle`, or
+/// `None` if the recording is finished.
+fn unsettled(job: &Job, settle: Duration) -> io::Result<Option<Duration>> {
+    let mut wait = Duration::ZERO;
+    for path in [&job.video].into_iter().chain(&job.xml).chain(&job.cover) {
+        let modified = fs::metadata(path)?.modified()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        wait = wait.max(settle.saturating_sub(age));
+    }
+    if wait.is_zero() && open_elsewhere(&job.video) == Some(true) {
+        return Ok(Some(settle.max(DEBOUNCE)));
+    }
+    Ok((!wait.is_zero()).then_some(wait))
+}
+
+/// Queue the finished recordings in `dir` not seen before, returning how
+/// long until the next unfinished one may be done.
+fn scan(
+    ctx: &Context,
+    queue: &mut Queue,
+    dir: &Path,
+    settle: Duration,
+) -> Result<Option<Duration>> {
+    let mut found = vec![];
+    find_recordings(dir, &mut found)
+        .with_context(|| format!("Failed to scan {}", dir.display()))?;
+
+    let mut next = None;
+    for job in found {
+        if queue.contains(&job.video) {
+            continue;
+        }
+        // Gone or replaced meanwhile; the next scan tells.
+        let Ok(wait) = unsettled(&job, settle) else {
+            continue;
+        };
+        if let Some(wait) = wait {
+            next = Some(next.map_or(wait, |n: Duration| n.min(wait)));
+            continue;
+        }
+        ctx.output
+            .info(format!("Found recording {}", job.video.display()));
+        queue.jobs.push(job);
+        queue.save()?;
+    }
+    Ok(next)
+}
+
+/// Ingest the pending jobs that are due at `now`, or all of them if not
+/// given, returning when the next one is due.
+fn run_due(
+    ctx: &Context,
+    queue: &mut Queue,
+    max_attempts: u32,
+    now: Option<DateTime<Utc>>,
+) -> Result<Option<DateTime<Utc>>> {
+    for i in 0..queue.jobs.len() {
+        if !queue.jobs[i].is_due(max_attempts, now) {
+            continue;
+        }
+        let mut job = queue.jobs[i].clone();
+        recording::ingest(ctx, &mut job, max_attempts, |job| {
+            queue.jobs[i] = job.clone();
+            queue.save()
+        })?;
+    }
+    Ok(queue.next_retry(max_attempts))
+}
+
+/// Start watching `dir`, sending on the returned channel whenever something
+/// in it changes.
+fn watch(
+    ctx: &Context,
+    dir: &Path,
+    poll: bool,
+    interval: Duration,
+) -> Result<(Box<dyn Watcher>, mpsc::Receiver<()>)> {
+    let (tx, rx) = mpsc::channel();
+    let handler = move |tx: mpsc::Sender<()>| {
+        move |res: notify::Result<notify::Event>| {
+            if res.is_ok() {
+                let _ = tx.send(());
+            }
+        }
+    };
+
+    if !poll {
+        let watcher = notify::recommended_watcher(handler(tx.clone())).and_then(|mut w| {
+            w.watch(dir, RecursiveMode::Recursive)?;
+            Ok(w)
+        });
+        match watcher {
+            Ok(watcher) => return Ok((Box::new(watcher), rx)),
+            Err(e) => eprintln!(
+                "warning: Cannot watch {} for changes ({e}); polling",
+                dir.display()
+            ),
+        }
+    }
+
+    let config = notify::Config::default().with_poll_interval(interval);
+    let watcher = PollWatcher::new(handler(tx), config)
+        .and_then(|mut w| w.watch(dir, RecursiveMode::Recursive).map(|_| w))
+        .map_err(io::Error::other)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+    ctx.output.info(format!(
+        "Polling {} every {}s",
+        dir.display(),
+        interval.as_secs()
+    ));
+    Ok((Box::new(watcher), rx))
+}
+
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let dir = fs::canonicalize(&args.dir)
+        .with_context(|| format!("Failed to open {}", args.dir.display()))?;
+    let state = match args.state {
+        Some(state) => state,
+        None => recording::default_queue_file("watch", &dir.to_string_lossy())?,
+    };
+    let mut queue = Queue::load(&state)?;
+    if args.retry_failed {
+        for job in &mut queue.jobs {
+            if job.is_failed(args.max_attempts) {
+                job.succeed();
+            }
+        }
+        queue.save()?;
+    }
+    let settle = Duration::from_secs(args.settle);
+    let interval = Duration::from_secs(args.interval.max(1));
+
+    if args.once {
+        scan(ctx, &mut queue, &dir, settle)?;
+        run_due(ctx, &mut queue, args.max_attempts, None)?;
+        let records = queue
+            .jobs
+            .iter()
+            .map(|j| recording::queued_recording(j, args.max_attempts));
+        return ctx.output.list(records, |_| {});
+    }
+
+    let (_watcher, events) = watch(ctx, &dir, args.poll, interval)?;
+    ctx.output.info(format!(
+        "Watching {} for recordings; queue in {}",
+        dir.display(),
+        state.display()
+    ));
+    loop {
+        let mut wait = interval;
+        match scan(ctx, &mut queue, &dir, settle) {
+            Ok(next) => wait = wait.min(next.unwrap_or(wait)),
+            Err(e) => eprintln!("warning: {}", recording::describe(&e)),
+        }
+        let next_retry = run_due(ctx, &mut queue, args.max_attempts, Some(Utc::now()))?;
+        if let Some(retry_at) = next_retry {
+            let until = (retry_at - Utc::now()).to_std().unwrap_or_default();
+            wait = wait.min(until);
+        }
+
+        match events.recv_timeout(wait) {
+            Ok(()) => {
+                thread::sleep(DEBOUNCE);
+                while events.try_recv().is_ok() {}
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(Error::validation(format!(
+                    "Stopped watching {}",
+                    dir.display()
+                )));
+            }
+        }
+    }
+}
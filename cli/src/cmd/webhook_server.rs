@@ -0,0 +1,411 @@
+//! Receiving the webhooks of BililiveRecorder and blrec, and ingesting the
+//! recordings they announce in the background.
+//!
+//! BililiveRecorder posts `FileClosed` to `/bililive-recorder` with a path
+//! relative to its work directory; blrec posts
+//! `VideoPostprocessingCompletedEvent` to `/blrec` with an absolute path,
+//! which must lie under `--root` or where `--map-path` maps paths to.
+//! `GET /status` lists the jobs.
+
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Cursor, Read},
+    path::{Component, Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use koishi::{Error, ErrorKind, Result, ResultExt};
+
+use super::{
+    Context,
+    recording::{
+        self, QueuedRecording,
+        queue::{Job, Queue, RecordingInfo},
+    },
+};
+
+/// Largest webhook body read; the payloads are a few hundred bytes.
+const MAX_BODY: u64 = 1024 * 1024;
+/// Wait for work when no job is waiting for a retry.
+const IDLE_WAIT: Duration = Duration::from_secs(3600);
+
+#[derive(Parser)]
+pub(crate) struct Args {
+    /// Address to listen on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8789")]
+    listen: String,
+    /// Work directory of BililiveRecorder, which the paths in its webhooks
+    /// are relative to; the paths from blrec must lie under it too, unless
+    /// mapped elsewhere with --map-path
+    #[arg(long, value_name = "DIR")]
+    root: Option<PathBuf>,
+    /// Read paths from webhooks starting with FROM as starting with TO,
+    /// such as for a recorder running in a container
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_path_map)]
+    map_path: Vec<(PathBuf, PathBuf)>,
+    /// Attempts at a recording before giving up on it
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    max_attempts: u32,
+    /// Queue file; kept in the state directory if not given
+    #[arg(long, value_name = "PATH")]
+    state: Option<PathBuf>,
+}
+
+fn parse_path_map(s: &str) -> std::result::Result<(PathBuf, PathBuf), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((from.into(), to.into())),
+        _ => Err("expected FROM=TO".to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BililiveRecorderEvent {
+    event_type: String,
+    #[serde(default)]
+    event_data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FileClosed {
+    relative_path: String,
+    room_id: u64,
+    title: String,
+    file_open_time: DateTime<FixedOffset>,
+}
+
+#[derive(Deserialize)]
+struct BlrecEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct BlrecFile {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct Ignored {
+    ignored: String,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    error: String,
+}
+
+struct Jobs {
+    queue: Queue,
+    running: Option<usize>,
+}
+
+struct Receiver {
+    root: Option<PathBuf>,
+    map_path: Vec<(PathBuf, PathBuf)>,
+    max_attempts: u32,
+    jobs: Mutex<Jobs>,
+    wake: Condvar,
+}
+
+type Reply = Response<Cursor<Vec<u8>>>;
+
+fn json<T: Serialize>(status: u16, body: &T) -> Reply {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Response::from_data(serde_json::to_vec(body).unwrap())
+        .with_status_code(StatusCode(status))
+        .with_header(header)
+}
+
+fn failure(status: u16, error: impl Into<String>) -> Reply {
+    json(
+        status,
+        &Failure {
+            error: error.into(),
+        },
+    )
+}
+
+impl Receiver {
+    /// Where a path from a webhook is found here.
+    fn local_path(&self, path: &Path) -> PathBuf {
+        for (from, to) in &self.map_path {
+            if let Ok(rest) = path.strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Whether recordings may be queued from `path`, found under the work
+    /// directory or where paths are mapped to.
+    fn is_confined(&self, path: &Path) -> bool {
+        let mut roots = self
+            .root
+            .iter()
+            .chain(self.map_path.iter().map(|(_, to)| to));
+        roots.any(|root| path.starts_with(root))
+    }
+
+    fn status(&self, job: &Job, i: usize, running: Option<usize>) -> QueuedRecording {
+        let mut record = recording::queued_recording(job, self.max_attempts);
+        if running == Some(i) {
+            record.status = "running";
+        }
+        record
+    }
+
+    /// Queue the recording of `video`, unless it already is.
+    fn enqueue(&self, video: PathBuf, info: Option<RecordingInfo>) -> Reply {
+        if !recording::is_video(&video) {
+            return failure(422, format!("{} is not a video", video.display()));
+        }
+        if !video.is_file() {
+            return failure(404, format!("{} not found", video.display()));
+        }
+        let (xml, cover) = recording::companions(&video);
+        if info.is_none() && xml.is_none() {
+            return failure(422, format!("No metadata XML beside {}", video.display()));
+        }
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let running = jobs.running;
+        if let Some(i) = jobs.queue.jobs.iter().position(|j| j.video == video) {
+            return json(200, &self.status(&jobs.queue.jobs[i], i, running));
+        }
+        let mut job = Job::new(video, xml, cover);
+        job.info = info;
+        let record = self.status(&job, jobs.queue.jobs.len(), running);
+        jobs.queue.jobs.push(job);
+        if let Err(e) = jobs.queue.save() {
+            jobs.queue.jobs.pop();
+            return failure(500, recording::describe(&e));
+        }
+        self.wake.notify_one();
+        json(202, &record)
+    }
+
+    fn bililive_recorder(&self, body: &[u8]) -> Reply {
+        let event: BililiveRecorderEvent = match serde_json::from_slice(body) {
+            Ok(event) => event,
+            Err(e) => return failure(400, e.to_string()),
+        };
+        if event.event_type != "FileClosed" {
+            return json(
+                200,
+                &Ignored {
+                    ignored: event.event_type,
+                },
+            );
+        }
+        let data: FileClosed = match serde_json::from_value(event.event_data) {
+            Ok(data) => data,
+            Err(e) => return failure(400, e.to_string()),
+        };
+        let Some(root) = &self.root else {
+            return failure(422, "Paths of BililiveRecorder need --root");
+        };
+        let relative = Path::new(&data.relative_path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return failure(400, format!("Bad path {}", data.relative_path));
+        }
+
+        // The event tells nothing of when the stream began, only when this
+        // file of it did.
+        let time = data.file_open_time.timestamp_millis();
+        let info = RecordingInfo {
+            room_id: data.room_id,
+            title: data.title,
+            stream_time: time,
+            record_time: time,
+        };
+        self.enqueue(self.local_path(&root.join(relative)), Some(info))
+    }
+
+    fn blrec(&self, body: &[u8]) -> Reply {
+        let event: BlrecEvent = match serde_json::from_slice(body) {
+            Ok(event) => event,
+            Err(e) => return failure(400, e.to_string()),
+        };
+        if event.kind != "VideoPostprocessingCompletedEvent" {
+            return json(
+                200,
+                &Ignored {
+                    ignored: event.kind,
+                },
+            );
+        }
+        let data: BlrecFile = match serde_json::from_value(event.data) {
+            Ok(data) => data,
+            Err(e) => return failure(400, e.to_string()),
+        };
+        let path = Path::new(&data.path);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return failure(400, format!("Bad path {}", data.path));
+        }
+        if self.root.is_none() && self.map_path.is_empty() {
+            return failure(422, "Paths of blrec need --root or --map-path");
+        }
+        let video = self.local_path(path);
+        if !self.is_confined(&video) {
+            return failure(
+                403,
+                format!("{} is outside --root and --map-path", video.display()),
+            );
+        }
+        self.enqueue(video, None)
+    }
+
+    fn list(&self, status: Option<&str>) -> Reply {
+        let jobs = self.jobs.lock().unwrap();
+        let records: Vec<_> = jobs
+            .queue
+            .jobs
+            .iter()
+            .enumerate()
+            .map(|(i, job)| self.status(job, i, jobs.running))
+            .filter(|r| status.is_none_or(|s| r.status == s))
+            .collect();
+        json(200, &records)
+    }
+
+    fn handle(&self, req: &mut Request) -> Reply {
+        let url = req.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        match (req.method(), path) {
+            (Method::Get, "/status") => {
+                let status = query.split('&').find_map(|p| p.strip_prefix("status="));
+                self.list(status)
+            }
+            (Method::Post, "/bililive-recorder" | "/blrec") => {
+                let mut body = vec![];
+                if let Err(e) = req.as_reader().take(MAX_BODY).read_to_end(&mut body) {
+                    return failure(400, e.to_string());
+                }
+                match path {
+                    "/blrec" => self.blrec(&body),
+                    _ => self.bililive_recorder(&body),
+                }
+            }
+            (_, "/status" | "/bililive-recorder" | "/blrec") => failure(405, "Method not allowed"),
+            _ => failure(404, "Not found"),
+        }
+    }
+
+    /// Ingest queued recordings one at a time, for as long as the server runs
+    /// or until an ingest is interrupted.
+    fn work(&self, ctx: &Context) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            let now = Utc::now();
+            let due = jobs
+                .queue
+                .jobs
+                .iter()
+                .position(|j| j.is_due(self.max_attempts, Some(now)));
+            let Some(i) = due else {
+                let wait = match jobs.queue.next_retry(self.max_attempts) {
+                    Some(retry_at) => (retry_at - now).to_std().unwrap_or_default(),
+                    None => IDLE_WAIT,
+                };
+                jobs = self.wake.wait_timeout(jobs, wait).unwrap().0;
+                continue;
+            };
+
+            jobs.running = Some(i);
+            let mut job = jobs.queue.jobs[i].clone();
+            drop(jobs);
+            let ingested = recording::ingest(ctx, &mut job, self.max_attempts, |job| {
+                let mut jobs = self.jobs.lock().unwrap();
+                jobs.queue.jobs[i] = job.clone();
+                jobs.queue.save()
+            });
+            match ingested {
+                Err(e) if e.kind() == ErrorKind::Interrupted => return Err(e),
+                Err(e) => eprintln!("warning: {}", recording::describe(&e)),
+                Ok(()) => {}
+            }
+            jobs = self.jobs.lock().unwrap();
+            jobs.running = None;
+        }
+    }
+}
+
+pub(crate) fn main(ctx: &Context, args: Args) -> Result<()> {
+    let state = match args.state {
+        Some(state) => state,
+        None => recording::default_queue_file("webhook", "queue")?,
+    };
+    let queue = Queue::load(&state)?;
+    let http = tiny_http::Server::http(&args.listen)
+        .map_err(|e| Error::validation(e.to_string()))
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    let receiver = Receiver {
+        root: args.root,
+        map_path: args.map_path,
+        max_attempts: args.max_attempts,
+        jobs: Mutex::new(Jobs {
+            queue,
+            running: None,
+        }),
+        wake: Condvar::new(),
+    };
+
+    ctx.output.info(format!(
+        "Listening for webhooks on http://{}",
+        http.server_addr()
+    ));
+    thread::scope(|s| {
+        let worker = s.spawn(|| {
+            let worked = receiver.work(ctx);
+            http.unblock();
+            worked
+        });
+        for mut req in http.incoming_requests() {
+            let res = receiver.handle(&mut req);
+            log::info!("{} {} {}", req.method(), req.url(), res.status_code().0);
+            let _ = req.respond(res);
+        }
+        worker.join().unwrap()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_path() {
+        let receiver = Receiver {
+            root: None,
+            map_path: vec![parse_path_map("/rec=/mnt/recorder").unwrap()],
+            max_attempts: 1,
+            jobs: Mutex::new(Jobs {
+                queue: Queue::default(),
+                running: None,
+            }),
+            wake: Condvar::new(),
+        };
+        assert_eq!(
+            receiver.local_path(Path::new("/rec/1000/a.flv")),
+            Path::new("/mnt/recorder/1000/a.flv")
+        );
+        assert_eq!(
+            receiver.local_path(Path::new("/record/a.flv")),
+            Path::new("/record/a.flv")
+        );
+        assert!(parse_path_map("/rec").is_err());
+        assert!(parse_path_map("=/mnt").is_err());
+    }
+}
@@ -0,0 +1,644 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use koishi::{
+    DEFAULT_BASE_URL, HttpVersion,
+    helpers::ratelimit::{Rate, Schedule},
+};
+
+pub(crate) const DEFAULT_PROFILE: &str = "default";
+
+/// On-disk layout of `config.toml`.
+///
+/// ```toml
+/// default_profile = "home"
+///
+/// [profiles.home]
+/// base_url = "https://koishi.example.com/api/"
+/// auth_key_file = "~/.config/koishi/home.key"
+/// part_size = 50000000
+/// thread_count = 4
+/// retry = 10
+/// retry_delay = 1.5
+/// stall_timeout = 60
+/// api_retry = 5
+/// timeout = 60
+/// connect_timeout = 10
+/// read_timeout = 120
+/// pool_size = 16
+/// http_version = "auto"
+/// user_agent = "my-batch-job/1.0"
+/// limit_rate = "5M"
+/// rate_schedule = "01:00-07:00=unlimited,19:00-23:00=pause"
+/// ```
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct ConfigFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub(crate) struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_key_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_delay: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stall_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_retry: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_rate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_schedule: Option<String>,
+}
+
+/// Keys accepted by `koishi config set`.
+pub(crate) const PROFILE_KEYS: &[&str] = &[
+    "base_url",
+    "auth_key",
+    "auth_key_file",
+    "part_size",
+    "thread_count",
+    "retry",
+    "retry_delay",
+    "stall_timeout",
+    "api_retry",
+    "timeout",
+    "connect_timeout",
+    "read_timeout",
+    "pool_size",
+    "http_version",
+    "user_agent",
+    "limit_rate",
+    "rate_schedule",
+];
+
+impl Profile {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(key.to_string(), value.to_string());
+        match key {
+            "base_url" => self.base_url = Some(value.to_string()),
+            "auth_key" => self.auth_key = Some(value.to_string()),
+            "auth_key_file" => self.auth_key_file = Some(value.into()),
+            "part_size" => self.part_size = Some(value.parse().map_err(|_| invalid())?),
+            "thread_count" => self.thread_count = Some(value.parse().map_err(|_| invalid())?),
+            "retry" => self.retry = Some(value.parse().map_err(|_| invalid())?),
+            "retry_delay" => {
+                let delay: f64 = value.parse().map_err(|_| invalid())?;
+                if !(delay >= 0.0 && delay.is_finite()) {
+                    return Err(invalid());
+                }
+                self.retry_delay = Some(delay)
+            }
+            "stall_timeout" => self.stall_timeout = Some(value.parse().map_err(|_| invalid())?),
+            "api_retry" => self.api_retry = Some(value.parse().map_err(|_| invalid())?),
+            "timeout" => self.timeout = Some(value.parse().map_err(|_| invalid())?),
+            "connect_timeout" => self.connect_timeout = Some(value.parse().map_err(|_| invalid())?),
+            "read_timeout" => self.read_timeout = Some(value.parse().map_err(|_| invalid())?),
+            "pool_size" => self.pool_size = Some(value.parse().map_err(|_| invalid())?),
+            "http_version" => {
+                value.parse::<HttpVersion>().map_err(|_| invalid())?;
+                self.http_version = Some(value.to_string())
+            }
+            "user_agent" => self.user_agent = Some(value.to_string()),
+            "limit_rate" => {
+                value.parse::<Rate>().map_err(|_| invalid())?;
+                self.limit_rate = Some(value.to_string())
+            }
+            "rate_schedule" => {
+                value.parse::<Schedule>().map_err(|_| invalid())?;
+                self.rate_schedule = Some(value.to_string())
+            }
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn unset(&mut self, key: &str) -> Result<(), ConfigError> {
+        match key {
+            "base_url" => self.base_url = None,
+            "auth_key" => self.auth_key = None,
+            "auth_key_file" => self.auth_key_file = None,
+            "part_size" => self.part_size = None,
+            "thread_count" => self.thread_count = None,
+            "retry" => self.retry = None,
+            "retry_delay" => self.retry_delay = None,
+            "stall_timeout" => self.stall_timeout = None,
+            "api_retry" => self.api_retry = None,
+            "timeout" => self.timeout = None,
+            "connect_timeout" => self.connect_timeout = None,
+            "read_timeout" => self.read_timeout = None,
+            "pool_size" => self.pool_size = None,
+            "http_version" => self.http_version = None,
+            "user_agent" => self.user_agent = None,
+            "limit_rate" => self.limit_rate = None,
+            "rate_schedule" => self.rate_schedule = None,
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+}
+
+impl ConfigFile {
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+        Some(config_home.join("koishi").join("config.toml"))
+    }
+
+    /// Read the config file; a missing file reads as an empty config.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| ConfigError::Parse(path.to_path_buf(), Box::new(e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(ConfigError::IO(path.to_path_buf(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let io_err = |e| ConfigError::IO(path.to_path_buf(), e);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_err)?;
+        }
+        let content = toml::to_string_pretty(self).expect("Config is always serializable");
+        fs::write(path, content).map_err(io_err)?;
+
+        // The file may hold auth keys, keep it private.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(io_err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings given on the command line; they take precedence over the
+/// environment and the config file.
+#[derive(Default)]
+pub(crate) struct Overrides {
+    pub config: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub base_url: Option<String>,
+    pub auth_key: Option<String>,
+    pub auth_key_file: Option<PathBuf>,
+    pub api_retry: Option<u32>,
+    pub limit_rate: Option<Rate>,
+    pub rate_schedule: Option<Schedule>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Source {
+    Default,
+    Profile,
+    Env(&'static str),
+    Cli,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Profile => write!(f, "profile"),
+            Self::Env(var) => write!(f, "env {var}"),
+            Self::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Resolved<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Effective settings after merging, in order of precedence, the command
+/// line, `KOISHI_*` environment variables, the selected profile and the
+/// built-in defaults.
+pub(crate) struct Settings {
+    pub config_path: Option<PathBuf>,
+    pub profile: Resolved<String>,
+    pub base_url: Resolved<String>,
+    pub auth_key: Resolved<Option<String>>,
+    pub part_size: Resolved<Option<u64>>,
+    pub thread_count: Resolved<Option<usize>>,
+    pub retry: Resolved<Option<u64>>,
+    /// Seconds before the first retry of a part, doubled after each failure.
+    pub retry_delay: Resolved<Option<f64>>,
+    pub api_retry: Resolved<Option<u32>>,
+    /// Timeouts in seconds; the stall timeout is how long an upload may go
+    /// without moving any data.
+    pub stall_timeout: Resolved<Option<u64>>,
+    pub timeout: Resolved<Option<u64>>,
+    pub connect_timeout: Resolved<Option<u64>>,
+    pub read_timeout: Resolved<Option<u64>>,
+    pub pool_size: Resolved<Option<usize>>,
+    pub http_version: Resolved<Option<HttpVersion>>,
+    pub user_agent: Resolved<Option<String>>,
+    pub limit_rate: Resolved<Option<Rate>>,
+    pub rate_schedule: Resolved<Option<Schedule>>,
+}
+
+fn env_var(name: &'static str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn env_parsed<T: std::str::FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    env_var(name)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| ConfigError::InvalidValue(name.to_string(), v))
+        })
+        .transpose()
+}
+
+/// Pick the first value present among the command line, an environment
+/// variable and the profile.
+fn pick<T>(
+    cli: Option<T>,
+    env: (&'static str, Option<T>),
+    profile: Option<T>,
+) -> Option<Resolved<T>> {
+    let (var, env) = env;
+    cli.map(|value| Resolved {
+        value,
+        source: Source::Cli,
+    })
+    .or(env.map(|value| Resolved {
+        value,
+        source: Source::Env(var),
+    }))
+    .or(profile.map(|value| Resolved {
+        value,
+        source: Source::Profile,
+    }))
+}
+
+fn optional<T>(resolved: Option<Resolved<T>>) -> Resolved<Option<T>> {
+    match resolved {
+        Some(Resolved { value, source }) => Resolved {
+            value: Some(value),
+            source,
+        },
+        None => Resolved {
+            value: None,
+            source: Source::Default,
+        },
+    }
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<String, ConfigError> {
+    let path = expand_home(path);
+    fs::read_to_string(&path)
+        .map(|v| v.trim().to_string())
+        .map_err(|e| ConfigError::KeyFile(path, e))
+}
+
+/// Name of the profile in effect, from `--profile`, `KOISHI_PROFILE` or the
+/// file's `default_profile`.
+pub(crate) fn select_profile(cli: Option<String>, file: &ConfigFile) -> Resolved<String> {
+    pick(
+        cli,
+        ("KOISHI_PROFILE", env_var("KOISHI_PROFILE")),
+        file.default_profile.clone(),
+    )
+    .unwrap_or(Resolved {
+        value: DEFAULT_PROFILE.into(),
+        source: Source::Default,
+    })
+}
+
+impl Settings {
+    pub fn config_path(overrides: &Overrides) -> Option<PathBuf> {
+        overrides
+            .config
+            .clone()
+            .or_else(|| env_var("KOISHI_CONFIG").map(PathBuf::from))
+            .or_else(ConfigFile::default_path)
+    }
+
+    pub fn resolve(overrides: Overrides) -> Result<Self, ConfigError> {
+        let config_path = Self::config_path(&overrides);
+        let file = match config_path.as_deref() {
+            Some(path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let profile = select_profile(overrides.profile, &file);
+
+        // Asking for a profile by name that is not there is most likely a
+        // typo; the implicit default profile may be absent.
+        let values = match file.profiles.get(&profile.value) {
+            Some(values) => values.clone(),
+            None if profile.source == Source::Default => Profile::default(),
+            None => return Err(ConfigError::UnknownProfile(profile.value)),
+        };
+
+        let base_url = pick(
+            overrides.base_url,
+            ("KOISHI_BASE_URL", env_var("KOISHI_BASE_URL")),
+            values.base_url,
+        )
+        .unwrap_or(Resolved {
+            value: DEFAULT_BASE_URL.into(),
+            source: Source::Default,
+        });
+
+        // A key given directly wins over a key file at the same level, but
+        // either wins over both at the levels below it. Key files named in
+        // the profile are relative to the config file.
+        let profile_key_file = values.auth_key_file.map(|path| {
+            let path = expand_home(&path);
+            match config_path.as_deref().and_then(Path::parent) {
+                Some(dir) if path.is_relative() => dir.join(path),
+                _ => path,
+            }
+        });
+        let env_key = match env_var("KOISHI_AUTH_KEY") {
+            Some(key) => ("KOISHI_AUTH_KEY", Some(Ok(key))),
+            None => (
+                "KOISHI_AUTH_KEY_FILE",
+                env_var("KOISHI_AUTH_KEY_FILE").map(|v| Err(PathBuf::from(v))),
+            ),
+        };
+        let auth_key = pick(
+            overrides
+                .auth_key
+                .map(Ok)
+                .or(overrides.auth_key_file.map(Err)),
+            env_key,
+            values.auth_key.map(Ok).or(profile_key_file.map(Err)),
+        );
+        let auth_key = match auth_key {
+            Some(Resolved { value, source }) => Some(Resolved {
+                value: match value {
+                    Ok(key) => key,
+                    Err(path) => read_key_file(&path)?,
+                },
+                source,
+            }),
+            None => None,
+        };
+
+        let part_size = pick(
+            None,
+            ("KOISHI_PART_SIZE", env_parsed("KOISHI_PART_SIZE")?),
+            values.part_size,
+        );
+        let thread_count = pick(
+            None,
+            ("KOISHI_THREAD_COUNT", env_parsed("KOISHI_THREAD_COUNT")?),
+            values.thread_count,
+        );
+        let retry = pick(
+            None,
+            ("KOISHI_RETRY", env_parsed("KOISHI_RETRY")?),
+            values.retry,
+        );
+        let retry_delay = pick(
+            None,
+            ("KOISHI_RETRY_DELAY", env_parsed("KOISHI_RETRY_DELAY")?),
+            values.retry_delay,
+        );
+        let stall_timeout = pick(
+            None,
+            ("KOISHI_STALL_TIMEOUT", env_parsed("KOISHI_STALL_TIMEOUT")?),
+            values.stall_timeout,
+        );
+        let api_retry = pick(
+            overrides.api_retry,
+            ("KOISHI_API_RETRY", env_parsed("KOISHI_API_RETRY")?),
+            values.api_retry,
+        );
+        let timeout = pick(
+            None,
+            ("KOISHI_TIMEOUT", env_parsed("KOISHI_TIMEOUT")?),
+            values.timeout,
+        );
+        let connect_timeout = pick(
+            None,
+            (
+                "KOISHI_CONNECT_TIMEOUT",
+                env_parsed("KOISHI_CONNECT_TIMEOUT")?,
+            ),
+            values.connect_timeout,
+        );
+        let read_timeout = pick(
+            None,
+            ("KOISHI_READ_TIMEOUT", env_parsed("KOISHI_READ_TIMEOUT")?),
+            values.read_timeout,
+        );
+        let pool_size = pick(
+            None,
+            ("KOISHI_POOL_SIZE", env_parsed("KOISHI_POOL_SIZE")?),
+            values.pool_size,
+        );
+        let http_version = pick(
+            None,
+            ("KOISHI_HTTP_VERSION", env_parsed("KOISHI_HTTP_VERSION")?),
+            values
+                .http_version
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| ConfigError::InvalidValue("http_version".into(), v))
+                })
+                .transpose()?,
+        );
+        let user_agent = pick(
+            None,
+            ("KOISHI_USER_AGENT", env_var("KOISHI_USER_AGENT")),
+            values.user_agent,
+        );
+        let limit_rate = pick(
+            overrides.limit_rate,
+            ("KOISHI_LIMIT_RATE", env_parsed("KOISHI_LIMIT_RATE")?),
+            values
+                .limit_rate
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| ConfigError::InvalidValue("limit_rate".into(), v))
+                })
+                .transpose()?,
+        );
+        let rate_schedule = pick(
+            overrides.rate_schedule,
+            ("KOISHI_RATE_SCHEDULE", env_parsed("KOISHI_RATE_SCHEDULE")?),
+            values
+                .rate_schedule
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| ConfigError::InvalidValue("rate_schedule".into(), v))
+                })
+                .transpose()?,
+        );
+
+        Ok(Self {
+            config_path,
+            profile,
+            base_url,
+            auth_key: optional(auth_key),
+            part_size: optional(part_size),
+            thread_count: optional(thread_count),
+            retry: optional(retry),
+            retry_delay: optional(retry_delay),
+            api_retry: optional(api_retry),
+            stall_timeout: optional(stall_timeout),
+            timeout: optional(timeout),
+            connect_timeout: optional(connect_timeout),
+            read_timeout: optional(read_timeout),
+            pool_size: optional(pool_size),
+            http_version: optional(http_version),
+            user_agent: optional(user_agent),
+            limit_rate: optional(limit_rate),
+            rate_schedule: optional(rate_schedule),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    IO(PathBuf, io::Error),
+    Parse(PathBuf, Box<toml::de::Error>),
+    UnknownProfile(String),
+    UnknownKey(String),
+    InvalidValue(String, String),
+    KeyFile(PathBuf, io::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IO(path, err) => write!(f, "Failed to access {}: {}", path.display(), err),
+            Self::Parse(path, err) => write!(f, "Failed to parse {}: {}", path.display(), err),
+            Self::UnknownProfile(name) => write!(f, "Profile {name} is not defined"),
+            Self::UnknownKey(key) => write!(
+                f,
+                "Unknown setting {key}; expected one of {}",
+                PROFILE_KEYS.join(", ")
+            ),
+            Self::InvalidValue(key, value) => write!(f, "Invalid value for {key}: {value}"),
+            Self::KeyFile(path, err) => {
+                write!(f, "Failed to read key file {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for koishi::Error {
+    fn from(value: ConfigError) -> Self {
+        // Whatever went wrong, it is the configuration that needs fixing.
+        koishi::Error::validation(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_set() {
+        let mut profile = Profile::default();
+        profile.set("part_size", "50000000").unwrap();
+        profile.set("base_url", "https://example.com/api/").unwrap();
+        assert_eq!(profile.part_size, Some(50_000_000));
+        assert!(profile.set("part_size", "big").is_err());
+        assert!(profile.set("colour", "red").is_err());
+
+        profile.unset("part_size").unwrap();
+        assert_eq!(profile.part_size, None);
+
+        profile.set("http_version", "2").unwrap();
+        assert_eq!(profile.http_version.as_deref(), Some("2"));
+        assert!(profile.set("http_version", "3").is_err());
+
+        profile.set("retry_delay", "0.5").unwrap();
+        assert_eq!(profile.retry_delay, Some(0.5));
+        assert!(profile.set("retry_delay", "-1").is_err());
+        assert!(profile.set("retry_delay", "NaN").is_err());
+
+        profile.set("limit_rate", "5M").unwrap();
+        profile
+            .set("rate_schedule", "01:00-07:00=unlimited,19:00-23:00=pause")
+            .unwrap();
+        assert!(profile.set("limit_rate", "fast").is_err());
+        assert!(profile.set("rate_schedule", "night=unlimited").is_err());
+    }
+
+    #[test]
+    fn test_config_file_roundtrip() {
+        let content = r#"
+            default_profile = "home"
+
+            [profiles.home]
+            base_url = "https://example.com/api/"
+            retry = 3
+        "#;
+        let config: ConfigFile = toml::from_str(content).unwrap();
+        assert_eq!(config.default_profile.as_deref(), Some("home"));
+        let home = &config.profiles["home"];
+        assert_eq!(home.base_url.as_deref(), Some("https://example.com/api/"));
+        assert_eq!(home.retry, Some(3));
+        assert_eq!(home.part_size, None);
+
+        let reparsed: ConfigFile = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(reparsed.profiles["home"].retry, Some(3));
+    }
+
+    #[test]
+    fn test_pick_precedence() {
+        let picked = pick(Some(1), ("KOISHI_X", Some(2)), Some(3)).unwrap();
+        assert_eq!((picked.value, picked.source), (1, Source::Cli));
+
+        let picked = pick(None, ("KOISHI_X", Some(2)), Some(3)).unwrap();
+        assert_eq!((picked.value, picked.source), (2, Source::Env("KOISHI_X")));
+
+        let picked = pick(None, ("KOISHI_X", None), Some(3)).unwrap();
+        assert_eq!((picked.value, picked.source), (3, Source::Profile));
+
+        assert!(pick::<u64>(None, ("KOISHI_X", None), None).is_none());
+    }
+}
@@ -0,0 +1,275 @@
+use std::{fmt::Display, io};
+
+use crate::api::{APIError, ServerErrorType};
+use crate::helpers::s3::S3UploaderError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Any failure of the library or the commands built on it.
+#[derive(Debug)]
+pub enum Error {
+    /// Error answered by the Koishi API, or failure to reach it.
+    API(APIError),
+    /// Failure transferring data to or from object storage.
+    S3(S3UploaderError),
+    /// HTTP failure outside of the Koishi API and object storage.
+    Http(reqwest::Error),
+    IO(io::Error),
+    Crypto(libsodium_rs::SodiumError),
+    XML(quick_xml::DeError),
+    /// Input rejected before anything was sent.
+    Validation(String),
+    /// Something looked up outside of the Koishi API does not exist.
+    NotFound(String),
+    /// Object in storage that does not match the local file it came from.
+    Integrity(String),
+    /// Stopped on request, such as by Ctrl-C, with its progress kept.
+    Interrupted,
+    /// Another error, along with what was being done when it happened.
+    Context(String, Box<Error>),
+}
+
+/// Class of an [`Error`], which determines the exit code of the CLI.
+///
+/// | Code | Kind          | Meaning                                               |
+/// |------|---------------|-------------------------------------------------------|
+/// | 1    | `Other`       | Anything not listed below                             |
+/// | 2    | `BadInput`    | Invalid arguments, config or input files              |
+/// | 3    | `Auth`        | Auth key missing, wrong, or not allowed to do this    |
+/// | 4    | `NotFound`    | Room, video or upload does not exist                  |
+/// | 5    | `Conflict`    | Target already exists or is in a conflicting state    |
+/// | 6    | `Network`     | Server unreachable, timed out or failed transiently   |
+/// | 7    | `Storage`     | Object storage or the database rejected the operation |
+/// | 130  | `Interrupted` | Stopped by Ctrl-C or SIGTERM, keeping its progress    |
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    Other,
+    BadInput,
+    Auth,
+    NotFound,
+    Conflict,
+    Network,
+    Storage,
+    Interrupted,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::BadInput => 2,
+            Self::Auth => 3,
+            Self::NotFound => 4,
+            Self::Conflict => 5,
+            Self::Network => 6,
+            Self::Storage => 7,
+            Self::Interrupted => 130,
+        }
+    }
+}
+
+fn request_error_kind(err: &reqwest::Error) -> ErrorKind {
+    if err.is_builder() {
+        ErrorKind::BadInput
+    } else {
+        ErrorKind::Network
+    }
+}
+
+impl Error {
+    pub fn validation<S: Into<String>>(message: S) -> Self {
+        Self::Validation(message.into())
+    }
+
+    /// Attach a description of what was being done to the error.
+    pub fn context<S: Into<String>>(self, message: S) -> Self {
+        Self::Context(message.into(), Box::new(self))
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::API(APIError::ServerError(err)) => match err.error_type {
+                ServerErrorType::Unauthorized | ServerErrorType::Forbidden => ErrorKind::Auth,
+                ServerErrorType::NotFound => ErrorKind::NotFound,
+                ServerErrorType::Conflict => ErrorKind::Conflict,
+                ServerErrorType::BadRequest
+                | ServerErrorType::MethodNotAllowed
+                | ServerErrorType::UnprocessableEntity => ErrorKind::BadInput,
+                ServerErrorType::DBTransactionError | ServerErrorType::S3Error => {
+                    ErrorKind::Storage
+                }
+                // A bug of the server rather than a failure to reach it,
+                // though still retried as it may be passing.
+                ServerErrorType::InternalServerError | ServerErrorType::Unknown(_) => {
+                    ErrorKind::Other
+                }
+            },
+            Self::API(APIError::RequestError(err)) => request_error_kind(err),
+            Self::S3(S3UploaderError::Request(err)) => request_error_kind(err),
+            Self::S3(S3UploaderError::Stalled(_)) => ErrorKind::Network,
+            Self::S3(_) => ErrorKind::Storage,
+            Self::Http(err) => request_error_kind(err),
+            Self::IO(err) => match err.kind() {
+                io::ErrorKind::NotFound
+                | io::ErrorKind::PermissionDenied
+                | io::ErrorKind::AlreadyExists
+                | io::ErrorKind::InvalidData
+                | io::ErrorKind::InvalidInput
+                | io::ErrorKind::UnexpectedEof => ErrorKind::BadInput,
+                _ => ErrorKind::Other,
+            },
+            Self::Crypto(_) => ErrorKind::Other,
+            Self::XML(_) | Self::Validation(_) => ErrorKind::BadInput,
+            Self::NotFound(_) => ErrorKind::NotFound,
+            Self::Integrity(_) => ErrorKind::Storage,
+            Self::Interrupted => ErrorKind::Interrupted,
+            Self::Context(_, err) => err.kind(),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.kind().exit_code()
+    }
+
+    /// Whether doing the same again may succeed; see
+    /// [`APIError::is_retriable`] and [`S3UploaderError::is_retriable`].
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Self::API(err) => err.is_retriable(),
+            Self::S3(err) => err.is_retriable(),
+            Self::Context(_, err) => err.is_retriable(),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::API(err) => err.fmt(f),
+            Self::S3(err) => err.fmt(f),
+            Self::Http(err) => write!(f, "HTTP request failed: {err}"),
+            Self::IO(err) => err.fmt(f),
+            Self::Crypto(err) => write!(f, "Cryptography error: {err}"),
+            Self::XML(err) => write!(f, "Invalid XML: {err}"),
+            Self::Validation(msg) | Self::NotFound(msg) | Self::Integrity(msg) => f.write_str(msg),
+            Self::Interrupted => f.write_str("Interrupted"),
+            Self::Context(msg, err) => {
+                write!(f, "{msg}: ")?;
+                err.fmt(f)
+            }
+        }
+    }
+}
+
+// Messages of the wrapped errors are part of `Display` already, so sources
+// start one level below them to keep reports free of repetition.
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::API(APIError::ServerError(_)) => None,
+            Self::API(APIError::RequestError(err)) => err.source(),
+            Self::S3(S3UploaderError::S3(_)) => None,
+            Self::S3(S3UploaderError::Request(err)) => err.source(),
+            Self::S3(S3UploaderError::IO(err)) => err.source(),
+            Self::S3(S3UploaderError::XML(err)) => err.source(),
+            Self::S3(S3UploaderError::Checksum { .. } | S3UploaderError::Stalled(_)) => None,
+            Self::Http(err) => err.source(),
+            Self::IO(err) => err.source(),
+            Self::Crypto(err) => err.source(),
+            Self::XML(err) => err.source(),
+            Self::Validation(_) | Self::NotFound(_) | Self::Integrity(_) | Self::Interrupted => {
+                None
+            }
+            Self::Context(_, err) => err.source(),
+        }
+    }
+}
+
+impl From<APIError> for Error {
+    fn from(value: APIError) -> Self {
+        Error::API(value)
+    }
+}
+
+impl From<S3UploaderError> for Error {
+    fn from(value: S3UploaderError) -> Self {
+        Error::S3(value)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Error::Http(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::IO(value)
+    }
+}
+
+impl From<libsodium_rs::SodiumError> for Error {
+    fn from(value: libsodium_rs::SodiumError) -> Self {
+        Error::Crypto(value)
+    }
+}
+
+impl From<quick_xml::DeError> for Error {
+    fn from(value: quick_xml::DeError) -> Self {
+        Error::XML(value)
+    }
+}
+
+/// Extension for attaching context to any result convertible to [`Error`].
+pub trait ResultExt<T> {
+    fn context<S: Into<String>>(self, message: S) -> Result<T>;
+    fn with_context<S: Into<String>, F: FnOnce() -> S>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn context<S: Into<String>>(self, message: S) -> Result<T> {
+        self.map_err(|e| e.into().context(message))
+    }
+
+    fn with_context<S: Into<String>, F: FnOnce() -> S>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api::ServerError;
+
+    fn server_error(error_type: ServerErrorType) -> Error {
+        Error::API(APIError::ServerError(ServerError {
+            error_type,
+            message: None,
+            details: None,
+        }))
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(server_error(ServerErrorType::Unauthorized).exit_code(), 3);
+        assert_eq!(server_error(ServerErrorType::NotFound).exit_code(), 4);
+        assert_eq!(server_error(ServerErrorType::Conflict).exit_code(), 5);
+        assert_eq!(server_error(ServerErrorType::S3Error).exit_code(), 7);
+        // Retried, but reported as what it is rather than as the network.
+        let err = server_error(ServerErrorType::InternalServerError);
+        assert!(err.is_retriable());
+        assert_eq!(err.exit_code(), 1);
+        assert_eq!(Error::validation("bad").exit_code(), 2);
+        assert_eq!(Error::Interrupted.context("Upload").exit_code(), 130);
+
+        let err = server_error(ServerErrorType::Forbidden).context("Failed to create video");
+        assert_eq!(err.kind(), ErrorKind::Auth);
+        assert_eq!(
+            err.to_string(),
+            "Failed to create video: ServerError: Forbidden"
+        );
+    }
+}
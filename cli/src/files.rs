@@ -0,0 +1,66 @@
+//! Looking at files other programs write, such as recordings, and writing
+//! files others may read at any moment.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Whether a process other than this one has `path` open, or `None` if
+/// that cannot be told at all. Processes whose open files cannot be looked
+/// at, such as those of other users, are taken as not having it open.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_elsewhere(path: &Path) -> Option<bool> {
+    let target = fs::canonicalize(path).ok()?;
+    let me = std::process::id().to_string();
+    for entry in fs::read_dir("/proc").ok()? {
+        let entry = entry.ok()?;
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+        else {
+            continue;
+        };
+        if pid == me {
+            continue;
+        }
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            // Exited meanwhile, or not ours to look at.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                continue;
+            }
+            Err(_) => return None,
+        };
+        for fd in fds.flatten() {
+            if fs::read_link(fd.path()).is_ok_and(|link| link == target) {
+                return Some(true);
+            }
+        }
+    }
+    Some(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_elsewhere(_path: &Path) -> Option<bool> {
+    None
+}
+
+/// Replace the file at `path` with `contents` so that readers see either
+/// the old or the new contents in full, even if writing is cut short.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let mut f = fs::File::create(tmp)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    fs::rename(tmp, path)
+}
@@ -1,4 +1,3 @@
-use hex;
 use libsodium_rs::{crypto_generichash, crypto_pwhash};
 
 type SodiumResult<T> = Result<T, libsodium_rs::SodiumError>;
@@ -25,8 +24,8 @@ fn derive_key(uuid: &str, pwd: &str) -> SodiumResult<Vec<u8>> {
     )
 }
 
-pub(crate) fn restricted_hash(uuid: &str, pwd: &str) -> SodiumResult<String> {
-    derive_key(uuid, pwd).map(|v| hex::encode(v))
+pub fn restricted_hash(uuid: &str, pwd: &str) -> SodiumResult<String> {
+    derive_key(uuid, pwd).map(hex::encode)
 }
 
 #[cfg(test)]
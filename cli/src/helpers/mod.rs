@@ -1,4 +1,6 @@
 pub mod cryptography;
+pub mod multipart;
+pub mod ratelimit;
 pub mod s3;
 pub mod se;
 pub mod tabled;
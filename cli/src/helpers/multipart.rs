@@ -0,0 +1,260 @@
+//! Part sizes and checksums for S3 multipart uploads and copies.
+//!
+//! S3 and R2 accept at most [`MAX_PARTS`] parts per upload, each between
+//! [`MIN_PART_SIZE`] and [`MAX_PART_SIZE`] except for the last one, which
+//! may be smaller.
+//!
+//! The ETag of each part is the hex MD5 of its content, and that of the
+//! assembled object the MD5 of the concatenated part digests followed by
+//! the number of parts, so both can be checked without downloading anything.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use md5::{Digest, Md5};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::Error;
+
+const MIB: u64 = 1024 * 1024;
+
+pub const MIN_PART_SIZE: u64 = 5 * MIB;
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * MIB;
+pub const MAX_PARTS: u64 = 10_000;
+
+/// Parts each worker should get at least, so that a slow part near the end
+/// does not leave the others idle for long.
+const PARTS_PER_WORKER: u64 = 4;
+
+/// Part size for an object of `size` bytes transferred by `concurrency`
+/// workers.
+///
+/// Starts from `preferred` and shrinks it until every worker gets a few
+/// parts, or grows it until the upload fits in [`MAX_PARTS`], staying within
+/// the storage limits either way.
+pub fn choose_part_size(size: u64, concurrency: usize, preferred: u64) -> u64 {
+    let workers = (concurrency.max(1) as u64).saturating_mul(PARTS_PER_WORKER);
+    let fitting = size.div_ceil(MAX_PARTS).next_multiple_of(MIB);
+
+    preferred
+        .min(size.div_ceil(workers))
+        .max(MIN_PART_SIZE)
+        .max(fitting)
+        .min(MAX_PART_SIZE)
+}
+
+/// Check a part size given by the user against the limits on any part,
+/// whatever the size of the object.
+pub fn validate_part_size_bounds(part_size: u64) -> crate::Result<()> {
+    if part_size == 0 {
+        return Err(Error::validation("Part size must be positive"));
+    }
+    if part_size > MAX_PART_SIZE {
+        return Err(Error::validation(format!(
+            "Part size {part_size} exceeds the limit of {MAX_PART_SIZE} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Check a part size given by the user against the storage limits for an
+/// object of `size` bytes.
+pub fn validate_part_size(size: u64, part_size: u64) -> crate::Result<()> {
+    validate_part_size_bounds(part_size)?;
+
+    // Only the last part may fall short of the minimum, so a single part
+    // of any size is fine.
+    let parts = size.div_ceil(part_size);
+    if parts > 1 && part_size < MIN_PART_SIZE {
+        return Err(Error::validation(format!(
+            "Part size {part_size} is below the minimum of {MIN_PART_SIZE} bytes"
+        )));
+    }
+    if parts > MAX_PARTS {
+        return Err(Error::validation(format!(
+            "Part size {part_size} splits {size} bytes into {parts} parts, \
+             more than the limit of {MAX_PARTS}; use at least {} bytes",
+            size.div_ceil(MAX_PARTS)
+        )));
+    }
+    Ok(())
+}
+
+/// Part sizes [`choose_part_size`] may have picked from any of `preferred`
+/// for an object of `size` bytes that ended up in `parts` parts, most
+/// likely first.
+///
+/// The ETag of an object tells how many parts it was assembled from but not
+/// how large they were, which is needed to compute it again.
+pub fn candidate_part_sizes(size: u64, parts: u64, preferred: &[u64]) -> Vec<u64> {
+    let mut ret: Vec<u64> = vec![];
+    for &p in preferred {
+        let chosen = (1..=256).map(|c| choose_part_size(size, c, p));
+        for part_size in [p].into_iter().chain(chosen) {
+            if part_size > 0 && size.div_ceil(part_size) == parts && !ret.contains(&part_size) {
+                ret.push(part_size);
+            }
+        }
+    }
+    ret
+}
+
+/// MD5 digest of a part, which S3 reports as its ETag.
+pub type PartDigest = [u8; 16];
+
+pub fn digest_reader<R: Read>(mut reader: R) -> io::Result<PartDigest> {
+    let mut hasher = Md5::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Digest of the `size` bytes of the file at `path` starting at `offset`.
+pub fn digest_part(path: &Path, offset: u64, size: u64) -> io::Result<PartDigest> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let digest = digest_reader(BufReader::new(f.by_ref().take(size)))?;
+    if f.stream_position()? != offset + size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} is shorter than expected", path.display()),
+        ));
+    }
+    Ok(digest)
+}
+
+/// Digest of the first, middle and last MiB of `size` bytes of `reader`,
+/// which cheaply tells a file from another of the same size.
+pub fn sample_digest<R: Read + Seek>(mut reader: R, size: u64) -> io::Result<PartDigest> {
+    let sample = MIB.min(size);
+    let mut hasher = Md5::new();
+    for offset in [0, (size - sample) / 2, size - sample] {
+        reader.seek(SeekFrom::Start(offset))?;
+        let copied = io::copy(&mut reader.by_ref().take(sample), &mut hasher)?;
+        if copied != sample {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Value of the `Content-MD5` header S3 checks the part against.
+pub fn content_md5(digest: &[u8]) -> String {
+    BASE64_STANDARD.encode(digest)
+}
+
+/// ETag S3 gives a part, or an object uploaded in one piece, of this digest.
+pub fn part_etag(digest: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(digest))
+}
+
+/// ETag S3 gives the object assembled from parts of these digests.
+pub fn multipart_etag(digests: &[PartDigest]) -> String {
+    let mut hasher = Md5::new();
+    for digest in digests {
+        hasher.update(digest);
+    }
+    format!("\"{}-{}\"", hex::encode(hasher.finalize()), digests.len())
+}
+
+/// Compare ETags, which some services send unquoted or in upper case.
+pub fn etag_eq(a: &str, b: &str) -> bool {
+    a.trim_matches('"')
+        .eq_ignore_ascii_case(b.trim_matches('"'))
+}
+
+/// Number of parts an ETag says its object was assembled from, if any.
+pub fn etag_parts(etag: &str) -> Option<u64> {
+    etag.trim_matches('"').split_once('-')?.1.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_choose_part_size() {
+        // Small files keep every worker busy, but parts stay above the minimum.
+        assert_eq!(choose_part_size(11 * MIB, 8, 10_000_000), MIN_PART_SIZE);
+        assert_eq!(choose_part_size(GB, 4, 10_000_000), 10_000_000);
+        assert_eq!(choose_part_size(GB, 64, 10_000_000), MIN_PART_SIZE);
+        assert_eq!(choose_part_size(200 * MIB, 4, 100_000_000), 200 * MIB / 16);
+
+        // 200 GB in 10 MB parts would need 20,000 parts.
+        let size = choose_part_size(200 * GB, 8, 10_000_000);
+        assert!(size.is_multiple_of(MIB));
+        assert!(200 * GB / size <= MAX_PARTS);
+        validate_part_size(200 * GB, size).unwrap();
+
+        // Beyond 10,000 maximum-sized parts nothing fits.
+        assert_eq!(choose_part_size(u64::MAX, 8, 10_000_000), MAX_PART_SIZE);
+    }
+
+    #[test]
+    fn test_validate_part_size() {
+        validate_part_size(MIB, MIB).unwrap();
+        validate_part_size(MIB, 100 * GB / 100).unwrap();
+        validate_part_size(0, 10_000_000).unwrap();
+
+        assert!(validate_part_size(MIB, 0).is_err());
+        assert!(validate_part_size_bounds(0).is_err());
+        assert!(validate_part_size_bounds(MAX_PART_SIZE + 1).is_err());
+        validate_part_size_bounds(MIB).unwrap();
+        assert!(validate_part_size(11 * MIB, MIB).is_err());
+        assert!(validate_part_size(11 * MIB, MAX_PART_SIZE + 1).is_err());
+        assert!(validate_part_size(200 * GB, 10_000_000).is_err());
+        validate_part_size(100 * GB, 10_000_000).unwrap();
+    }
+
+    #[test]
+    fn test_candidate_part_sizes() {
+        let size = 11 * MIB;
+        assert_eq!(
+            candidate_part_sizes(size, 3, &[10_000_000]),
+            [MIN_PART_SIZE]
+        );
+        assert_eq!(candidate_part_sizes(size, 2, &[10_000_000]), [10_000_000]);
+        assert!(candidate_part_sizes(size, 7, &[10_000_000]).is_empty());
+
+        for part_size in candidate_part_sizes(GB, 64, &[10_000_000, 100_000_000]) {
+            assert_eq!(GB.div_ceil(part_size), 64);
+        }
+    }
+
+    #[test]
+    fn test_etags() {
+        let hello = digest_reader(&b"hello"[..]).unwrap();
+        assert_eq!(part_etag(&hello), "\"5d41402abc4b2a76b9719d911017c592\"");
+        assert_eq!(content_md5(&hello), "XUFAKrxLKna5cZ2REBfFkg==");
+
+        // Same as the local store, which follows S3.
+        let parts = [b"hello ".as_slice(), b"world"].map(|p| digest_reader(p).unwrap());
+        let etag = multipart_etag(&parts);
+        assert_eq!(etag, "\"e09e4fd6265b36115fe3db32df945d84-2\"");
+        assert_eq!(etag_parts(&etag), Some(2));
+        assert_eq!(etag_parts(&part_etag(&hello)), None);
+
+        assert!(etag_eq(&etag, "E09E4FD6265B36115FE3DB32DF945D84-2"));
+        assert!(!etag_eq(&etag, &part_etag(&hello)));
+    }
+
+    #[test]
+    fn test_sample_digest() {
+        let mut data = vec![0; 5 * MIB as usize];
+        let digest = |data: &[u8]| sample_digest(io::Cursor::new(data), data.len() as u64);
+        let before = digest(&data).unwrap();
+
+        // Outside the samples goes unnoticed, within them does not.
+        data[MIB as usize + 1] = 1;
+        assert_eq!(digest(&data).unwrap(), before);
+        let middle = data.len() / 2;
+        data[middle] = 1;
+        assert_ne!(digest(&data).unwrap(), before);
+
+        assert_ne!(digest(b"hello").unwrap(), digest(b"hellp").unwrap());
+        assert!(sample_digest(io::Cursor::new(b"short"), 6).is_err());
+    }
+}
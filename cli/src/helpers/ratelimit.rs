@@ -0,0 +1,336 @@
+//! Bandwidth limiting for uploads.
+//!
+//! A [`RateLimiter`] is a token bucket shared by every request made through
+//! a client, so that concurrent parts together stay within the limit. The
+//! limit may change with the time of day according to a [`Schedule`], for
+//! example to pause uploads in the evening and lift the limit at night.
+
+use chrono::{Local, NaiveTime};
+use std::{
+    fmt::Display,
+    io::{self, Read},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a paused limiter looks at the clock again.
+const PAUSE_POLL: Duration = Duration::from_secs(10);
+
+/// Largest read charged at once, so that a single large buffer does not
+/// hold back every other reader.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Upload rate in bytes per second.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rate {
+    #[default]
+    Unlimited,
+    Paused,
+    Limited(u64),
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parse `unlimited`, `pause` or a number of bytes per second with an
+    /// optional binary `K`, `M` or `G` suffix, such as `5M`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate {s}; expected e.g. 500K, 5M, pause or unlimited");
+        match s.to_ascii_lowercase().as_str() {
+            "unlimited" | "off" => return Ok(Self::Unlimited),
+            "pause" | "paused" => return Ok(Self::Paused),
+            _ => {}
+        }
+
+        let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => s.split_at(i),
+            None => (s, ""),
+        };
+        let shift = match unit.to_ascii_uppercase().trim_end_matches(['B', '/', 'S']) {
+            "" => 0,
+            "K" => 10,
+            "M" => 20,
+            "G" => 30,
+            _ => return Err(invalid()),
+        };
+        match digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|v| v.checked_mul(1 << shift))
+        {
+            Some(0) | None => Err(invalid()),
+            Some(rate) => Ok(Self::Limited(rate)),
+        }
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::Unlimited => write!(f, "unlimited"),
+            Self::Paused => write!(f, "pause"),
+            Self::Limited(rate) => {
+                for (unit, shift) in [("G", 30), ("M", 20), ("K", 10)] {
+                    if rate > 0 && rate.is_multiple_of(1 << shift) {
+                        return write!(f, "{}{unit}", rate >> shift);
+                    }
+                }
+                write!(f, "{rate}")
+            }
+        }
+    }
+}
+
+/// Rate in effect during a time of day, such as `01:00-07:00=unlimited`.
+///
+/// Windows ending before they start run past midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub rate: Rate,
+}
+
+impl RateWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for RateWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid schedule window {s}; expected e.g. 01:00-07:00=5M");
+        let (times, rate) = s.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let time = |v: &str| NaiveTime::parse_from_str(v.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+            rate: rate.trim().parse()?,
+        })
+    }
+}
+
+impl Display for RateWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}={}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M"),
+            self.rate
+        )
+    }
+}
+
+/// Comma-separated [`RateWindow`]s overriding the default rate; the first
+/// window containing the current local time wins.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Schedule(pub Vec<RateWindow>);
+
+impl Schedule {
+    pub fn rate_at(&self, time: NaiveTime, default: Rate) -> Rate {
+        self.0
+            .iter()
+            .find(|w| w.contains(time))
+            .map_or(default, |w| w.rate)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let windows: Vec<_> = self.0.iter().map(ToString::to_string).collect();
+        f.write_str(&windows.join(","))
+    }
+}
+
+struct Bucket {
+    /// Bytes that may be sent right away; negative while readers wait for
+    /// bytes they have already been granted.
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Take `n` bytes from the bucket at `rate` bytes per second, returning
+    /// how long the caller has to wait before they are paid for.
+    ///
+    /// At most a second worth of bytes builds up while idle.
+    fn take(&mut self, n: u64, rate: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - n as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Token bucket shared by clones of it.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: Rate,
+    schedule: Arc<Schedule>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// Limit to `rate`, or to the rate of the window of `schedule` in effect.
+    pub fn new(rate: Rate, schedule: Schedule) -> Self {
+        Self {
+            rate,
+            schedule: Arc::new(schedule),
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// Rate in effect now.
+    pub fn current_rate(&self) -> Rate {
+        self.schedule.rate_at(Local::now().time(), self.rate)
+    }
+
+    /// Block until `n` more bytes may be sent.
+    ///
+    /// A pause only holds back new uploads, see [`Self::wait_while_paused`];
+    /// those under way go on at the default rate so that storage does not
+    /// drop them half sent.
+    pub fn acquire(&self, n: u64) {
+        let rate = match self.current_rate() {
+            Rate::Paused => self.rate,
+            rate => rate,
+        };
+        if let Rate::Limited(rate) = rate {
+            let wait = self.bucket.lock().unwrap().take(n, rate, Instant::now());
+            thread::sleep(wait);
+        }
+    }
+
+    /// Block while the schedule pauses uploads.
+    pub fn wait_while_paused(&self) {
+        if self.current_rate() != Rate::Paused {
+            return;
+        }
+        log::warn!("Uploads paused by the rate schedule");
+        while self.current_rate() == Rate::Paused {
+            thread::sleep(PAUSE_POLL);
+        }
+    }
+
+    pub fn wrap_read<R: Read>(&self, read: R) -> RateLimitedRead<R> {
+        RateLimitedRead {
+            read,
+            limiter: self.clone(),
+        }
+    }
+}
+
+pub struct RateLimitedRead<R: Read> {
+    read: R,
+    limiter: RateLimiter,
+}
+
+impl<R: Read> Read for RateLimitedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_CHUNK);
+        let bytes_read = self.read.read(&mut buf[..len])?;
+        self.limiter.acquire(bytes_read as u64);
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!("5M".parse(), Ok(Rate::Limited(5 << 20)));
+        assert_eq!("500k".parse(), Ok(Rate::Limited(500 << 10)));
+        assert_eq!("1MB/s".parse(), Ok(Rate::Limited(1 << 20)));
+        assert_eq!("1000".parse(), Ok(Rate::Limited(1000)));
+        assert_eq!("pause".parse(), Ok(Rate::Paused));
+        assert_eq!("unlimited".parse(), Ok(Rate::Unlimited));
+        for invalid in ["", "0", "5X", "M", "-1K"] {
+            assert!(invalid.parse::<Rate>().is_err(), "{invalid}");
+        }
+
+        for rate in ["5M", "1536K", "1000", "pause", "unlimited"] {
+            assert_eq!(rate.parse::<Rate>().unwrap().to_string(), rate);
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule: Schedule = "01:00-07:00=unlimited, 19:00-23:30=pause,23:30-01:00=1M"
+            .parse()
+            .unwrap();
+        let default = Rate::Limited(5 << 20);
+        assert_eq!(schedule.rate_at(time(3, 0), default), Rate::Unlimited);
+        assert_eq!(schedule.rate_at(time(7, 0), default), default);
+        assert_eq!(schedule.rate_at(time(19, 0), default), Rate::Paused);
+        assert_eq!(
+            schedule.rate_at(time(23, 45), default),
+            Rate::Limited(1 << 20)
+        );
+        assert_eq!(
+            schedule.rate_at(time(0, 30), default),
+            Rate::Limited(1 << 20)
+        );
+        assert_eq!(
+            schedule.to_string(),
+            "01:00-07:00=unlimited,19:00-23:30=pause,23:30-01:00=1M"
+        );
+
+        assert_eq!("".parse(), Ok(Schedule::default()));
+        assert!("01:00=5M".parse::<Schedule>().is_err());
+        assert!("25:00-01:00=5M".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            last: start,
+        };
+
+        // Bytes granted ahead of time are paid for by waiting.
+        assert_eq!(bucket.take(1000, 1000, start), Duration::from_secs(1));
+        assert_eq!(bucket.take(500, 1000, start), Duration::from_millis(1500));
+
+        // Idle time refills at most a second worth of bytes.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(1000, 1000, later), Duration::ZERO);
+        assert_eq!(bucket.take(500, 1000, later), Duration::from_millis(500));
+    }
+}
@@ -1,20 +1,46 @@
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
 use reqwest::{
-    IntoUrl, Result as ReqResult,
+    IntoUrl, Result as ReqResult, Url,
     blocking::{Body, Client, RequestBuilder, Response},
 };
 use serde::Deserialize;
+
+use super::{multipart, ratelimit::RateLimiter};
 use std::{
     fmt::Display,
     fs::File,
     io::{self, BufReader, Read},
     path::Path,
-    time::Duration,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+/// Format of the `X-Amz-Date` of presigned URLs.
+pub(crate) const AMZ_DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// When a presigned URL stops working, going by its `X-Amz-Date` and
+/// `X-Amz-Expires` parameters; `None` if it has neither.
+pub fn presigned_expiry(url: &str) -> Option<DateTime<Utc>> {
+    let url = Url::parse(url).ok()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    let date = NaiveDateTime::parse_from_str(&param("X-Amz-Date")?, AMZ_DATE_FORMAT).ok()?;
+    let expires = TimeDelta::try_seconds(param("X-Amz-Expires")?.parse().ok()?)?;
+    Some(date.and_utc() + expires)
+}
+
 #[derive(Debug)]
-pub(crate) struct S3Error {
-    status: u16,
-    xml: String,
+pub struct S3Error {
+    pub(crate) status: u16,
+    pub(crate) xml: String,
 }
 
 impl Display for S3Error {
@@ -29,19 +55,80 @@ impl Display for S3Error {
 
 impl std::error::Error for S3Error {}
 
+impl S3Error {
+    /// The `Code` of the error document S3 answered with.
+    pub fn code(&self) -> Option<&str> {
+        let start = self.xml.find("<Code>")? + "<Code>".len();
+        let len = self.xml[start..].find("</Code>")?;
+        Some(&self.xml[start..start + len])
+    }
+}
+
 #[derive(Debug)]
-pub(crate) enum S3UploaderError {
+pub enum S3UploaderError {
     S3(S3Error),
     Request(reqwest::Error),
     IO(io::Error),
+    /// Response body that is not the XML S3 should have sent.
+    XML(quick_xml::DeError),
+    /// ETag of an upload that does not match the MD5 of what was sent.
+    Checksum {
+        expected: String,
+        etag: String,
+    },
+    /// No data moved for this long, so the upload was given up on.
+    Stalled(Duration),
+}
+
+impl S3UploaderError {
+    /// Whether sending the same upload again may succeed.
+    ///
+    /// Failures of storage itself, throttling, dropped or stalled
+    /// connections and data corrupted on the way are; a refused signature,
+    /// such as of an expired URL, an upload that no longer exists and a
+    /// body that cannot be read are not.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            S3UploaderError::S3(err) => {
+                err.status >= 500
+                    || matches!(err.status, 408 | 429)
+                    || matches!(err.code(), Some("BadDigest" | "RequestTimeout"))
+            }
+            S3UploaderError::Request(err) => {
+                err.is_timeout()
+                    || err.is_connect()
+                    || err.is_request()
+                    || err.is_body()
+                    || err.is_decode()
+            }
+            S3UploaderError::IO(_) => false,
+            S3UploaderError::XML(_)
+            | S3UploaderError::Checksum { .. }
+            | S3UploaderError::Stalled(_) => true,
+        }
+    }
 }
 
 impl Display for S3UploaderError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if f.alternate() {
-            write!(f, "{:?}", self)
-        } else {
-            write!(f, "{:#?}", self)
+        match self {
+            S3UploaderError::S3(err) => err.fmt(f),
+            S3UploaderError::Request(err) => write!(f, "S3 request failed: {err}"),
+            S3UploaderError::IO(err) => write!(f, "Failed to read upload body: {err}"),
+            S3UploaderError::XML(err) => write!(f, "Invalid S3 response: {err}"),
+            S3UploaderError::Checksum { expected, etag } => {
+                write!(
+                    f,
+                    "ETag {etag} does not match the MD5 {expected} of the data sent"
+                )
+            }
+            S3UploaderError::Stalled(timeout) => {
+                write!(
+                    f,
+                    "Upload stalled: no data moved for {}s",
+                    timeout.as_secs()
+                )
+            }
         }
     }
 }
@@ -52,6 +139,8 @@ impl std::error::Error for S3UploaderError {
             S3UploaderError::S3(err) => Some(err),
             S3UploaderError::Request(err) => Some(err),
             S3UploaderError::IO(err) => Some(err),
+            S3UploaderError::XML(err) => Some(err),
+            S3UploaderError::Checksum { .. } | S3UploaderError::Stalled(_) => None,
         }
     }
 }
@@ -74,8 +163,77 @@ impl From<io::Error> for S3UploaderError {
     }
 }
 
-pub(crate) struct Uploader {
+/// When the body of an upload last moved, shared between the reader that
+/// sends it and the thread watching for a stall.
+#[derive(Clone)]
+struct Activity {
+    start: Instant,
+    /// Milliseconds from `start` to the last read.
+    last: Arc<AtomicU64>,
+    /// Set while the clock is stopped: while the body is being read, which
+    /// includes waiting for the rate limit.
+    stopped: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: Arc::default(),
+            stopped: Arc::default(),
+            cancelled: Arc::default(),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        if self.stopped.load(Ordering::Relaxed) {
+            return Duration::ZERO;
+        }
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// Body of an upload that records its progress in an [`Activity`], and
+/// fails once the upload has been given up on.
+///
+/// Only the time the connection takes to ask for more, or to answer once
+/// all of the body is sent, counts towards a stall; not the time reading
+/// the body takes.
+struct WatchedRead<R> {
+    read: R,
+    activity: Activity,
+}
+
+impl<R: Read> Read for WatchedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.activity.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::other("upload given up on"));
+        }
+        self.activity.stopped.store(true, Ordering::Relaxed);
+        let res = self.read.read(buf);
+        self.activity.touch();
+        self.activity.stopped.store(false, Ordering::Relaxed);
+        res
+    }
+}
+
+/// Uploads to presigned object storage URLs.
+///
+/// Prefer [`crate::KoishiClient::uploader`], which reuses the connections of
+/// the API client, over creating one with its own client here.
+#[derive(Clone)]
+pub struct Uploader {
     client: Client,
+    timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl Uploader {
@@ -85,17 +243,60 @@ impl Uploader {
 
     pub fn with_timeout<T: Into<Option<Duration>>>(timeout: T) -> ReqResult<Self> {
         let client = Client::builder().timeout(timeout).build()?;
-        Ok(Self { client })
+        Ok(Self::from_client(client))
+    }
+
+    pub fn from_client(client: Client) -> Self {
+        Self {
+            client,
+            timeout: None,
+            stall_timeout: None,
+            rate_limiter: None,
+        }
+    }
+
+    /// Total timeout of each upload made through this uploader.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on an upload once the connection has taken no more of the
+    /// body, or not answered once all of it is sent, for `timeout`. Waiting
+    /// for the rate limit does not count. `None` disables it.
+    pub fn stall_timeout<T: Into<Option<Duration>>>(mut self, timeout: T) -> Self {
+        self.stall_timeout = timeout.into();
+        self
+    }
+
+    /// Limiter that bodies read through [`UploadTaskBuilder::from_reader_sized`]
+    /// are subject to.
+    pub fn rate_limiter(mut self, rate_limiter: Option<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
     }
 
     pub fn url<U: IntoUrl>(&self, url: U) -> UploadTaskBuilder {
-        let rb = self.client.put(url);
-        UploadTaskBuilder { rb }
+        let mut rb = self.client.put(url);
+        if let Some(timeout) = self.timeout {
+            rb = rb.timeout(timeout);
+        }
+        UploadTaskBuilder {
+            rb,
+            expected_etag: None,
+            rate_limiter: self.rate_limiter.clone(),
+            stall_timeout: self.stall_timeout,
+            activity: Activity::new(),
+        }
     }
 }
 
-pub(crate) struct UploadTaskBuilder {
+pub struct UploadTaskBuilder {
     rb: RequestBuilder,
+    expected_etag: Option<String>,
+    rate_limiter: Option<RateLimiter>,
+    stall_timeout: Option<Duration>,
+    activity: Activity,
 }
 
 impl UploadTaskBuilder {
@@ -104,7 +305,7 @@ impl UploadTaskBuilder {
         F: FnOnce(RequestBuilder) -> RequestBuilder,
     {
         let rb = f(self.rb);
-        Self { rb }
+        Self { rb, ..self }
     }
 
     pub fn mimetype<S: AsRef<str>>(self, value: S) -> Self {
@@ -123,20 +324,51 @@ impl UploadTaskBuilder {
         self.map_inner(|rb| rb.body(body))
     }
 
+    /// Have storage check the body against its MD5 `digest`, and check the
+    /// ETag it answers with in turn.
+    pub fn content_md5(self, digest: &[u8]) -> Self {
+        let mut task =
+            self.map_inner(|rb| rb.header("Content-MD5", multipart::content_md5(digest)));
+        task.expected_etag = Some(multipart::part_etag(digest));
+        task
+    }
+
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_reader_sized<R: Read + Send + 'static>(self, reader: R, limit: u64) -> Self {
-        self.body(Body::sized(reader.take(limit), limit))
+        let reader = reader.take(limit);
+        match self.rate_limiter.clone() {
+            Some(limiter) => {
+                let reader = self.watch(limiter.wrap_read(reader));
+                self.body(Body::sized(reader, limit))
+            }
+            None => {
+                let reader = self.watch(reader);
+                self.body(Body::sized(reader, limit))
+            }
+        }
+    }
+
+    fn watch<R: Read>(&self, read: R) -> WatchedRead<R> {
+        WatchedRead {
+            read,
+            activity: self.activity.clone(),
+        }
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_file_path<P: AsRef<Path>>(self, path: P) -> io::Result<Self> {
         let f = File::open(path)?;
         let f_size = f.metadata()?.len();
 
         let buf_reader = BufReader::new(f);
 
-        Ok(self.from_reader_sized(buf_reader, f_size as u64))
+        Ok(self.from_reader_sized(buf_reader, f_size))
     }
 
     fn send(self) -> Result<Response, S3UploaderError> {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.wait_while_paused();
+        }
         let res = self.rb.send()?;
         if !res.status().is_success() {
             let status = res.status().as_u16();
@@ -148,29 +380,157 @@ impl UploadTaskBuilder {
     }
 
     pub fn upload(self) -> Result<UploadResult, S3UploaderError> {
+        match self.stall_timeout {
+            Some(timeout) => self.upload_watched(timeout),
+            None => self.upload_now(),
+        }
+    }
+
+    /// Upload on another thread, giving up on it once it stalls. A blocked
+    /// request cannot be cancelled, so it is left to its thread, where the
+    /// body fails as soon as it is read again.
+    fn upload_watched(self, timeout: Duration) -> Result<UploadResult, S3UploaderError> {
+        // Paused uploads are not stalled ones.
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.wait_while_paused();
+        }
+        let activity = self.activity.clone();
+        activity.touch();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(self.upload_now());
+        });
+        loop {
+            let idle = activity.idle();
+            if idle >= timeout {
+                activity.cancelled.store(true, Ordering::Relaxed);
+                return Err(S3UploaderError::Stalled(timeout));
+            }
+            match rx.recv_timeout(timeout - idle) {
+                Ok(res) => return res,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("upload thread panicked").into());
+                }
+            }
+        }
+    }
+
+    fn upload_now(self) -> Result<UploadResult, S3UploaderError> {
+        let expected = self.expected_etag.clone();
         let res = self.send()?;
         let etag_header = res
             .headers()
             .get("ETag")
             .and_then(|v| v.to_str().ok())
             .map(|v| v.to_string());
-        if let Some(etag) = etag_header {
-            Ok(UploadResult { etag })
+        let result = if let Some(etag) = etag_header {
+            UploadResult { etag }
         } else {
             let xml = res.text()?;
-            let result: CopyPartResult =
-                quick_xml::de::from_str(&xml).expect("Failed to parse XML");
-            Ok(UploadResult { etag: result.etag })
-        }
+            UploadResult::from_copy_part_result(&xml)?
+        };
+        result.check(expected)
     }
 }
 
-pub(crate) struct UploadResult {
+pub struct UploadResult {
     pub etag: String,
 }
 
+impl UploadResult {
+    pub(crate) fn check(self, expected: Option<String>) -> Result<Self, S3UploaderError> {
+        match expected {
+            Some(expected) if !multipart::etag_eq(&expected, &self.etag) => {
+                Err(S3UploaderError::Checksum {
+                    expected,
+                    etag: self.etag,
+                })
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// UploadPartCopy answers with the ETag in a `CopyPartResult` body
+    /// rather than in the header.
+    pub(crate) fn from_copy_part_result(xml: &str) -> Result<Self, S3UploaderError> {
+        let result: CopyPartResult = quick_xml::de::from_str(xml).map_err(S3UploaderError::XML)?;
+        Ok(Self { etag: result.etag })
+    }
+}
+
 #[derive(Deserialize)]
 struct CopyPartResult {
     #[serde(rename = "ETag")]
     etag: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presigned_expiry() {
+        let url = "https://s3.example.com/b/k?partNumber=1&X-Amz-Algorithm=AWS4-HMAC-SHA256\
+                   &X-Amz-Date=20250101T120000Z&X-Amz-Expires=86400&X-Amz-Signature=x";
+        assert_eq!(
+            presigned_expiry(url).unwrap().to_rfc3339(),
+            "2025-01-02T12:00:00+00:00"
+        );
+        assert_eq!(presigned_expiry("https://s3.example.com/b/k"), None);
+        assert_eq!(
+            presigned_expiry("https://s3.example.com/b/k?X-Amz-Date=soon&X-Amz-Expires=60"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_watched_read_stops_clock() {
+        /// Reader as slow as one held back by the rate limit.
+        struct Slow(Activity);
+
+        impl Read for Slow {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(20));
+                assert_eq!(self.0.idle(), Duration::ZERO);
+                buf[0] = 0;
+                Ok(1)
+            }
+        }
+
+        let activity = Activity::new();
+        let mut read = WatchedRead {
+            read: Slow(activity.clone()),
+            activity: activity.clone(),
+        };
+        let mut buf = [0; 1];
+        assert_eq!(read.read(&mut buf).unwrap(), 1);
+        thread::sleep(Duration::from_millis(20));
+        assert!(activity.idle() >= Duration::from_millis(20));
+
+        // Waiting for the answer once all of the body is sent counts too.
+        assert_eq!(read.read(&mut buf).unwrap(), 1);
+        thread::sleep(Duration::from_millis(20));
+        assert!(activity.idle() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_is_retriable() {
+        let s3 = |status, code: &str| {
+            S3UploaderError::S3(S3Error {
+                status,
+                xml: format!("<Error><Code>{code}</Code><Message>m</Message></Error>"),
+            })
+        };
+        assert!(s3(500, "InternalError").is_retriable());
+        assert!(s3(503, "SlowDown").is_retriable());
+        assert!(s3(400, "BadDigest").is_retriable());
+        assert!(s3(400, "RequestTimeout").is_retriable());
+        assert!(!s3(403, "AccessDenied").is_retriable());
+        assert!(!s3(404, "NoSuchUpload").is_retriable());
+        assert!(!s3(400, "EntityTooSmall").is_retriable());
+        assert!(S3UploaderError::Stalled(Duration::from_secs(60)).is_retriable());
+        assert!(!S3UploaderError::IO(io::Error::other("gone")).is_retriable());
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
-pub(crate) struct BoolAsInt {
+pub struct BoolAsInt {
     value: bool,
 }
 
@@ -0,0 +1,181 @@
+//! Stopping transfers cleanly on Ctrl-C or SIGTERM.
+//!
+//! While a transfer runs, the first signal asks it to stop: it starts no new
+//! parts, lets those in flight finish and keeps where it got to for a
+//! resume. A second signal, or one arriving while no transfer runs, ends the
+//! process at once as it would have without a handler.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use koishi::{Error, ErrorKind, Result};
+
+/// Transfers running that stop on their own when asked to.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Handle Ctrl-C and SIGTERM for the rest of the process.
+pub(crate) fn install() {
+    let handler = || {
+        if RUNNING.load(Ordering::SeqCst) == 0 || REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(ErrorKind::Interrupted.exit_code());
+        }
+        eprintln!("\nInterrupted; finishing the parts in flight. Press Ctrl-C again to quit now.");
+    };
+    if let Err(e) = ctrlc::set_handler(handler) {
+        log::warn!("Failed to handle interruptions: {e}");
+    }
+}
+
+/// Mark of a transfer that stops when asked to, for as long as it is held.
+pub(crate) struct Running(());
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn running() -> Running {
+    RUNNING.fetch_add(1, Ordering::SeqCst);
+    Running(())
+}
+
+/// Whether the process was asked to stop.
+pub(crate) fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Fail if the process was asked to stop.
+pub(crate) fn check() -> Result<()> {
+    match requested() {
+        true => Err(Error::Interrupted),
+        false => Ok(()),
+    }
+}
+
+/// Options whose values are secrets, along with what stands in for them.
+const SECRETS: &[(&[&str], &str)] = &[
+    (&["-p", "--password"], "<password>"),
+    (&["-k", "--auth-key"], "<auth-key>"),
+];
+
+/// The command line the process was started with, ready to paste into a
+/// shell, after `edit` changed its arguments. Secrets are masked.
+pub(crate) fn command_line(edit: impl FnOnce(&mut Vec<String>)) -> String {
+    // Not `args()`, which panics on arguments that are not Unicode, such as
+    // some paths; those are shown as near as they can be.
+    let mut args: Vec<String> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    edit(&mut args);
+    shell_line(args)
+}
+
+fn shell_line(args: Vec<String>) -> String {
+    let mut masked = vec![];
+    let mut secret = None;
+    for arg in args {
+        if let Some(mask) = secret.take() {
+            masked.push(mask);
+            continue;
+        }
+        let mut quoted = quote(&arg);
+        for (flags, mask) in SECRETS {
+            if flags.contains(&arg.as_str()) {
+                secret = Some(mask.to_string());
+            } else if let Some(flag) = flags.iter().find(|f| with_value(&arg, f)) {
+                quoted = match flag.starts_with("--") {
+                    true => format!("{flag}={mask}"),
+                    false => format!("{flag} {mask}"),
+                };
+            }
+        }
+        masked.push(quoted);
+    }
+    masked.join(" ")
+}
+
+/// Quote `arg` for a POSIX shell if it needs to be.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Whether `arg` is `flag` with its value in the same argument, as in
+/// `--flag=value`, or `-fvalue` for short flags.
+fn with_value(arg: &str, flag: &str) -> bool {
+    match flag.starts_with("--") {
+        true => arg.starts_with(&format!("{flag}=")),
+        false => arg.len() > flag.len() && arg.starts_with(flag),
+    }
+}
+
+/// Take out any of `flags` from `args` along with its value, given either
+/// as `--flag value`, as `--flag=value` or as `-fvalue`.
+pub(crate) fn remove_option(args: &mut Vec<String>, flags: &[&str]) {
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if flags.contains(&arg.as_str()) {
+            args.drain(i..(i + 2).min(args.len()));
+        } else if flags.iter().any(|flag| with_value(arg, flag)) {
+            args.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a.flv"), "a.flv");
+        assert_eq!(quote("my video.flv"), "'my video.flv'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn test_shell_line_masks_secrets() {
+        let args = [
+            "koishi",
+            "-k",
+            "key",
+            "video",
+            "restrict",
+            "--password=pw",
+            "a b",
+        ];
+        assert_eq!(
+            shell_line(args.map(String::from).into()),
+            "koishi -k <auth-key> video restrict --password=<password> 'a b'"
+        );
+
+        let args = ["koishi", "-kkey", "video", "restrict", "-ppw", "u"];
+        assert_eq!(
+            shell_line(args.map(String::from).into()),
+            "koishi -k <auth-key> video restrict -p <password> u"
+        );
+    }
+
+    #[test]
+    fn test_remove_option() {
+        let mut args: Vec<String> = ["upload", "-s", "100", "--part-size=5", "u", "a.flv"]
+            .map(String::from)
+            .into();
+        remove_option(&mut args, &["-s", "--part-size"]);
+        assert_eq!(args, ["upload", "u", "a.flv"]);
+
+        let mut args: Vec<String> = ["upload", "-s10000000", "--since", "u"]
+            .map(String::from)
+            .into();
+        remove_option(&mut args, &["-s", "--part-size"]);
+        assert_eq!(args, ["upload", "--since", "u"]);
+    }
+}
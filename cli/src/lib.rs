@@ -0,0 +1,14 @@
+pub mod api;
+mod client;
+mod error;
+pub mod helpers;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+#[cfg(feature = "server")]
+pub mod server;
+
+pub use client::{
+    ClientBuildError, DEFAULT_BASE_URL, DEFAULT_READ_TIMEOUT, DEFAULT_USER_AGENT, HttpVersion,
+    KoishiClient, KoishiClientBuilder,
+};
+pub use error::{Error, ErrorKind, Result, ResultExt};
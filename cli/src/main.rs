@@ -1,62 +1,180 @@
 use clap::{Parser, Subcommand};
+use koishi::{
+    KoishiClient,
+    api::RetryPolicy,
+    helpers::ratelimit::{Rate, Schedule},
+};
+use std::{error::Error as _, path::PathBuf, time::Duration};
 
-mod api;
 mod cmd;
-mod global_options;
-mod helpers;
+mod config;
+mod files;
+mod interrupt;
+mod output;
+
+const EXIT_CODES: &str = "\
+Exit codes:
+    0  Success
+    1  Other failure
+    2  Bad input: invalid arguments, config or input files
+    3  Authentication: auth key missing, wrong or not permitted
+    4  Not found: room, video or upload does not exist
+    5  Conflict: target already exists or is in a conflicting state
+    6  Network: server unreachable, timed out or failed transiently
+    7  Storage: object storage or the database rejected the operation
+  130  Interrupted: stopped by Ctrl-C or SIGTERM; progress is kept for resuming";
 
 #[derive(Parser)]
+#[command(after_long_help = EXIT_CODES)]
 pub(crate) struct Cli {
-    #[arg(
-        short = 'u',
-        long,
-        value_name = "URL",
-        default_value = "http://localhost:8788/api/"
-    )]
-    base_url: String,
+    /// Config file to read instead of ~/.config/koishi/config.toml
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Profile of the config file to use
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    #[arg(short = 'u', long, value_name = "URL")]
+    base_url: Option<String>,
 
     #[arg(short = 'k', long, value_name = "KEY")]
     auth_key: Option<String>,
 
+    #[arg(long, value_name = "PATH", conflicts_with = "auth_key")]
+    auth_key_file: Option<PathBuf>,
+
     #[arg(long)]
     dry: bool,
 
+    /// Attempts per API request before giving up on transient errors
+    #[arg(long, value_name = "N", global = true)]
+    api_retry: Option<u32>,
+
+    /// Upload rate limit shared by all transfers, such as 5M; or unlimited
+    #[arg(long, value_name = "RATE", global = true)]
+    limit_rate: Option<Rate>,
+
+    /// Time-of-day windows overriding the rate limit, such as
+    /// "01:00-07:00=unlimited,19:00-23:00=pause"
+    #[arg(long, value_name = "WINDOWS", global = true)]
+    rate_schedule: Option<Schedule>,
+
+    /// How results are printed
+    #[arg(long, value_enum, default_value_t, global = true)]
+    output: output::OutputFormat,
+
+    /// Print each result through a template such as "{uuid}\t{title}"
+    #[arg(long, value_name = "TEMPLATE", global = true)]
+    format: Option<String>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    Config(cmd::config::Args),
     GenId,
     RestrictedHash(cmd::restricted_hash::Args),
     Room(cmd::room::Args),
+    #[cfg(feature = "server")]
+    Serve(Box<cmd::serve::Args>),
     Video(cmd::video::Args),
+    /// Ingest the recordings finished in a directory as they come
+    Watch(cmd::watch::Args),
+    /// Ingest the recordings BililiveRecorder or blrec announce by webhook
+    #[cfg(feature = "server")]
+    WebhookServer(cmd::webhook_server::Args),
+}
+
+fn report(err: &koishi::Error) {
+    eprintln!("error: {err}");
+    let mut source = err.source();
+    while let Some(cause) = source {
+        eprintln!("  caused by: {cause}");
+        source = cause.source();
+    }
 }
 
 fn main() {
-    env_logger::init();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    interrupt::install();
 
-    let cli = Cli::parse();
+    if let Err(e) = run(Cli::parse()) {
+        report(&e);
+        std::process::exit(e.exit_code())
+    }
+}
+
+fn run(cli: Cli) -> koishi::Result<()> {
+    let overrides = config::Overrides {
+        config: cli.config,
+        profile: cli.profile,
+        base_url: cli.base_url,
+        auth_key: cli.auth_key,
+        auth_key_file: cli.auth_key_file,
+        api_retry: cli.api_retry,
+        limit_rate: cli.limit_rate,
+        rate_schedule: cli.rate_schedule,
+    };
+
+    let command = match cli.command {
+        Some(Commands::Config(args)) => return cmd::config::main(overrides, args),
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    let settings = config::Settings::resolve(overrides)?;
+
+    let mut builder = KoishiClient::builder()
+        .base_url(&settings.base_url.value)
+        .auth_key(settings.auth_key.value.clone())
+        .dry(cli.dry);
+    if let Some(attempts) = settings.api_retry.value {
+        builder = builder.retry(RetryPolicy::attempts(attempts));
+    }
+    if let Some(secs) = settings.timeout.value {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    if let Some(version) = settings.http_version.value {
+        builder = builder.http_version(version);
+    }
+    if let Some(user_agent) = &settings.user_agent.value {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(rate) = settings.limit_rate.value {
+        builder = builder.rate_limit(rate);
+    }
+    if let Some(schedule) = &settings.rate_schedule.value {
+        builder = builder.rate_schedule(schedule.clone());
+    }
+    if let Some(secs) = settings.read_timeout.value {
+        builder = builder.read_timeout(Duration::from_secs(secs));
+    }
+    builder = builder
+        .connect_timeout(settings.connect_timeout.value.map(Duration::from_secs))
+        .pool_max_idle_per_host(settings.pool_size.value);
+    let client = builder
+        .build()
+        .map_err(|e| koishi::Error::validation(e.to_string()))?;
 
-    let base_url = if cli.base_url.ends_with('/') {
-        cli.base_url
-    } else {
-        let mut base_url = cli.base_url;
-        base_url.push('/');
-        base_url
+    let ctx = cmd::Context {
+        client,
+        settings,
+        output: output::Output::new(cli.output, cli.format),
     };
 
-    global_options::BASE_URL.set(base_url).unwrap();
-    global_options::AUTH_KEY.set(cli.auth_key).unwrap();
-    global_options::DRY.set(cli.dry).unwrap();
-
-    match cli.command {
-        Some(command) => match command {
-            Commands::GenId => cmd::gen_id::main(),
-            Commands::RestrictedHash(args) => cmd::restricted_hash::main(args),
-            Commands::Room(args) => cmd::room::main(args),
-            Commands::Video(args) => cmd::video::main(args),
-        },
-        None => {}
+    match command {
+        Commands::Config(_) => unreachable!(),
+        Commands::GenId => cmd::gen_id::main(),
+        Commands::RestrictedHash(args) => cmd::restricted_hash::main(args),
+        Commands::Room(args) => cmd::room::main(&ctx, args),
+        #[cfg(feature = "server")]
+        Commands::Serve(args) => cmd::serve::main(&ctx, *args),
+        Commands::Video(args) => cmd::video::main(&ctx, args),
+        Commands::Watch(args) => cmd::watch::main(&ctx, args),
+        #[cfg(feature = "server")]
+        Commands::WebhookServer(args) => cmd::webhook_server::main(&ctx, args),
     }
 }
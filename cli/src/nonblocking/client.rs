@@ -0,0 +1,69 @@
+use reqwest::{Client, Url};
+use std::time::Duration;
+
+use crate::api::RetryPolicy;
+
+use super::s3;
+
+/// Async flavour of [`crate::KoishiClient`].
+///
+/// Created through [`crate::KoishiClientBuilder::build_async`].
+#[derive(Clone)]
+pub struct KoishiClient {
+    base_url: Url,
+    auth_key: Option<String>,
+    dry: bool,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    http: Client,
+}
+
+impl KoishiClient {
+    pub(crate) fn from_parts(
+        base_url: Url,
+        auth_key: Option<String>,
+        dry: bool,
+        retry: RetryPolicy,
+        timeout: Option<Duration>,
+        http: Client,
+    ) -> Self {
+        Self {
+            base_url,
+            auth_key,
+            dry,
+            retry,
+            timeout,
+            http,
+        }
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub fn auth_key(&self) -> Option<&str> {
+        self.auth_key.as_deref()
+    }
+
+    pub fn is_dry(&self) -> bool {
+        self.dry
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Total timeout of a single API request.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn http(&self) -> &Client {
+        &self.http
+    }
+
+    /// Uploader to object storage sharing this client's connection pool.
+    pub fn uploader(&self) -> s3::Uploader {
+        s3::Uploader::from_client(self.http.clone())
+    }
+}
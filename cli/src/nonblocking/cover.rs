@@ -0,0 +1,56 @@
+use std::{path::Path, time::Duration};
+
+use crate::api::{
+    self,
+    cover::{Req, ResCover, UploadCoverResult},
+};
+
+use super::{KoishiClient, request::send_api_replayable};
+
+type Result<T> = crate::Result<T>;
+
+async fn upload_url(client: &KoishiClient, hash: String) -> api::Result<ResCover> {
+    let req_body = Req { hash };
+
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok(ResCover {
+            exists: true,
+            url: None,
+        });
+    }
+
+    send_api_replayable(client, client.post("cover").json(&req_body)).await
+}
+
+pub async fn upload_cover(client: &KoishiClient, content: Vec<u8>) -> Result<UploadCoverResult> {
+    let hash = api::cover::hash(content.as_slice());
+
+    let res_url = upload_url(client, hash.clone()).await?;
+    let ret = UploadCoverResult {
+        exists: res_url.exists,
+        hash,
+    };
+    if res_url.exists {
+        return Ok(ret);
+    }
+
+    client
+        .uploader()
+        .timeout(Duration::from_secs(300))
+        .url(res_url.url.unwrap())
+        .mimetype("image/jpeg")
+        .body(content)
+        .upload()
+        .await?;
+
+    Ok(ret)
+}
+
+pub async fn upload_cover_from_file<P: AsRef<Path>>(
+    client: &KoishiClient,
+    path: P,
+) -> Result<UploadCoverResult> {
+    let content = tokio::fs::read(path).await?;
+    upload_cover(client, content).await
+}
@@ -0,0 +1,19 @@
+//! Async counterparts of [`crate::api`] and the S3 upload helpers.
+//!
+//! Only available with the `async` feature. Request and response types are
+//! the ones from [`crate::api`]; only the transport differs.
+//!
+//! Uploads made through it are neither subject to the rate limit of
+//! [`crate::KoishiClientBuilder::rate_limit`] nor watched for stalls; only
+//! the read timeout of [`crate::KoishiClientBuilder::read_timeout`] keeps
+//! them from hanging.
+
+mod client;
+pub mod cover;
+mod request;
+pub mod room;
+pub mod s3;
+pub mod transfer;
+pub mod video;
+
+pub use client::KoishiClient;
@@ -0,0 +1,115 @@
+use reqwest::{Method, RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+
+use crate::api::{APIError, Result, request::APIRawResult};
+
+use super::KoishiClient;
+
+impl KoishiClient {
+    fn api_url<P: AsRef<str>>(&self, path: P) -> Url {
+        self.base_url().join(path.as_ref()).unwrap()
+    }
+
+    fn request<P: AsRef<str>>(&self, method: Method, path: P) -> RequestBuilder {
+        let rb = self.http().request(method, self.api_url(path));
+        match self.timeout() {
+            Some(timeout) => rb.timeout(timeout),
+            None => rb,
+        }
+    }
+
+    pub(super) fn get<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::GET, path)
+    }
+
+    pub(super) fn post<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::POST, path).api_auth(self)
+    }
+
+    pub(super) fn put<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::PUT, path).api_auth(self)
+    }
+
+    pub(super) fn delete<P: AsRef<str>>(&self, path: P) -> RequestBuilder {
+        self.request(Method::DELETE, path).api_auth(self)
+    }
+}
+
+pub(super) trait APIRequestBuilder {
+    fn api_auth(self, client: &KoishiClient) -> Self;
+    fn limit_offset(self, limit: u64, offset: u64) -> Self;
+}
+
+impl APIRequestBuilder for RequestBuilder {
+    fn api_auth(self, client: &KoishiClient) -> Self {
+        match client.auth_key() {
+            Some(token) => self.bearer_auth(token),
+            None => self,
+        }
+    }
+
+    fn limit_offset(self, limit: u64, offset: u64) -> Self {
+        self.query(&[("limit", limit.to_string()), ("offset", offset.to_string())])
+    }
+}
+
+pub(super) async fn api_result<T: DeserializeOwned + 'static>(res: Response) -> Result<T> {
+    let status_error = res.error_for_status_ref().err();
+    match res.json::<APIRawResult<T>>().await {
+        Ok(raw) => raw.into_result(),
+        Err(e) => Err(status_error.unwrap_or(e).into()),
+    }
+}
+
+/// Send the request and parse the result, retrying according to the
+/// client's [`crate::api::RetryPolicy`] if its method is idempotent.
+pub(super) async fn send_api<T: DeserializeOwned + 'static>(
+    client: &KoishiClient,
+    rb: RequestBuilder,
+) -> Result<T> {
+    send(client, rb, false).await
+}
+
+/// Like [`send_api`], for a `POST` that does no more when sent twice, like
+/// one that only signs URLs.
+pub(super) async fn send_api_replayable<T: DeserializeOwned + 'static>(
+    client: &KoishiClient,
+    rb: RequestBuilder,
+) -> Result<T> {
+    send(client, rb, true).await
+}
+
+async fn send<T: DeserializeOwned + 'static>(
+    client: &KoishiClient,
+    rb: RequestBuilder,
+    replayable: bool,
+) -> Result<T> {
+    let policy = client.retry_policy();
+    let mut attempt = 1;
+    loop {
+        let Some(req) = rb.try_clone() else {
+            return api_result(rb.send().await?).await;
+        };
+        let req = req.build()?;
+        let replayable = replayable || req.method().is_idempotent();
+
+        let res = match client.http().execute(req).await {
+            Ok(res) => api_result(res).await,
+            Err(e) => Err(APIError::from(e)),
+        };
+        match res {
+            Err(e) if policy.should_retry(&e, attempt, replayable) => {
+                let delay = policy.delay(attempt);
+                log::warn!(
+                    "{e}; retrying in {:.1}s (attempt {}/{})",
+                    delay.as_secs_f64(),
+                    attempt + 1,
+                    policy.max_attempts
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use futures_util::{Stream, TryStreamExt, stream};
+
+use crate::api::{
+    APIError, MAX_PAGE_SIZE, Result,
+    room::{Room, RoomListVideoEntry},
+};
+
+use super::{
+    KoishiClient,
+    request::{APIRequestBuilder, send_api},
+};
+
+pub async fn create(client: &KoishiClient, room: Room) -> Result<()> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok(());
+    }
+
+    send_api(client, client.post(format!("room/{}", room.id)).json(&room)).await
+}
+
+pub async fn get(client: &KoishiClient, id: u64) -> Result<Room> {
+    send_api(client, client.get(format!("room/{id}"))).await
+}
+
+pub async fn list(client: &KoishiClient, limit: u64, offset: u64) -> Result<Vec<Room>> {
+    send_api(client, client.get("room").limit_offset(limit, offset)).await
+}
+
+pub async fn list_videos(
+    client: &KoishiClient,
+    id: u64,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<RoomListVideoEntry>> {
+    send_api(
+        client,
+        client
+            .get(format!("room/{id}/video"))
+            .limit_offset(limit, offset),
+    )
+    .await
+}
+
+/// Stream entries of a paginated listing, fetching the next page once the
+/// current one has been consumed and stopping after a short page.
+fn paginate<'a, T, F, Fut>(fetch: F) -> impl Stream<Item = Result<T>> + 'a
+where
+    T: 'a,
+    F: Fn(u64, u64) -> Fut + 'a,
+    Fut: Future<Output = Result<Vec<T>>> + 'a,
+{
+    stream::try_unfold(Some(0), move |offset| {
+        let page = offset.map(|offset| (offset, fetch(MAX_PAGE_SIZE, offset)));
+        async move {
+            let Some((offset, page)) = page else {
+                return Ok::<_, APIError>(None);
+            };
+            let page = page.await?;
+            let len = page.len() as u64;
+            let next = (len == MAX_PAGE_SIZE).then_some(offset + len);
+            Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+        }
+    })
+    .try_flatten()
+}
+
+/// Stream every room, fetching pages as needed.
+pub fn iter(client: &KoishiClient) -> impl Stream<Item = Result<Room>> + '_ {
+    paginate(move |limit, offset| list(client, limit, offset))
+}
+
+/// Stream every video of room `id`, fetching pages as needed.
+pub fn iter_videos(
+    client: &KoishiClient,
+    id: u64,
+) -> impl Stream<Item = Result<RoomListVideoEntry>> + '_ {
+    paginate(move |limit, offset| list_videos(client, id, limit, offset))
+}
@@ -0,0 +1,120 @@
+use reqwest::{Body, Client, IntoUrl, RequestBuilder, Response, Result as ReqResult};
+use std::time::Duration;
+
+use crate::helpers::{
+    multipart,
+    s3::{S3Error, S3UploaderError, UploadResult},
+};
+
+/// Async flavour of [`crate::helpers::s3::Uploader`].
+#[derive(Clone)]
+pub struct Uploader {
+    client: Client,
+    timeout: Option<Duration>,
+}
+
+impl Uploader {
+    pub fn new() -> ReqResult<Self> {
+        Self::with_timeout(None)
+    }
+
+    pub fn with_timeout<T: Into<Option<Duration>>>(timeout: T) -> ReqResult<Self> {
+        let mut builder = Client::builder();
+        if let Some(timeout) = timeout.into() {
+            builder = builder.timeout(timeout);
+        }
+        Ok(Self::from_client(builder.build()?))
+    }
+
+    pub fn from_client(client: Client) -> Self {
+        Self {
+            client,
+            timeout: None,
+        }
+    }
+
+    /// Total timeout of each upload made through this uploader.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn url<U: IntoUrl>(&self, url: U) -> UploadTaskBuilder {
+        let mut rb = self.client.put(url);
+        if let Some(timeout) = self.timeout {
+            rb = rb.timeout(timeout);
+        }
+        UploadTaskBuilder {
+            rb,
+            expected_etag: None,
+        }
+    }
+}
+
+pub struct UploadTaskBuilder {
+    rb: RequestBuilder,
+    expected_etag: Option<String>,
+}
+
+impl UploadTaskBuilder {
+    fn map_inner<F>(self, f: F) -> Self
+    where
+        F: FnOnce(RequestBuilder) -> RequestBuilder,
+    {
+        let rb = f(self.rb);
+        Self { rb, ..self }
+    }
+
+    pub fn mimetype<S: AsRef<str>>(self, value: S) -> Self {
+        self.map_inner(|rb| rb.header("Content-Type", value.as_ref()))
+    }
+
+    pub fn copy<S: AsRef<str>>(self, source: S) -> Self {
+        self.map_inner(|rb| rb.header("x-amz-copy-source", source.as_ref()))
+    }
+
+    pub fn copy_range_from_to(self, from: u64, to: u64) -> Self {
+        self.map_inner(|rb| rb.header("x-amz-copy-source-range", format!("bytes={}-{}", from, to)))
+    }
+
+    pub fn body<B: Into<Body>>(self, body: B) -> Self {
+        self.map_inner(|rb| rb.body(body))
+    }
+
+    /// Have storage check the body against its MD5 `digest`, and check the
+    /// ETag it answers with in turn.
+    pub fn content_md5(self, digest: &[u8]) -> Self {
+        let mut task =
+            self.map_inner(|rb| rb.header("Content-MD5", multipart::content_md5(digest)));
+        task.expected_etag = Some(multipart::part_etag(digest));
+        task
+    }
+
+    async fn send(self) -> Result<Response, S3UploaderError> {
+        let res = self.rb.send().await?;
+        if !res.status().is_success() {
+            let status = res.status().as_u16();
+            let xml = res.text().await?;
+            Err(S3Error { status, xml }.into())
+        } else {
+            Ok(res)
+        }
+    }
+
+    pub async fn upload(self) -> Result<UploadResult, S3UploaderError> {
+        let expected = self.expected_etag.clone();
+        let res = self.send().await?;
+        let etag_header = res
+            .headers()
+            .get("ETag")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let result = if let Some(etag) = etag_header {
+            UploadResult { etag }
+        } else {
+            let xml = res.text().await?;
+            UploadResult::from_copy_part_result(&xml)?
+        };
+        result.check(expected)
+    }
+}
@@ -0,0 +1,250 @@
+//! Async versions of the multipart upload and copy flows behind the
+//! `video upload` and `video restrict` commands.
+
+use futures_util::{StreamExt, TryStreamExt, stream};
+use md5::{Digest, Md5};
+use reqwest::Body;
+use std::{
+    cmp::min,
+    io::{self, SeekFrom},
+    path::Path,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, Take},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::api::{RetryPolicy, video::Video};
+use crate::helpers::multipart::{self, PartDigest};
+use crate::{Error, ErrorKind, Result};
+
+use super::{KoishiClient, s3, video};
+
+pub struct TransferOptions {
+    /// Parts transferred at the same time.
+    pub concurrency: usize,
+    /// Attempts made for each part before giving up.
+    pub retry: u64,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            retry: 10,
+        }
+    }
+}
+
+pub struct UploadOutcome {
+    pub upload_id: String,
+    pub video: Video,
+}
+
+/// Make `attempt` until it succeeds, fails in a way that would only repeat,
+/// or has been made `attempts` times, waiting between attempts as `policy`
+/// says.
+async fn retry<T, F>(
+    policy: &RetryPolicy,
+    attempts: u64,
+    mut attempt: impl FnMut() -> F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let mut n = 1;
+    loop {
+        match attempt().await {
+            Err(e) if e.is_retriable() && n < attempts => {
+                tokio::time::sleep(policy.delay(n as u32)).await;
+                n += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// The `size` bytes of the file at `path` starting at `offset`.
+async fn open_part(path: &Path, offset: u64, size: u64) -> io::Result<Take<File>> {
+    let mut f = File::open(path).await?;
+    f.seek(SeekFrom::Start(offset)).await?;
+    Ok(f.take(size))
+}
+
+async fn digest_part(path: &Path, offset: u64, size: u64) -> io::Result<PartDigest> {
+    let mut part = open_part(path, offset, size).await?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut read = 0;
+    loop {
+        let n = part.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    if read != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} is shorter than expected", path.display()),
+        ));
+    }
+    Ok(hasher.finalize().into())
+}
+
+async fn upload_part(
+    uploader: &s3::Uploader,
+    policy: &RetryPolicy,
+    path: &Path,
+    url: &str,
+    offset: u64,
+    size: u64,
+    attempts: u64,
+) -> Result<String> {
+    // Hashed up front so that S3 can refuse a part corrupted on the way.
+    let digest = digest_part(path, offset, size).await?;
+
+    // Streamed from the file anew for each attempt rather than held in memory.
+    retry(policy, attempts, || async {
+        let part = open_part(path, offset, size).await?;
+        let res = uploader
+            .url(url)
+            .mimetype("video/mp4")
+            .content_md5(&digest)
+            .body(Body::wrap_stream(ReaderStream::new(part)))
+            .upload()
+            .await?;
+        Ok(res.etag)
+    })
+    .await
+}
+
+/// Upload a local file as the video object of `uuid` with a multipart upload.
+///
+/// `part_size` is checked against the storage limits before the upload is
+/// started; [`multipart::choose_part_size`] picks one that fits.
+pub async fn upload_file(
+    client: &KoishiClient,
+    uuid: &str,
+    path: &Path,
+    part_size: u64,
+    hash: Option<String>,
+    options: &TransferOptions,
+) -> Result<UploadOutcome> {
+    let f_size = tokio::fs::metadata(path).await?.len();
+    multipart::validate_part_size(f_size, part_size)?;
+
+    let upload_start = video::upload_start(client, uuid, f_size, part_size, hash.clone()).await?;
+    let uploader = client.uploader();
+
+    let etags: Vec<String> = stream::iter(upload_start.urls.iter().enumerate())
+        .map(|(i, url)| {
+            let offset = (i as u64) * part_size;
+            let size = min(part_size, f_size - offset);
+            upload_part(
+                &uploader,
+                client.retry_policy(),
+                path,
+                url,
+                offset,
+                size,
+                options.retry,
+            )
+        })
+        .buffered(options.concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    video::upload_finish(client, uuid, upload_start.upload_id.clone(), etags, hash).await?;
+
+    Ok(UploadOutcome {
+        upload_id: upload_start.upload_id,
+        video: upload_start.video,
+    })
+}
+
+async fn copy_part(
+    uploader: &s3::Uploader,
+    policy: &RetryPolicy,
+    source: &str,
+    url: &str,
+    range_from: u64,
+    range_to: u64,
+    attempts: u64,
+) -> Result<String> {
+    retry(policy, attempts, || async {
+        let res = uploader
+            .url(url)
+            .mimetype("video/mp4")
+            .copy(source)
+            .copy_range_from_to(range_from, range_to)
+            .upload()
+            .await?;
+        Ok(res.etag)
+    })
+    .await
+}
+
+/// Change the restricted state of a video, moving its object to the new key
+/// with a multipart copy if it was already uploaded.
+///
+/// Returns whether an object had to be copied.
+pub async fn restrict(
+    client: &KoishiClient,
+    uuid: &str,
+    restricted: bool,
+    hash: &str,
+    part_size: u64,
+    options: &TransferOptions,
+) -> Result<bool> {
+    // Refused before the restricted state changes, which would leave the
+    // video restricted at the old key.
+    match video::object(client, uuid, Some(hash))
+        .await
+        .map_err(Error::from)
+    {
+        Ok(object) => multipart::validate_part_size(object.size, part_size)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let ret = video::set_restricted(client, uuid, restricted, hash).await?;
+    let Some(source) = ret.copy_source else {
+        return Ok(false);
+    };
+
+    let hash = if restricted {
+        Some(hash.to_string())
+    } else {
+        None
+    };
+
+    let copy_start =
+        video::restricted_copy_start(client, uuid, &source, hash.clone(), part_size).await?;
+    let uploader = client.uploader();
+
+    let etags: Vec<String> = stream::iter(copy_start.urls.iter().enumerate())
+        .map(|(i, url)| {
+            let range_from = (i as u64) * part_size;
+            let range_to = min(range_from + part_size, copy_start.length) - 1;
+            copy_part(
+                &uploader,
+                client.retry_policy(),
+                &source,
+                url,
+                range_from,
+                range_to,
+                options.retry,
+            )
+        })
+        .buffered(options.concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    video::restricted_copy_finish(client, uuid, &source, hash, &copy_start.upload_id, etags)
+        .await?;
+
+    Ok(true)
+}
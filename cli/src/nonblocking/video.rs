@@ -0,0 +1,333 @@
+use std::{path::Path, time::Duration};
+
+use crate::api::{
+    Result,
+    video::{
+        MetadataUploadResponse, ReqPostRestricted, ReqSetRestricted, ReqUploadAbort,
+        ReqUploadFinish, ReqUploadStart, ReqUploadUrls, RestrictedCopyStartResponse, Video,
+        VideoCreateInfo, VideoObject, VideoSetRestrictedResponse, VideoUpdateInfo,
+        VideoUploadParts, VideoUploadStartResponse, VideoUploadUrlsResponse,
+    },
+};
+use crate::helpers::se::BoolAsInt;
+
+use super::{
+    KoishiClient,
+    request::{send_api, send_api_replayable},
+};
+
+pub async fn get(client: &KoishiClient, uuid: &str) -> Result<Video> {
+    send_api(client, client.get(format!("video/{uuid}"))).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn create(
+    client: &KoishiClient,
+    uuid: &str,
+    title: String,
+    cover: Option<String>,
+    stream_time: i64,
+    record_time: i64,
+    room: u64,
+    restricted_hash: Option<String>,
+) -> Result<()> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok(());
+    }
+
+    let restricted: BoolAsInt = restricted_hash.is_some().into();
+
+    let video = VideoCreateInfo {
+        title,
+        cover,
+        room,
+        stream_time,
+        record_time,
+        restricted,
+        restricted_hash,
+    };
+
+    send_api(client, client.post(format!("video/{uuid}")).json(&video)).await
+}
+
+pub async fn update(
+    client: &KoishiClient,
+    uuid: &str,
+    title: Option<String>,
+    cover: Option<String>,
+    stream_time: Option<i64>,
+    record_time: Option<i64>,
+) -> Result<()> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok(());
+    }
+
+    let video = VideoUpdateInfo {
+        title,
+        cover,
+        stream_time,
+        record_time,
+    };
+
+    send_api(client, client.put(format!("video/{uuid}")).json(&video)).await
+}
+
+/// Remove the video with its object and metadata; restricted videos need
+/// their `hash`.
+pub async fn delete(client: &KoishiClient, uuid: &str, hash: Option<&str>) -> Result<()> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok(());
+    }
+
+    let mut rb = client.delete(format!("video/{uuid}"));
+    if let Some(hash) = hash {
+        rb = rb.query(&[("hash", hash)]);
+    }
+    send_api(client, rb).await
+}
+
+async fn metadata_upload_url(client: &KoishiClient, uuid: &str) -> Result<String> {
+    if client.is_dry() {
+        log::warn!("Skipping request due to being dry run");
+        return Ok("".into());
+    }
+
+    let res: MetadataUploadResponse =
+        send_api_replayable(client, client.post(format!("video/{uuid}/upload_metadata"))).await?;
+
+    Ok(res.url)
+}
+
+pub async fn upload_metadata<P: AsRef<Path>>(
+    client: &KoishiClient,
+    uuid: &str,
+    path: P,
+) -> crate::Result<()> {
+    let url = metadata_upload_url(client, uuid).await?;
+
+    if client.is_dry() {
+        return Ok(());
+    }
+
+    let content = tokio::fs::read(path).await?;
+    client
+        .uploader()
+        .timeout(Duration::from_secs(300))
+        .url(url)
+        .body(content)
+        .upload()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn upload_start(
+    client: &KoishiClient,
+    uuid: &str,
+    file_size: u64,
+    part_size: u64,
+    hash: Option<String>,
+) -> Result<VideoUploadStartResponse> {
+    let req_body = ReqUploadStart {
+        size: file_size,
+        part_size,
+        upload_id: None,
+        restricted_hash: hash,
+    };
+    send_api(
+        client,
+        client
+            .post(format!("video/{uuid}/upload_start"))
+            .json(&req_body),
+    )
+    .await
+}
+
+/// Get new part URLs for the unfinished upload `upload_id`.
+pub async fn upload_resume(
+    client: &KoishiClient,
+    uuid: &str,
+    file_size: u64,
+    part_size: u64,
+    upload_id: String,
+    hash: Option<String>,
+) -> Result<VideoUploadStartResponse> {
+    let req_body = ReqUploadStart {
+        size: file_size,
+        part_size,
+        upload_id: Some(upload_id),
+        restricted_hash: hash,
+    };
+    send_api(
+        client,
+        client
+            .post(format!("video/{uuid}/upload_start"))
+            .json(&req_body),
+    )
+    .await
+}
+
+pub async fn upload_finish(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: String,
+    etags: Vec<String>,
+    hash: Option<String>,
+) -> Result<()> {
+    let req_body = ReqUploadFinish {
+        upload_id,
+        etags,
+        restricted_hash: hash,
+    };
+
+    send_api(
+        client,
+        client
+            .post(format!("video/{uuid}/upload_finish"))
+            .json(&req_body),
+    )
+    .await
+}
+
+/// Discard an unfinished upload along with the parts stored so far.
+pub async fn upload_abort(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: String,
+    hash: Option<String>,
+) -> Result<()> {
+    let req_body = ReqUploadAbort {
+        upload_id,
+        restricted_hash: hash,
+    };
+
+    send_api(
+        client,
+        client
+            .post(format!("video/{uuid}/upload_abort"))
+            .json(&req_body),
+    )
+    .await
+}
+
+/// Sign the URLs of parts `part_numbers` of `upload_id` again.
+pub async fn upload_urls(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: String,
+    part_numbers: Vec<u64>,
+    hash: Option<String>,
+) -> Result<VideoUploadUrlsResponse> {
+    let req_body = ReqUploadUrls {
+        upload_id,
+        part_numbers,
+        restricted_hash: hash,
+    };
+
+    send_api_replayable(
+        client,
+        client
+            .post(format!("video/{uuid}/upload_urls"))
+            .json(&req_body),
+    )
+    .await
+}
+
+/// List the parts storage has received for `upload_id`, or for the latest
+/// unfinished upload of the video if not given.
+pub async fn upload_parts(
+    client: &KoishiClient,
+    uuid: &str,
+    upload_id: Option<&str>,
+    hash: Option<&str>,
+) -> Result<VideoUploadParts> {
+    let mut rb = client.get(format!("video/{uuid}/upload_parts"));
+    if let Some(upload_id) = upload_id {
+        rb = rb.query(&[("upload_id", upload_id)]);
+    }
+    if let Some(hash) = hash {
+        rb = rb.query(&[("hash", hash)]);
+    }
+    send_api(client, rb).await
+}
+
+/// Look up the stored video object; restricted videos need their `hash`.
+pub async fn object(client: &KoishiClient, uuid: &str, hash: Option<&str>) -> Result<VideoObject> {
+    let mut rb = client.get(format!("video/{uuid}/object"));
+    if let Some(hash) = hash {
+        rb = rb.query(&[("hash", hash)]);
+    }
+    send_api(client, rb).await
+}
+
+pub async fn set_restricted(
+    client: &KoishiClient,
+    uuid: &str,
+    restricted: bool,
+    hash: &str,
+) -> Result<VideoSetRestrictedResponse> {
+    let req_body = ReqSetRestricted {
+        restricted: restricted as u64,
+        hash: hash.to_string(),
+    };
+
+    send_api(
+        client,
+        client
+            .put(format!("video/{uuid}/restricted"))
+            .json(&req_body),
+    )
+    .await
+}
+
+pub async fn restricted_copy_start(
+    client: &KoishiClient,
+    uuid: &str,
+    copy_source: &str,
+    hash: Option<String>,
+    part_size: u64,
+) -> Result<RestrictedCopyStartResponse> {
+    let copy_source = copy_source.to_string();
+    let req_body = ReqPostRestricted::CopyStart {
+        copy_source,
+        hash,
+        part_size,
+    };
+
+    send_api(
+        client,
+        client
+            .post(format!("video/{uuid}/restricted"))
+            .json(&req_body),
+    )
+    .await
+}
+
+pub async fn restricted_copy_finish(
+    client: &KoishiClient,
+    uuid: &str,
+    copy_source: &str,
+    hash: Option<String>,
+    upload_id: &str,
+    etags: Vec<String>,
+) -> Result<()> {
+    let copy_source = copy_source.to_string();
+    let upload_id = upload_id.to_string();
+    let req_body = ReqPostRestricted::CopyFinish {
+        copy_source,
+        hash,
+        etags,
+        upload_id,
+    };
+
+    send_api(
+        client,
+        client
+            .post(format!("video/{uuid}/restricted"))
+            .json(&req_body),
+    )
+    .await
+}
@@ -0,0 +1,292 @@
+use chrono::{DateTime, SecondsFormat};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{fmt::Display, io};
+use tabled::{Table, Tabled, settings::Style};
+
+use koishi::{
+    Error, Result,
+    api::{
+        room::{Room, RoomListVideoEntry},
+        video::Video,
+    },
+};
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Jsonl,
+    Csv,
+    Yaml,
+}
+
+/// Anything a command can print as its result.
+pub(crate) trait Record: Serialize + Tabled {
+    /// Fields holding millisecond timestamps. They stay raw numbers in JSON
+    /// and YAML, and are rendered as ISO-8601 where the output is text.
+    const TIMESTAMP_FIELDS: &'static [&'static str] = &[];
+}
+
+impl Record for Room {}
+
+impl Record for RoomListVideoEntry {
+    const TIMESTAMP_FIELDS: &'static [&'static str] = &["stream_time"];
+}
+
+impl Record for Video {
+    const TIMESTAMP_FIELDS: &'static [&'static str] = &["stream_time", "record_time"];
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Output {
+    format: OutputFormat,
+    template: Option<String>,
+}
+
+fn iso8601(millis: i64) -> String {
+    DateTime::from_timestamp_millis(millis)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_else(|| millis.to_string())
+}
+
+/// Flatten a record into field name and text value pairs.
+fn text_fields<T: Record>(record: &T) -> Map<String, Value> {
+    let Value::Object(mut fields) = serde_json::to_value(record).expect("Failed to serialize")
+    else {
+        panic!("Records must serialize to objects");
+    };
+
+    for name in T::TIMESTAMP_FIELDS {
+        if let Some(value) = fields.get_mut(*name)
+            && let Some(millis) = value.as_i64()
+        {
+            *value = Value::String(iso8601(millis));
+        }
+    }
+    fields
+}
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Expand `{field}` placeholders in `template`, along with `\t`, `\n` and
+/// `{{`/`}}` escapes.
+fn render_template(template: &str, fields: &Map<String, Value>) -> Result<String> {
+    let mut ret = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                ret.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                ret.push('}');
+            }
+            '{' => {
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let value = fields.get(&name).ok_or_else(|| {
+                    let known: Vec<&str> = fields.keys().map(|k| k.as_str()).collect();
+                    Error::validation(format!(
+                        "Unknown field {{{name}}} in format; available: {}",
+                        known.join(", ")
+                    ))
+                })?;
+                ret.push_str(&text_value(value));
+            }
+            '\\' if chars.peek() == Some(&'t') => {
+                chars.next();
+                ret.push('\t');
+            }
+            '\\' if chars.peek() == Some(&'n') => {
+                chars.next();
+                ret.push('\n');
+            }
+            c => ret.push(c),
+        }
+    }
+    Ok(ret)
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, template: Option<String>) -> Self {
+        Self { format, template }
+    }
+
+    /// Whether results go out in a form meant for humans.
+    pub fn is_human(&self) -> bool {
+        self.format == OutputFormat::Table && self.template.is_none()
+    }
+
+    /// Print a progress or status message. Those go to stderr whenever
+    /// stdout carries machine-readable results.
+    pub fn info<D: Display>(&self, msg: D) {
+        if self.is_human() {
+            println!("{msg}");
+        } else {
+            eprintln!("{msg}");
+        }
+    }
+
+    fn template_line<T: Record>(&self, template: &str, record: &T) -> Result<()> {
+        let line = render_template(template, &text_fields(record))?;
+        println!("{line}");
+        Ok(())
+    }
+
+    /// Print a list of records. `table` may adjust the table before it is
+    /// printed, e.g. to drop columns.
+    pub fn list<T, I, F>(&self, records: I, table: F) -> Result<()>
+    where
+        T: Record,
+        I: IntoIterator<Item = T>,
+        F: FnOnce(&mut Table),
+    {
+        if let Some(template) = self.template.as_ref() {
+            for record in records {
+                self.template_line(template, &record)?;
+            }
+            return Ok(());
+        }
+
+        match self.format {
+            OutputFormat::Table => {
+                let mut t = Table::new(records);
+                t.with(Style::modern());
+                table(&mut t);
+                println!("{t}");
+            }
+            OutputFormat::Json => {
+                let records: Vec<T> = records.into_iter().collect();
+                println!("{}", serde_json::to_string_pretty(&records).unwrap());
+            }
+            OutputFormat::Jsonl => {
+                for record in records {
+                    println!("{}", serde_json::to_string(&record).unwrap());
+                }
+            }
+            OutputFormat::Csv => {
+                let mut w = csv::Writer::from_writer(io::stdout());
+                let mut header_written = false;
+                for record in records {
+                    let fields = text_fields(&record);
+                    if !header_written {
+                        w.write_record(fields.keys()).map_err(io::Error::from)?;
+                        header_written = true;
+                    }
+                    w.write_record(fields.values().map(text_value))
+                        .map_err(io::Error::from)?;
+                    w.flush()?;
+                }
+            }
+            OutputFormat::Yaml => {
+                let records: Vec<T> = records.into_iter().collect();
+                print!("{}", serde_yaml::to_string(&records).unwrap());
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Output::list`], for records that may fail to arrive, such as
+    /// pages fetched along the way. Stops at the first error and returns it
+    /// once what came before has been printed.
+    pub fn try_list<T, E, I, F>(&self, records: I, table: F) -> Result<()>
+    where
+        T: Record,
+        E: Into<Error>,
+        I: IntoIterator<Item = std::result::Result<T, E>>,
+        F: FnOnce(&mut Table),
+    {
+        let mut error = None;
+        let records = records
+            .into_iter()
+            .map_while(|res| res.map_err(|e| error = Some(e)).ok());
+        self.list(records, table)?;
+
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Print a single record; JSON and YAML get an object instead of a
+    /// one-element list.
+    pub fn one<T, F>(&self, record: T, table: F) -> Result<()>
+    where
+        T: Record,
+        F: FnOnce(&mut Table),
+    {
+        match (self.template.is_some(), self.format) {
+            (false, OutputFormat::Json) => {
+                println!("{}", serde_json::to_string_pretty(&record).unwrap());
+                Ok(())
+            }
+            (false, OutputFormat::Yaml) => {
+                print!("{}", serde_yaml::to_string(&record).unwrap());
+                Ok(())
+            }
+            _ => self.list([record], table),
+        }
+    }
+
+    /// Report the result of a command that changed something. Human output
+    /// keeps the usual message; other formats get the record itself.
+    pub fn result<T: Record, D: Display>(&self, record: T, message: D) -> Result<()> {
+        if self.is_human() {
+            println!("{message}");
+            Ok(())
+        } else {
+            self.one(record, |_| {})
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use koishi::helpers::se::BoolAsInt;
+
+    fn entry() -> RoomListVideoEntry {
+        RoomListVideoEntry {
+            uuid: "0196".into(),
+            title: "Title".into(),
+            cover: None,
+            stream_time: 1_744_000_000_000,
+            restricted: BoolAsInt::from(false),
+        }
+    }
+
+    #[test]
+    fn test_text_fields() {
+        let fields = text_fields(&entry());
+        let keys: Vec<&str> = fields.keys().map(|k| k.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["uuid", "title", "cover", "stream_time", "restricted"]
+        );
+        assert_eq!(fields["stream_time"], "2025-04-07T04:26:40.000Z");
+        assert_eq!(text_value(&fields["cover"]), "");
+        assert_eq!(text_value(&fields["restricted"]), "0");
+    }
+
+    #[test]
+    fn test_render_template() {
+        let fields = text_fields(&entry());
+        assert_eq!(
+            render_template(r"{uuid}\t{title} {{x}}", &fields).unwrap(),
+            "0196\tTitle {x}"
+        );
+        assert!(render_template("{nope}", &fields).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! SQLite database of rooms and videos, laid out like `web/misc/init.sql`
+//! so that a D1 export can be loaded as is.
+
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ResultExt};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS room (
+       id INTEGER PRIMARY KEY NOT NULL,
+       short_id INTEGER,
+       username TEXT NOT NULL,
+       image TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS video (
+       uuid BLOB PRIMARY KEY NOT NULL,
+       title TEXT NOT NULL,
+       cover TEXT,
+       room INTEGER NOT NULL,
+       stream_time INTEGER NOT NULL,
+       record_time INTEGER NOT NULL,
+       len INTEGER,
+       restricted INTEGER NOT NULL DEFAULT 0,
+       restricted_hash TEXT,
+       FOREIGN KEY (room) REFERENCES room(id)
+);
+";
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RoomRow {
+    pub id: i64,
+    pub short_id: Option<i64>,
+    pub username: String,
+    pub image: String,
+}
+
+#[derive(Serialize)]
+pub(super) struct VideoRow {
+    pub uuid: String,
+    pub title: String,
+    pub cover: Option<String>,
+    pub room: i64,
+    pub stream_time: i64,
+    pub record_time: i64,
+    pub restricted: i64,
+    #[serde(skip)]
+    pub restricted_hash: Option<String>,
+}
+
+/// Entry of a room's video listing, standing for all parts of one stream.
+#[derive(Serialize)]
+pub(super) struct RoomVideoRow {
+    pub uuid: String,
+    pub title: String,
+    pub cover: Option<String>,
+    pub stream_time: i64,
+    pub record_time: i64,
+    pub restricted: i64,
+    pub parts: i64,
+}
+
+#[derive(Serialize)]
+pub(super) struct VideoPartRow {
+    pub uuid: String,
+    pub title: String,
+    pub cover: Option<String>,
+    pub stream_time: i64,
+    pub record_time: i64,
+    pub restricted: i64,
+}
+
+pub struct Catalog {
+    conn: Mutex<Connection>,
+}
+
+impl Catalog {
+    /// Open the database at `path`, creating it and its tables as needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let path = path.as_ref();
+        Connection::open(path)
+            .and_then(Self::init)
+            .map_err(|e| Error::IO(std::io::Error::other(e)))
+            .with_context(|| format!("Failed to open database {}", path.display()))
+    }
+
+    pub fn open_in_memory() -> crate::Result<Self> {
+        Connection::open_in_memory()
+            .and_then(Self::init)
+            .map_err(|e| Error::IO(std::io::Error::other(e)))
+    }
+
+    fn init(conn: Connection) -> rusqlite::Result<Self> {
+        // D1 enforces foreign keys, so a video cannot outlive its room here
+        // either.
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(super) fn rooms(&self, limit: u64, offset: u64) -> rusqlite::Result<Vec<RoomRow>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare_cached("SELECT id, short_id, username, image FROM room LIMIT ? OFFSET ?")?;
+        stmt.query_map(params![limit as i64, offset as i64], room_row)?
+            .collect()
+    }
+
+    pub(super) fn room(&self, id: i64) -> rusqlite::Result<Option<RoomRow>> {
+        self.conn()
+            .query_row(
+                "SELECT id, short_id, username, image FROM room WHERE id = ?",
+                [id],
+                room_row,
+            )
+            .optional()
+    }
+
+    /// Add a room, returning `false` if one with the same ID exists.
+    pub(super) fn insert_room(&self, room: &RoomRow) -> rusqlite::Result<bool> {
+        let changes = self.conn().execute(
+            "INSERT OR IGNORE INTO room (id, short_id, username, image) VALUES (?, ?, ?, ?)",
+            params![room.id, room.short_id, room.username, room.image],
+        )?;
+        Ok(changes > 0)
+    }
+
+    pub(super) fn room_videos(
+        &self,
+        room: i64,
+        limit: u64,
+        offset: u64,
+    ) -> rusqlite::Result<Vec<RoomVideoRow>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT LOWER(HEX(uuid)) as uuid, title, cover, stream_time, record_time, restricted, parts
+             FROM (
+                SELECT *,
+                  ROW_NUMBER() OVER (PARTITION BY room, stream_time ORDER BY record_time ASC) AS row_num,
+                  COUNT(*) OVER (PARTITION BY room, stream_time) as parts
+                FROM video
+             ) WHERE room = ? AND row_num = 1 ORDER BY stream_time DESC LIMIT ? OFFSET ?",
+        )?;
+        stmt.query_map(params![room, limit as i64, offset as i64], |row| {
+            Ok(RoomVideoRow {
+                uuid: row.get(0)?,
+                title: row.get(1)?,
+                cover: row.get(2)?,
+                stream_time: row.get(3)?,
+                record_time: row.get(4)?,
+                restricted: row.get(5)?,
+                parts: row.get(6)?,
+            })
+        })?
+        .collect()
+    }
+
+    pub(super) fn video(&self, uuid: &str) -> rusqlite::Result<Option<VideoRow>> {
+        self.conn()
+            .query_row(
+                "SELECT LOWER(HEX(uuid)) as uuid, title, cover, room, stream_time, record_time,
+                   restricted, restricted_hash
+                 FROM video WHERE uuid = UNHEX(?)",
+                [uuid],
+                |row| {
+                    Ok(VideoRow {
+                        uuid: row.get(0)?,
+                        title: row.get(1)?,
+                        cover: row.get(2)?,
+                        room: row.get(3)?,
+                        stream_time: row.get(4)?,
+                        record_time: row.get(5)?,
+                        restricted: row.get(6)?,
+                        restricted_hash: row.get(7)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Add a video, returning `false` if one with the same UUID exists.
+    pub(super) fn insert_video(&self, video: &VideoRow) -> rusqlite::Result<bool> {
+        let changes = self.conn().execute(
+            "INSERT OR IGNORE INTO video
+               (uuid, title, cover, room, stream_time, record_time, restricted, restricted_hash)
+             VALUES (UNHEX(?), ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                video.uuid,
+                video.title,
+                video.cover,
+                video.room,
+                video.stream_time,
+                video.record_time,
+                video.restricted,
+                video.restricted_hash,
+            ],
+        )?;
+        Ok(changes > 0)
+    }
+
+    pub(super) fn update_video(&self, video: &VideoRow) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE video SET title=?, cover=?, stream_time=?, record_time=? WHERE uuid=UNHEX(?)",
+            params![
+                video.title,
+                video.cover,
+                video.stream_time,
+                video.record_time,
+                video.uuid,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Remove the video, returning whether there was one.
+    pub(super) fn delete_video(&self, uuid: &str) -> rusqlite::Result<bool> {
+        let changes = self
+            .conn()
+            .execute("DELETE FROM video WHERE uuid=UNHEX(?)", params![uuid])?;
+        Ok(changes > 0)
+    }
+
+    /// Restrict the video under `hash`, or lift the restriction if `None`.
+    pub(super) fn set_restricted(&self, uuid: &str, hash: Option<&str>) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE video SET restricted=?, restricted_hash=? WHERE uuid=UNHEX(?)",
+            params![hash.is_some() as i64, hash, uuid],
+        )?;
+        Ok(())
+    }
+
+    /// All parts recorded from the same stream as `video`, in order.
+    pub(super) fn video_parts(&self, video: &VideoRow) -> rusqlite::Result<Vec<VideoPartRow>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT LOWER(HEX(uuid)) as uuid, title, cover, stream_time, record_time, restricted
+             FROM video WHERE room = ? AND stream_time = ? ORDER BY record_time ASC",
+        )?;
+        stmt.query_map(params![video.room, video.stream_time], |row| {
+            Ok(VideoPartRow {
+                uuid: row.get(0)?,
+                title: row.get(1)?,
+                cover: row.get(2)?,
+                stream_time: row.get(3)?,
+                record_time: row.get(4)?,
+                restricted: row.get(5)?,
+            })
+        })?
+        .collect()
+    }
+}
+
+fn room_row(row: &Row) -> rusqlite::Result<RoomRow> {
+    Ok(RoomRow {
+        id: row.get(0)?,
+        short_id: row.get(1)?,
+        username: row.get(2)?,
+        image: row.get(3)?,
+    })
+}
@@ -0,0 +1,103 @@
+//! Small helpers around `tiny_http` requests and responses.
+
+use std::io::{Cursor, Read};
+
+use tiny_http::{Header, Request, Response, StatusCode};
+
+pub(super) type HttpResponse = Response<Box<dyn Read + Send>>;
+
+pub(super) fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Invalid header")
+}
+
+/// Value of the first header called `name`, compared case-insensitively.
+pub(super) fn header_value(req: &Request, name: &str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+pub(super) fn reader<R: Read + Send + 'static>(
+    status: u16,
+    headers: Vec<Header>,
+    body: R,
+    length: Option<usize>,
+) -> HttpResponse {
+    // tiny_http switches to chunked encoding above 32 KiB by default, which
+    // drops the Content-Length that HEAD requests and players rely on.
+    let body: Box<dyn Read + Send> = Box::new(body);
+    Response::new(StatusCode(status), headers, body, length, None)
+        .with_chunked_threshold(usize::MAX)
+}
+
+pub(super) fn bytes(status: u16, content_type: &str, data: Vec<u8>) -> HttpResponse {
+    let length = data.len();
+    reader(
+        status,
+        vec![header("Content-Type", content_type)],
+        Cursor::new(data),
+        Some(length),
+    )
+}
+
+pub(super) fn empty(status: u16) -> HttpResponse {
+    reader(status, vec![], std::io::empty(), Some(0))
+}
+
+/// Path and decoded query parameters of a request target.
+pub(super) struct Target {
+    pub path: String,
+    pub query: Vec<(String, String)>,
+}
+
+impl Target {
+    pub fn parse(raw: &str) -> Option<Self> {
+        let url = url::Url::parse("http://localhost").ok()?.join(raw).ok()?;
+        Some(Self {
+            path: url.path().to_string(),
+            query: url.query_pairs().into_owned().collect(),
+        })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parse a single `bytes=from-to` range against an object of `size` bytes
+/// into an inclusive range.
+pub(super) fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let (from, to) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (from, to) = match (from, to) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.checked_sub(suffix.min(size))?, size.checked_sub(1)?)
+        }
+        (from, "") => (from.parse().ok()?, size.checked_sub(1)?),
+        (from, to) => (
+            from.parse().ok()?,
+            to.parse::<u64>().ok()?.min(size.checked_sub(1)?),
+        ),
+    };
+    (from <= to).then_some((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some((990, 999)));
+        assert_eq!(parse_range("bytes=10-5", 1000), None);
+        assert_eq!(parse_range("bytes=0-0", 0), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+}
@@ -0,0 +1,208 @@
+//! Self-hostable implementation of the Koishi API.
+//!
+//! Speaks the same protocol as the Cloudflare deployment under `web/`, so the
+//! CLI and the web UI work against either one. Rooms and videos live in a
+//! SQLite database with the schema of `web/misc/init.sql`; objects go to an
+//! S3-compatible service or to a directory the server serves itself.
+
+use std::{
+    fs::File,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use tiny_http::{Method, Request};
+use url::Url;
+
+mod catalog;
+mod http;
+mod routes;
+pub mod store;
+
+pub use catalog::Catalog;
+
+use crate::{Error, ResultExt};
+use http::{HttpResponse, Target};
+use store::{LocalStore, ObjectStore, S3Config, S3Store};
+
+pub struct ServerConfig {
+    /// Key that requests other than GET must carry as a bearer token.
+    pub auth_key: String,
+    /// First segment of every object key, like `S3_BUCKET`.
+    pub bucket: String,
+    /// Built web UI to serve at `/`, with `index.html` for unknown paths.
+    pub static_dir: Option<PathBuf>,
+}
+
+/// Where the server keeps video, cover and metadata objects.
+pub enum StoreConfig {
+    /// Files below `root`, served under `/s3`. Presigned URLs point at
+    /// `public_url`, or at the listen address if unset.
+    Local {
+        root: PathBuf,
+        public_url: Option<Url>,
+    },
+    S3(S3Config),
+}
+
+struct State {
+    config: ServerConfig,
+    catalog: Catalog,
+    store: Arc<dyn ObjectStore>,
+    local: Option<Arc<LocalStore>>,
+}
+
+pub struct Server {
+    http: tiny_http::Server,
+    state: State,
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+impl Server {
+    /// Listen on `addr` without serving requests yet; see [`Server::run`].
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        config: ServerConfig,
+        catalog: Catalog,
+        store: StoreConfig,
+    ) -> crate::Result<Self> {
+        let http = tiny_http::Server::http(addr)
+            .map_err(|e| Error::IO(io::Error::other(e)))
+            .context("Failed to listen")?;
+        let local_addr = http
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| Error::validation("Server must listen on an IP address"))?;
+
+        let (store, local): (Arc<dyn ObjectStore>, _) = match store {
+            StoreConfig::Local { root, public_url } => {
+                let public_url = match public_url {
+                    Some(url) => url,
+                    None => Url::parse(&format!("http://{local_addr}/"))
+                        .expect("Socket addresses make valid URLs"),
+                };
+                // Signing with the auth key keeps URLs handed out before a
+                // restart valid after it.
+                let local = Arc::new(
+                    LocalStore::new(&root, public_url, config.auth_key.as_bytes())
+                        .with_context(|| format!("Failed to set up store in {}", root.display()))?,
+                );
+                (local.clone(), Some(local))
+            }
+            StoreConfig::S3(config) => (Arc::new(S3Store::new(config)), None),
+        };
+
+        Ok(Self {
+            http,
+            state: State {
+                config,
+                catalog,
+                store,
+                local,
+            },
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.http
+            .server_addr()
+            .to_ip()
+            .expect("Checked when binding")
+    }
+
+    /// Serve requests on `threads` threads until [`Server::unblock`] has been
+    /// called once per thread.
+    pub fn run(&self, threads: usize) {
+        thread::scope(|s| {
+            for _ in 0..threads.max(1) {
+                s.spawn(|| {
+                    for req in self.http.incoming_requests() {
+                        self.serve(req);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Make one thread of [`Server::run`] return.
+    pub fn unblock(&self) {
+        self.http.unblock();
+    }
+
+    fn serve(&self, mut req: Request) {
+        let method = req.method().clone();
+        let url = req.url().to_string();
+
+        let res = match Target::parse(&url) {
+            Some(target) => self.route(&mut req, &target),
+            None => http::empty(400),
+        };
+        log::info!("{method} {url} {}", res.status_code().0);
+
+        if let Err(e) = req.respond(res) {
+            log::warn!("Failed to respond to {method} {url}: {e}");
+        }
+    }
+
+    fn route(&self, req: &mut Request, target: &Target) -> HttpResponse {
+        if let Some(path) = target.path.strip_prefix("/api/") {
+            return routes::handle(&self.state, req, path, target);
+        }
+        if let Some(local) = &self.state.local
+            && let Some(key) = target.path.strip_prefix(LocalStore::PREFIX)
+        {
+            return local.handle(req, key, target);
+        }
+        if matches!(req.method(), Method::Get | Method::Head)
+            && let Some(dir) = &self.state.config.static_dir
+        {
+            return static_file(dir, &target.path);
+        }
+        http::empty(404)
+    }
+}
+
+/// Serve a file of the web UI, falling back to `index.html` so that client
+/// side routes resolve.
+fn static_file(dir: &Path, path: &str) -> HttpResponse {
+    let relative = Path::new(path.trim_start_matches('/'));
+    let safe = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+
+    let mut file_path = dir.join(relative);
+    if !safe || !file_path.is_file() {
+        file_path = dir.join("index.html");
+    }
+
+    match File::open(&file_path).and_then(|f| Ok((f.metadata()?.len(), f))) {
+        Ok((size, file)) => http::reader(
+            200,
+            vec![http::header("Content-Type", content_type(&file_path))],
+            file,
+            Some(size as usize),
+        ),
+        Err(e) => {
+            log::warn!("Failed to open {}: {e}", file_path.display());
+            http::empty(404)
+        }
+    }
+}
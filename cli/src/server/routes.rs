@@ -0,0 +1,655 @@
+//! The `/api` routes, mirroring the Pages Functions under `web/functions`
+//! down to status codes and error messages.
+
+use std::io::Read;
+
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tiny_http::{Method, Request};
+
+use super::{
+    State,
+    catalog::{RoomRow, VideoRow},
+    http::{self, HttpResponse, Target},
+    store::{self, CopyRange, StoreError},
+};
+use crate::helpers::multipart::MAX_PARTS;
+
+/// Largest request body accepted by the API routes.
+const MAX_BODY: u64 = 1 << 20;
+
+/// Error reply, shaped like those of `functions_libs/responses.ts`.
+struct Failure {
+    status: u16,
+    error: &'static str,
+    message: Option<String>,
+    details: Option<Value>,
+}
+
+type Reply = Result<Value, Failure>;
+
+macro_rules! failures {
+    ($($name:ident => $status:literal),* $(,)?) => {
+        impl Failure {
+            $(
+                fn $name<S: Into<String>>(message: S) -> Self {
+                    Self {
+                        status: $status,
+                        error: stringify!($name),
+                        message: Some(message.into()),
+                        details: None,
+                    }
+                }
+            )*
+        }
+    };
+}
+
+failures! {
+    bad_request => 400,
+    unauthorized => 401,
+    forbidden => 403,
+    not_found => 404,
+    method_not_allowed => 405,
+    conflict => 409,
+    unprocessable_entity => 422,
+    db_transaction_error => 500,
+    s3_error => 500,
+}
+
+impl Failure {
+    fn details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl From<rusqlite::Error> for Failure {
+    fn from(value: rusqlite::Error) -> Self {
+        log::error!("Database error: {value}");
+        Failure::db_transaction_error(value.to_string())
+    }
+}
+
+impl From<StoreError> for Failure {
+    fn from(value: StoreError) -> Self {
+        log::error!("Object storage error: {value}");
+        let failure = Failure::s3_error(value.to_string());
+        match value {
+            StoreError::Status { body, .. } => failure.details(json!({ "xml": body })),
+            _ => failure,
+        }
+    }
+}
+
+fn respond(reply: Reply) -> HttpResponse {
+    let (status, body) = match reply {
+        Ok(result) => (200, json!({ "result": result })),
+        Err(f) => {
+            let mut body = json!({ "error": f.error });
+            if let Some(message) = f.message {
+                body["message"] = message.into();
+            }
+            if let Some(details) = f.details {
+                body["details"] = details;
+            }
+            (f.status, body)
+        }
+    };
+    http::bytes(status, "application/json", body.to_string().into_bytes())
+}
+
+fn body<T: DeserializeOwned>(req: &mut Request) -> Result<T, Failure> {
+    let mut data = vec![];
+    req.as_reader()
+        .take(MAX_BODY)
+        .read_to_end(&mut data)
+        .map_err(|e| Failure::bad_request(format!("Failed to read body: {e}")))?;
+    serde_json::from_slice(&data).map_err(|e| {
+        Failure::unprocessable_entity("Body verification failed").details(e.to_string().into())
+    })
+}
+
+/// `limit` and `offset` query parameters of the listings.
+fn page(target: &Target) -> Result<(u64, u64), Failure> {
+    let param = |name, default: u64| match target.param(name) {
+        Some(v) => v
+            .parse::<u64>()
+            .map_err(|_| Failure::bad_request(format!("Invalid {name}: {v}"))),
+        None => Ok(default),
+    };
+    Ok((param("limit", 50)?.min(100), param("offset", 0)?))
+}
+
+fn is_uuid(s: &str) -> bool {
+    s.len() == 32 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Answer a request for `/api/...`; `path` is what follows `/api/`.
+pub(super) fn handle(
+    state: &State,
+    req: &mut Request,
+    path: &str,
+    target: &Target,
+) -> HttpResponse {
+    if *req.method() != Method::Get {
+        let expected = format!("Bearer {}", state.config.auth_key);
+        if http::header_value(req, "Authorization").as_deref() != Some(expected.as_str()) {
+            return respond(Err(Failure::unauthorized("Invalid authentication key")));
+        }
+    }
+
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    let method = req.method().clone();
+    let reply = match (segments.as_slice(), method) {
+        (["room"], Method::Get) => list_rooms(state, target),
+        (["room", id], method) => match id.parse::<i64>() {
+            Ok(id) => match method {
+                Method::Get => get_room(state, id),
+                Method::Post => create_room(state, body(req)),
+                _ => Err(Failure::method_not_allowed("Method not allowed")),
+            },
+            Err(_) => Err(Failure::bad_request(format!("Invalid room ID: {id}"))),
+        },
+        (["room", id, "video"], Method::Get) => match id.parse::<i64>() {
+            Ok(id) => list_room_videos(state, id, target),
+            Err(_) => Err(Failure::bad_request(format!("Invalid room ID: {id}"))),
+        },
+        (["cover"], Method::Post) => set_cover(state, body(req)),
+        (["video", uuid, rest @ ..], method) => {
+            if !is_uuid(uuid) {
+                Err(Failure::bad_request(format!("Invalid UUID: {uuid}")))
+            } else {
+                video_route(state, req, uuid, rest, method, target)
+            }
+        }
+        (["room"] | ["room", _, "video"] | ["cover"], _) => {
+            Err(Failure::method_not_allowed("Method not allowed"))
+        }
+        _ => Err(Failure::not_found("Not found")),
+    };
+
+    if let Err(f) = &reply {
+        log::debug!("{} /api/{path}: {} {:?}", req.method(), f.error, f.message);
+    }
+    respond(reply)
+}
+
+fn video_route(
+    state: &State,
+    req: &mut Request,
+    uuid: &str,
+    rest: &[&str],
+    method: Method,
+    target: &Target,
+) -> Reply {
+    match (rest, method) {
+        ([], Method::Get) => get_video(state, uuid),
+        ([], Method::Post) => create_video(state, uuid, body(req)),
+        ([], Method::Put) => update_video(state, uuid, body(req)),
+        ([], Method::Delete) => delete_video(state, uuid, target),
+        (["upload_start"], Method::Post) => upload_start(state, uuid, body(req)),
+        (["upload_finish"], Method::Post) => upload_finish(state, uuid, body(req)),
+        (["upload_abort"], Method::Post) => upload_abort(state, uuid, body(req)),
+        (["upload_parts"], Method::Get) => upload_parts(state, uuid, target),
+        (["upload_urls"], Method::Post) => upload_urls(state, uuid, body(req)),
+        (["upload_metadata"], Method::Post) => upload_metadata(state, uuid),
+        (["parts"], Method::Get) => video_parts(state, uuid),
+        (["object"], Method::Get) => video_object(state, uuid, target),
+        (["restricted"], Method::Get) => get_restricted(state, uuid, target),
+        (["restricted"], Method::Put) => set_restricted(state, uuid, body(req)),
+        (["restricted"], Method::Post) => copy_restricted(state, uuid, body(req)),
+        (
+            []
+            | [
+                "upload_start" | "upload_finish" | "upload_abort" | "upload_parts" | "upload_urls"
+                | "upload_metadata" | "parts" | "object" | "restricted",
+            ],
+            _,
+        ) => Err(Failure::method_not_allowed("Method not allowed")),
+        _ => Err(Failure::not_found("Not found")),
+    }
+}
+
+fn find_video(state: &State, uuid: &str) -> Result<VideoRow, Failure> {
+    state
+        .catalog
+        .video(uuid)?
+        .ok_or_else(|| Failure::not_found(format!("Video {uuid} not found")))
+}
+
+fn video_key(state: &State, video: &VideoRow) -> String {
+    let bucket = &state.config.bucket;
+    match (video.restricted, video.restricted_hash.as_deref()) {
+        (0, _) | (_, None) => store::video_key_unrestricted(bucket, video.room, &video.uuid),
+        (_, Some(hash)) => store::video_key_restricted(bucket, video.room, hash),
+    }
+}
+
+/// Refuse to touch a restricted video without its hash.
+fn check_hash(video: &VideoRow, hash: Option<&str>, message: &str) -> Result<(), Failure> {
+    if video.restricted != 0 && video.restricted_hash.as_deref() != hash {
+        return Err(Failure::forbidden(message));
+    }
+    Ok(())
+}
+
+fn list_rooms(state: &State, target: &Target) -> Reply {
+    let (limit, offset) = page(target)?;
+    Ok(json!(state.catalog.rooms(limit, offset)?))
+}
+
+fn get_room(state: &State, id: i64) -> Reply {
+    match state.catalog.room(id)? {
+        Some(room) => Ok(json!(room)),
+        None => Err(Failure::not_found(format!("Room {id} not found"))),
+    }
+}
+
+fn create_room(state: &State, room: Result<RoomRow, Failure>) -> Reply {
+    let room = room?;
+    if !state.catalog.insert_room(&room)? {
+        return Err(Failure::conflict(format!(
+            "Room {} already exists",
+            room.id
+        )));
+    }
+    Ok(Value::Null)
+}
+
+fn list_room_videos(state: &State, room: i64, target: &Target) -> Reply {
+    let (limit, offset) = page(target)?;
+    Ok(json!(state.catalog.room_videos(room, limit, offset)?))
+}
+
+#[derive(Deserialize)]
+struct ReqCover {
+    hash: String,
+}
+
+fn set_cover(state: &State, req: Result<ReqCover, Failure>) -> Reply {
+    let ReqCover { hash } = req?;
+    if hash.is_empty() {
+        return Err(Failure::unprocessable_entity("hash must not be empty"));
+    }
+
+    let key = store::cover_key(&state.config.bucket, &hash);
+    if state.store.head(&key)?.is_some() {
+        return Ok(json!({ "exists": true, "url": null }));
+    }
+    Ok(json!({ "exists": false, "url": state.store.presign_put(&key)? }))
+}
+
+#[derive(Deserialize)]
+struct ReqInsertVideo {
+    title: String,
+    cover: Option<String>,
+    room: i64,
+    stream_time: i64,
+    record_time: i64,
+    restricted: Option<i64>,
+    restricted_hash: Option<String>,
+}
+
+fn get_video(state: &State, uuid: &str) -> Reply {
+    Ok(json!(find_video(state, uuid)?))
+}
+
+fn create_video(state: &State, uuid: &str, req: Result<ReqInsertVideo, Failure>) -> Reply {
+    let req = req?;
+    if req.title.is_empty() {
+        return Err(Failure::unprocessable_entity("title must not be empty"));
+    }
+    let restricted = req.restricted.unwrap_or(0);
+    match (restricted, &req.restricted_hash) {
+        (0, None) | (1, Some(_)) => {}
+        _ => {
+            return Err(Failure::unprocessable_entity(
+                "restricted must be 0 without restricted_hash, or 1 with one",
+            ));
+        }
+    }
+
+    let video = VideoRow {
+        uuid: uuid.to_string(),
+        title: req.title,
+        cover: req.cover,
+        room: req.room,
+        stream_time: req.stream_time,
+        record_time: req.record_time,
+        restricted,
+        restricted_hash: req.restricted_hash,
+    };
+    if !state.catalog.insert_video(&video)? {
+        return Err(Failure::conflict(format!("Video {uuid} already exists")));
+    }
+    Ok(Value::Null)
+}
+
+#[derive(Deserialize)]
+struct ReqUpdateVideo {
+    title: Option<String>,
+    cover: Option<String>,
+    stream_time: Option<i64>,
+    record_time: Option<i64>,
+}
+
+fn update_video(state: &State, uuid: &str, req: Result<ReqUpdateVideo, Failure>) -> Reply {
+    let req = req?;
+    let mut video = find_video(state, uuid)?;
+
+    if let Some(title) = req.title {
+        video.title = title;
+    }
+    if let Some(cover) = req.cover {
+        video.cover = Some(cover);
+    }
+    video.stream_time = req.stream_time.unwrap_or(video.stream_time);
+    video.record_time = req.record_time.unwrap_or(video.record_time);
+
+    state.catalog.update_video(&video)?;
+    Ok(Value::Null)
+}
+
+/// Remove the video along with its object and metadata; the cover may be
+/// shared with other videos and stays.
+fn delete_video(state: &State, uuid: &str, target: &Target) -> Reply {
+    let video = find_video(state, uuid)?;
+    check_hash(
+        &video,
+        target.param("hash"),
+        "Invalid hash for restricted video",
+    )?;
+
+    // Objects go first, so that a failure leaves the row to delete again.
+    state.store.delete(&video_key(state, &video))?;
+    state
+        .store
+        .delete(&store::metadata_key(&state.config.bucket, &video.uuid))?;
+    state.catalog.delete_video(uuid)?;
+    Ok(Value::Null)
+}
+
+#[derive(Deserialize)]
+struct ReqUploadStart {
+    size: u64,
+    part_size: u64,
+    /// Upload to go on with instead of starting a new one.
+    upload_id: Option<String>,
+    restricted_hash: Option<String>,
+}
+
+fn upload_start(state: &State, uuid: &str, req: Result<ReqUploadStart, Failure>) -> Reply {
+    let req = req?;
+    if req.part_size == 0 {
+        return Err(Failure::unprocessable_entity("part_size must be positive"));
+    }
+    let parts = req.size.div_ceil(req.part_size);
+    if parts > MAX_PARTS {
+        return Err(Failure::unprocessable_entity(format!(
+            "{parts} parts exceed the limit of {MAX_PARTS}"
+        )));
+    }
+
+    let video = find_video(state, uuid)?;
+    check_hash(
+        &video,
+        req.restricted_hash.as_deref(),
+        "Invalid hash for restricted video",
+    )?;
+
+    let key = video_key(state, &video);
+    let upload_id = match req.upload_id {
+        Some(upload_id) => {
+            if state.store.list_parts(&key, &upload_id)?.is_none() {
+                return Err(Failure::not_found(format!("Upload {upload_id} not found")));
+            }
+            upload_id
+        }
+        None => state.store.create_multipart(&key)?,
+    };
+    let urls = (1..=parts)
+        .map(|i| state.store.presign_part(&key, &upload_id, i, None))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(json!({ "urls": urls, "upload_id": upload_id, "video": video }))
+}
+
+#[derive(Deserialize)]
+struct ReqUploadFinish {
+    upload_id: String,
+    etags: Vec<String>,
+    restricted_hash: Option<String>,
+}
+
+fn upload_finish(state: &State, uuid: &str, req: Result<ReqUploadFinish, Failure>) -> Reply {
+    let req = req?;
+    let video = find_video(state, uuid)?;
+    check_hash(
+        &video,
+        req.restricted_hash.as_deref(),
+        "Invalid hash for restricted video",
+    )?;
+
+    state
+        .store
+        .complete_multipart(&video_key(state, &video), &req.upload_id, &req.etags)?;
+    Ok(Value::Null)
+}
+
+#[derive(Deserialize)]
+struct ReqUploadAbort {
+    upload_id: String,
+    restricted_hash: Option<String>,
+}
+
+fn upload_abort(state: &State, uuid: &str, req: Result<ReqUploadAbort, Failure>) -> Reply {
+    let req = req?;
+    let video = find_video(state, uuid)?;
+    check_hash(
+        &video,
+        req.restricted_hash.as_deref(),
+        "Invalid hash for restricted video",
+    )?;
+
+    state
+        .store
+        .abort_multipart(&video_key(state, &video), &req.upload_id)?;
+    Ok(Value::Null)
+}
+
+#[derive(Deserialize)]
+struct ReqUploadUrls {
+    upload_id: String,
+    part_numbers: Vec<u64>,
+    restricted_hash: Option<String>,
+}
+
+/// Sign the URLs of some parts of an upload again, once those handed out
+/// by `upload_start` are about to expire.
+fn upload_urls(state: &State, uuid: &str, req: Result<ReqUploadUrls, Failure>) -> Reply {
+    let req = req?;
+    if let Some(n) = req
+        .part_numbers
+        .iter()
+        .find(|n| !(1..=MAX_PARTS).contains(*n))
+    {
+        return Err(Failure::unprocessable_entity(format!(
+            "Invalid part number {n}"
+        )));
+    }
+
+    let video = find_video(state, uuid)?;
+    check_hash(
+        &video,
+        req.restricted_hash.as_deref(),
+        "Invalid hash for restricted video",
+    )?;
+
+    let key = video_key(state, &video);
+    let urls = req
+        .part_numbers
+        .iter()
+        .map(|&i| state.store.presign_part(&key, &req.upload_id, i, None))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(json!({ "urls": urls }))
+}
+
+/// Parts stored so far for the given upload, or for the latest one of the
+/// video if not given.
+fn upload_parts(state: &State, uuid: &str, target: &Target) -> Reply {
+    let video = find_video(state, uuid)?;
+    let hash = target.param("hash").map(str::to_lowercase);
+    check_hash(&video, hash.as_deref(), "Invalid hash for restricted video")?;
+
+    let key = video_key(state, &video);
+    let upload_id = match target.param("upload_id") {
+        Some(upload_id) => upload_id.to_string(),
+        None => state
+            .store
+            .latest_multipart(&key)?
+            .ok_or_else(|| Failure::not_found(format!("No unfinished upload of video {uuid}")))?,
+    };
+    let Some(parts) = state.store.list_parts(&key, &upload_id)? else {
+        return Err(Failure::not_found(format!("Upload {upload_id} not found")));
+    };
+    Ok(json!({ "upload_id": upload_id, "parts": parts }))
+}
+
+fn upload_metadata(state: &State, uuid: &str) -> Reply {
+    let video = find_video(state, uuid)?;
+    let key = store::metadata_key(&state.config.bucket, &video.uuid);
+    Ok(json!({ "url": state.store.presign_put(&key)? }))
+}
+
+fn video_parts(state: &State, uuid: &str) -> Reply {
+    let video = find_video(state, uuid)?;
+    Ok(json!(state.catalog.video_parts(&video)?))
+}
+
+fn video_object(state: &State, uuid: &str, target: &Target) -> Reply {
+    let video = find_video(state, uuid)?;
+    let hash = target.param("hash").map(str::to_lowercase);
+    check_hash(&video, hash.as_deref(), "Invalid restricted hash")?;
+
+    let Some(info) = state.store.head(&video_key(state, &video))? else {
+        return Err(Failure::not_found(format!("Video {uuid} not uploaded")));
+    };
+    Ok(json!({ "size": info.size, "etag": info.etag }))
+}
+
+fn get_restricted(state: &State, uuid: &str, target: &Target) -> Reply {
+    let video = find_video(state, uuid)?;
+    if video.restricted == 0 {
+        return Ok(json!({ "restricted": video.restricted }));
+    }
+
+    let mut ret = json!({ "restricted": video.restricted });
+    if let Some(hash) = target.param("verify_hash").filter(|h| !h.is_empty()) {
+        ret["hash_verified"] = (Some(hash.to_lowercase()) == video.restricted_hash).into();
+    }
+    Ok(ret)
+}
+
+#[derive(Deserialize)]
+struct ReqSetRestricted {
+    restricted: i64,
+    hash: String,
+}
+
+fn set_restricted(state: &State, uuid: &str, req: Result<ReqSetRestricted, Failure>) -> Reply {
+    let ReqSetRestricted { restricted, hash } = req?;
+    let video = find_video(state, uuid)?;
+    check_hash(&video, Some(&hash), "Invalid restricted hash")?;
+
+    let bucket = &state.config.bucket;
+    let old_key = if restricted == 0 {
+        state.catalog.set_restricted(uuid, None)?;
+        store::video_key_restricted(bucket, video.room, &hash)
+    } else {
+        state.catalog.set_restricted(uuid, Some(&hash))?;
+        store::video_key_unrestricted(bucket, video.room, &video.uuid)
+    };
+
+    let Some(source) = state.store.head(&old_key)? else {
+        return Ok(json!({}));
+    };
+    Ok(json!({ "copy_source": old_key, "length": source.size }))
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ReqCopy {
+    CopyStart {
+        copy_source: String,
+        part_size: u64,
+        hash: Option<String>,
+    },
+    CopyFinish {
+        copy_source: String,
+        etags: Vec<String>,
+        upload_id: String,
+        hash: Option<String>,
+    },
+}
+
+fn copy_restricted(state: &State, uuid: &str, req: Result<ReqCopy, Failure>) -> Reply {
+    let req = req?;
+    let hash = match &req {
+        ReqCopy::CopyStart { hash, .. } | ReqCopy::CopyFinish { hash, .. } => hash.as_deref(),
+    };
+    let video = find_video(state, uuid)?;
+    check_hash(&video, hash, "Invalid restricted hash")?;
+    let dst_key = video_key(state, &video);
+
+    match req {
+        ReqCopy::CopyStart {
+            copy_source,
+            part_size,
+            ..
+        } => {
+            let Some(source) = state.store.head(&copy_source)? else {
+                return Err(Failure::not_found("copy_source does not exist"));
+            };
+            if part_size == 0 {
+                return Err(Failure::unprocessable_entity("part_size must be positive"));
+            }
+            let length = source.size;
+            let parts = length.div_ceil(part_size);
+            if parts > MAX_PARTS {
+                return Err(Failure::unprocessable_entity(format!(
+                    "{parts} parts exceed the limit of {MAX_PARTS}"
+                )));
+            }
+
+            let upload_id = state.store.create_multipart(&dst_key)?;
+            let urls = (0..parts)
+                .map(|i| {
+                    let from = part_size * i;
+                    let copy = CopyRange {
+                        source: &copy_source,
+                        from,
+                        to: length.min(from + part_size) - 1,
+                    };
+                    state
+                        .store
+                        .presign_part(&dst_key, &upload_id, i + 1, Some(copy))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(json!({ "length": length, "upload_id": upload_id, "urls": urls }))
+        }
+        ReqCopy::CopyFinish {
+            copy_source,
+            etags,
+            upload_id,
+            ..
+        } => {
+            state
+                .store
+                .complete_multipart(&dst_key, &upload_id, &etags)?;
+            state.store.delete(&copy_source)?;
+            Ok(Value::Null)
+        }
+    }
+}
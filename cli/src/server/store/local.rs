@@ -0,0 +1,654 @@
+//! Object store on the local filesystem, for deployments without S3.
+//!
+//! Objects are served by the server itself under [`LocalStore::PREFIX`] and
+//! written through presigned URLs shaped like those of S3, so the CLI cannot
+//! tell the two apart. Signatures are HMACs under a secret of the server
+//! instead of SigV4, as nobody else has to check them.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use md5::{Digest, Md5};
+use tiny_http::{Method, Request};
+use url::Url;
+
+use super::{
+    CopyRange, ObjectInfo, ObjectStore, PRESIGN_EXPIRES_SECS, PartInfo, StoreError, StoreResult,
+    sigv4::{hmac, uri_encode},
+};
+use crate::helpers::{
+    multipart::{self, MAX_PARTS},
+    s3::AMZ_DATE_FORMAT,
+};
+use crate::server::http::{self, HttpResponse, Target};
+
+const UPLOADS_DIR: &str = ".uploads";
+const META_DIR: &str = ".meta";
+
+pub struct LocalStore {
+    root: PathBuf,
+    base_url: Url,
+    secret: Vec<u8>,
+}
+
+/// S3-style error reply of the object routes.
+struct Failure {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new<S: Into<String>>(status: u16, code: &'static str, message: S) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn respond(self) -> HttpResponse {
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Error><Code>{}</Code><Message>{}</Message></Error>",
+            self.code, self.message
+        );
+        http::bytes(self.status, "application/xml", xml.into_bytes())
+    }
+}
+
+impl From<StoreError> for Failure {
+    fn from(value: StoreError) -> Self {
+        match value {
+            StoreError::Invalid(msg) => Failure::new(400, "InvalidRequest", msg),
+            StoreError::IO(err) if err.kind() == io::ErrorKind::NotFound => {
+                Failure::new(404, "NoSuchKey", "The specified key does not exist.")
+            }
+            err => Failure::new(500, "InternalError", err.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(value: io::Error) -> Self {
+        StoreError::IO(value).into()
+    }
+}
+
+/// Path of `key` relative to the store root, refusing anything that could
+/// leave it or reach the bookkeeping directories.
+fn relative_path(key: &str) -> StoreResult<PathBuf> {
+    let invalid = || StoreError::Invalid(format!("Invalid key {key}"));
+
+    let mut ret = PathBuf::new();
+    for segment in key.strip_prefix('/').ok_or_else(invalid)?.split('/') {
+        let valid = !segment.is_empty()
+            && !segment.starts_with('.')
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.~".contains(&b));
+        if !valid {
+            return Err(invalid());
+        }
+        ret.push(segment);
+    }
+    Ok(ret)
+}
+
+/// Copy `reader` to a new file at `path`, returning the MD5 of what was
+/// written.
+fn write_hashed<R: Read>(mut reader: R, path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::create(path)?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+    }
+    file.sync_all()?;
+    Ok(hasher.finalize().to_vec())
+}
+
+impl LocalStore {
+    /// Path under which the server exposes objects of a local store.
+    pub const PREFIX: &'static str = "/s3";
+
+    /// Store objects below `root`, handing out URLs under `base_url`, which
+    /// clients must be able to reach the server at.
+    pub fn new<P: Into<PathBuf>>(root: P, mut base_url: Url, secret: &[u8]) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(root.join(UPLOADS_DIR))?;
+        fs::create_dir_all(root.join(META_DIR))?;
+
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(Self {
+            root,
+            base_url,
+            secret: secret.to_vec(),
+        })
+    }
+
+    /// URL the object at `key` can be downloaded from.
+    pub fn public_url(&self, key: &str) -> String {
+        format!(
+            "{}{}{key}",
+            self.base_url.as_str().trim_end_matches('/'),
+            Self::PREFIX
+        )
+    }
+
+    fn object_path(&self, key: &str) -> StoreResult<PathBuf> {
+        Ok(self.root.join(relative_path(key)?))
+    }
+
+    fn etag_path(&self, key: &str) -> StoreResult<PathBuf> {
+        Ok(self.root.join(META_DIR).join(relative_path(key)?))
+    }
+
+    fn upload_dir(&self, upload_id: &str) -> StoreResult<PathBuf> {
+        if upload_id.len() != 32 || !upload_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(StoreError::Invalid(format!(
+                "Invalid upload ID {upload_id}"
+            )));
+        }
+        Ok(self.root.join(UPLOADS_DIR).join(upload_id))
+    }
+
+    /// Directory of an upload to `key`, if there is one in progress.
+    fn upload_for(&self, key: &str, upload_id: &str) -> StoreResult<Option<PathBuf>> {
+        let dir = self.upload_dir(upload_id)?;
+        match fs::read_to_string(dir.join("key")) {
+            Ok(upload_key) if upload_key == key => Ok(Some(dir)),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.root
+            .join(UPLOADS_DIR)
+            .join(format!("tmp-{:016x}", fastrand::u64(..)))
+    }
+
+    /// Move a finished temporary file into place as the object at `key`.
+    fn commit(&self, temp: &Path, key: &str, etag: &str) -> StoreResult<()> {
+        let path = self.object_path(key)?;
+        let etag_path = self.etag_path(key)?;
+        for parent in [path.parent(), etag_path.parent()].into_iter().flatten() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&etag_path, etag)?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    fn signature(
+        &self,
+        method: &str,
+        key: &str,
+        params: &[(String, String)],
+        copy_headers: (&str, &str),
+    ) -> String {
+        let mut params: Vec<String> = params
+            .iter()
+            .filter(|(k, _)| k != "X-Amz-Signature")
+            .map(|(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        params.sort();
+
+        let canonical = format!(
+            "{method}\n{key}\n{}\n{}\n{}",
+            params.join("&"),
+            copy_headers.0,
+            copy_headers.1
+        );
+        hex::encode(hmac(&self.secret, canonical.as_bytes()))
+    }
+
+    fn presign(
+        &self,
+        key: &str,
+        mut params: Vec<(String, String)>,
+        copy_headers: (&str, &str),
+    ) -> StoreResult<String> {
+        relative_path(key)?;
+        params.extend([
+            (
+                "X-Amz-Date".to_string(),
+                Utc::now().format(AMZ_DATE_FORMAT).to_string(),
+            ),
+            (
+                "X-Amz-Expires".to_string(),
+                PRESIGN_EXPIRES_SECS.to_string(),
+            ),
+        ]);
+        let signature = self.signature("PUT", key, &params, copy_headers);
+
+        let mut url = Url::parse(&self.public_url(key))
+            .map_err(|e| StoreError::Invalid(format!("Invalid key {key}: {e}")))?;
+        url.query_pairs_mut()
+            .extend_pairs(params)
+            .append_pair("X-Amz-Signature", &signature);
+        Ok(url.to_string())
+    }
+
+    /// Check signature and expiry of a presigned request.
+    fn verify(&self, req: &Request, key: &str, target: &Target) -> Result<(), Failure> {
+        let copy_source = http::header_value(req, "x-amz-copy-source").unwrap_or_default();
+        let copy_range = http::header_value(req, "x-amz-copy-source-range").unwrap_or_default();
+        let expected = self.signature(
+            req.method().as_str(),
+            key,
+            &target.query,
+            (&copy_source, &copy_range),
+        );
+        if target.param("X-Amz-Signature") != Some(expected.as_str()) {
+            return Err(Failure::new(
+                403,
+                "SignatureDoesNotMatch",
+                "The request signature does not match.",
+            ));
+        }
+
+        let date = target
+            .param("X-Amz-Date")
+            .and_then(|d| NaiveDateTime::parse_from_str(d, AMZ_DATE_FORMAT).ok());
+        let expires = target
+            .param("X-Amz-Expires")
+            .and_then(|e| e.parse::<i64>().ok())
+            .and_then(TimeDelta::try_seconds);
+        match (date, expires) {
+            (Some(date), Some(expires)) if date.and_utc() + expires >= Utc::now() => Ok(()),
+            _ => Err(Failure::new(403, "AccessDenied", "Request has expired")),
+        }
+    }
+
+    /// Answer a request for `/s3/{key}`.
+    pub(in crate::server) fn handle(
+        &self,
+        req: &mut Request,
+        key: &str,
+        target: &Target,
+    ) -> HttpResponse {
+        let ret = match req.method() {
+            Method::Get | Method::Head => self.get(req, key),
+            Method::Put => self
+                .verify(req, key, target)
+                .and_then(|_| self.put(req, key, target)),
+            _ => Err(Failure::new(
+                405,
+                "MethodNotAllowed",
+                "The specified method is not allowed against this resource.",
+            )),
+        };
+        ret.unwrap_or_else(Failure::respond)
+    }
+
+    fn get(&self, req: &Request, key: &str) -> Result<HttpResponse, Failure> {
+        let path = self.object_path(key)?;
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        let mut headers = vec![
+            http::header("Content-Type", "application/octet-stream"),
+            http::header("Accept-Ranges", "bytes"),
+            http::header("Access-Control-Allow-Origin", "*"),
+        ];
+        if let Ok(etag) = fs::read_to_string(self.etag_path(key)?) {
+            headers.push(http::header("ETag", &etag));
+        }
+
+        let range = http::header_value(req, "Range");
+        let Some(range) = range else {
+            return Ok(http::reader(
+                200,
+                headers,
+                BufReader::new(file),
+                Some(size as usize),
+            ));
+        };
+        let Some((from, to)) = http::parse_range(&range, size) else {
+            headers.push(http::header("Content-Range", &format!("bytes */{size}")));
+            return Ok(http::reader(416, headers, io::empty(), Some(0)));
+        };
+
+        file.seek(SeekFrom::Start(from))?;
+        let length = to - from + 1;
+        headers.push(http::header(
+            "Content-Range",
+            &format!("bytes {from}-{to}/{size}"),
+        ));
+        Ok(http::reader(
+            206,
+            headers,
+            BufReader::new(file).take(length),
+            Some(length as usize),
+        ))
+    }
+
+    fn put(&self, req: &mut Request, key: &str, target: &Target) -> Result<HttpResponse, Failure> {
+        let temp = self.temp_path();
+
+        let (Some(upload_id), Some(part_number)) =
+            (target.param("uploadId"), target.param("partNumber"))
+        else {
+            let digest = write_hashed(req.as_reader(), &temp)?;
+            check_content_md5(req, &digest, &temp)?;
+            let etag = multipart::part_etag(&digest);
+            self.commit(&temp, key, &etag)?;
+            return Ok(etag_response(&etag));
+        };
+
+        let part_number = part_number
+            .parse::<u64>()
+            .ok()
+            .filter(|n| (1..=MAX_PARTS).contains(n))
+            .ok_or_else(|| Failure::new(400, "InvalidArgument", "Invalid part number"))?;
+        let dir = self.upload_for(key, upload_id)?.ok_or_else(|| {
+            Failure::new(404, "NoSuchUpload", "The specified upload does not exist.")
+        })?;
+
+        let copy_source = http::header_value(req, "x-amz-copy-source");
+        let digest = match copy_source.as_deref() {
+            Some(source) => {
+                let mut file = File::open(self.object_path(source)?)?;
+                let size = file.metadata()?.len();
+                let range = http::header_value(req, "x-amz-copy-source-range");
+                let (from, to) = match range {
+                    Some(range) => http::parse_range(&range, size).ok_or_else(|| {
+                        Failure::new(400, "InvalidArgument", "Invalid copy source range")
+                    })?,
+                    None => (0, size.saturating_sub(1)),
+                };
+                file.seek(SeekFrom::Start(from))?;
+                write_hashed(BufReader::new(file).take(to + 1 - from), &temp)?
+            }
+            None => {
+                let digest = write_hashed(req.as_reader(), &temp)?;
+                check_content_md5(req, &digest, &temp)?;
+                digest
+            }
+        };
+
+        let etag = multipart::part_etag(&digest);
+        fs::write(dir.join(format!("{part_number}.etag")), &etag)?;
+        fs::rename(&temp, dir.join(part_number.to_string()))?;
+
+        if copy_source.is_some() {
+            let xml = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <CopyPartResult><LastModified>{}</LastModified><ETag>{etag}</ETag>\
+                 </CopyPartResult>",
+                Utc::now().to_rfc3339()
+            );
+            Ok(http::bytes(200, "application/xml", xml.into_bytes()))
+        } else {
+            Ok(etag_response(&etag))
+        }
+    }
+}
+
+/// Refuse a body that does not match the digest the client sent along.
+fn check_content_md5(req: &Request, digest: &[u8], temp: &Path) -> Result<(), Failure> {
+    match http::header_value(req, "Content-MD5") {
+        Some(expected) if expected != multipart::content_md5(digest) => {
+            let _ = fs::remove_file(temp);
+            Err(Failure::new(
+                400,
+                "BadDigest",
+                "The Content-MD5 you specified did not match what we received.",
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn etag_response(etag: &str) -> HttpResponse {
+    let mut res = http::empty(200);
+    res.add_header(http::header("ETag", etag));
+    res
+}
+
+fn trim_etag(etag: &str) -> &str {
+    etag.trim().trim_matches('"')
+}
+
+impl ObjectStore for LocalStore {
+    fn head(&self, key: &str) -> StoreResult<Option<ObjectInfo>> {
+        match fs::metadata(self.object_path(key)?) {
+            Ok(meta) => Ok(Some(ObjectInfo {
+                size: meta.len(),
+                etag: fs::read_to_string(self.etag_path(key)?).ok(),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn presign_put(&self, key: &str) -> StoreResult<String> {
+        self.presign(key, vec![], ("", ""))
+    }
+
+    fn create_multipart(&self, key: &str) -> StoreResult<String> {
+        relative_path(key)?;
+        let upload_id = uuid::Uuid::now_v7().simple().to_string();
+        let dir = self.upload_dir(&upload_id)?;
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("key"), key)?;
+        Ok(upload_id)
+    }
+
+    fn presign_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: u64,
+        copy: Option<CopyRange>,
+    ) -> StoreResult<String> {
+        let params = vec![
+            ("partNumber".to_string(), part_number.to_string()),
+            ("uploadId".to_string(), upload_id.to_string()),
+        ];
+        match copy {
+            Some(copy) => {
+                let range = format!("bytes={}-{}", copy.from, copy.to);
+                self.presign(key, params, (copy.source, &range))
+            }
+            None => self.presign(key, params, ("", "")),
+        }
+    }
+
+    fn complete_multipart(&self, key: &str, upload_id: &str, etags: &[String]) -> StoreResult<()> {
+        let dir = self
+            .upload_for(key, upload_id)?
+            .ok_or_else(|| StoreError::Invalid(format!("No upload {upload_id} to {key}")))?;
+        if etags.is_empty() {
+            return Err(StoreError::Invalid("No parts to complete".to_string()));
+        }
+
+        let temp = self.temp_path();
+        let mut out = File::create(&temp)?;
+        let mut digests = vec![];
+        for (i, etag) in etags.iter().enumerate() {
+            let part_number = i + 1;
+            let stored = fs::read_to_string(dir.join(format!("{part_number}.etag")))
+                .map_err(|_| StoreError::Invalid(format!("Part {part_number} was not uploaded")))?;
+            if trim_etag(&stored) != trim_etag(etag) {
+                return Err(StoreError::Invalid(format!(
+                    "ETag of part {part_number} does not match"
+                )));
+            }
+
+            let digest = hex::decode(trim_etag(&stored)).unwrap_or_default();
+            digests.push(digest.try_into().unwrap_or_default());
+            io::copy(
+                &mut File::open(dir.join(part_number.to_string()))?,
+                &mut out,
+            )?;
+        }
+        out.sync_all()?;
+
+        let etag = multipart::multipart_etag(&digests);
+        self.commit(&temp, key, &etag)?;
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    fn list_parts(&self, key: &str, upload_id: &str) -> StoreResult<Option<Vec<PartInfo>>> {
+        let Some(dir) = self.upload_for(key, upload_id)? else {
+            return Ok(None);
+        };
+
+        let mut parts = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Some(part_number) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            // Parts get their ETag once fully written.
+            let Ok(etag) = fs::read_to_string(dir.join(format!("{part_number}.etag"))) else {
+                continue;
+            };
+            parts.push(PartInfo {
+                part_number,
+                etag,
+                size: entry.metadata()?.len(),
+            });
+        }
+        parts.sort_by_key(|p| p.part_number);
+        Ok(Some(parts))
+    }
+
+    fn latest_multipart(&self, key: &str) -> StoreResult<Option<String>> {
+        // Upload IDs are UUIDv7, which sort by creation time.
+        let mut latest: Option<String> = None;
+        for entry in fs::read_dir(self.root.join(UPLOADS_DIR))? {
+            let entry = entry?;
+            let Some(upload_id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if self.upload_dir(&upload_id).is_ok()
+                && self.upload_for(key, &upload_id)?.is_some()
+                && latest.as_ref().is_none_or(|l| upload_id > *l)
+            {
+                latest = Some(upload_id);
+            }
+        }
+        Ok(latest)
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> StoreResult<()> {
+        if let Some(dir) = self.upload_for(key, upload_id)? {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> StoreResult<()> {
+        for path in [self.object_path(key)?, self.etag_path(key)?] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        assert!(relative_path("/koishi/video/1/abc").is_ok());
+        assert!(relative_path("koishi/video").is_err());
+        assert!(relative_path("/koishi/../etc").is_err());
+        assert!(relative_path("/.meta/koishi").is_err());
+        assert!(relative_path("/koishi//video").is_err());
+        assert!(relative_path("/koishi/vid eo").is_err());
+    }
+
+    #[test]
+    fn test_multipart_etag() {
+        let root = std::env::temp_dir().join(format!("koishi-store-{:016x}", fastrand::u64(..)));
+        let store = LocalStore::new(&root, Url::parse("http://localhost/").unwrap(), b"k").unwrap();
+        let key = "/koishi/video/1/abc";
+
+        let upload_id = store.create_multipart(key).unwrap();
+        let dir = store.upload_dir(&upload_id).unwrap();
+        let mut etags = vec![];
+        for (i, data) in [b"hello ".as_slice(), b"world"].into_iter().enumerate() {
+            let digest = write_hashed(data, &dir.join((i + 1).to_string())).unwrap();
+            let etag = multipart::part_etag(&digest);
+            fs::write(dir.join(format!("{}.etag", i + 1)), &etag).unwrap();
+            etags.push(etag);
+        }
+        store.complete_multipart(key, &upload_id, &etags).unwrap();
+
+        let info = store.head(key).unwrap().unwrap();
+        assert_eq!(info.size, 11);
+        // Same as S3: MD5 of the concatenated part MD5s, then the part count.
+        assert_eq!(
+            info.etag.as_deref(),
+            Some("\"e09e4fd6265b36115fe3db32df945d84-2\"")
+        );
+        assert_eq!(
+            fs::read(store.object_path(key).unwrap()).unwrap(),
+            b"hello world"
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_list_parts() {
+        let root = std::env::temp_dir().join(format!("koishi-store-{:016x}", fastrand::u64(..)));
+        let store = LocalStore::new(&root, Url::parse("http://localhost/").unwrap(), b"k").unwrap();
+        let key = "/koishi/video/1/abc";
+        assert_eq!(store.latest_multipart(key).unwrap(), None);
+
+        let first = store.create_multipart(key).unwrap();
+        let upload_id = store.create_multipart(key).unwrap();
+        store.create_multipart("/koishi/video/1/def").unwrap();
+        assert_eq!(
+            store.latest_multipart(key).unwrap(),
+            Some(upload_id.clone())
+        );
+        assert_eq!(store.list_parts(key, &first).unwrap(), Some(vec![]));
+        assert_eq!(
+            store.list_parts("/koishi/video/1/def", &first).unwrap(),
+            None
+        );
+
+        let dir = store.upload_dir(&upload_id).unwrap();
+        let digest = write_hashed(b"hello".as_slice(), &dir.join("2")).unwrap();
+        let etag = multipart::part_etag(&digest);
+        fs::write(dir.join("2.etag"), &etag).unwrap();
+        // Not fully written yet.
+        fs::write(dir.join("1"), b"hel").unwrap();
+
+        let parts = store.list_parts(key, &upload_id).unwrap().unwrap();
+        assert_eq!(
+            parts,
+            vec![PartInfo {
+                part_number: 2,
+                etag,
+                size: 5
+            }]
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
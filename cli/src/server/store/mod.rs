@@ -0,0 +1,151 @@
+//! Object storage behind the server.
+//!
+//! Keys follow the layout of `functions_libs/objects.ts`, bucket included, so
+//! that copy sources handed out to clients look the same on every backend.
+
+use serde::Serialize;
+use std::{fmt::Display, io};
+
+mod local;
+mod s3;
+mod sigv4;
+
+pub use local::LocalStore;
+pub use s3::{S3Config, S3Store};
+
+/// How long presigned URLs stay valid, matching the default of `aws4fetch`.
+pub const PRESIGN_EXPIRES_SECS: u64 = 86400;
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// Storage answered with an unexpected status.
+    Status {
+        status: u16,
+        body: String,
+    },
+    Request(reqwest::Error),
+    IO(io::Error),
+    /// Key, upload or part that the store refuses to work with.
+    Invalid(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Status { status, .. } => write!(f, "Status {status} from object storage"),
+            Self::Request(err) => write!(f, "Request to object storage failed: {err}"),
+            Self::IO(err) => write!(f, "Object storage I/O error: {err}"),
+            Self::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<reqwest::Error> for StoreError {
+    fn from(value: reqwest::Error) -> Self {
+        StoreError::Request(value)
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(value: io::Error) -> Self {
+        StoreError::IO(value)
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+pub struct ObjectInfo {
+    pub size: u64,
+    pub etag: Option<String>,
+}
+
+/// Part stored so far for a multipart upload.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PartInfo {
+    pub part_number: u64,
+    pub etag: String,
+    pub size: u64,
+}
+
+/// Byte range of the source object copied into one part, both ends included.
+pub struct CopyRange<'a> {
+    pub source: &'a str,
+    pub from: u64,
+    pub to: u64,
+}
+
+pub trait ObjectStore: Send + Sync {
+    /// Size and ETag of the object at `key`, or `None` if there is none.
+    fn head(&self, key: &str) -> StoreResult<Option<ObjectInfo>>;
+
+    /// URL that lets its holder PUT the object at `key`.
+    fn presign_put(&self, key: &str) -> StoreResult<String>;
+
+    /// Start a multipart upload to `key` and return its upload ID.
+    fn create_multipart(&self, key: &str) -> StoreResult<String>;
+
+    /// URL that lets its holder PUT part `part_number`, counting from 1, of
+    /// an upload, or copy a range of another object into it.
+    fn presign_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: u64,
+        copy: Option<CopyRange>,
+    ) -> StoreResult<String>;
+
+    /// Assemble the parts with the given ETags, in order, into the object.
+    fn complete_multipart(&self, key: &str, upload_id: &str, etags: &[String]) -> StoreResult<()>;
+
+    /// Parts stored so far for an upload to `key`, ordered by part number,
+    /// or `None` if there is no such upload.
+    fn list_parts(&self, key: &str, upload_id: &str) -> StoreResult<Option<Vec<PartInfo>>>;
+
+    /// ID of the most recently started upload to `key` still in progress.
+    fn latest_multipart(&self, key: &str) -> StoreResult<Option<String>>;
+
+    /// Discard an upload and the parts sent so far; aborting an upload that
+    /// is gone already is fine.
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> StoreResult<()>;
+
+    /// Remove the object at `key`; removing a missing object is fine.
+    fn delete(&self, key: &str) -> StoreResult<()>;
+}
+
+pub fn cover_key(bucket: &str, hash: &str) -> String {
+    format!("/{bucket}/cover/{}", hash.to_lowercase())
+}
+
+pub fn video_key_unrestricted(bucket: &str, room: i64, uuid: &str) -> String {
+    format!("/{bucket}/video/{room}/{}", uuid.to_lowercase())
+}
+
+pub fn video_key_restricted(bucket: &str, room: i64, hash: &str) -> String {
+    format!("/{bucket}/video_restricted/{room}/{}", hash.to_lowercase())
+}
+
+pub fn metadata_key(bucket: &str, uuid: &str) -> String {
+    format!("/{bucket}/metadata/{}", uuid.to_lowercase())
+}
+
+/// `CompleteMultipartUpload` request body listing `etags` as parts 1..=n.
+pub(crate) fn complete_multipart_xml(etags: &[String]) -> String {
+    let parts: Vec<String> = etags
+        .iter()
+        .enumerate()
+        .map(|(i, etag)| {
+            format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                etag
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <CompleteMultipartUpload>\n{}\n</CompleteMultipartUpload>",
+        parts.join("\n")
+    )
+}
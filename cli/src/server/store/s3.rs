@@ -0,0 +1,319 @@
+use chrono::Utc;
+use quick_xml::de::from_str;
+use reqwest::{Method, blocking::Client};
+use serde::Deserialize;
+use url::Url;
+
+use super::{
+    CopyRange, ObjectInfo, ObjectStore, PRESIGN_EXPIRES_SECS, PartInfo, StoreError, StoreResult,
+    complete_multipart_xml,
+    sigv4::{self, Credentials},
+};
+
+/// Connection details of an S3-compatible service, the same ones the
+/// Cloudflare deployment takes from `S3_ENDPOINT`, `S3_KEY_ID` and `S3_KEY`.
+#[derive(Clone)]
+pub struct S3Config {
+    /// Path-style endpoint, e.g. `https://<account>.r2.cloudflarestorage.com`.
+    pub endpoint: Url,
+    pub key_id: String,
+    pub key: String,
+    /// Signing region; R2 accepts `auto`, MinIO usually wants `us-east-1`.
+    pub region: String,
+}
+
+/// Store backed by an S3-compatible service, addressed path-style.
+pub struct S3Store {
+    config: S3Config,
+    http: Client,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+    upload_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListPartsResult {
+    #[serde(default)]
+    is_truncated: bool,
+    next_part_number_marker: Option<u64>,
+    #[serde(default, rename = "Part")]
+    parts: Vec<ListedPart>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedPart {
+    part_number: u64,
+    #[serde(rename = "ETag")]
+    etag: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListMultipartUploadsResult {
+    #[serde(default)]
+    is_truncated: bool,
+    next_key_marker: Option<String>,
+    next_upload_id_marker: Option<String>,
+    #[serde(default, rename = "Upload")]
+    uploads: Vec<ListedUpload>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedUpload {
+    key: String,
+    upload_id: String,
+    /// ISO 8601, which sorts by time as it is.
+    initiated: String,
+}
+
+impl S3Store {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            config,
+            http: Client::new(),
+        }
+    }
+
+    fn credentials(&self) -> Credentials<'_> {
+        Credentials {
+            key_id: &self.config.key_id,
+            secret: &self.config.key,
+            region: &self.config.region,
+        }
+    }
+
+    fn url(&self, key: &str, query: &[(&str, &str)]) -> StoreResult<Url> {
+        let mut url = self
+            .config
+            .endpoint
+            .join(key)
+            .map_err(|e| StoreError::Invalid(format!("Invalid key {key}: {e}")))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url)
+    }
+
+    /// Read an XML reply of a listing request.
+    fn list<T: serde::de::DeserializeOwned>(&self, url: Url, what: &str) -> StoreResult<Option<T>> {
+        let res = self.send(Method::GET, url, vec![])?;
+        match res.status().as_u16() {
+            200 => {}
+            404 => return Ok(None),
+            _ => return Err(unexpected(res)),
+        }
+        let text = res.text()?;
+        from_str(&text)
+            .map(Some)
+            .map_err(|e| StoreError::Invalid(format!("Invalid {what} reply: {e}")))
+    }
+
+    /// Send a request signed with the server's credentials.
+    fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Vec<u8>,
+    ) -> StoreResult<reqwest::blocking::Response> {
+        let headers = sigv4::authorization_headers(
+            &self.credentials(),
+            method.as_str(),
+            &url,
+            &body,
+            Utc::now(),
+        );
+
+        let mut req = self.http.request(method, url);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        Ok(req.body(body).send()?)
+    }
+
+    fn presign(&self, method: &str, url: &Url, headers: &[(String, String)]) -> String {
+        sigv4::presign(
+            &self.credentials(),
+            method,
+            url,
+            headers,
+            PRESIGN_EXPIRES_SECS,
+            Utc::now(),
+        )
+        .to_string()
+    }
+}
+
+fn unexpected(res: reqwest::blocking::Response) -> StoreError {
+    let status = res.status().as_u16();
+    let body = res.text().unwrap_or_default();
+    log::warn!("Object storage returned {status}: {body}");
+    StoreError::Status { status, body }
+}
+
+fn header<'a>(res: &'a reqwest::blocking::Response, name: &str) -> Option<&'a str> {
+    res.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+impl ObjectStore for S3Store {
+    fn head(&self, key: &str) -> StoreResult<Option<ObjectInfo>> {
+        let res = self.send(Method::HEAD, self.url(key, &[])?, vec![])?;
+        match res.status().as_u16() {
+            // `content_length()` reports the (empty) body of a HEAD reply,
+            // so the object size has to come from the header itself.
+            200 => Ok(Some(ObjectInfo {
+                size: header(&res, "Content-Length")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_default(),
+                etag: header(&res, "ETag").map(str::to_string),
+            })),
+            404 => Ok(None),
+            _ => Err(unexpected(res)),
+        }
+    }
+
+    fn presign_put(&self, key: &str) -> StoreResult<String> {
+        Ok(self.presign("PUT", &self.url(key, &[])?, &[]))
+    }
+
+    fn create_multipart(&self, key: &str) -> StoreResult<String> {
+        let res = self.send(Method::POST, self.url(key, &[("uploads", "")])?, vec![])?;
+        if !res.status().is_success() {
+            return Err(unexpected(res));
+        }
+
+        let text = res.text()?;
+        let result: InitiateMultipartUploadResult = from_str(&text).map_err(|e| {
+            StoreError::Invalid(format!("Invalid CreateMultipartUpload reply: {e}"))
+        })?;
+        Ok(result.upload_id)
+    }
+
+    fn presign_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: u64,
+        copy: Option<CopyRange>,
+    ) -> StoreResult<String> {
+        let part_number = part_number.to_string();
+        let url = self.url(
+            key,
+            &[("partNumber", &part_number), ("uploadId", upload_id)],
+        )?;
+
+        let headers = match copy {
+            Some(copy) => vec![
+                ("x-amz-copy-source".to_string(), copy.source.to_string()),
+                (
+                    "x-amz-copy-source-range".to_string(),
+                    format!("bytes={}-{}", copy.from, copy.to),
+                ),
+            ],
+            None => vec![],
+        };
+        Ok(self.presign("PUT", &url, &headers))
+    }
+
+    fn complete_multipart(&self, key: &str, upload_id: &str, etags: &[String]) -> StoreResult<()> {
+        let url = self.url(key, &[("uploadId", upload_id)])?;
+        let res = self.send(
+            Method::POST,
+            url,
+            complete_multipart_xml(etags).into_bytes(),
+        )?;
+        // S3 may report a failed completion with status 200 and an <Error>
+        // body, which the Cloudflare deployment does not look for either.
+        if res.status().as_u16() != 200 {
+            return Err(unexpected(res));
+        }
+        Ok(())
+    }
+
+    fn list_parts(&self, key: &str, upload_id: &str) -> StoreResult<Option<Vec<PartInfo>>> {
+        let mut parts = vec![];
+        let mut marker = 0;
+        loop {
+            let marker_str = marker.to_string();
+            let url = self.url(
+                key,
+                &[("uploadId", upload_id), ("part-number-marker", &marker_str)],
+            )?;
+            let Some(result) = self.list::<ListPartsResult>(url, "ListParts")? else {
+                return Ok(None);
+            };
+            parts.extend(result.parts.into_iter().map(|p| PartInfo {
+                part_number: p.part_number,
+                etag: p.etag,
+                size: p.size,
+            }));
+            match result.next_part_number_marker {
+                Some(next) if result.is_truncated && next > marker => marker = next,
+                _ => return Ok(Some(parts)),
+            }
+        }
+    }
+
+    fn latest_multipart(&self, key: &str) -> StoreResult<Option<String>> {
+        // Keys start with the bucket, which ListMultipartUploads addresses
+        // separately from the prefix of the object key.
+        let (bucket, object_key) = key
+            .trim_start_matches('/')
+            .split_once('/')
+            .ok_or_else(|| StoreError::Invalid(format!("Invalid key {key}")))?;
+        let bucket = format!("/{bucket}");
+
+        let mut latest: Option<ListedUpload> = None;
+        let mut markers: Option<(String, String)> = None;
+        loop {
+            let mut query = vec![("uploads", ""), ("prefix", object_key)];
+            if let Some((key_marker, upload_id_marker)) = &markers {
+                query.push(("key-marker", key_marker));
+                query.push(("upload-id-marker", upload_id_marker));
+            }
+            let url = self.url(&bucket, &query)?;
+            let Some(result) =
+                self.list::<ListMultipartUploadsResult>(url, "ListMultipartUploads")?
+            else {
+                return Ok(None);
+            };
+
+            for upload in result.uploads {
+                if upload.key == object_key
+                    && latest
+                        .as_ref()
+                        .is_none_or(|l| upload.initiated > l.initiated)
+                {
+                    latest = Some(upload);
+                }
+            }
+            match (result.next_key_marker, result.next_upload_id_marker) {
+                (Some(k), Some(u)) if result.is_truncated => markers = Some((k, u)),
+                _ => return Ok(latest.map(|l| l.upload_id)),
+            }
+        }
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> StoreResult<()> {
+        let url = self.url(key, &[("uploadId", upload_id)])?;
+        let res = self.send(Method::DELETE, url, vec![])?;
+        match res.status().as_u16() {
+            200 | 204 | 404 => Ok(()),
+            _ => Err(unexpected(res)),
+        }
+    }
+
+    fn delete(&self, key: &str) -> StoreResult<()> {
+        let res = self.send(Method::DELETE, self.url(key, &[])?, vec![])?;
+        match res.status().as_u16() {
+            200 | 204 | 404 => Ok(()),
+            _ => Err(unexpected(res)),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::{fs, path::Path, process::Command};
+
+/// The `koishi` binary with only `env` set, using the config file at
+/// `config` from the directory `cwd`.
+fn koishi(config: &Path, cwd: &Path, env: &[(&str, &str)]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_koishi"));
+    cmd.env_clear()
+        .env("KOISHI_CONFIG", config)
+        .envs(env.iter().copied())
+        .current_dir(cwd);
+    cmd
+}
+
+fn show(mut cmd: Command) -> String {
+    let output = cmd
+        .args(["config", "show", "--show-secrets"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_auth_key_file_on_command_line_wins_over_env() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("cli.key");
+    fs::write(&key_file, "from-file\n").unwrap();
+
+    let mut cmd = koishi(
+        &dir.path().join("config.toml"),
+        dir.path(),
+        &[("KOISHI_AUTH_KEY", "from-env")],
+    );
+    cmd.arg("--auth-key-file").arg(&key_file);
+    let shown = show(cmd);
+    assert!(shown.contains("from-file  (command line)"), "{shown}");
+}
+
+#[test]
+fn test_profile_key_file_is_relative_to_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_dir = dir.path().join("conf");
+    fs::create_dir(&config_dir).unwrap();
+    fs::write(config_dir.join("home.key"), "from-profile\n").unwrap();
+    let config = config_dir.join("config.toml");
+    fs::write(
+        &config,
+        "[profiles.default]\nauth_key_file = \"home.key\"\n",
+    )
+    .unwrap();
+
+    let shown = show(koishi(&config, dir.path(), &[]));
+    assert!(shown.contains("from-profile  (profile)"), "{shown}");
+}
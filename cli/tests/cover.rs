@@ -0,0 +1,79 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use support::{BUCKET, Fault, TestEnv, stdout_json};
+
+#[test]
+fn test_set_cover_uploads_once() {
+    let env = TestEnv::new();
+    let first = env.create_video(None);
+    let second = env.create_video(None);
+    let (path, data) = env.random_file("cover.jpg", 4096);
+
+    let output = env.run(&[
+        "video",
+        "set-cover",
+        "--output",
+        "json",
+        &first,
+        path.to_str().unwrap(),
+    ]);
+    let result = stdout_json(&output);
+    assert_eq!(result["uploaded"], true);
+    let hash = result["cover"].as_str().unwrap().to_string();
+    assert!(
+        env.s3
+            .object(&format!("/{BUCKET}/cover/{hash}"))
+            .unwrap()
+            .data
+            == data
+    );
+
+    let puts = env
+        .s3
+        .requests()
+        .iter()
+        .filter(|r| r.method == "PUT")
+        .count();
+    let output = env.run(&[
+        "video",
+        "set-cover",
+        "--output",
+        "json",
+        &second,
+        path.to_str().unwrap(),
+    ]);
+    let result = stdout_json(&output);
+    assert_eq!(result["uploaded"], false);
+    assert_eq!(result["cover"], hash.as_str());
+    assert_eq!(
+        env.s3
+            .requests()
+            .iter()
+            .filter(|r| r.method == "PUT")
+            .count(),
+        puts
+    );
+
+    let video = koishi::api::video::get(&env.client(), &second).unwrap();
+    assert_eq!(video.cover.as_deref(), Some(hash.as_str()));
+}
+
+#[test]
+fn test_set_cover_storage_failure() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("cover.jpg", 4096);
+
+    env.s3.inject(Fault::method("HEAD").always());
+    let output = env.run(&[
+        "--api-retry",
+        "1",
+        "video",
+        "set-cover",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(7));
+}
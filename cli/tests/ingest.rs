@@ -0,0 +1,135 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use std::{fs, path::PathBuf};
+
+use support::{BUCKET, Fault, MIB, ROOM, TestEnv, assert_success, stdout_json};
+
+/// Write a video and its XML and cover to the test directory.
+fn write_recording(env: &TestEnv, size: u64) -> (PathBuf, Vec<u8>) {
+    fs::write(
+        env.dir.path().join("a.xml"),
+        format!(
+            "<i><metadata><room_id>{ROOM}</room_id><room_title>Ingested</room_title>\
+             <live_start_time>2025-01-01T12:00:00+08:00</live_start_time>\
+             <record_start_time>2025-01-01T12:05:00+08:00</record_start_time></metadata></i>"
+        ),
+    )
+    .unwrap();
+    fs::write(env.dir.path().join("cover.jpg"), "cover").unwrap();
+    env.random_file("a.flv", size)
+}
+
+fn ingest(env: &TestEnv, extra: &[&str]) -> std::process::Output {
+    let mut args = vec!["video", "ingest", "--output", "json"];
+    args.extend(extra);
+    args.push("a.flv");
+    env.run(&args)
+}
+
+fn journal(env: &TestEnv) -> serde_json::Value {
+    serde_json::from_slice(&fs::read(env.dir.path().join("a.ingest")).unwrap()).unwrap()
+}
+
+#[test]
+fn test_ingest() {
+    let env = TestEnv::new();
+    let (_, data) = write_recording(&env, 3 * MIB);
+
+    let output = ingest(&env, &["--cover", "cover.jpg"]);
+    assert_success(&output);
+    let uuid = stdout_json(&output)["uuid"].as_str().unwrap().to_string();
+
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert!(
+        env.s3
+            .object(&format!("/{BUCKET}/metadata/{uuid}"))
+            .is_some()
+    );
+    let video = koishi::api::video::get(&env.client(), &uuid).unwrap();
+    assert_eq!(video.title, "Ingested");
+    assert!(video.cover.is_some());
+    assert!(!env.dir.path().join("a.ingest").exists());
+}
+
+#[test]
+fn test_ingest_resumes_at_failed_step() {
+    let env = TestEnv::new();
+    let (_, data) = write_recording(&env, 3 * MIB);
+
+    env.s3.inject(Fault::part(1).always());
+    let output = ingest(&env, &[]);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--rollback"));
+    let journal = journal(&env);
+    assert_eq!(journal["step"], "upload");
+    let uuid = journal["uuid"].as_str().unwrap().to_string();
+    // Created before the upload failed, and not again on resuming.
+    koishi::api::video::get(&env.client(), &uuid).unwrap();
+
+    // Files other than the ones it was started with are refused.
+    let attempts = env.s3.part_attempts(1);
+    let output = ingest(&env, &["--cover", "cover.jpg"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("without --cover"), "{stderr}");
+    let output = ingest(&env, &["--xml", "a.flv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(env.s3.part_attempts(1), attempts);
+
+    env.s3.clear_faults();
+    let output = ingest(&env, &["--xml", "a.xml"]);
+    assert_success(&output);
+    assert_eq!(stdout_json(&output)["uuid"], uuid.as_str());
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert!(!env.dir.path().join("a.ingest").exists());
+}
+
+#[test]
+fn test_ingest_rollback() {
+    let env = TestEnv::new();
+    write_recording(&env, 3 * MIB);
+    let output = ingest(&env, &["--rollback"]);
+    assert_eq!(output.status.code(), Some(4));
+
+    env.s3.inject(Fault::part(1).always());
+    let output = ingest(&env, &["--password", "secret"]);
+    assert_eq!(output.status.code(), Some(7));
+    let uuid = journal(&env)["uuid"].as_str().unwrap().to_string();
+    assert_eq!(env.s3.pending_uploads(), 1);
+
+    // Going on with a restricted video needs its password.
+    let output = ingest(&env, &[]);
+    assert_eq!(output.status.code(), Some(2));
+
+    env.s3.clear_faults();
+    let output = ingest(&env, &["--rollback", "--password", "secret"]);
+    assert_success(&output);
+    assert_eq!(env.s3.pending_uploads(), 0);
+    let Err(err) = koishi::api::video::get(&env.client(), &uuid) else {
+        panic!("video {uuid} was not deleted");
+    };
+    assert_eq!(koishi::Error::from(err).kind(), koishi::ErrorKind::NotFound);
+    assert!(!env.dir.path().join("a.ingest").exists());
+}
+
+#[test]
+fn test_delete_video() {
+    let env = TestEnv::new();
+    write_recording(&env, MIB);
+    let output = ingest(&env, &[]);
+    assert_success(&output);
+    let uuid = stdout_json(&output)["uuid"].as_str().unwrap().to_string();
+
+    let client = env.client();
+    koishi::api::video::delete(&client, &uuid, None).unwrap();
+    assert!(env.s3.object(&env.video_key(&uuid)).is_none());
+    assert!(
+        env.s3
+            .object(&format!("/{BUCKET}/metadata/{uuid}"))
+            .is_none()
+    );
+    let err = koishi::api::video::delete(&client, &uuid, None).unwrap_err();
+    assert_eq!(koishi::Error::from(err).kind(), koishi::ErrorKind::NotFound);
+}
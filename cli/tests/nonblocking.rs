@@ -0,0 +1,89 @@
+#![cfg(all(feature = "server", feature = "async"))]
+
+mod support;
+
+use koishi::{
+    ErrorKind,
+    api::video,
+    helpers::cryptography::restricted_hash,
+    nonblocking::transfer::{self, TransferOptions},
+};
+use support::{MIB, TestEnv};
+
+const PART_SIZE: u64 = 5 * MIB;
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[test]
+fn test_async_upload_file() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    let outcome = block_on(transfer::upload_file(
+        &env.async_client(),
+        &uuid,
+        &path,
+        PART_SIZE,
+        None,
+        &TransferOptions::default(),
+    ))
+    .unwrap();
+    assert_eq!(outcome.video.uuid, uuid);
+    assert_eq!(env.s3.part_attempts(3), 1);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+}
+
+#[test]
+fn test_async_restrict_copies_video() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (_, data) = env.random_file("video.mp4", 11 * MIB);
+    env.s3.put_object(&env.video_key(&uuid), data.clone());
+
+    let hash = restricted_hash(&uuid, "pw").unwrap();
+    let copied = block_on(transfer::restrict(
+        &env.async_client(),
+        &uuid,
+        true,
+        &hash,
+        PART_SIZE,
+        &TransferOptions::default(),
+    ))
+    .unwrap();
+    assert!(copied);
+    assert!(env.s3.object(&env.video_key(&uuid)).is_none());
+    let object = env.s3.object(&env.restricted_video_key(&uuid, "pw"));
+    assert!(object.unwrap().data == data);
+    let video = video::get(&env.client(), &uuid).unwrap();
+    assert!(bool::from(&video.restricted));
+}
+
+#[test]
+fn test_async_restrict_refuses_invalid_part_size() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    env.s3
+        .put_object(&env.video_key(&uuid), vec![0; 11 * MIB as usize]);
+
+    let hash = restricted_hash(&uuid, "pw").unwrap();
+    let err = block_on(transfer::restrict(
+        &env.async_client(),
+        &uuid,
+        true,
+        &hash,
+        MIB,
+        &TransferOptions::default(),
+    ))
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::BadInput);
+    assert!(env.s3.object(&env.video_key(&uuid)).is_some());
+    let video = video::get(&env.client(), &uuid).unwrap();
+    assert!(!bool::from(&video.restricted));
+}
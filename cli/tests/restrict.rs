@@ -0,0 +1,180 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use support::{Fault, MIB, TestEnv, assert_success, stdout_json};
+
+const PART_SIZE: &str = "5242880";
+
+#[test]
+fn test_restrict_copies_video() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let data: Vec<u8> = (0..11 * MIB).map(|i| (i % 251) as u8).collect();
+    env.s3.put_object(&env.video_key(&uuid), data.clone());
+
+    let output = env.run(&[
+        "video", "restrict", "-P", "-p", "pw", "-s", PART_SIZE, "--output", "json", &uuid,
+    ]);
+    let result = stdout_json(&output);
+    assert_eq!(result["restricted"], true);
+    assert_eq!(result["copied"], true);
+
+    let copies = env.s3.requests().iter().filter(|r| r.copy).count();
+    assert_eq!(copies, 3);
+    assert!(env.s3.object(&env.video_key(&uuid)).is_none());
+    let restricted = env
+        .s3
+        .object(&env.restricted_video_key(&uuid, "pw"))
+        .unwrap();
+    assert!(restricted.data == data);
+
+    let output = env.run(&[
+        "video",
+        "unrestrict",
+        "-P",
+        "-p",
+        "pw",
+        "-s",
+        PART_SIZE,
+        &uuid,
+    ]);
+    assert_success(&output);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert!(
+        env.s3
+            .object(&env.restricted_video_key(&uuid, "pw"))
+            .is_none()
+    );
+}
+
+#[test]
+fn test_restrict_progress_events() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    env.s3
+        .put_object(&env.video_key(&uuid), vec![0; (11 * MIB) as usize]);
+
+    let output = env.run(&[
+        "video",
+        "restrict",
+        "--progress",
+        "jsonl",
+        "-p",
+        "pw",
+        "-s",
+        PART_SIZE,
+        &uuid,
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let events: Vec<serde_json::Value> = stderr
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events[0]["event"], "started", "{stderr}");
+    let done = events.iter().filter(|e| e["event"] == "part_done").count();
+    assert_eq!(done, 3, "{stderr}");
+    let last = events.last().unwrap();
+    assert_eq!(last["event"], "finished", "{stderr}");
+    assert_eq!(last["bytes"], 11 * MIB);
+    assert_eq!(last["total_bytes"], 11 * MIB);
+}
+
+#[test]
+fn test_restrict_refuses_invalid_part_size() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    env.s3
+        .put_object(&env.video_key(&uuid), vec![0; 11 * MIB as usize]);
+
+    let output = env.run(&["video", "restrict", "-P", "-p", "pw", "-s", "0", &uuid]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(env.s3.requests().is_empty());
+
+    // Too small only for this video, which the server reports the size of.
+    let output = env.run(&[
+        "video", "restrict", "-P", "-p", "pw", "-s", "1048576", &uuid,
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(env.s3.pending_uploads(), 0);
+    assert!(env.s3.object(&env.video_key(&uuid)).is_some());
+    assert!(
+        env.s3
+            .object(&env.restricted_video_key(&uuid, "pw"))
+            .is_none()
+    );
+    let video = koishi::api::video::get(&env.client(), &uuid).unwrap();
+    assert!(!bool::from(&video.restricted));
+}
+
+#[test]
+fn test_restrict_without_upload() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+
+    let output = env.run(&[
+        "video", "restrict", "-P", "-p", "pw", "--output", "json", &uuid,
+    ]);
+    let result = stdout_json(&output);
+    assert_eq!(result["copied"], false);
+    assert!(env.s3.requests().iter().all(|r| r.method == "HEAD"));
+}
+
+#[test]
+fn test_unrestrict_wrong_password() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(Some("pw"));
+
+    let output = env.run(&["video", "unrestrict", "-P", "-p", "nope", &uuid]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn test_restrict_copy_failure_keeps_source() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let data = vec![7; MIB as usize];
+    env.s3.put_object(&env.video_key(&uuid), data.clone());
+
+    env.s3.inject(Fault::part(1).always());
+    let output = env.run(&["video", "restrict", "-P", "-p", "pw", &uuid]);
+    assert_eq!(output.status.code(), Some(7));
+
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert!(
+        env.s3
+            .object(&env.restricted_video_key(&uuid, "pw"))
+            .is_none()
+    );
+}
+
+#[test]
+fn test_restrict_resumes_copy() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let data: Vec<u8> = (0..11 * MIB).map(|i| (i % 251) as u8).collect();
+    env.s3.put_object(&env.video_key(&uuid), data.clone());
+    let args = [
+        "video", "restrict", "-P", "-p", "pw", "-s", PART_SIZE, &uuid,
+    ];
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env.run(&args);
+    assert_eq!(output.status.code(), Some(7));
+
+    env.s3.clear_faults();
+    let output = env.run(&args);
+    assert_success(&output);
+    assert_eq!(env.s3.part_attempts(1), 1);
+    assert_eq!(env.s3.part_attempts(2), 1);
+    assert_eq!(env.s3.pending_uploads(), 0);
+    let restricted = env
+        .s3
+        .object(&env.restricted_video_key(&uuid, "pw"))
+        .unwrap();
+    assert!(restricted.data == data);
+    let copies = env.dir.path().join("state/koishi/copies");
+    assert_eq!(std::fs::read_dir(copies).unwrap().count(), 0);
+}
@@ -0,0 +1,82 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use koishi::helpers::s3::{S3UploaderError, Uploader};
+use support::{FakeS3, Fault};
+
+/// The fake only checks that a signature is present.
+fn presigned(s3: &FakeS3, key: &str, query: &str) -> String {
+    format!("{}{}?{query}&X-Amz-Signature=x", s3.endpoint(), &key[1..])
+}
+
+fn start_upload(s3: &FakeS3, key: &str) -> String {
+    let res = reqwest::blocking::Client::new()
+        .post(format!("{}{}?uploads", s3.endpoint(), &key[1..]))
+        .header("Authorization", "AWS4-HMAC-SHA256 test")
+        .send()
+        .unwrap()
+        .text()
+        .unwrap();
+    let from = res.find("<UploadId>").unwrap() + "<UploadId>".len();
+    let to = res.find("</UploadId>").unwrap();
+    res[from..to].to_string()
+}
+
+#[test]
+fn test_upload_etag_from_header() {
+    let s3 = FakeS3::start();
+    let upload_id = start_upload(&s3, "/b/video");
+
+    let url = presigned(
+        &s3,
+        "/b/video",
+        &format!("partNumber=1&uploadId={upload_id}"),
+    );
+    let res = Uploader::new()
+        .unwrap()
+        .url(url)
+        .body(b"hello".to_vec())
+        .upload()
+        .unwrap();
+    assert_eq!(res.etag, "\"5d41402abc4b2a76b9719d911017c592\"");
+}
+
+#[test]
+fn test_upload_etag_from_copy_part_result() {
+    let s3 = FakeS3::start();
+    s3.put_object("/b/source", b"hello world".to_vec());
+    let upload_id = start_upload(&s3, "/b/target");
+
+    let url = presigned(
+        &s3,
+        "/b/target",
+        &format!("partNumber=1&uploadId={upload_id}"),
+    );
+    let res = Uploader::new()
+        .unwrap()
+        .url(url)
+        .copy("/b/source")
+        .copy_range_from_to(0, 4)
+        .upload()
+        .unwrap();
+    assert_eq!(res.etag, "\"5d41402abc4b2a76b9719d911017c592\"");
+}
+
+#[test]
+fn test_upload_error_status() {
+    let s3 = FakeS3::start();
+    s3.inject(Fault::method("PUT").status(503));
+
+    let err = Uploader::new()
+        .unwrap()
+        .url(presigned(&s3, "/b/cover", "X-Amz-Expires=60"))
+        .body(b"x".to_vec())
+        .upload()
+        .err()
+        .unwrap();
+    match err {
+        S3UploaderError::S3(err) => assert_eq!(err.to_string(), "S3Error 503"),
+        err => panic!("Unexpected error {err}"),
+    }
+}
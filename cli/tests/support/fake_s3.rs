@@ -0,0 +1,571 @@
+//! In-memory stand-in for an S3-compatible service.
+//!
+//! Understands the subset of the S3 API the server and the CLI use: object
+//! PUT, GET, HEAD and DELETE, multipart uploads with UploadPart and
+//! UploadPartCopy, their listing, and completion. Signatures are only checked for presence;
+//! the SigV4 implementation has unit tests of its own, while the expiry of
+//! presigned URLs and `Content-MD5` are checked like S3 does.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use tiny_http::{Header, Request, Response, StatusCode};
+use url::Url;
+
+const THREADS: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct Object {
+    pub data: Vec<u8>,
+    pub etag: String,
+}
+
+impl Object {
+    fn new(data: Vec<u8>) -> Self {
+        let etag = format!("\"{}\"", hex::encode(Md5::digest(&data)));
+        Self { data, etag }
+    }
+}
+
+/// Request as seen by the fake, recorded before any fault applies.
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: String,
+    pub key: String,
+    pub part_number: Option<u64>,
+    pub copy: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Status(u16),
+    Delay(Duration),
+    /// Flip a byte of the body, as a faulty network path might.
+    Corrupt,
+}
+
+/// Misbehaviour injected into matching requests, a limited number of times.
+#[derive(Clone, Debug)]
+pub struct Fault {
+    method: Option<&'static str>,
+    part_number: Option<u64>,
+    remaining: usize,
+    action: Action,
+}
+
+impl Fault {
+    /// Fail uploads and copies of part `part_number`.
+    pub fn part(part_number: u64) -> Self {
+        Self {
+            method: Some("PUT"),
+            part_number: Some(part_number),
+            remaining: 1,
+            action: Action::Status(500),
+        }
+    }
+
+    /// Fail any request with `method`.
+    pub fn method(method: &'static str) -> Self {
+        Self {
+            method: Some(method),
+            part_number: None,
+            remaining: 1,
+            action: Action::Status(500),
+        }
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.action = Action::Status(status);
+        self
+    }
+
+    /// Hold the request for `delay` before handling it normally.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.action = Action::Delay(delay);
+        self
+    }
+
+    pub fn corrupt(mut self) -> Self {
+        self.action = Action::Corrupt;
+        self
+    }
+
+    pub fn times(mut self, times: usize) -> Self {
+        self.remaining = times;
+        self
+    }
+
+    pub fn always(self) -> Self {
+        self.times(usize::MAX)
+    }
+
+    fn matches(&self, req: &Recorded) -> bool {
+        self.remaining > 0
+            && self.method.is_none_or(|m| m == req.method)
+            && self.part_number.is_none_or(|n| Some(n) == req.part_number)
+    }
+}
+
+struct Upload {
+    key: String,
+    /// Order in which uploads were started, standing in for `Initiated`.
+    initiated: u64,
+    parts: BTreeMap<u64, Object>,
+}
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<String, Object>,
+    uploads: HashMap<String, Upload>,
+    requests: Vec<Recorded>,
+    faults: Vec<Fault>,
+    next_upload: u64,
+}
+
+struct Inner {
+    http: tiny_http::Server,
+    state: Mutex<State>,
+}
+
+pub struct FakeS3 {
+    inner: Arc<Inner>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+type Reply = Response<Cursor<Vec<u8>>>;
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn header_value(req: &Request, name: &str) -> Option<String> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+fn xml(status: u16, body: String) -> Reply {
+    Response::from_data(body.into_bytes())
+        .with_status_code(StatusCode(status))
+        .with_header(header("Content-Type", "application/xml"))
+}
+
+fn error(status: u16, code: &str) -> Reply {
+    xml(
+        status,
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{code}</Code></Error>"),
+    )
+}
+
+fn empty(status: u16) -> Reply {
+    Response::from_data(vec![]).with_status_code(StatusCode(status))
+}
+
+fn with_etag(status: u16, etag: &str) -> Reply {
+    empty(status).with_header(header("ETag", etag))
+}
+
+#[derive(Deserialize)]
+struct CompleteMultipartUpload {
+    #[serde(rename = "Part", default)]
+    parts: Vec<CompletePart>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CompletePart {
+    part_number: u64,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+fn parse_range(value: &str) -> Option<(usize, usize)> {
+    let (from, to) = value.strip_prefix("bytes=")?.split_once('-')?;
+    Some((from.parse().ok()?, to.parse().ok()?))
+}
+
+impl FakeS3 {
+    pub fn start() -> Self {
+        let http = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let inner = Arc::new(Inner {
+            http,
+            state: Mutex::default(),
+        });
+
+        let threads = (0..THREADS)
+            .map(|_| {
+                let inner = inner.clone();
+                thread::spawn(move || {
+                    for req in inner.http.incoming_requests() {
+                        inner.serve(req);
+                    }
+                })
+            })
+            .collect();
+        Self { inner, threads }
+    }
+
+    /// Path-style endpoint to hand to the server.
+    pub fn endpoint(&self) -> Url {
+        let addr = self.inner.http.server_addr().to_ip().unwrap();
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.inner.state().faults.push(fault);
+    }
+
+    pub fn clear_faults(&self) {
+        self.inner.state().faults.clear();
+    }
+
+    pub fn object(&self, key: &str) -> Option<Object> {
+        self.inner.state().objects.get(key).cloned()
+    }
+
+    pub fn put_object(&self, key: &str, data: Vec<u8>) {
+        self.inner
+            .state()
+            .objects
+            .insert(key.to_string(), Object::new(data));
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.inner.state().requests.clone()
+    }
+
+    /// Number of uploads or copies received for part `part_number`.
+    pub fn part_attempts(&self, part_number: u64) -> usize {
+        self.requests()
+            .iter()
+            .filter(|r| r.method == "PUT" && r.part_number == Some(part_number))
+            .count()
+    }
+
+    /// Number of multipart uploads started and neither completed nor aborted.
+    pub fn pending_uploads(&self) -> usize {
+        self.inner.state().uploads.len()
+    }
+}
+
+impl Drop for FakeS3 {
+    fn drop(&mut self) {
+        for _ in 0..self.threads.len() {
+            self.inner.http.unblock();
+        }
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Inner {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn serve(&self, mut req: Request) {
+        let url = Url::parse("http://localhost")
+            .unwrap()
+            .join(req.url())
+            .unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let key = url.path().to_string();
+
+        let recorded = Recorded {
+            method: req.method().as_str().to_string(),
+            key: key.clone(),
+            part_number: query.get("partNumber").and_then(|n| n.parse().ok()),
+            copy: header_value(&req, "x-amz-copy-source").is_some(),
+        };
+
+        let action = {
+            let mut state = self.state();
+            state.requests.push(recorded.clone());
+            let fault = state.faults.iter_mut().find(|f| f.matches(&recorded));
+            fault.map(|f| {
+                f.remaining = f.remaining.saturating_sub(1);
+                f.action
+            })
+        };
+        let res = match action {
+            Some(Action::Status(status)) => {
+                // Drain the body so that the client sees the reply rather
+                // than a broken pipe.
+                let _ = std::io::copy(req.as_reader(), &mut std::io::sink());
+                error(status, "InternalError")
+            }
+            Some(Action::Delay(delay)) => {
+                thread::sleep(delay);
+                self.handle(&mut req, &key, &query, false)
+            }
+            Some(Action::Corrupt) => self.handle(&mut req, &key, &query, true),
+            None => self.handle(&mut req, &key, &query, false),
+        };
+        let _ = req.respond(res);
+    }
+
+    fn handle(
+        &self,
+        req: &mut Request,
+        key: &str,
+        query: &HashMap<String, String>,
+        corrupt: bool,
+    ) -> Reply {
+        let signed = query.contains_key("X-Amz-Signature")
+            || header_value(req, "Authorization")
+                .is_some_and(|a| a.starts_with("AWS4-HMAC-SHA256"));
+        if !signed {
+            return error(403, "AccessDenied");
+        }
+        let date = query
+            .get("X-Amz-Date")
+            .and_then(|d| NaiveDateTime::parse_from_str(d, "%Y%m%dT%H%M%SZ").ok());
+        let expires = query
+            .get("X-Amz-Expires")
+            .and_then(|e| TimeDelta::try_seconds(e.parse().ok()?));
+        if let (Some(date), Some(expires)) = (date, expires)
+            && date.and_utc() + expires < Utc::now()
+        {
+            let _ = std::io::copy(req.as_reader(), &mut std::io::sink());
+            return error(403, "AccessDenied");
+        }
+
+        let mut body = vec![];
+        if req.as_reader().read_to_end(&mut body).is_err() {
+            return error(400, "IncompleteBody");
+        }
+        if corrupt && let Some(b) = body.first_mut() {
+            *b ^= 0xff;
+        }
+        if let Some(expected) = header_value(req, "Content-MD5")
+            && expected != BASE64_STANDARD.encode(Md5::digest(&body))
+        {
+            return error(400, "BadDigest");
+        }
+
+        let method = req.method().as_str().to_string();
+        let upload_id = query.get("uploadId");
+        match (method.as_str(), upload_id) {
+            ("GET", None) if query.contains_key("uploads") => self.list_uploads(key, query),
+            ("GET", Some(upload_id)) => self.list_parts(key, upload_id),
+            ("GET" | "HEAD", None) => match self.state().objects.get(key) {
+                Some(obj) => Response::from_data(obj.data.clone())
+                    .with_chunked_threshold(usize::MAX)
+                    .with_header(header("ETag", &obj.etag)),
+                None => error(404, "NoSuchKey"),
+            },
+            ("PUT", None) => {
+                let obj = Object::new(body);
+                let etag = obj.etag.clone();
+                self.state().objects.insert(key.to_string(), obj);
+                with_etag(200, &etag)
+            }
+            ("DELETE", None) => {
+                self.state().objects.remove(key);
+                empty(204)
+            }
+            ("POST", None) if query.contains_key("uploads") => {
+                let mut state = self.state();
+                state.next_upload += 1;
+                let initiated = state.next_upload;
+                let upload_id = format!("upload-{initiated}");
+                state.uploads.insert(
+                    upload_id.clone(),
+                    Upload {
+                        key: key.to_string(),
+                        initiated,
+                        parts: BTreeMap::new(),
+                    },
+                );
+                xml(
+                    200,
+                    format!(
+                        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                         <InitiateMultipartUploadResult><Key>{key}</Key>\
+                         <UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"
+                    ),
+                )
+            }
+            ("PUT", Some(upload_id)) => {
+                let Some(part_number) = query.get("partNumber").and_then(|n| n.parse().ok()) else {
+                    return error(400, "InvalidArgument");
+                };
+                self.upload_part(req, key, upload_id, part_number, body)
+            }
+            ("POST", Some(upload_id)) => self.complete(key, upload_id, &body),
+            ("DELETE", Some(upload_id)) => match self.state().uploads.remove(upload_id) {
+                Some(_) => empty(204),
+                None => error(404, "NoSuchUpload"),
+            },
+            _ => error(405, "MethodNotAllowed"),
+        }
+    }
+
+    fn list_uploads(&self, bucket: &str, query: &HashMap<String, String>) -> Reply {
+        let prefix = format!(
+            "{bucket}/{}",
+            query.get("prefix").map_or("", String::as_str)
+        );
+        let state = self.state();
+        let uploads: String = state
+            .uploads
+            .iter()
+            .filter(|(_, u)| u.key.starts_with(&prefix))
+            .map(|(upload_id, u)| {
+                format!(
+                    "<Upload><Key>{}</Key><UploadId>{upload_id}</UploadId>\
+                     <Initiated>2025-01-01T00:00:{:02}.000Z</Initiated></Upload>",
+                    u.key.strip_prefix(&format!("{bucket}/")).unwrap(),
+                    u.initiated
+                )
+            })
+            .collect();
+        xml(
+            200,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <ListMultipartUploadsResult><IsTruncated>false</IsTruncated>{uploads}\
+                 </ListMultipartUploadsResult>"
+            ),
+        )
+    }
+
+    fn list_parts(&self, key: &str, upload_id: &str) -> Reply {
+        let state = self.state();
+        let Some(upload) = state.uploads.get(upload_id).filter(|u| u.key == key) else {
+            return error(404, "NoSuchUpload");
+        };
+        let parts: String = upload
+            .parts
+            .iter()
+            .map(|(n, part)| {
+                format!(
+                    "<Part><PartNumber>{n}</PartNumber><ETag>{}</ETag><Size>{}</Size></Part>",
+                    part.etag.replace('"', "&quot;"),
+                    part.data.len()
+                )
+            })
+            .collect();
+        xml(
+            200,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <ListPartsResult><UploadId>{upload_id}</UploadId>\
+                 <IsTruncated>false</IsTruncated>{parts}</ListPartsResult>"
+            ),
+        )
+    }
+
+    fn upload_part(
+        &self,
+        req: &Request,
+        key: &str,
+        upload_id: &str,
+        part_number: u64,
+        body: Vec<u8>,
+    ) -> Reply {
+        let mut state = self.state();
+
+        let copy_source = header_value(req, "x-amz-copy-source");
+        let data = match &copy_source {
+            Some(source) => {
+                let Some(obj) = state.objects.get(source) else {
+                    return error(404, "NoSuchKey");
+                };
+                match header_value(req, "x-amz-copy-source-range").as_deref() {
+                    Some(range) => match parse_range(range) {
+                        Some((from, to)) if from <= to && to < obj.data.len() => {
+                            obj.data[from..=to].to_vec()
+                        }
+                        _ => return error(416, "InvalidRange"),
+                    },
+                    None => obj.data.clone(),
+                }
+            }
+            None => body,
+        };
+
+        let Some(upload) = state.uploads.get_mut(upload_id).filter(|u| u.key == key) else {
+            return error(404, "NoSuchUpload");
+        };
+        let part = Object::new(data);
+        let etag = part.etag.clone();
+        upload.parts.insert(part_number, part);
+
+        if copy_source.is_some() {
+            xml(
+                200,
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                     <CopyPartResult><LastModified>2025-01-01T00:00:00.000Z</LastModified>\
+                     <ETag>{etag}</ETag></CopyPartResult>"
+                ),
+            )
+        } else {
+            with_etag(200, &etag)
+        }
+    }
+
+    fn complete(&self, key: &str, upload_id: &str, body: &[u8]) -> Reply {
+        let Ok(request) =
+            quick_xml::de::from_str::<CompleteMultipartUpload>(&String::from_utf8_lossy(body))
+        else {
+            return error(400, "MalformedXML");
+        };
+
+        let mut state = self.state();
+        let Some(upload) = state.uploads.get(upload_id).filter(|u| u.key == key) else {
+            return error(404, "NoSuchUpload");
+        };
+
+        let mut data = vec![];
+        let mut digests = Md5::new();
+        for (i, part) in request.parts.iter().enumerate() {
+            let stored = upload.parts.get(&part.part_number);
+            match stored {
+                Some(stored) if part.part_number == i as u64 + 1 && stored.etag == part.etag => {
+                    data.extend_from_slice(&stored.data);
+                    digests.update(hex::decode(stored.etag.trim_matches('"')).unwrap());
+                }
+                _ => return error(400, "InvalidPart"),
+            }
+        }
+        if request.parts.is_empty() {
+            return error(400, "MalformedXML");
+        }
+
+        let etag = format!(
+            "\"{}-{}\"",
+            hex::encode(digests.finalize()),
+            request.parts.len()
+        );
+        state.uploads.remove(upload_id);
+        state.objects.insert(
+            key.to_string(),
+            Object {
+                data,
+                etag: etag.clone(),
+            },
+        );
+
+        xml(
+            200,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <CompleteMultipartUploadResult><Key>{key}</Key><ETag>{etag}</ETag>\
+                 </CompleteMultipartUploadResult>"
+            ),
+        )
+    }
+}
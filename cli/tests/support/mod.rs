@@ -0,0 +1,185 @@
+//! Harness for end-to-end tests: the API server backed by a fake S3, both
+//! on loopback, and helpers to drive the `koishi` binary against them.
+
+// Each test crate uses a different part of the harness.
+#![allow(dead_code)]
+
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use koishi::{
+    KoishiClient,
+    api::{self, room::Room},
+    helpers::cryptography::restricted_hash,
+    server::{Catalog, Server, ServerConfig, StoreConfig, store::S3Config},
+};
+use tempfile::TempDir;
+
+pub mod fake_s3;
+
+#[allow(unused_imports)]
+pub use fake_s3::{FakeS3, Fault};
+
+pub const AUTH_KEY: &str = "test-auth-key";
+pub const BUCKET: &str = "koishi";
+pub const ROOM: u64 = 1000;
+pub const MIB: u64 = 1024 * 1024;
+
+const API_THREADS: usize = 4;
+
+pub struct TestEnv {
+    pub s3: FakeS3,
+    pub dir: TempDir,
+    api: Arc<Server>,
+    api_thread: Option<JoinHandle<()>>,
+}
+
+impl TestEnv {
+    /// Start a fake S3 and an API server using it, with one room created.
+    pub fn new() -> Self {
+        let s3 = FakeS3::start();
+        let config = ServerConfig {
+            auth_key: AUTH_KEY.to_string(),
+            bucket: BUCKET.to_string(),
+            static_dir: None,
+        };
+        let store = StoreConfig::S3(S3Config {
+            endpoint: s3.endpoint(),
+            key_id: "test".to_string(),
+            key: "test".to_string(),
+            region: "auto".to_string(),
+        });
+        let catalog = Catalog::open_in_memory().unwrap();
+        let api = Arc::new(Server::bind("127.0.0.1:0", config, catalog, store).unwrap());
+
+        let api_thread = {
+            let api = api.clone();
+            thread::spawn(move || api.run(API_THREADS))
+        };
+
+        let env = Self {
+            s3,
+            dir: tempfile::tempdir().unwrap(),
+            api,
+            api_thread: Some(api_thread),
+        };
+        api::room::create(
+            &env.client(),
+            Room {
+                id: ROOM,
+                short_id: None,
+                username: "tester".to_string(),
+                image: String::new(),
+            },
+        )
+        .unwrap();
+        env
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}/api/", self.api.local_addr())
+    }
+
+    pub fn client(&self) -> KoishiClient {
+        KoishiClient::builder()
+            .base_url(self.base_url())
+            .auth_key(Some(AUTH_KEY))
+            .build()
+            .unwrap()
+    }
+
+    #[cfg(feature = "async")]
+    pub fn async_client(&self) -> koishi::nonblocking::KoishiClient {
+        KoishiClient::builder()
+            .base_url(self.base_url())
+            .auth_key(Some(AUTH_KEY))
+            .build_async()
+            .unwrap()
+    }
+
+    /// The `koishi` binary, pointed at the test server and isolated from the
+    /// environment and config of whoever runs the tests.
+    pub fn koishi(&self) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_koishi"));
+        cmd.env_clear()
+            .env("KOISHI_CONFIG", self.dir.path().join("config.toml"))
+            .env("XDG_STATE_HOME", self.dir.path().join("state"))
+            // Parts are retried at once unless a test asks for a delay.
+            .env("KOISHI_RETRY_DELAY", "0")
+            .current_dir(self.dir.path())
+            .args(["-u", &self.base_url(), "-k", AUTH_KEY]);
+        cmd
+    }
+
+    /// Run `koishi` with `args` and capture what it printed.
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.koishi().args(args).output().unwrap()
+    }
+
+    /// Create a video in the test room, restricted if `password` is given.
+    pub fn create_video(&self, password: Option<&str>) -> String {
+        let uuid = uuid::Uuid::now_v7().simple().to_string();
+        let hash = password.map(|p| restricted_hash(&uuid, p).unwrap());
+        api::video::create(
+            &self.client(),
+            &uuid,
+            "Test video".to_string(),
+            None,
+            1_700_000_000_000,
+            1_700_000_000_000,
+            ROOM,
+            hash,
+        )
+        .unwrap();
+        uuid
+    }
+
+    /// Write `size` random bytes to `name` in the test directory.
+    pub fn random_file(&self, name: &str, size: u64) -> (PathBuf, Vec<u8>) {
+        let mut rng = fastrand::Rng::with_seed(size);
+        let data: Vec<u8> = (0..size).map(|_| rng.u8(..)).collect();
+        let path = self.dir.path().join(name);
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    pub fn video_key(&self, uuid: &str) -> String {
+        format!("/{BUCKET}/video/{ROOM}/{uuid}")
+    }
+
+    pub fn restricted_video_key(&self, uuid: &str, password: &str) -> String {
+        let hash = restricted_hash(uuid, password).unwrap();
+        format!("/{BUCKET}/video_restricted/{ROOM}/{hash}")
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for _ in 0..API_THREADS {
+            self.api.unblock();
+        }
+        if let Some(t) = self.api_thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// Panic with everything the command printed unless it succeeded.
+pub fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "koishi failed with {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+pub fn stdout_json(output: &Output) -> serde_json::Value {
+    assert_success(output);
+    serde_json::from_slice(&output.stdout).unwrap()
+}
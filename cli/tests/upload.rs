@@ -0,0 +1,885 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use support::{Fault, MIB, TestEnv, assert_success, stdout_json};
+
+const PART_SIZE: &str = "5242880";
+
+#[test]
+fn test_upload_multipart() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-s",
+        PART_SIZE,
+        "--output",
+        "json",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    let result = stdout_json(&output);
+    assert_eq!(result["parts"], 3);
+    assert_eq!(result["size"], 11 * MIB);
+
+    let obj = env.s3.object(&env.video_key(&uuid)).unwrap();
+    assert!(obj.data == data);
+    assert!(obj.etag.ends_with("-3\""));
+    assert!(!path.with_extension("progress").exists());
+}
+
+#[test]
+fn test_upload_picks_part_size() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-t",
+        "4",
+        "--output",
+        "json",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    // 5 MiB is the smallest part storage accepts.
+    assert_eq!(stdout_json(&output)["parts"], 3);
+}
+
+#[test]
+fn test_upload_refuses_invalid_part_size() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    for part_size in ["0", "1048576", "6000000000"] {
+        let output = env.run(&[
+            "video",
+            "upload",
+            "-P",
+            "-s",
+            part_size,
+            &uuid,
+            path.to_str().unwrap(),
+        ]);
+        assert_eq!(output.status.code(), Some(2), "part size {part_size}");
+    }
+    assert!(env.s3.requests().is_empty());
+    assert!(!path.with_extension("progress").exists());
+}
+
+#[test]
+fn test_upload_retries_failed_part() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(2).status(503).times(2));
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-s",
+        PART_SIZE,
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_success(&output);
+
+    assert_eq!(env.s3.part_attempts(2), 3);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+}
+
+#[test]
+fn test_upload_backs_off_between_attempts() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 6 * MIB);
+
+    env.s3.inject(Fault::part(1).times(2));
+    let start = Instant::now();
+    let output = env
+        .koishi()
+        .env("KOISHI_RETRY_DELAY", "0.4")
+        .args(["video", "upload", "-P", "-s", PART_SIZE, &uuid])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_success(&output);
+    // Half to all of 0.4s, then of 0.8s.
+    assert!(start.elapsed() >= Duration::from_millis(600));
+    assert_eq!(env.s3.part_attempts(1), 3);
+}
+
+#[test]
+fn test_upload_does_not_retry_refused_part() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    // Such as for a signature that expired.
+    env.s3.inject(Fault::part(2).status(403).always());
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-s",
+        PART_SIZE,
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(env.s3.part_attempts(2), 1);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Failed to upload part 2"), "{stderr}");
+}
+
+#[test]
+fn test_upload_reports_failed_parts() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(1).always());
+    env.s3.inject(Fault::part(3).status(503).always());
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "2",
+        "-s",
+        PART_SIZE,
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(7));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 parts failed:"), "{stderr}");
+    assert!(
+        stderr.contains("Failed to upload part 1: Gave up after 2 attempts: S3Error 500"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Failed to upload part 3: Gave up after 2 attempts: S3Error 503"),
+        "{stderr}"
+    );
+    // The part in between still went up, for a resume to skip.
+    assert_eq!(env.s3.part_attempts(2), 1);
+    let progress: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(path.with_extension("progress")).unwrap()).unwrap();
+    assert!(progress["etags"][0].is_null());
+    assert!(progress["etags"][1].is_string());
+}
+
+#[test]
+fn test_upload_progress_events() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(2).status(503).times(1));
+    let output = env.run(&[
+        "video",
+        "upload",
+        "--progress",
+        "jsonl",
+        "-s",
+        PART_SIZE,
+        "--output",
+        "json",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(stdout_json(&output)["parts"], 3);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let events: Vec<serde_json::Value> = stderr
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let named = |name: &'static str| events.iter().filter(move |e| e["event"] == name);
+    assert_eq!(events[0]["event"], "started", "{stderr}");
+    assert_eq!(events[0]["parts"], 3);
+    assert_eq!(events[0]["total_bytes"], 11 * MIB);
+    assert_eq!(named("part_started").count(), 3, "{stderr}");
+    assert_eq!(named("part_done").count(), 3, "{stderr}");
+    let retry = named("retry").next().expect(&stderr);
+    assert_eq!(retry["part"], 2);
+    assert_eq!(retry["attempt"], 2);
+    assert!(retry["error"].as_str().unwrap().contains("503"), "{stderr}");
+    let last = events.last().unwrap();
+    assert_eq!(last["event"], "finished", "{stderr}");
+    assert_eq!(last["bytes"], 11 * MIB);
+    assert!(!stderr.contains("Part 1 uploaded"), "{stderr}");
+}
+
+#[test]
+fn test_upload_gives_up_on_stalled_part() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    // Storage holds the first attempt at part 1 for longer than the whole
+    // upload takes once it is given up on.
+    env.s3.inject(Fault::part(1).delay(Duration::from_secs(5)));
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-s",
+        PART_SIZE,
+        "--stall-timeout",
+        "1",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_success(&output);
+    assert_eq!(env.s3.part_attempts(1), 2);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Upload stalled"), "{stderr}");
+}
+
+#[test]
+fn test_upload_interrupted_keeps_progress() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(1).delay(Duration::from_secs(2)));
+    let child = env
+        .koishi()
+        .args(["video", "upload", "-P", "-t", "1", "-s", PART_SIZE, &uuid])
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    while env.s3.part_attempts(1) == 0 {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let killed = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    let output = child.wait_with_output().unwrap();
+
+    // The part in flight finished, and no other started.
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(env.s3.part_attempts(2), 0);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut lines = stderr
+        .lines()
+        .skip_while(|l| !l.ends_with("resume it with:"));
+    let command = lines.nth(1).unwrap();
+    assert!(command.contains("-k <auth-key>"), "{stderr}");
+    assert!(
+        command.ends_with(&format!("{uuid} {} --resume", path.display())),
+        "{stderr}"
+    );
+    assert!(!command.contains(PART_SIZE), "{stderr}");
+    let progress: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(path.with_extension("progress")).unwrap()).unwrap();
+    assert!(progress["etags"][0].is_string());
+    assert!(progress["etags"][1].is_null());
+
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "--resume",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_success(&output);
+    assert_eq!(env.s3.part_attempts(1), 1);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+}
+
+#[test]
+fn test_upload_resume_skips_finished_parts() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+    let args = [
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "2",
+        &uuid,
+        path.to_str().unwrap(),
+    ];
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env
+        .koishi()
+        .args(args)
+        .args(["-s", PART_SIZE])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7), "storage errors exit with 7");
+    assert!(path.with_extension("progress").exists());
+    assert_eq!(env.s3.part_attempts(3), 2);
+    assert!(env.s3.object(&env.video_key(&uuid)).is_none());
+
+    env.s3.clear_faults();
+    let output = env.koishi().args(args).arg("--resume").output().unwrap();
+    assert_success(&output);
+
+    assert_eq!(env.s3.part_attempts(1), 1);
+    assert_eq!(env.s3.part_attempts(2), 1);
+    assert_eq!(env.s3.part_attempts(3), 3);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert!(!path.with_extension("progress").exists());
+}
+
+#[test]
+fn test_upload_resume_without_progress_file() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+    let args = [
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "1",
+        &uuid,
+        path.to_str().unwrap(),
+    ];
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env
+        .koishi()
+        .args(args)
+        .args(["-s", PART_SIZE])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7));
+    std::fs::remove_file(path.with_extension("progress")).unwrap();
+
+    env.s3.clear_faults();
+    let output = env.koishi().args(args).arg("--resume").output().unwrap();
+    assert_success(&output);
+
+    assert_eq!(env.s3.part_attempts(1), 1);
+    assert_eq!(env.s3.part_attempts(2), 1);
+    assert_eq!(env.s3.part_attempts(3), 2);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert!(!path.with_extension("progress").exists());
+}
+
+#[test]
+fn test_upload_resume_from_another_machine() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, mut data) = env.random_file("video.mp4", 11 * MIB);
+    let args = [
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "1",
+        &uuid,
+        path.to_str().unwrap(),
+    ];
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env
+        .koishi()
+        .args(args)
+        .args(["-s", PART_SIZE])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7));
+
+    // Nothing is known locally about the upload, and the copy of the file
+    // differs in the first part.
+    std::fs::remove_file(path.with_extension("progress")).unwrap();
+    std::fs::remove_dir_all(env.dir.path().join("state")).unwrap();
+    data[0] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+
+    env.s3.clear_faults();
+    let output = env.koishi().args(args).arg("--resume").output().unwrap();
+    assert_success(&output);
+
+    assert_eq!(env.s3.part_attempts(1), 2);
+    assert_eq!(env.s3.part_attempts(2), 1);
+    assert_eq!(env.s3.part_attempts(3), 2);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert_eq!(env.s3.pending_uploads(), 0);
+}
+
+#[test]
+fn test_upload_resume_unknown_upload() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+    let path = path.to_str().unwrap();
+
+    let output = env.run(&["video", "upload", "-P", "--resume", &uuid, path]);
+    assert_eq!(output.status.code(), Some(4));
+
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "--resume",
+        "--upload-id",
+        "upload-42",
+        &uuid,
+        path,
+    ]);
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn test_upload_resume_refreshes_expired_urls() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+    let args = [
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "1",
+        &uuid,
+        path.to_str().unwrap(),
+    ];
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env
+        .koishi()
+        .args(args)
+        .args(["-s", PART_SIZE])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7));
+
+    // Signed long ago, as if resumed days later.
+    let progress = path.with_extension("progress");
+    let state = std::fs::read_to_string(&progress).unwrap();
+    let date = state.find("X-Amz-Date=").unwrap() + "X-Amz-Date=".len();
+    let stale = &state[date..date + 16];
+    std::fs::write(&progress, state.replace(stale, "20200101T000000Z")).unwrap();
+
+    env.s3.clear_faults();
+    let output = env.koishi().args(args).arg("--resume").output().unwrap();
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("URLs of 1 parts expired"), "{stderr}");
+
+    assert_eq!(env.s3.part_attempts(3), 2);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+}
+
+#[test]
+fn test_upload_stdin() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    let output = env
+        .koishi()
+        .args(["video", "upload", "-P", "-s", PART_SIZE, "--output", "json"])
+        .args([&uuid, "-"])
+        .stdin(std::fs::File::open(&path).unwrap())
+        .output()
+        .unwrap();
+    let finished = stdout_json(&output);
+    assert_eq!(finished["parts"], 3);
+    assert_eq!(finished["size"], 11 * MIB);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    assert_eq!(env.s3.pending_uploads(), 0);
+}
+
+#[test]
+fn test_upload_stdin_refuses_progress_events() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+
+    let output = env
+        .koishi()
+        .args(["video", "upload", "--progress", "jsonl", &uuid, "-"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(env.s3.pending_uploads(), 0);
+}
+
+#[test]
+fn test_upload_follow_growing_file() {
+    use std::io::Write;
+
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (_, data) = env.random_file("source.mp4", 11 * MIB);
+    let path = env.dir.path().join("recording.mp4");
+    let mut writer = std::fs::File::create(&path).unwrap();
+
+    let child = env
+        .koishi()
+        .args(["video", "upload", "-P", "-s", PART_SIZE, "--follow"])
+        .args(["--idle-timeout", "20", &uuid, path.to_str().unwrap()])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    for chunk in data.chunks(MIB as usize) {
+        writer.write_all(chunk).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // Finished once the writer closes the file, well before the timeout.
+    let closed = Instant::now();
+    drop(writer);
+    let output = child.wait_with_output().unwrap();
+    assert_success(&output);
+    assert!(closed.elapsed() < Duration::from_secs(15));
+
+    assert_eq!(env.s3.part_attempts(3), 1);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+}
+
+#[test]
+fn test_upload_follow_waits_for_writer_past_idle_timeout() {
+    use std::io::Write;
+
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (_, data) = env.random_file("source.mp4", 6 * MIB);
+    let path = env.dir.path().join("recording.mp4");
+    let mut writer = std::fs::File::create(&path).unwrap();
+    writer.write_all(&data[..MIB as usize]).unwrap();
+
+    let mut child = env
+        .koishi()
+        .args(["video", "upload", "-P", "-s", PART_SIZE, "--follow"])
+        .args(["--idle-timeout", "1", &uuid, path.to_str().unwrap()])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The writer pauses for longer than the idle timeout, but still holds
+    // the file open.
+    std::thread::sleep(Duration::from_secs(4));
+    assert!(child.try_wait().unwrap().is_none());
+
+    writer.write_all(&data[MIB as usize..]).unwrap();
+    drop(writer);
+    let output = child.wait_with_output().unwrap();
+    assert_success(&output);
+    assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+}
+
+#[test]
+fn test_upload_stream_failure_aborts() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(2).always());
+    let output = env
+        .koishi()
+        .args([
+            "video", "upload", "-P", "-R", "1", "-s", PART_SIZE, &uuid, "-",
+        ])
+        .stdin(std::fs::File::open(&path).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(env.s3.pending_uploads(), 0);
+    assert!(env.s3.object(&env.video_key(&uuid)).is_none());
+}
+
+#[test]
+fn test_upload_refuses_to_overwrite_progress() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", MIB);
+    std::fs::write(path.with_extension("progress"), "{}").unwrap();
+
+    let output = env.run(&["video", "upload", "-P", &uuid, path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(env.s3.requests().is_empty());
+}
+
+#[test]
+fn test_upload_restricted_needs_password() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(Some("secret"));
+    let (path, data) = env.random_file("video.mp4", MIB);
+
+    let output = env.run(&["video", "upload", "-P", &uuid, path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-p",
+        "secret",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_success(&output);
+    let key = env.restricted_video_key(&uuid, "secret");
+    assert!(env.s3.object(&key).unwrap().data == data);
+}
+
+#[test]
+fn test_upload_retries_corrupted_part() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(2).corrupt());
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-s",
+        PART_SIZE,
+        "--output",
+        "json",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    let result = stdout_json(&output);
+
+    assert_eq!(env.s3.part_attempts(2), 2);
+    let obj = env.s3.object(&env.video_key(&uuid)).unwrap();
+    assert!(obj.data == data);
+    assert_eq!(result["etag"], obj.etag);
+}
+
+#[test]
+fn test_upload_resume_refuses_changed_file() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, mut data) = env.random_file("video.mp4", 11 * MIB);
+    let args = [
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "1",
+        &uuid,
+        path.to_str().unwrap(),
+    ];
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env
+        .koishi()
+        .args(args)
+        .args(["-s", PART_SIZE])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(7));
+
+    data[0] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    env.s3.clear_faults();
+    let output = env.koishi().args(args).arg("--resume").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("The content of"), "{stderr}");
+    assert_eq!(env.s3.part_attempts(3), 1);
+
+    // Progress files from before the fingerprint are left to the ETags.
+    let progress = path.with_extension("progress");
+    let mut state: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&progress).unwrap()).unwrap();
+    assert_eq!(state["version"], 1);
+    let state = state.as_object_mut().unwrap();
+    state.remove("version");
+    state.remove("source");
+    std::fs::write(&progress, serde_json::to_vec(&state).unwrap()).unwrap();
+    let output = env.koishi().args(args).arg("--resume").output().unwrap();
+    assert_eq!(output.status.code(), Some(7));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("parts 1 do not match"), "{stderr}");
+    assert!(env.s3.object(&env.video_key(&uuid)).is_none());
+}
+
+#[test]
+fn test_upload_resume_refuses_newer_progress_file() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", MIB);
+    std::fs::write(
+        path.with_extension("progress"),
+        r#"{"version": 99, "upload_id": "x", "parts": []}"#,
+    )
+    .unwrap();
+
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "--resume",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is of format 99"), "{stderr}");
+}
+
+#[test]
+fn test_upload_limit_rate() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, data) = env.random_file("video.mp4", 2 * MIB);
+    let path = path.to_str().unwrap();
+
+    // Two windows covering the whole day override the unlimited default.
+    for limit in [
+        &["--limit-rate", "1M"][..],
+        &["--rate-schedule", "00:00-12:00=1M,12:00-00:00=1M"],
+    ] {
+        let start = Instant::now();
+        let output = env
+            .koishi()
+            .args(["video", "upload", "-P", &uuid, path])
+            .args(limit)
+            .output()
+            .unwrap();
+        assert_success(&output);
+        assert!(start.elapsed() >= Duration::from_millis(1500), "{limit:?}");
+        assert!(env.s3.object(&env.video_key(&uuid)).unwrap().data == data);
+    }
+
+    let output = env.run(&["video", "upload", "-P", "--limit-rate", "fast", &uuid, path]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_upload_status_list_abort() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+    let path = path.to_str().unwrap();
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env.run(&[
+        "video", "upload", "-P", "-R", "1", "-s", PART_SIZE, &uuid, path,
+    ]);
+    assert_eq!(output.status.code(), Some(7));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("koishi video upload abort"), "{stderr}");
+    assert_eq!(env.s3.pending_uploads(), 1);
+
+    let output = env.run(&["video", "upload", "status", "--output", "json", path]);
+    let status = stdout_json(&output);
+    assert_eq!(status["uuid"], uuid.as_str());
+    assert_eq!(status["parts"], 3);
+    assert_eq!(status["finished"], 2);
+    assert_eq!(status["missing"], serde_json::json!([3]));
+
+    let output = env.run(&["video", "upload", "list", "--output", "json"]);
+    let list = stdout_json(&output);
+    assert_eq!(list.as_array().unwrap().len(), 1);
+    assert_eq!(list[0]["upload_id"], status["upload_id"]);
+
+    let output = env.run(&["video", "upload", "abort", path]);
+    assert_success(&output);
+    assert_eq!(env.s3.pending_uploads(), 0);
+    assert!(
+        !std::path::Path::new(path)
+            .with_extension("progress")
+            .exists()
+    );
+
+    let output = env.run(&["video", "upload", "list", "--output", "json"]);
+    assert_eq!(stdout_json(&output), serde_json::json!([]));
+    let output = env.run(&["video", "upload", "status", path]);
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn test_upload_list_keeps_unreadable_uploads() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(3).always());
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "1",
+        "-s",
+        PART_SIZE,
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(7));
+
+    // Written by a newer version, which this one cannot read.
+    let progress = path.with_extension("progress");
+    std::fs::write(&progress, r#"{"version": 999}"#).unwrap();
+    for _ in 0..2 {
+        let output = env.run(&["video", "upload", "list", "--output", "json"]);
+        let list = stdout_json(&output);
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(list[0]["uuid"], uuid.as_str());
+        assert!(list[0]["parts"].is_null());
+        assert!(list[0]["error"].as_str().unwrap().contains("newer"));
+    }
+
+    // Gone for good once the progress file is.
+    std::fs::remove_file(&progress).unwrap();
+    let output = env.run(&["video", "upload", "list", "--output", "json"]);
+    assert_eq!(stdout_json(&output), serde_json::json!([]));
+}
+
+#[test]
+fn test_upload_abort_on_failure() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+
+    env.s3.inject(Fault::part(2).always());
+    let output = env.run(&[
+        "video",
+        "upload",
+        "-P",
+        "-R",
+        "1",
+        "-s",
+        PART_SIZE,
+        "--abort-on-failure",
+        &uuid,
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(env.s3.pending_uploads(), 0);
+    assert!(!path.with_extension("progress").exists());
+
+    let output = env.run(&["video", "upload", "list", "--output", "json"]);
+    assert_eq!(stdout_json(&output), serde_json::json!([]));
+}
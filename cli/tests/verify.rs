@@ -0,0 +1,70 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use support::{MIB, TestEnv, assert_success, stdout_json};
+
+const PART_SIZE: &str = "5242880";
+
+fn upload(env: &TestEnv, uuid: &str, path: &str) {
+    let output = env.run(&["video", "upload", "-P", "-s", PART_SIZE, uuid, path]);
+    assert_success(&output);
+}
+
+#[test]
+fn test_verify_guesses_part_size() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", 11 * MIB);
+    let path = path.to_str().unwrap();
+    upload(&env, &uuid, path);
+
+    let output = env.run(&["video", "verify", "--output", "json", &uuid, path]);
+    let result = stdout_json(&output);
+    assert_eq!(result["part_size"], 5 * MIB);
+    assert_eq!(
+        result["etag"],
+        env.s3.object(&env.video_key(&uuid)).unwrap().etag
+    );
+}
+
+#[test]
+fn test_verify_single_part() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", MIB);
+    let path = path.to_str().unwrap();
+    upload(&env, &uuid, path);
+
+    let output = env.run(&["video", "verify", "-s", PART_SIZE, &uuid, path]);
+    assert_success(&output);
+}
+
+#[test]
+fn test_verify_detects_changed_object() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, mut data) = env.random_file("video.mp4", 11 * MIB);
+    let path = path.to_str().unwrap();
+    upload(&env, &uuid, path);
+
+    // Same size, so only the ETag tells them apart.
+    data[0] ^= 0xff;
+    env.s3.put_object(&env.video_key(&uuid), data);
+    let output = env.run(&["video", "verify", &uuid, path]);
+    assert_eq!(output.status.code(), Some(7));
+
+    env.s3.put_object(&env.video_key(&uuid), vec![0; 10]);
+    let output = env.run(&["video", "verify", &uuid, path]);
+    assert_eq!(output.status.code(), Some(7));
+}
+
+#[test]
+fn test_verify_missing_video() {
+    let env = TestEnv::new();
+    let uuid = env.create_video(None);
+    let (path, _) = env.random_file("video.mp4", MIB);
+
+    let output = env.run(&["video", "verify", &uuid, path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(4));
+}
@@ -0,0 +1,150 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use std::{
+    fs,
+    process::Stdio,
+    thread,
+    time::{Duration, Instant},
+};
+
+use support::{BUCKET, Fault, MIB, ROOM, TestEnv, stdout_json};
+
+/// Write a finished recording to `rec/` in the test directory: a video,
+/// its XML and a cover.
+fn write_recording(env: &TestEnv, name: &str, size: u64) -> Vec<u8> {
+    let dir = env.dir.path().join("rec");
+    fs::create_dir_all(&dir).unwrap();
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <i>\n\
+         <metadata>\n\
+         <room_id>{ROOM}</room_id>\n\
+         <room_title>Recording {name}</room_title>\n\
+         <live_start_time>2025-01-01T12:00:00+08:00</live_start_time>\n\
+         <record_start_time>2025-01-01T12:05:00+08:00</record_start_time>\n\
+         </metadata>\n\
+         </i>\n"
+    );
+    fs::write(dir.join(format!("{name}.xml")), xml).unwrap();
+    fs::write(dir.join(format!("{name}.jpg")), format!("cover of {name}")).unwrap();
+    // Moved in last, as a recorder finishing it would.
+    let (path, data) = env.random_file(&format!("{name}.flv"), size);
+    fs::rename(&path, dir.join(format!("{name}.flv"))).unwrap();
+    data
+}
+
+fn watch_once(env: &TestEnv, extra: &[&str]) -> serde_json::Value {
+    let rec = env.dir.path().join("rec");
+    let mut args = vec!["watch", "--once", "--settle", "0", "--output", "json"];
+    args.extend(extra);
+    args.push(rec.to_str().unwrap());
+    stdout_json(&env.run(&args))
+}
+
+fn assert_ingested(env: &TestEnv, uuid: &str, data: &[u8], name: &str) {
+    let key = format!("/{BUCKET}/video/{ROOM}/{uuid}");
+    assert!(env.s3.object(&key).unwrap().data == data);
+    assert!(
+        env.s3
+            .object(&format!("/{BUCKET}/metadata/{uuid}"))
+            .is_some()
+    );
+    let video = koishi::api::video::get(&env.client(), uuid).unwrap();
+    assert_eq!(video.title, format!("Recording {name}"));
+    assert!(video.cover.is_some());
+}
+
+#[test]
+fn test_watch_ingests_recording_once() {
+    let env = TestEnv::new();
+    let data = write_recording(&env, "a", 3 * MIB);
+    // Not a recording without its XML.
+    env.random_file("rec/b.flv", MIB);
+
+    let jobs = watch_once(&env, &[]);
+    let jobs = jobs.as_array().unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0]["status"], "done");
+    let uuid = jobs[0]["uuid"].as_str().unwrap().to_string();
+    assert_ingested(&env, &uuid, &data, "a");
+
+    // Recordings in the queue are not ingested again.
+    let requests = env.s3.requests().len();
+    let jobs = watch_once(&env, &[]);
+    assert_eq!(jobs.as_array().unwrap().len(), 1);
+    assert_eq!(env.s3.requests().len(), requests);
+}
+
+#[test]
+fn test_watch_retries_failed_recording() {
+    let env = TestEnv::new();
+    let data = write_recording(&env, "a", 3 * MIB);
+
+    env.s3.inject(Fault::part(1).always());
+    let jobs = watch_once(&env, &["--max-attempts", "2"]);
+    assert_eq!(jobs[0]["status"], "queued");
+    assert_eq!(jobs[0]["step"], "upload");
+    assert_eq!(jobs[0]["attempts"], 1);
+    let uuid = jobs[0]["uuid"].as_str().unwrap().to_string();
+
+    let jobs = watch_once(&env, &["--max-attempts", "2"]);
+    assert_eq!(jobs[0]["status"], "failed");
+    assert_eq!(jobs[0]["attempts"], 2);
+
+    // Given up on until asked to try again, then picked up where it stopped.
+    env.s3.clear_faults();
+    let jobs = watch_once(&env, &["--max-attempts", "2"]);
+    assert_eq!(jobs[0]["status"], "failed");
+    let jobs = watch_once(&env, &["--max-attempts", "2", "--retry-failed"]);
+    assert_eq!(jobs[0]["status"], "done");
+    assert_eq!(jobs[0]["uuid"], uuid.as_str());
+    assert_ingested(&env, &uuid, &data, "a");
+}
+
+#[test]
+fn test_watch_waits_for_recording_to_settle() {
+    let env = TestEnv::new();
+    write_recording(&env, "a", MIB);
+
+    let rec = env.dir.path().join("rec");
+    let output = env.run(&["watch", "--once", "--output", "json", rec.to_str().unwrap()]);
+    assert_eq!(stdout_json(&output), serde_json::json!([]));
+    assert!(env.s3.requests().is_empty());
+}
+
+#[test]
+fn test_watch_picks_up_new_recording() {
+    let env = TestEnv::new();
+    let rec = env.dir.path().join("rec");
+    fs::create_dir_all(&rec).unwrap();
+    let state = env.dir.path().join("queue.json");
+
+    let mut watcher = env
+        .koishi()
+        .args(["watch", "--settle", "0", "--state"])
+        .args([&state, &rec])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let data = write_recording(&env, "a", MIB);
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let uuid = loop {
+        let queue: Option<serde_json::Value> = fs::read(&state)
+            .ok()
+            .and_then(|q| serde_json::from_slice(&q).ok());
+        if let Some(job) = queue.as_ref().map(|q| &q["jobs"][0])
+            && job["step"] == "done"
+        {
+            break job["uuid"].as_str().unwrap().to_string();
+        }
+        assert!(Instant::now() < deadline, "recording was not ingested");
+        thread::sleep(Duration::from_millis(100));
+    };
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    assert_ingested(&env, &uuid, &data, "a");
+}
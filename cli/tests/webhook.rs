@@ -0,0 +1,204 @@
+#![cfg(feature = "server")]
+
+mod support;
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Child, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{Value, json};
+use support::{BUCKET, MIB, ROOM, TestEnv};
+
+struct WebhookServer {
+    child: Child,
+    url: String,
+}
+
+impl WebhookServer {
+    fn start(env: &TestEnv) -> Self {
+        let rec = env.dir.path().join("rec");
+        fs::create_dir_all(&rec).unwrap();
+        let mut child = env
+            .koishi()
+            .args(["webhook-server", "--listen", "127.0.0.1:0", "--root"])
+            .arg(&rec)
+            .arg("--state")
+            .arg(env.dir.path().join("queue.json"))
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let url = line
+            .trim()
+            .strip_prefix("Listening for webhooks on ")
+            .unwrap_or_else(|| panic!("unexpected first line {line:?}"))
+            .to_string();
+        // Keep the pipe drained so that the server never blocks on it.
+        thread::spawn(move || stdout.read_to_end(&mut vec![]));
+        Self { child, url }
+    }
+
+    fn post(&self, path: &str, body: &Value) -> (u16, Value) {
+        let res = reqwest::blocking::Client::new()
+            .post(format!("{}{path}", self.url))
+            .json(body)
+            .send()
+            .unwrap();
+        (res.status().as_u16(), res.json().unwrap())
+    }
+
+    fn status(&self) -> Vec<Value> {
+        reqwest::blocking::get(format!("{}/status", self.url))
+            .unwrap()
+            .json()
+            .unwrap()
+    }
+
+    /// Wait until job `i` is done, returning the uuid of its video.
+    fn wait_done(&self, i: usize) -> String {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let jobs = self.status();
+            if let Some(job) = jobs.get(i)
+                && job["status"] == "done"
+            {
+                return job["uuid"].as_str().unwrap().to_string();
+            }
+            assert!(Instant::now() < deadline, "job {i} not done: {jobs:?}");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for WebhookServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn file_closed(relative_path: &str) -> Value {
+    json!({
+        "EventType": "FileClosed",
+        "EventTimestamp": "2025-01-01T13:00:00.1234567+08:00",
+        "EventId": "5d3b2f2c-3c5e-4d3a-9a1e-0c6b5a1f7d2e",
+        "EventData": {
+            "RelativePath": relative_path,
+            "FileSize": MIB,
+            "Duration": 3600.0,
+            "FileOpenTime": "2025-01-01T12:00:00.9401002+08:00",
+            "FileCloseTime": "2025-01-01T13:00:00.1234567+08:00",
+            "SessionId": "7c1e8d9a-0b2f-4e6a-8c3d-1f2e3d4c5b6a",
+            "RoomId": ROOM,
+            "ShortId": 0,
+            "Name": "tester",
+            "Title": "Webhook recording",
+            "AreaNameParent": "Games",
+            "AreaNameChild": "Other",
+            "Recording": true,
+            "Streaming": false,
+            "DanmakuConnected": true
+        }
+    })
+}
+
+#[test]
+fn test_webhook_bililive_recorder() {
+    let env = TestEnv::new();
+    let server = WebhookServer::start(&env);
+    let (path, data) = env.random_file("a.flv", 3 * MIB);
+    fs::create_dir_all(env.dir.path().join("rec/1000")).unwrap();
+    fs::rename(path, env.dir.path().join("rec/1000/a.flv")).unwrap();
+
+    let (status, body) = server.post(
+        "/bililive-recorder",
+        &json!({"EventType": "SessionStarted", "EventData": {}}),
+    );
+    assert_eq!((status, &body["ignored"]), (200, &json!("SessionStarted")));
+    let (status, _) = server.post("/bililive-recorder", &file_closed("../a.flv"));
+    assert_eq!(status, 400);
+    let (status, _) = server.post("/bililive-recorder", &file_closed("1000/missing.flv"));
+    assert_eq!(status, 404);
+
+    let (status, body) = server.post("/bililive-recorder", &file_closed("1000/a.flv"));
+    assert_eq!(status, 202);
+    assert!(matches!(
+        body["status"].as_str(),
+        Some("queued" | "running")
+    ));
+    let uuid = server.wait_done(0);
+
+    let key = format!("/{BUCKET}/video/{ROOM}/{uuid}");
+    assert!(env.s3.object(&key).unwrap().data == data);
+    let video = koishi::api::video::get(&env.client(), &uuid).unwrap();
+    assert_eq!(video.title, "Webhook recording");
+
+    // Announced again, such as on a retried delivery.
+    let (status, body) = server.post("/bililive-recorder", &file_closed("1000/a.flv"));
+    assert_eq!((status, &body["uuid"]), (200, &json!(uuid)));
+    assert_eq!(server.status().len(), 1);
+}
+
+#[test]
+fn test_webhook_blrec() {
+    let env = TestEnv::new();
+    let server = WebhookServer::start(&env);
+    let (path, data) = env.random_file("b.flv", MIB);
+    let video = env.dir.path().join("rec/b.flv");
+    fs::rename(path, &video).unwrap();
+    fs::write(
+        video.with_extension("xml"),
+        format!(
+            "<i><metadata><room_id>{ROOM}</room_id><room_title>blrec recording</room_title>\
+             <live_start_time>2025-01-01T12:00:00+08:00</live_start_time>\
+             <record_start_time>2025-01-01T12:05:00+08:00</record_start_time></metadata></i>"
+        ),
+    )
+    .unwrap();
+
+    let event = |kind: &str, path: &Path| {
+        json!({
+            "id": "0b4c9a3e",
+            "date": "2025-01-01 13:00:00",
+            "type": kind,
+            "data": {"room_id": ROOM, "path": path}
+        })
+    };
+    let completed = |path: &Path| event("VideoPostprocessingCompletedEvent", path);
+    let (status, _) = server.post("/blrec", &event("VideoFileCompletedEvent", &video));
+    assert_eq!(status, 200);
+    assert!(server.status().is_empty());
+
+    // Only videos under --root are taken.
+    let (outside, _) = env.random_file("outside.flv", MIB);
+    let (status, _) = server.post("/blrec", &completed(&outside));
+    assert_eq!(status, 403);
+    let escaping = env.dir.path().join("rec/../outside.flv");
+    let (status, _) = server.post("/blrec", &completed(&escaping));
+    assert_eq!(status, 400);
+    let (status, _) = server.post("/blrec", &completed(&video.with_extension("xml")));
+    assert_eq!(status, 422);
+    assert!(server.status().is_empty());
+
+    let (status, _) = server.post("/blrec", &completed(&video));
+    assert_eq!(status, 202);
+    let uuid = server.wait_done(0);
+
+    let key = format!("/{BUCKET}/video/{ROOM}/{uuid}");
+    assert!(env.s3.object(&key).unwrap().data == data);
+    assert!(
+        env.s3
+            .object(&format!("/{BUCKET}/metadata/{uuid}"))
+            .is_some()
+    );
+    let video = koishi::api::video::get(&env.client(), &uuid).unwrap();
+    assert_eq!(video.title, "blrec recording");
+}
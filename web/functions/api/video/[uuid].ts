@@ -1,7 +1,9 @@
+import { AwsClient } from 'aws4fetch'
 import * as v from 'valibot'
 
+import { obj_urls } from '@flib/objects'
 import { Env } from '@flib/types'
-import { run_query, video_by_uuid } from '@flib/queries'
+import { run_query, video_by_uuid, video_by_uuid_with_hash } from '@flib/queries'
 import { get_req_body } from '@flib/requests'
 import { res } from '@flib/responses'
 
@@ -91,6 +93,47 @@ async function update(id: string, d: v.InferOutput<typeof ReqUpdate>, db: D1Data
     return res.ok()
 }
 
+// The cover may be shared with other videos, so only the video object and
+// the metadata go along with the row.
+async function remove(id: string, env: Env, request: Request) {
+    const { success, video, error } = await video_by_uuid_with_hash(env.DB, id)
+    if (!success) {
+        return res.db_transaction_error(error)
+    }
+    if (!video) {
+        return res.not_found(`Video ${id} not found`)
+    }
+
+    const hash = new URL(request.url).searchParams.get("hash")?.toLowerCase()
+    if (video.restricted && video.restricted_hash != hash) {
+        return res.forbidden("Invalid hash for restricted video")
+    }
+
+    const aws = new AwsClient({
+        accessKeyId: env.S3_KEY_ID,
+        secretAccessKey: env.S3_KEY
+    });
+
+    // Objects go first, so that a failure leaves the row to delete again.
+    for (const url of [obj_urls.video(env, video), obj_urls.metadata(env, video)]) {
+        const deleteRes = await aws.fetch(url, { method: "DELETE" })
+        if (!deleteRes.ok && deleteRes.status != 404) {
+            const xml = await deleteRes.text()
+            return res.s3_error(
+                `Status ${deleteRes.status} from S3 while issuing DELETE request`,
+                { xml }
+            )
+        }
+    }
+
+    const ps = env.DB.prepare("DELETE FROM video WHERE uuid=UNHEX(?)").bind(id)
+    const ret = await run_query(ps)
+    if (!ret.success) {
+        return res.db_transaction_error(ret.error)
+    }
+    return res.ok()
+}
+
 async function post(uuid: string, db: D1Database, request: Body) {
     const { success, output } = await get_req_body(request, ReqInsert)
     if (!success) {
@@ -124,6 +167,8 @@ export const onRequest: PagesFunction<Env> = async (context) => {
             return await get(uuid, context.env.DB)
         case "PUT":
             return await put(uuid, context.env.DB, context.request)
+        case "DELETE":
+            return await remove(uuid, context.env, context.request)
         default:
             return res.method_not_allowed()
     }
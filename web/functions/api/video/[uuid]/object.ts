@@ -0,0 +1,40 @@
+import { AwsClient } from 'aws4fetch'
+
+import { obj_urls } from '@flib/objects'
+import { video_by_uuid_with_hash } from '@flib/queries'
+import { res } from '@flib/responses'
+import { Env } from '@flib/types'
+
+export const onRequestGet: PagesFunction<Env> = async (context) => {
+    const uuid = context.params.uuid as string
+
+    const { success, video, error } = await video_by_uuid_with_hash(context.env.DB, uuid)
+    if (!success) {
+        return res.db_transaction_error(error)
+    }
+    if (!video) {
+        return res.not_found(`Video ${uuid} not found`)
+    }
+
+    const hash = new URL(context.request.url).searchParams.get("hash")?.toLowerCase()
+    if (video.restricted && video.restricted_hash != hash) {
+        return res.forbidden("Invalid restricted hash")
+    }
+
+    const aws = new AwsClient({
+        accessKeyId: context.env.S3_KEY_ID,
+        secretAccessKey: context.env.S3_KEY
+    });
+
+    const head = await aws.fetch(obj_urls.video(context.env, video), { method: "HEAD" })
+    if (head.status === 404) {
+        return res.not_found(`Video ${uuid} not uploaded`)
+    } else if (!head.ok) {
+        return res.s3_error(`Status ${head.status} from S3`)
+    }
+
+    return res.ok({
+        size: parseInt(head.headers.get("Content-Length")!, 10),
+        etag: head.headers.get("ETag"),
+    })
+}
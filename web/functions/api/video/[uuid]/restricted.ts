@@ -235,7 +235,7 @@ export const onRequestPut: PagesFunction<Env> = async (context) => {
         secretAccessKey: context.env.S3_KEY
     });
     const url = obj_urls.from_key(context.env, old_key)
-    const [exists] = await get_s3_url_info(aws, url)
+    const [exists, length] = await get_s3_url_info(aws, url)
     try {
         if (!exists) {
             return res.ok({})
@@ -248,5 +248,5 @@ export const onRequestPut: PagesFunction<Env> = async (context) => {
         }
     }
 
-    return res.ok({ copy_source: old_key })
+    return res.ok({ copy_source: old_key, length })
 }
@@ -0,0 +1,54 @@
+import { AwsClient } from 'aws4fetch'
+import * as v from 'valibot'
+
+import { obj_urls } from '@flib/objects'
+import { video_by_uuid_with_hash } from '@flib/queries'
+import { get_req_body } from '@flib/requests'
+import { res } from '@flib/responses'
+import { Env } from '@flib/types'
+
+const ReqBody = v.object({
+    upload_id: v.string(),
+    restricted_hash: v.nullish(v.string()),
+})
+
+export const onRequestPost: PagesFunction<Env> = async (context) => {
+    const uuid = context.params.uuid as string
+
+    const { success: parse_success, output } = await get_req_body(context.request, ReqBody)
+    if (!parse_success) {
+        return res.unprocessable_entity()
+    }
+
+    const { upload_id, restricted_hash } = output
+
+    const { success, video, error } = await video_by_uuid_with_hash(context.env.DB, uuid)
+    if (!success) {
+        return res.db_transaction_error(error)
+    }
+    if (!video) {
+        return res.not_found(`Video ${uuid} not found`)
+    }
+    if (video.restricted && video.restricted_hash != restricted_hash) {
+        return res.forbidden("Invalid hash for restricted video")
+    }
+
+    const aws = new AwsClient({
+        accessKeyId: context.env.S3_KEY_ID,
+        secretAccessKey: context.env.S3_KEY
+    });
+
+    const obj_url = obj_urls.video(context.env, video)
+
+    // Aborting an upload that is already gone is not an error.
+    const abort = await aws.fetch(
+        `${obj_url}?uploadId=${encodeURIComponent(upload_id)}`,
+        { method: 'DELETE' }
+    );
+
+    const res_text = await abort.text();
+    if (!abort.ok && abort.status != 404) {
+        return res.s3_error(`Status ${abort.status} from S3`, { xml: res_text })
+    }
+    return res.ok()
+}
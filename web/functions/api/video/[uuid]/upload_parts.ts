@@ -0,0 +1,125 @@
+import { AwsClient } from 'aws4fetch'
+
+import { obj_urls } from '@flib/objects'
+import { video_by_uuid_with_hash } from '@flib/queries'
+import { res } from '@flib/responses'
+import { Env } from '@flib/types'
+
+function xml_value(xml: string, tag: string) {
+    return new RegExp(`<${tag}>([^<]*)</${tag}>`).exec(xml)?.[1] ?? ''
+}
+
+function unescape_xml(value: string) {
+    return value
+        .replaceAll('&quot;', '"')
+        .replaceAll('&apos;', "'")
+        .replaceAll('&lt;', '<')
+        .replaceAll('&gt;', '>')
+        .replaceAll('&amp;', '&')
+}
+
+// ID of the latest upload to the object still in progress.
+async function latest_upload(aws: AwsClient, env: Env, key: string) {
+    const object_key = key.replace(`/${env.S3_BUCKET}/`, '')
+    let latest: { upload_id: string, initiated: string } | undefined
+    let markers: { key: string, upload_id: string } | undefined
+
+    while (true) {
+        const url = new URL(obj_urls.from_key(env, `/${env.S3_BUCKET}`))
+        url.searchParams.set('uploads', '')
+        url.searchParams.set('prefix', object_key)
+        if (markers) {
+            url.searchParams.set('key-marker', markers.key)
+            url.searchParams.set('upload-id-marker', markers.upload_id)
+        }
+
+        const list = await aws.fetch(url.href, { method: 'GET' })
+        const xml = await list.text()
+        if (!list.ok) {
+            return { ok: false as const, status: list.status, xml }
+        }
+
+        for (const [, upload] of xml.matchAll(/<Upload>([\s\S]*?)<\/Upload>/g)) {
+            const initiated = xml_value(upload, 'Initiated')
+            if (unescape_xml(xml_value(upload, 'Key')) === object_key
+                && (!latest || initiated > latest.initiated)) {
+                latest = { upload_id: unescape_xml(xml_value(upload, 'UploadId')), initiated }
+            }
+        }
+
+        if (xml_value(xml, 'IsTruncated') !== 'true') {
+            return { ok: true as const, upload_id: latest?.upload_id }
+        }
+        markers = {
+            key: unescape_xml(xml_value(xml, 'NextKeyMarker')),
+            upload_id: unescape_xml(xml_value(xml, 'NextUploadIdMarker')),
+        }
+    }
+}
+
+export const onRequestGet: PagesFunction<Env> = async (context) => {
+    const uuid = context.params.uuid as string
+
+    const { success, video, error } = await video_by_uuid_with_hash(context.env.DB, uuid)
+    if (!success) {
+        return res.db_transaction_error(error)
+    }
+    if (!video) {
+        return res.not_found(`Video ${uuid} not found`)
+    }
+
+    const params = new URL(context.request.url).searchParams
+    const hash = params.get("hash")?.toLowerCase()
+    if (video.restricted && video.restricted_hash != hash) {
+        return res.forbidden("Invalid hash for restricted video")
+    }
+
+    const aws = new AwsClient({
+        accessKeyId: context.env.S3_KEY_ID,
+        secretAccessKey: context.env.S3_KEY
+    });
+
+    let upload_id = params.get("upload_id")
+    if (!upload_id) {
+        const latest = await latest_upload(aws, context.env, obj_urls.video_key(context.env, video))
+        if (!latest.ok) {
+            return res.s3_error(`Status ${latest.status} from S3`, { xml: latest.xml })
+        }
+        if (!latest.upload_id) {
+            return res.not_found(`No unfinished upload of video ${uuid}`)
+        }
+        upload_id = latest.upload_id
+    }
+
+    const parts = []
+    let marker = '0'
+    while (true) {
+        const url = new URL(obj_urls.video(context.env, video))
+        url.searchParams.set("uploadId", upload_id)
+        url.searchParams.set("part-number-marker", marker)
+
+        const list = await aws.fetch(url.href, { method: 'GET' })
+        const xml = await list.text()
+        if (list.status === 404) {
+            return res.not_found(`Upload ${upload_id} not found`)
+        } else if (!list.ok) {
+            return res.s3_error(`Status ${list.status} from S3`, { xml })
+        }
+
+        for (const [, part] of xml.matchAll(/<Part>([\s\S]*?)<\/Part>/g)) {
+            parts.push({
+                part_number: parseInt(xml_value(part, 'PartNumber'), 10),
+                etag: unescape_xml(xml_value(part, 'ETag')),
+                size: parseInt(xml_value(part, 'Size'), 10),
+            })
+        }
+
+        const next = xml_value(xml, 'NextPartNumberMarker')
+        if (xml_value(xml, 'IsTruncated') !== 'true' || !next || next === marker) {
+            break
+        }
+        marker = next
+    }
+
+    return res.ok({ upload_id, parts })
+}
@@ -10,6 +10,7 @@ import { Env } from '@flib/types'
 const ReqBody = v.object({
     size: v.number(),
     part_size: v.number(),
+    upload_id: v.nullish(v.string()),
     restricted_hash: v.nullish(v.string()),
 })
 
@@ -21,6 +22,7 @@ export const onRequestPost: PagesFunction<Env> = async (context) => {
         return res.unprocessable_entity("Body verification failed", req_body.issues)
     }
     const { size, part_size, restricted_hash } = req_body.output
+    let upload_id = req_body.output.upload_id
     const parts = Math.ceil(size / part_size)
 
     const { success, video, error } = await video_by_uuid_with_hash(context.env.DB, uuid)
@@ -41,13 +43,27 @@ export const onRequestPost: PagesFunction<Env> = async (context) => {
 
     const obj_url = obj_urls.video(context.env, video)
 
-    // init multipart upload
-    const init = await aws.fetch(
-        `${obj_url}?uploads`,
-        { method: 'POST' }
-    );
-    const init_xml = await init.text();
-    const upload_id = /<UploadId>([^<]+)<\/UploadId>/.exec(init_xml)?.[1];
+    if (upload_id) {
+        // go on with an upload started before, if it is still there
+        const list = await aws.fetch(
+            `${obj_url}?uploadId=${encodeURIComponent(upload_id)}&max-parts=1`,
+            { method: 'GET' }
+        );
+        const list_xml = await list.text();
+        if (list.status === 404) {
+            return res.not_found(`Upload ${upload_id} not found`)
+        } else if (!list.ok) {
+            return res.s3_error(`Status ${list.status} from S3`, { xml: list_xml })
+        }
+    } else {
+        // init multipart upload
+        const init = await aws.fetch(
+            `${obj_url}?uploads`,
+            { method: 'POST' }
+        );
+        const init_xml = await init.text();
+        upload_id = /<UploadId>([^<]+)<\/UploadId>/.exec(init_xml)?.[1];
+    }
 
     const urls = [];
     for (let i = 1; i <= parts; i++) {
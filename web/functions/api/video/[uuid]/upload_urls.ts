@@ -0,0 +1,62 @@
+import { AwsClient } from 'aws4fetch'
+import * as v from 'valibot'
+
+import { obj_urls } from '@flib/objects'
+import { video_by_uuid_with_hash } from '@flib/queries'
+import { get_req_body } from '@flib/requests'
+import { res } from '@flib/responses'
+import { Env } from '@flib/types'
+
+const MAX_PARTS = 10000
+
+const ReqBody = v.object({
+    upload_id: v.string(),
+    part_numbers: v.array(v.pipe(v.number(), v.integer(), v.minValue(1), v.maxValue(MAX_PARTS))),
+    restricted_hash: v.nullish(v.string()),
+})
+
+// Sign the URLs of some parts of an upload again, once those handed out by
+// upload_start are about to expire.
+export const onRequestPost: PagesFunction<Env> = async (context) => {
+    const uuid = context.params.uuid as string
+
+    const req_body = await get_req_body(context.request, ReqBody)
+    if (!req_body.success) {
+        return res.unprocessable_entity("Body verification failed", req_body.issues)
+    }
+    const { upload_id, part_numbers, restricted_hash } = req_body.output
+
+    const { success, video, error } = await video_by_uuid_with_hash(context.env.DB, uuid)
+    if (!success) {
+        return res.db_transaction_error(error)
+    }
+    if (!video) {
+        return res.not_found(`Video ${uuid} not found`)
+    }
+    if (video.restricted && video.restricted_hash != restricted_hash) {
+        return res.forbidden("Invalid hash for restricted video")
+    }
+
+    const aws = new AwsClient({
+        accessKeyId: context.env.S3_KEY_ID,
+        secretAccessKey: context.env.S3_KEY
+    });
+
+    const obj_url = obj_urls.video(context.env, video)
+
+    const urls = [];
+    for (const i of part_numbers) {
+        const url = new URL(obj_url)
+        url.searchParams.set("partNumber", i.toString());
+        url.searchParams.set("uploadId", upload_id);
+
+        const signed = await aws.sign(url.href, {
+            method: "PUT",
+            aws: { signQuery: true }
+        });
+
+        urls.push(signed.url);
+    }
+
+    return res.ok({ urls })
+}